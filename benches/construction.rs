@@ -0,0 +1,33 @@
+//! Ring constructions per second with each mapping backend. Run with
+//! `cargo bench --bench construction`.
+
+use borrow_checker_demo::ringbuf::{BackendKind, RingBuf};
+use std::time::{Duration, Instant};
+
+const RUN_FOR: Duration = Duration::from_secs(1);
+
+fn constructions_per_sec(backend: BackendKind) -> f64 {
+    let builder = RingBuf::builder().backend(backend);
+    let start = Instant::now();
+    let mut count = 0u64;
+    while start.elapsed() < RUN_FOR {
+        for _ in 0..100 {
+            std::hint::black_box(builder.build().expect("Construction failed."));
+        }
+        count += 100;
+    }
+    count as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    // Warm up the page tables and the allocator.
+    constructions_per_sec(BackendKind::Reserve);
+
+    let reserve = constructions_per_sec(BackendKind::Reserve);
+    let double = constructions_per_sec(BackendKind::DoubleMap);
+    println!("Reserve:   {reserve:>10.0} constructions/s");
+    println!(
+        "DoubleMap: {double:>10.0} constructions/s ({:+.1}%)",
+        (double / reserve - 1.0) * 100.0
+    );
+}
@@ -0,0 +1,39 @@
+//! 8-byte records per second through `write` and through `push_bytes_small`. Run with
+//! `cargo bench --bench small_writes`.
+
+use borrow_checker_demo::ringbuf::RingBuf;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const RUN_FOR: Duration = Duration::from_secs(1);
+const BATCH: u64 = 256;
+
+fn records_per_sec(mut push: impl FnMut(&mut RingBuf, [u8; 8])) -> f64 {
+    let mut ring = RingBuf::new(1).expect("Construction failed.");
+    let start = Instant::now();
+    let mut count = 0u64;
+    while start.elapsed() < RUN_FOR {
+        for i in 0..BATCH {
+            push(&mut ring, black_box(i.to_le_bytes()));
+        }
+        ring.consume(ring.len()).expect("Consume failed.");
+        count += BATCH;
+    }
+    count as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let write = |ring: &mut RingBuf, record: [u8; 8]| ring.write(&record).expect("Fits.");
+    let small = |ring: &mut RingBuf, record| ring.push_bytes_small(record).expect("Fits.");
+
+    // Warm up the mapping and the caches.
+    records_per_sec(write);
+
+    let generic = records_per_sec(write);
+    let fast = records_per_sec(small);
+    println!("write:            {generic:>12.0} records/s");
+    println!(
+        "push_bytes_small: {fast:>12.0} records/s ({:+.1}%)",
+        (fast / generic - 1.0) * 100.0
+    );
+}
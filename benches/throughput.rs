@@ -0,0 +1,71 @@
+//! Bytes per second through a produce/consume loop of 64-byte and 4 KB messages, for `RingBuf`
+//! and for a `VecDeque<u8>` doing the same job. Run with `cargo bench --bench throughput`.
+
+use borrow_checker_demo::ringbuf::RingBuf;
+use std::collections::VecDeque;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const RUN_FOR: Duration = Duration::from_secs(1);
+const RING_PAGES: usize = 16;
+
+/// Writes messages of `size` bytes until the ring is full, then reads them all back, over and
+/// over, so every offset ends up straddling the wrap.
+fn ring_bytes_per_sec(size: usize) -> f64 {
+    let mut ring = RingBuf::new(RING_PAGES).expect("Construction failed.");
+    let msg = vec![0xa5; size];
+    let mut out = vec![0; size];
+    let start = Instant::now();
+    let mut moved = 0u64;
+    while start.elapsed() < RUN_FOR {
+        while ring.write(black_box(&msg)).is_ok() {}
+        while ring.read_exact_into(&mut out).is_ok() {
+            black_box(&out);
+            moved += size as u64;
+        }
+    }
+    moved as f64 / start.elapsed().as_secs_f64()
+}
+
+/// The same loop over a `VecDeque` of the same capacity.
+fn vecdeque_bytes_per_sec(size: usize) -> f64 {
+    let cap = RingBuf::new(RING_PAGES)
+        .expect("Construction failed.")
+        .capacity();
+    let mut deque = VecDeque::with_capacity(cap);
+    let msg = vec![0xa5; size];
+    let mut out = vec![0; size];
+    let start = Instant::now();
+    let mut moved = 0u64;
+    while start.elapsed() < RUN_FOR {
+        while deque.len() + size <= cap {
+            deque.extend(black_box(&msg));
+        }
+        while deque.len() >= size {
+            let (front, back) = deque.as_slices();
+            let split = front.len().min(size);
+            out[..split].copy_from_slice(&front[..split]);
+            out[split..].copy_from_slice(&back[..size - split]);
+            deque.drain(..size);
+            black_box(&out);
+            moved += size as u64;
+        }
+    }
+    moved as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    // Warm up the mapping and the caches.
+    ring_bytes_per_sec(64);
+
+    for size in [64, 4096] {
+        let ring = ring_bytes_per_sec(size);
+        let deque = vecdeque_bytes_per_sec(size);
+        println!(
+            "{size:>5}-byte messages: RingBuf {:>8.2} GB/s, VecDeque {:>8.2} GB/s ({:+.1}%)",
+            ring / 1e9,
+            deque / 1e9,
+            (ring / deque - 1.0) * 100.0
+        );
+    }
+}
@@ -0,0 +1,11 @@
+use cfg_aliases::cfg_aliases;
+
+fn main() {
+    cfg_aliases! {
+        // The memfd and mirrored mapping behind rings, and everything else that needs fds. Without
+        // it only `BackendKind::Heap` rings exist.
+        mapped: { all(unix, not(ringbuf_heap_only)) },
+        // `Consumer::data_fd` and `Producer::space_fd`.
+        eventfd: { all(mapped, any(target_os = "linux", target_os = "android")) },
+    }
+}
@@ -1,11 +1,14 @@
-mod ringbuf;
+pub mod ringbuf;
 
+// The demo structs only ever get printed, which dead-code analysis doesn't count as a read.
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct Bundle {
     s: String,
     v: usize,
 }
 
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct BiggerBundle {
     b: Bundle,
@@ -25,6 +28,7 @@ pub fn move_within_func() {
     println!("bb: {bb:#?}");
 }
 
+#[allow(unused_mut)] // `mut` is for the commented-out `mutate_bundle` call.
 pub fn move_into_other_func() {
     // `String` is a heap-allocated string.
     let mut b = Bundle { s: String::from("Dear Pesky Plumbers..."), v: 42 };
@@ -51,6 +55,7 @@ pub fn mutate_bundle(b: &mut Bundle) {
     b.v = 0x33ccff;
 }
 
+#[allow(unused_variables)]
 pub fn aliasing_enforced() {
     let mut x = 12;
     // let ref_x1 = &x;
@@ -89,6 +94,7 @@ pub fn alias_analyzed(a: &mut usize, b: &mut usize) -> usize {
 // This struct is "generic" over the lifetime of `string_view` (the pointer, not its pointee.)
 // This constrains `NeedExplicitLifetime` s.t. it does not outlive the string which `string_view`
 // points to.
+#[allow(dead_code)]
 pub struct NeedExplicitLifetime<'a> {
     string_view: &'a str,
 }
@@ -4,239 +4,5453 @@
 //!
 //! Correct me if I'm wrong, but I think this primarily means vectorized copies.
 
-use nix::{
-    sys::{
-        memfd::{memfd_create, MemFdCreateFlag},
-        mman::{mmap, mmap_anonymous, munmap, MapFlags, ProtFlags},
-    },
-    unistd::ftruncate,
-};
+mod ack;
+mod broadcast;
+mod builder;
+mod capture;
+mod chain;
+mod clock;
+mod crc;
+#[cfg(not(mapped))]
+mod errno;
+#[cfg(mapped)]
+mod fault;
+mod group;
+mod index;
+mod leak;
+mod mirror;
+mod pod;
+mod rate;
+mod shmem;
+mod slot;
+#[cfg(mapped)]
+mod socket;
+#[cfg(feature = "linux-splice")]
+mod splice;
+mod spsc;
+mod sync;
+mod tap;
+mod typed;
+mod typetag;
+
+// Splicing moves bytes through the ring's fd, which heap rings don't have.
+#[cfg(all(feature = "linux-splice", not(mapped)))]
+compile_error!("The `linux-splice` feature needs the mapped backend, so a Unix target without `ringbuf_heap_only`.");
+
+pub use ack::{AckingConsumer, Delivery};
+pub use broadcast::ReadCursor;
+pub use builder::RingBufBuilder;
+pub use capture::{CaptureHandle, Framing};
+pub use chain::ChainedReader;
+pub use clock::{Clock, MockClock, SystemClock};
+#[cfg(not(mapped))]
+pub use errno::Errno;
+#[cfg(mapped)]
+pub use fault::OsOp;
+#[cfg(all(mapped, any(test, feature = "fault-inject")))]
+pub use fault::{clear_injected_failures, inject_failure};
+pub use group::RingGroup;
+#[cfg(any(test, feature = "leak-check"))]
+pub use leak::{assert_no_leaks, LeakCheck, Live};
+pub use mirror::BackendKind;
+pub use pod::Pod;
+pub use rate::{RateLimitedProducer, ThrottleStats};
+pub use shmem::FdSource;
+pub use slot::{SlotConsumer, SlotIndex, SlotProducer, SlotRing};
+#[cfg(mapped)]
+pub use socket::{RingListener, RingStream};
+pub use spsc::{BlockingConsumer, BlockingProducer, Consumer, Producer};
+pub use sync::{FullPolicy, SharedRingBuf, SharedWriter};
+pub use tap::RingTap;
+pub use typed::TypedRingBuf;
+
+use mirror::{MapOptions, Mirror};
+#[cfg(mapped)]
+use nix::errno::Errno;
 use std::{
-    borrow::Borrow,
+    borrow::Cow,
+    collections::VecDeque,
     error::Error as ErrTrait,
-    ffi::{c_void, CStr},
     fmt::Display,
+    io::{self, BufRead, IoSlice, IoSliceMut, Read, Write},
+    mem::MaybeUninit,
     num::NonZeroUsize,
-    os::fd::OwnedFd,
+    ops::{Bound, Deref, Range, RangeBounds},
+    os::fd::BorrowedFd,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
 };
 
+/// The system's page size, which ring capacities are a multiple of: 4 KiB on most machines, but
+/// 16 KiB or 64 KiB on some arm64 and POWER kernels. Looked up once, on first use.
+pub fn page_size() -> Result<usize> {
+    static PAGE_SIZE: OnceLock<Option<usize>> = OnceLock::new();
+    PAGE_SIZE
+        .get_or_init(|| {
+            let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+            usize::try_from(size)
+                .ok()
+                .filter(|size| size.is_power_of_two())
+        })
+        .ok_or_else(|| BufError::UnknownPageSize.into())
+}
+
+/// How many write timestamps `oldest_data_age` keeps around. Once they're all in use, further
+/// writes go unmarked until the reader catches up, which can only make the reported age too old,
+/// never too young.
+const MAX_AGE_MARKS: usize = 64;
+
+/// What `RingBufBuilder::debug_fill` rings keep their free space filled with.
+const POISON: u8 = 0xa5;
+
+/// How many bytes of nothing but `POISON` the `poison` feature takes as a sign of bytes that were
+/// never written, rather than a coincidence.
+const SUSPICIOUS_POISON_RUN: usize = 16;
+
 /// A raw-bytes ring buffer.
+///
+/// A ring can be moved to another thread, but not shared between threads: it isn't `Sync`. Put
+/// it behind a `Mutex` for that, or split it with `spsc` if one thread writes and another reads.
 pub struct RingBuf {
     // Could we do *mut [u8]? Rust seems to understand it as a type.
     // I also thought I saw a stdlib type that understands it, but we'd still
     // need `buf_size` since the "slice length" would have to be 2*`buf_size` to prevent
     // indexing past the 4K boundary from panicking. Though I suppose I could just do `buf.len() >> 1`.
     buf: *mut u8,
-    buf_size: NonZeroUsize,
-    contents_size: usize,
-    _mem_fd: OwnedFd,
+    // Zero for the unmapped placeholder built by `Default`.
+    buf_size: usize,
+    // Owns what `buf` points into; `buf` and `buf_size` are just cached copies. `None` for the
+    // placeholder.
+    mirror: Option<Mirror>,
+    // For lazy rings that haven't been mapped yet, what to map on first use.
+    lazy: Option<(NonZeroUsize, MapOptions)>,
     head: usize,
     tail: usize,
+    // Size of the window last handed out by `writable_slice`, so `commit_write` can catch misuse.
+    write_window: Option<usize>,
+    // In zeroize or debug-fill mode, how many consumed bytes right behind the head still need
+    // scrubbing. Always the tail end of the free space.
+    unscrubbed: usize,
+    // Running totals, used as positions in the byte stream that don't wrap. Their difference is
+    // the number of pending bytes, which tells a full ring from an empty one when `head == tail`.
+    bytes_written: u64,
+    bytes_read: u64,
+    // (stream position, time) of recent writes, oldest first. The front mark is always at or
+    // before the oldest unread byte.
+    age_marks: VecDeque<(u64, Instant)>,
+    clock: Arc<dyn Clock>,
+    interval: IntervalStats,
+    // What `stats` reports since the last `reset_stats`.
+    counters: Counters,
+    // Whether the typed API tags values with their type. See `RingBufBuilder::typed_checks`.
+    typed_checks: bool,
+    // Whether `write` evicts instead of failing. See `RingBufBuilder::overwrite`.
+    overwrite: bool,
+    // Shared with the cursors from `subscribe`, if there have been any since the last detach.
+    subscribers: Option<Arc<broadcast::Subscribers>>,
+    // The pipe `splice_to_pipe` last fed, while it may still reference consumed bytes.
+    #[cfg(feature = "linux-splice")]
+    pipe_hold: Option<splice::PipeHold>,
 }
 
+// SAFETY: `buf` points into the mapping (or heap views) that `mirror` owns, not into the struct,
+// so moving or `mem::swap`ping a `RingBuf` leaves it valid, and neither the mapping nor the memfd
+// is tied to the thread that made it. Everything else is plain owned state or `Send` already.
+// `Sync` is left off: it would need every `&self` method checked for writes through `buf`, and
+// `Mirror::sync` on the heap backend is one.
+unsafe impl Send for RingBuf {}
+
 impl RingBuf {
+    /// A ring of `num_pages` pages. Fails with `BufError::ZeroCapacity` for zero pages.
     pub fn new(num_pages: usize) -> Result<Self> {
-        let num_pages =
-            NonZeroUsize::new(num_pages).expect("Num pages per buffer must be at least zero!");
-        let page_size = 4096; // TODO: replace with actual page-size lookup fn
+        let buf_size = num_pages
+            .checked_mul(page_size()?)
+            .ok_or(BufError::CapacityOverflow)?;
+        Self::with_capacity(buf_size)
+    }
+
+    /// A ring of at least `min_bytes`, rounded up to whole pages. Fails with
+    /// `BufError::ZeroCapacity` for zero bytes, and with `BufError::CapacityOverflow` if rounding
+    /// up overflows.
+    pub fn with_capacity(min_bytes: usize) -> Result<Self> {
+        let buf_size = min_bytes
+            .checked_next_multiple_of(page_size()?)
+            .ok_or(BufError::CapacityOverflow)?;
+        let buf_size = NonZeroUsize::new(buf_size).ok_or(BufError::ZeroCapacity)?;
+        Ok(Self::from_mirror(Mirror::new(buf_size)?))
+    }
+
+    /// A ring just big enough for `data`, rounded up to whole pages (one for an empty slice),
+    /// with `data` already written to it. Handy for tests and for replaying captures.
+    pub fn from_slice(data: &[u8]) -> Result<Self> {
+        let mut ring = Self::with_capacity(data.len().max(1))?;
+        ring.write(data)?;
+        Ok(ring)
+    }
+
+    /// For when the defaults of `new` won't do.
+    pub fn builder() -> RingBufBuilder {
+        RingBufBuilder::default()
+    }
+
+    /// An unmapped ring that will map `size` bytes with `options` on first use.
+    fn lazy(size: NonZeroUsize, options: MapOptions) -> Self {
+        Self {
+            lazy: Some((size, options)),
+            ..Self::default()
+        }
+    }
+
+    /// Maps a lazy ring now rather than on its first write, so construction errors surface here.
+    /// If mapping fails the ring stays lazy and the next attempt tries again. Does nothing for
+    /// rings that are already mapped, or the `Default` placeholder.
+    pub fn ensure_mapped(&mut self) -> Result<()> {
+        let Some((size, options)) = self.lazy.clone() else {
+            return Ok(());
+        };
+        let mirror = Mirror::with_options(size, options)?;
+        self.buf = mirror.ptr;
+        self.buf_size = mirror.size;
+        self.mirror = Some(mirror);
+        self.lazy = None;
+        self.debug_check_invariants();
+        Ok(())
+    }
+
+    fn from_mirror(mirror: Mirror) -> Self {
+        Self {
+            buf: mirror.ptr,
+            buf_size: mirror.size,
+            mirror: Some(mirror),
+            lazy: None,
+            head: 0,
+            tail: 0,
+            write_window: None,
+            unscrubbed: 0,
+            bytes_written: 0,
+            bytes_read: 0,
+            age_marks: VecDeque::with_capacity(MAX_AGE_MARKS),
+            clock: Arc::new(SystemClock),
+            interval: IntervalStats::starting_at(Instant::now(), 0),
+            counters: Counters::default(),
+            typed_checks: cfg!(feature = "typed-checks"),
+            overwrite: false,
+            subscribers: None,
+            #[cfg(feature = "linux-splice")]
+            pipe_hold: None,
+        }
+    }
+
+    /// Moves the pending bytes into a fresh, smaller mapping of at least `new_min_capacity`
+    /// bytes (rounded up to whole pages, or huge pages if the ring was built with them) and
+    /// releases the old one. Asking for less than `len()` is an error rather than a truncation,
+    /// and if building the new mapping fails the ring is left exactly as it was. Does nothing if
+    /// the ring is already that small.
+    ///
+    /// Any window from `writable_slice` is forgotten, so it has to be asked for again.
+    pub fn shrink_to(&mut self, new_min_capacity: usize) -> Result<()> {
+        if new_min_capacity < self.contents_size() {
+            return Err(BufError::NotEnoughSpace {
+                requested: self.contents_size(),
+                available: new_min_capacity,
+            }
+            .into());
+        }
+        if let Some((size, options)) = &mut self.lazy {
+            let granularity = options.granularity()?;
+            let new_size = new_min_capacity.div_ceil(granularity).max(1) * granularity;
+            *size = (*size).min(NonZeroUsize::new(new_size).unwrap());
+            return Ok(());
+        }
+        let Some(options) = self.mirror.as_ref().map(|m| m.options.clone()) else {
+            return Ok(());
+        };
+        let granularity = options.granularity()?;
+        let new_size = new_min_capacity.div_ceil(granularity).max(1) * granularity;
+        if new_size >= self.buf_size {
+            return Ok(());
+        }
+        self.move_to(new_size, options)
+    }
+
+    /// Moves the pending bytes into a fresh, bigger mapping of `new_num_pages` pages (rounded up
+    /// to whole huge pages if the ring was built with them) and releases the old one. If
+    /// building the new mapping fails the ring is left exactly as it was. Does nothing if the
+    /// ring is already that big; see `shrink_to` for going the other way.
+    ///
+    /// The `Default` placeholder gets a mapping of its own, with the default options. Any window
+    /// from `writable_slice` is forgotten, so it has to be asked for again.
+    pub fn grow(&mut self, new_num_pages: usize) -> Result<()> {
+        let options = match &self.lazy {
+            Some((_, options)) => options.clone(),
+            None => self.options().clone(),
+        };
+        let granularity = options.granularity()?;
+        let new_size = new_num_pages
+            .checked_mul(page_size()?)
+            .and_then(|size| size.checked_next_multiple_of(granularity))
+            .ok_or(BufError::CapacityOverflow)?;
+        if let Some((size, _)) = &mut self.lazy {
+            *size = (*size).max(NonZeroUsize::new(new_size).ok_or(BufError::ZeroCapacity)?);
+            return Ok(());
+        }
+        if new_size <= self.buf_size {
+            return Ok(());
+        }
+        self.move_to(new_size, options)
+    }
+
+    /// Builds a new mirror of `new_size`, a nonzero multiple of `options.granularity()` that fits
+    /// what's pending, and moves the pending bytes to the start of it. The ring is untouched if
+    /// that fails.
+    fn move_to(&mut self, new_size: usize, options: MapOptions) -> Result<()> {
+        let new_mirror = Mirror::with_options(NonZeroUsize::new(new_size).unwrap(), options)?;
         unsafe {
-            let buf_size = NonZeroUsize::new_unchecked(num_pages.get() * page_size);
-            let map_size = NonZeroUsize::new_unchecked(buf_size.get() * 2);
-            // Yes Rust, I trivially know this is sound.
-            let buf_name = &CStr::from_bytes_with_nul(b"ringbuf\0".as_slice()).unwrap();
-            // I forget why we need the FD to do this trick.
-            // Apparently the file system guarantees we have this page unperturbed?
-            let mem_fd = memfd_create(buf_name, MemFdCreateFlag::empty())?;
-            ftruncate(mem_fd.borrow(), buf_size.get() as i64)?;
-
-            // I don't quite trust that if any of these fails everything will be sound.
-            // I would prefer to have a safe interface if possible.
-            let buf = mmap_anonymous(None, map_size, ProtFlags::PROT_NONE, MapFlags::MAP_PRIVATE)?
-                .as_ptr() as *mut u8;
-            mmap(
-                Some(NonZeroUsize::new_unchecked(buf as usize)),
-                buf_size,
-                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-                MapFlags::MAP_SHARED | MapFlags::MAP_FIXED,
-                mem_fd.borrow(),
-                0,
-            )?;
-            mmap(
-                Some(NonZeroUsize::new_unchecked(buf.add(buf_size.get()) as usize)),
-                buf_size,
-                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-                MapFlags::MAP_SHARED | MapFlags::MAP_FIXED,
-                mem_fd.borrow(),
-                0,
-            )?;
-
-            Ok(Self {
-                buf,
-                buf_size,
-                contents_size: 0,
-                _mem_fd: mem_fd,
-                head: 0,
-                tail: 0,
-            })
+            std::ptr::copy_nonoverlapping(
+                self.buf.add(self.head),
+                new_mirror.ptr,
+                self.contents_size(),
+            );
+            new_mirror.sync(0, self.contents_size());
         }
+        self.detach_cursors();
+        // The pipe keeps its own references to the old pages.
+        #[cfg(feature = "linux-splice")]
+        {
+            self.pipe_hold = None;
+        }
+        self.buf = new_mirror.ptr;
+        self.buf_size = new_size;
+        // Unmaps the old region.
+        self.mirror = Some(new_mirror);
+        self.head = 0;
+        self.tail = index::advance(0, self.contents_size(), new_size);
+        self.write_window = None;
+        self.unscrubbed = 0;
+        self.debug_check_invariants();
+        Ok(())
+    }
+
+    /// `shrink_to(self.len())`: the smallest ring that still holds what's pending.
+    pub fn shrink_to_fit(&mut self) -> Result<()> {
+        self.shrink_to(self.contents_size())
     }
 
+    /// Appends all of `raw`, or fails with `BufError::NotEnoughSpace` and writes nothing. Rings
+    /// built with `RingBufBuilder::overwrite` evict the oldest bytes to make room instead, as
+    /// `write_overwriting` does.
     pub fn write(&mut self, raw: &[u8]) -> Result<()> {
-        if raw.len() > self.buf_size.get() - self.contents_size {
-            return Err(BufError::TooSmall.into());
+        if self.overwrite {
+            return self.write_overwriting(raw).map(drop);
         }
+        self.check_fits(raw.len())?;
 
         unsafe {
-            std::ptr::copy(raw.as_ptr(), self.buf.add(self.tail), raw.len());
-            self.tail = (self.tail + raw.len()) % self.buf_size.get();
-            self.contents_size += raw.len();
+            self.copy_in(raw);
             Ok(())
         }
     }
 
-    // Is it possible to convey to the borrow checker which regions of `buf`
-    // are "borrowed" and which ones are not?
-    pub fn read(&mut self, num_bytes: usize) -> Result<&mut [u8]> {
-        if num_bytes > self.contents_size {
-            return Err(BufError::TooSmall.into());
+    /// Writes as much of `raw` as fits right now and returns how much that was: zero for a full
+    /// ring, and for a lazy ring whose mapping can't be made, where `write` would fail.
+    pub fn write_up_to(&mut self, raw: &[u8]) -> usize {
+        if self.ensure_mapped().is_err() {
+            return 0;
+        }
+        self.reclaim();
+        let n = raw.len().min(self.free_space());
+        if n > 0 {
+            self.write(&raw[..n]).expect("Only what fits.");
+        }
+        n
+    }
+
+    /// `write` that makes room by evicting the oldest pending bytes instead of failing, for
+    /// keeping only the most recent `capacity()` bytes of a log. Of a `raw` longer than the whole
+    /// ring, only the last `capacity()` bytes are kept. Returns how many pending bytes were
+    /// evicted, not counting the front of an oversized `raw`.
+    ///
+    /// Evicted bytes go the same way as consumed ones: scrubbed if the ring was built to, and
+    /// counted in `IntervalStats::bytes_out`. A ring with cursors from `subscribe` never evicts
+    /// what one of them hasn't read, and fails like `write` instead.
+    pub fn write_overwriting(&mut self, raw: &[u8]) -> Result<usize> {
+        self.ensure_mapped()?;
+        if self.buf_size == 0 {
+            return Err(BufError::ZeroCapacity.into());
+        }
+        self.reclaim();
+        let raw = &raw[raw.len().saturating_sub(self.buf_size)..];
+        let evicted = raw.len().saturating_sub(self.free_space());
+        if evicted > 0 && self.has_cursors() {
+            return Err(BufError::NotEnoughSpace {
+                requested: raw.len(),
+                available: self.free_space(),
+            }
+            .into());
         }
+        self.consume(evicted)?;
+        self.check_fits(raw.len())?;
+        unsafe { self.copy_in(raw) };
+        Ok(evicted)
+    }
 
+    /// `write` for an `N`-byte value, `N` in `1..=16`, for streams of tiny records. The free-space
+    /// check is a single branch and the copy is one fixed-size store; the mirror means it never
+    /// has to be split at the wrap. Anything unusual (a full, lazy or zero-capacity ring, or
+    /// consumed bytes waiting to be scrubbed) goes through `write` instead, so this behaves
+    /// exactly like it.
+    #[inline]
+    pub fn push_bytes_small<const N: usize>(&mut self, bytes: [u8; N]) -> Result<()> {
+        const { assert!(N >= 1 && N <= 16, "push_bytes_small is for 1 to 16 bytes") };
+        if N > self.free_space() || self.unscrubbed != 0 {
+            return self.write(&bytes);
+        }
         unsafe {
-            let view = std::slice::from_raw_parts_mut(self.buf.add(self.head), num_bytes);
-            self.head = (self.head + num_bytes) % self.buf_size.get();
-            self.contents_size -= num_bytes;
-            Ok(view)
+            self.buf
+                .add(self.tail)
+                .cast::<[u8; N]>()
+                .write_unaligned(bytes);
+            self.advance_write(N);
+        }
+        Ok(())
+    }
+
+    /// Routes a write of up to 16 bytes through `push_bytes_small`. Only worth calling where
+    /// `raw.len()` is known at compile time, so the match folds away.
+    #[inline]
+    fn push_small(&mut self, raw: &[u8]) -> Result<()> {
+        macro_rules! dispatch {
+            ($($n:literal)*) => {
+                match raw.len() {
+                    $($n => self.push_bytes_small::<$n>(raw.try_into().expect("Length matched.")),)*
+                    _ => self.write(raw),
+                }
+            };
+        }
+        dispatch!(1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16)
+    }
+
+    /// Writes every slice in `parts` back-to-back as if they were one big write. Either all of
+    /// them make it into the buffer or none of them do, so a frame assembled from several pieces
+    /// never shows up half-written.
+    pub fn write_all_slices(&mut self, parts: &[&[u8]]) -> Result<()> {
+        self.write_parts(parts)
+    }
+
+    /// Writes bytes from `iter` until it runs out or the ring is full, and returns how many that
+    /// was. Nothing is pulled from `iter` once the ring is full, so whatever didn't fit is still
+    /// there to pick up later. The bytes go straight into the free space, which the mirror keeps
+    /// contiguous, and are published in one go at the end.
+    pub fn try_extend(&mut self, iter: impl IntoIterator<Item = u8>) -> Result<usize> {
+        self.check_fits(0)?;
+        let window = unsafe {
+            std::slice::from_raw_parts_mut(
+                self.data_ptr().add(self.write_offset()),
+                self.free_space(),
+            )
+        };
+        let mut n = 0;
+        // The window goes first, so `zip` stops without taking a byte there's no room for.
+        for (slot, byte) in window.iter_mut().zip(iter) {
+            *slot = byte;
+            n += 1;
+        }
+        unsafe { self.advance_write(n) };
+        Ok(n)
+    }
+
+    /// `write_all_slices` for the `IoSlice`s that socket and `std::io` code tends to have.
+    pub fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<()> {
+        self.write_parts(bufs)
+    }
+
+    fn write_parts<P: Deref<Target = [u8]>>(&mut self, parts: &[P]) -> Result<()> {
+        let total = parts
+            .iter()
+            .try_fold(0usize, |acc, part| acc.checked_add(part.len()));
+        self.check_fits(total.unwrap_or(usize::MAX))?;
+
+        let mut offset = 0;
+        for part in parts {
+            unsafe { self.copy_at(offset, part) };
+            offset += part.len();
         }
+        unsafe { self.advance_write(offset) };
+        Ok(())
     }
 
-    pub fn write_typed<T>(&mut self, value: T) -> Result<()> {
+    /// Hands out exactly `n` bytes of free space to be filled in place. Nothing becomes visible
+    /// to readers until `commit_write` says how much of the window was actually used, which
+    /// suits callers that only learn whether the fill succeeded afterwards (e.g. FFI decoders).
+    ///
+    /// The mirror mapping means this window is contiguous even when it crosses the end of the
+    /// buffer.
+    pub fn writable_slice(&mut self, n: usize) -> Result<&mut [u8]> {
+        self.check_fits(n)?;
+
+        self.write_window = Some(n);
         unsafe {
-            let as_bytes = as_u8_slice(&value);
-            self.write(as_bytes)
+            Ok(std::slice::from_raw_parts_mut(
+                self.data_ptr().add(self.write_offset()),
+                n,
+            ))
         }
     }
 
-    pub fn read_typed<T>(&mut self) -> Result<&mut T> {
-        let raw_struct = self.read(size_of::<T>())?;
-        unsafe { std::mem::transmute(raw_struct) }
+    /// The free space as two slices, the way `as_slices` splits the pending bytes: from the tail
+    /// to the end of the ring, then from its start. Together they're `free_space()` bytes, and
+    /// `commit_write` publishes however many of them were filled, in order, as it does for
+    /// `writable_slice`. Both are empty for a lazy ring that can't be mapped.
+    pub fn spare_capacity_slices(&mut self) -> (&mut [u8], &mut [u8]) {
+        if self.ensure_mapped().is_err() {
+            return (&mut [], &mut []);
+        }
+        self.reclaim();
+        self.scrub();
+        let free = self.free_space();
+        self.write_window = Some(free);
+        let spare = unsafe { std::slice::from_raw_parts_mut(self.buf.add(self.tail), free) };
+        spare.split_at_mut(free.min(self.buf_size - self.tail))
     }
-}
 
-impl Drop for RingBuf {
-    fn drop(&mut self) {
-        // munmap the buffer.
-        // Not sure why you wouldn't keep a structure like this around for the duration of the
-        // whole program but you know best.
+    /// Publishes the first `n` bytes of the window handed out by the last `writable_slice`.
+    ///
+    /// Committing without a preceding `writable_slice`, after another write has moved the tail,
+    /// or committing more than was asked for are all bugs in the caller and trip debug
+    /// assertions. Committing more than the free space panics in every build, since that would
+    /// corrupt the buffer.
+    pub fn commit_write(&mut self, n: usize) {
+        let window = self.write_window;
+        debug_assert!(
+            window.is_some(),
+            "commit_write called without a preceding writable_slice"
+        );
+        debug_assert!(
+            n <= window.unwrap_or(0),
+            "committed {n} bytes from a window of {}",
+            window.unwrap_or(0)
+        );
+        assert!(n <= self.free_space(), "commit_write past the free space");
+
+        self.close_write_window(n);
+        unsafe { self.advance_write(n) };
+        self.debug_check_invariants();
+    }
+
+    /// Returns a handle for taking any number of simultaneous, non-consuming views into the
+    /// pending data. Every view borrows the ring immutably, so nothing can be written or read
+    /// while one is alive:
+    ///
+    /// ```compile_fail,E0502
+    /// # use borrow_checker_demo::ringbuf::RingBuf;
+    /// let mut buf = RingBuf::new(1).unwrap();
+    /// buf.write(b"header|payload").unwrap();
+    /// let header = buf.viewer().view(..6).unwrap();
+    /// buf.write(b"more").unwrap(); // Would clobber what `header` might be looking at.
+    /// assert_eq!(header, b"header");
+    /// ```
+    pub fn viewer(&self) -> RingViewer<'_> {
+        RingViewer {
+            pending: self.pending(),
+        }
+    }
+
+    /// Everything pending as one slice, even across the wrap, without consuming it. Look for a
+    /// complete message in it, then `consume` however much that turned out to be.
+    pub fn peek(&self) -> &[u8] {
+        let pending = self.pending();
+        self.assert_not_poison(pending);
+        pending
+    }
+
+    /// The `len` pending bytes starting `offset` bytes past the oldest one, without consuming
+    /// anything.
+    pub fn peek_at(&self, offset: usize, len: usize) -> Result<&[u8]> {
+        let end = offset.saturating_add(len);
+        self.pending().get(offset..end).ok_or_else(|| {
+            BufError::NotEnoughData {
+                requested: end,
+                available: self.contents_size(),
+            }
+            .into()
+        })
+    }
+
+    /// Iterates over the pending bytes without consuming them. The iterator is cheap to clone, so
+    /// a scanner can make several passes; follow up with `consume` to drop what it matched.
+    pub fn peek_iter(&self) -> PeekIter<'_> {
+        self.pending().iter().copied()
+    }
+
+    /// Removes the oldest `n` pending bytes, yielding them one by one, or fails with
+    /// `BufError::NotEnoughData` if fewer are pending. Like `Vec::drain`, all `n` are gone once the
+    /// iterator is dropped, yielded or not.
+    pub fn drain(&mut self, n: usize) -> Result<Drain<'_>> {
+        self.check_pending(n)?;
+        Ok(Drain {
+            ring: self,
+            front: 0,
+            back: n,
+            len: n,
+        })
+    }
+
+    /// Discards the oldest `n` pending bytes without looking at them.
+    pub fn consume(&mut self, n: usize) -> Result<()> {
+        self.check_pending(n)?;
+        unsafe { self.advance_read(n) };
+        self.scrub();
+        Ok(())
+    }
+
+    /// Copies as many pending bytes into `out` as fit and consumes them. Returns how many that
+    /// was, which is zero once the ring is empty.
+    pub fn read_up_to(&mut self, out: &mut [u8]) -> usize {
+        let n = out.len().min(self.contents_size());
+        out[..n].copy_from_slice(&self.pending()[..n]);
+        self.consume(n).expect("Only what's pending.");
+        n
+    }
+
+    /// `read_up_to` into storage that hasn't been initialized, like a `Vec`'s spare capacity, so
+    /// draining a big chunk doesn't mean zeroing the destination first. Copies
+    /// `min(dst.len(), len())` bytes into the start of `dst`, consumes them, and returns exactly
+    /// that prefix, now initialized; it's empty once the ring is. The rest of `dst` is neither
+    /// read nor written.
+    pub fn read_into_uninit<'a>(&mut self, dst: &'a mut [MaybeUninit<u8>]) -> &'a mut [u8] {
+        let n = dst.len().min(self.contents_size());
+        let dst = dst.as_mut_ptr().cast::<u8>();
+        unsafe { std::ptr::copy_nonoverlapping(self.pending().as_ptr(), dst, n) };
+        self.consume(n).expect("Only what's pending.");
+        // SAFETY: The first `n` bytes were just copied in.
+        unsafe { std::slice::from_raw_parts_mut(dst, n) }
+    }
+
+    /// `read_up_to` that scatters the pending bytes across `bufs` in order, filling each before
+    /// moving on to the next. Returns how many bytes that was in all.
+    pub fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> usize {
+        let mut n = 0;
+        for buf in bufs {
+            let rest = &self.pending()[n..];
+            let len = buf.len().min(rest.len());
+            buf[..len].copy_from_slice(&rest[..len]);
+            n += len;
+        }
+        self.consume(n).expect("Only what's pending.");
+        n
+    }
+
+    /// Copies exactly `out.len()` pending bytes into `out` and consumes them, or fails with
+    /// `BufError::NotEnoughData` and consumes nothing if fewer are pending. Unlike `read`, nothing
+    /// stays borrowed afterwards, so the ring can be written to straight away. (`split_to` does
+    /// the same into a new `Vec`.)
+    pub fn read_exact_into(&mut self, out: &mut [u8]) -> Result<()> {
+        self.check_pending(out.len())?;
+        self.read_up_to(out);
+        Ok(())
+    }
+
+    /// Removes the first `n` pending bytes and hands them back as an owned `Vec`, in one copy out
+    /// of the contiguous view.
+    pub fn split_to(&mut self, n: usize) -> Result<Vec<u8>> {
+        self.check_pending(n)?;
+        let owned = self.pending()[..n].to_vec();
+        unsafe { self.advance_read(n) };
+        self.scrub();
+        Ok(owned)
+    }
+
+    /// Removes everything pending and returns it as an owned `Vec`.
+    pub fn split_off_pending(&mut self) -> Vec<u8> {
+        let owned = self.pending().to_vec();
+        unsafe { self.advance_read(owned.len()) };
+        self.scrub();
+        owned
+    }
+
+    /// Which syscall sequence built the ring's mapping, or `None` for the `Default` placeholder.
+    pub fn backend_kind(&self) -> Option<BackendKind> {
+        self.mirror.as_ref().map(|m| m.backend)
+    }
+
+    /// Whether the ring lives in hugetlb pages, which it only does if it was built with
+    /// `RingBufBuilder::huge_pages` and didn't have to fall back to normal ones. A lazy ring says
+    /// whether it asked for them until it's mapped.
+    pub fn huge_pages(&self) -> bool {
+        match (&self.lazy, &self.mirror) {
+            (Some((_, options)), _) => options.fd.hugetlb,
+            (None, Some(mirror)) => mirror.options.fd.hugetlb,
+            (None, None) => false,
+        }
+    }
+
+    /// `RingBufBuilder::lock_memory` after the fact: locks the ring into memory and faults every
+    /// page in, mapping a lazy ring first. Fails with `BufError::MemoryLockLimit` over
+    /// `RLIMIT_MEMLOCK`, leaving the ring usable, just not locked. Does nothing for the `Default`
+    /// placeholder.
+    pub fn lock_memory(&mut self) -> Result<()> {
+        self.ensure_mapped()?;
+        match &mut self.mirror {
+            Some(mirror) => mirror.lock(),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the ring is locked into memory.
+    pub fn memory_locked(&self) -> bool {
+        self.mirror.as_ref().is_some_and(|mirror| mirror.locked)
+    }
+
+    /// What kind of fd the ring's memory lives in, or `None` if nothing is mapped yet or it's on
+    /// the heap. Anything but `FdSource::MemFd` means `memfd_create` wasn't available.
+    pub fn fd_source(&self) -> Option<FdSource> {
+        self.mirror.as_ref().and_then(Mirror::fd_source)
+    }
+
+    /// Maps the ring's memory a second time and returns a reader over it whose cursor starts at
+    /// the current head and moves independently of the ring's. Handy for watching traffic while
+    /// debugging; see `RingTap` for what it can and can't promise.
+    pub fn try_clone_reader(&self) -> Result<RingTap> {
+        RingTap::new(self)
+    }
+
+    /// A new `ReadCursor` for fanning the ring's bytes out to several readers, starting at the
+    /// current head. Lazy rings get mapped now.
+    ///
+    /// While it has cursors the ring's own head belongs to them: it moves up to the slowest
+    /// cursor on each write, so `len` and `free_space` only catch up then. Don't read from the
+    /// ring itself as well, which would free space a cursor still needs. Once the last cursor is
+    /// dropped the ring is an ordinary one again. `clear`, `wipe`, `swap_contents`, `split` and
+    /// anything that remaps the ring detach all its cursors, which read as empty from then on.
+    pub fn subscribe(&mut self) -> Result<ReadCursor> {
+        self.ensure_mapped()?;
+        let (written, read) = (self.bytes_written, self.bytes_read);
+        let subscribers = Arc::clone(
+            self.subscribers
+                .get_or_insert_with(|| Arc::new(broadcast::Subscribers::new(written, read))),
+        );
+        ReadCursor::new(self, subscribers)
+    }
+
+    /// Whether any cursor from `subscribe` is still alive.
+    fn has_cursors(&self) -> bool {
+        self.subscribers
+            .as_ref()
+            .is_some_and(|subscribers| subscribers.slowest().is_some())
+    }
+
+    /// Frees whatever every cursor from `subscribe` has read past, and whatever
+    /// `splice_to_pipe` put in a pipe that has since been read.
+    fn reclaim(&mut self) {
+        #[cfg(feature = "linux-splice")]
+        self.release_spliced();
+        let Some(slowest) = self.subscribers.as_ref().and_then(|s| s.slowest()) else {
+            return;
+        };
+        if slowest > self.bytes_read {
+            self.advance_head((slowest - self.bytes_read) as usize);
+        }
+    }
+
+    /// Cuts the ring's cursors off, for when their offsets into the mapping stop meaning anything.
+    fn detach_cursors(&mut self) {
+        if let Some(subscribers) = self.subscribers.take() {
+            subscribers.detach();
+        }
+    }
+
+    /// Splits the ring into a `Producer` and a `Consumer` that can go to two different threads
+    /// and work concurrently without a lock, keeping whatever is pending. Lazy rings get mapped
+    /// now. The halves keep the ring's clock, what `oldest_data_age` knows and the current
+    /// `stats_interval`, but start their stats from zero. The mapping goes once both are dropped.
+    ///
+    /// Fails with `EBUSY` while a pipe still holds bytes from `splice_to_pipe`, since the halves
+    /// wouldn't know to keep clear of them.
+    pub fn split(mut self) -> Result<(Producer, Consumer)> {
+        self.reclaim();
+        if self.reserved() > 0 {
+            return Err(Errno::EBUSY.into());
+        }
+        self.detach_cursors();
+        spsc::split(self)
+    }
+
+    /// A `Producer` and `Consumer` pair like `split` makes, of `num_pages` pages, but with the
+    /// indices kept in a header page in the memfd rather than in this process. Send the fd from
+    /// `memfd` to another process (over a Unix socket with `SCM_RIGHTS`, or just `fork`) and use
+    /// `Producer::from_fd` or `Consumer::from_fd` there to get a lock-free queue between them.
+    /// The two processes have to agree on the page size and the width of `usize`.
+    #[cfg(mapped)]
+    pub fn new_shared(num_pages: usize) -> Result<(Producer, Consumer)> {
+        spsc::new_shared(num_pages)
+    }
+
+    /// The fd the ring's memory lives in, or `None` if it isn't mapped yet or is on the heap.
+    /// Another process can map it to see the same bytes, but not which of them are pending:
+    /// the indices are in this `RingBuf`. See `new_shared` for a ring both sides can use.
+    pub fn memfd(&self) -> Option<BorrowedFd<'_>> {
+        self.mirror.as_ref().and_then(Mirror::fd)
+    }
+
+    /// Moves up to `max` pending bytes into the pipe `pipe` with `splice(2)`, without copying
+    /// them through this process, and returns how many went. Stops early when the ring runs dry
+    /// or the pipe fills up; like `write`, a blocking pipe only blocks until some bytes have gone.
+    /// Fails with `EOPNOTSUPP` for heap rings.
+    ///
+    /// The pipe takes references to the ring's pages rather than a copy, so the spliced bytes
+    /// count as consumed but stay out of the free space until the pipe's reader has taken them
+    /// (going by `FIONREAD`). A reader that splices or `tee`s them on to somewhere else should
+    /// copy them first, since the ring can't see that far. Only one pipe at a time can hold
+    /// bytes: splicing to another one fails with `EBUSY` until the first has been read dry.
+    /// Until then the ring keeps a read end of the pipe open, reopened through `/proc/self/fd`, to
+    /// ask it. So the reader still sees end-of-file once the writers close theirs, but a reader
+    /// that goes away early leaves later splices to a full pipe blocking (or failing with
+    /// `EAGAIN`) rather than failing with `EPIPE`. Fails with the error from `open(2)` if procfs
+    /// isn't there.
+    #[cfg(feature = "linux-splice")]
+    pub fn splice_to_pipe(&mut self, pipe: BorrowedFd<'_>, max: usize) -> Result<usize> {
+        splice::to_pipe(self, pipe, max)
+    }
+
+    /// The other way round from `splice_to_pipe`: moves up to `max` bytes from the pipe `pipe`
+    /// into the free space and returns how many came, zero once every writer has closed the
+    /// pipe. Like `read`, a blocking pipe only blocks until some bytes have come. These are copied
+    /// into the ring's pages, so nothing is held afterwards.
+    #[cfg(feature = "linux-splice")]
+    pub fn splice_from_pipe(&mut self, pipe: BorrowedFd<'_>, max: usize) -> Result<usize> {
+        splice::from_pipe(self, pipe, max)
+    }
+
+    /// Copies everything pending into a `Vec` without consuming it, e.g. to put whatever is
+    /// buffered in a crash dump while leaving it for the reader.
+    pub fn copy_to_vec(&self) -> Vec<u8> {
+        self.pending().to_vec()
+    }
+
+    /// Copies everything pending into an immutable, shareable snapshot along with the ring's
+    /// position at that moment. The ring carries on as normal afterwards; the snapshot can be
+    /// handed to another thread.
+    pub fn freeze(&self) -> FrozenSnapshot {
+        self.freeze_snapshot(0..self.contents_size())
+    }
+
+    /// Like `freeze`, but only copies `range` (offsets from the head) of the pending data.
+    pub fn freeze_range(&self, range: impl RangeBounds<usize>) -> Result<FrozenSnapshot> {
+        let range = resolve_range(range, self.contents_size())?;
+        Ok(self.freeze_snapshot(range))
+    }
+
+    fn freeze_snapshot(&self, range: Range<usize>) -> FrozenSnapshot {
+        FrozenSnapshot {
+            offset: range.start,
+            // One copy, wrap or no wrap.
+            data: Arc::from(&self.pending()[range]),
+            head: self.head,
+            tail: self.tail,
+            len: self.contents_size(),
+            bytes_written: self.bytes_written,
+            bytes_read: self.bytes_read,
+        }
+    }
+
+    /// Offset from the head of the last occurrence of `needle` in the pending data. Matches that
+    /// straddle the end of the buffer are found like any other. An empty needle matches at the
+    /// very end.
+    pub fn rfind(&self, needle: &[u8]) -> Option<usize> {
+        let pending = self.pending();
+        let Some((&first, rest)) = needle.split_first() else {
+            return Some(pending.len());
+        };
+        // Only positions with room for the whole needle after them are candidates.
+        let mut end = (pending.len() + 1).checked_sub(needle.len())?;
+        while let Some(pos) = memrchr(first, &pending[..end]) {
+            if pending[pos + 1..pos + needle.len()] == *rest {
+                return Some(pos);
+            }
+            end = pos;
+        }
+        None
+    }
+
+    /// Offset from the head of the last `b` in the pending data.
+    pub fn rfind_byte(&self, b: u8) -> Option<usize> {
+        memrchr(b, self.pending())
+    }
+
+    /// The pending bytes up to and including the first `delim`, consumed, or `None` (consuming
+    /// nothing) while there's no `delim` yet. A line that runs across the wrap comes back in one
+    /// piece. This shadows `BufRead::read_until`, which copies into a `Vec` and treats an empty
+    /// ring as EOF; call it as `BufRead::read_until(&mut ring, ..)` to get that one.
+    pub fn read_until(&mut self, delim: u8) -> Result<Option<&[u8]>> {
+        match memchr(delim, self.pending()) {
+            Some(pos) => self.read(pos + 1).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Discards everything before the last occurrence of `needle`, leaving the match itself at
+    /// the head. Returns how many bytes were skipped, or `None` (and discards nothing) if there
+    /// is no match.
+    pub fn skip_to_last(&mut self, needle: &[u8]) -> Option<usize> {
+        let pos = self.rfind(needle)?;
+        unsafe { self.advance_read(pos) };
+        self.scrub();
+        Some(pos)
+    }
+
+    /// The pending bytes as two slices, like `VecDeque::as_slices`: up to the end of the ring,
+    /// then whatever wrapped around to its start, which is empty if nothing did. For code written
+    /// against split buffers; `peek` has them as one slice.
+    pub fn as_slices(&self) -> (&[u8], &[u8]) {
+        self.pending()
+            .split_at(self.contents_size().min(self.buf_size - self.head))
+    }
+
+    /// All unread bytes as one slice, courtesy of the mirror mapping.
+    fn pending(&self) -> &[u8] {
         unsafe {
-            munmap(
-                std::ptr::NonNull::new_unchecked(self.buf as *mut c_void),
-                2 * self.buf_size.get(),
+            std::slice::from_raw_parts(
+                self.data_ptr().add(self.read_offset()),
+                self.contents_size(),
             )
-            .expect("Well shit, what do we do now?");
         }
     }
-}
 
-unsafe fn as_u8_slice<T>(value: &T) -> &[u8] {
-    std::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>())
-}
+    /// Reads from `src` straight into the free region, with no intermediate buffer, until `max`
+    /// bytes have come in, `src` hits EOF, or the ring is full; `Filled::stop` says which.
+    /// `Interrupted` reads are retried. Any other error is returned as is, and whatever was
+    /// read before it stays in the ring.
+    pub fn fill_from<R: Read>(&mut self, src: &mut R, max: usize) -> io::Result<Filled> {
+        self.ensure_mapped()?;
+        self.reclaim();
+        self.scrub();
+        let mut bytes = 0;
+        let stop = loop {
+            if bytes == max {
+                break FillStop::Max;
+            }
+            let want = self.free_space().min(max - bytes);
+            if want == 0 {
+                break FillStop::Full;
+            }
+            match self.read_once(src, want)? {
+                0 => break FillStop::Eof,
+                n => bytes += n,
+            }
+        };
+        Ok(Filled { bytes, stop })
+    }
 
-#[derive(Debug)]
-pub enum Error {
-    Nix(nix::Error),
-    Ours(BufError),
-}
+    /// A single `read` from `src` straight into the free region, for pumping a socket into the
+    /// ring one read at a time. Returns how many bytes came in: zero at EOF, and for a full ring
+    /// without asking `src` at all. `Interrupted` reads are retried; any other error, `WouldBlock`
+    /// included, is returned as is. `fill_from` keeps reading until the ring is full.
+    pub fn read_from<R: Read>(&mut self, src: &mut R) -> io::Result<usize> {
+        self.ensure_mapped()?;
+        self.reclaim();
+        self.scrub();
+        let want = self.free_space();
+        if want == 0 {
+            return Ok(0);
+        }
+        self.read_once(src, want)
+    }
 
-type Result<T> = std::result::Result<T, Error>;
+    /// Reads from `src` into the first `want` bytes of the free region, retrying `Interrupted`,
+    /// and publishes whatever came in.
+    fn read_once<R: Read>(&mut self, src: &mut R, want: usize) -> io::Result<usize> {
+        // The mirror keeps the free region contiguous, even when it runs past the end.
+        let window = unsafe {
+            std::slice::from_raw_parts_mut(self.data_ptr().add(self.write_offset()), want)
+        };
+        loop {
+            match src.read(window) {
+                Ok(n) => {
+                    assert!(
+                        n <= want,
+                        "Reader claims to have read more than it was given."
+                    );
+                    unsafe { self.advance_write(n) };
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
 
-impl Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Nix(e) => write!(f, "{e}"),
-            Self::Ours(e) => write!(f, "{e}"),
+    /// Writes pending bytes straight from the ring into `dst` until `max` bytes have gone out or
+    /// the ring is empty, and returns how many went. Only what `dst` accepted is consumed, so
+    /// short writes are fine. `Interrupted` writes are retried. Any other error, `WouldBlock`
+    /// included, is returned as is; whatever was written before it has already been consumed.
+    pub fn drain_to<W: Write>(&mut self, dst: &mut W, max: usize) -> io::Result<usize> {
+        let mut written = 0;
+        while written < max && self.contents_size() > 0 {
+            let chunk = self.contents_size().min(max - written);
+            match self.write_once(dst, chunk)? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => written += n,
+            }
         }
+        Ok(written)
     }
-}
 
-impl ErrTrait for Error {
-    fn source(&self) -> Option<&(dyn ErrTrait + 'static)> {
-        match self {
-            Self::Nix(e) => Some(e),
-            Self::Ours(e) => Some(e),
+    /// A single `write` of everything pending into `dst`, straight from the ring, for flushing
+    /// into a socket one write at a time. Only what `dst` accepted is consumed, and that's what
+    /// it returns; zero for an empty ring, without asking `dst` at all. `Interrupted` writes are
+    /// retried; any other error is returned as is, with nothing consumed. `drain_to` keeps
+    /// writing until the ring is empty.
+    pub fn write_to<W: Write>(&mut self, dst: &mut W) -> io::Result<usize> {
+        if self.contents_size() == 0 {
+            return Ok(0);
         }
+        self.write_once(dst, self.contents_size())
     }
-}
 
-impl From<nix::Error> for Error {
-    fn from(value: nix::Error) -> Self {
-        Self::Nix(value)
+    /// Writes the first `chunk` pending bytes to `dst`, retrying `Interrupted`, and consumes
+    /// whatever it took.
+    fn write_once<W: Write>(&mut self, dst: &mut W, chunk: usize) -> io::Result<usize> {
+        loop {
+            match dst.write(&self.pending()[..chunk]) {
+                Ok(n) => {
+                    assert!(
+                        n <= chunk,
+                        "Writer claims to have taken more than it was given."
+                    );
+                    unsafe { self.advance_read(n) };
+                    self.scrub();
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
     }
-}
 
-impl From<BufError> for Error {
-    fn from(value: BufError) -> Self {
-        Self::Ours(value)
+    /// Exchanges the buffered data of two rings of the same capacity by swapping their mappings
+    /// and indices; no bytes are copied. Each ring keeps its own clock.
+    pub fn swap_contents(&mut self, other: &mut RingBuf) -> Result<()> {
+        if self.capacity() != other.capacity() {
+            return Err(BufError::CapacityMismatch.into());
+        }
+
+        self.detach_cursors();
+        other.detach_cursors();
+        std::mem::swap(&mut self.buf, &mut other.buf);
+        // Differ only if one of them hasn't been mapped yet.
+        std::mem::swap(&mut self.buf_size, &mut other.buf_size);
+        std::mem::swap(&mut self.mirror, &mut other.mirror);
+        std::mem::swap(&mut self.lazy, &mut other.lazy);
+        std::mem::swap(&mut self.head, &mut other.head);
+        std::mem::swap(&mut self.tail, &mut other.tail);
+        std::mem::swap(&mut self.write_window, &mut other.write_window);
+        std::mem::swap(&mut self.unscrubbed, &mut other.unscrubbed);
+        std::mem::swap(&mut self.bytes_written, &mut other.bytes_written);
+        std::mem::swap(&mut self.bytes_read, &mut other.bytes_read);
+        std::mem::swap(&mut self.age_marks, &mut other.age_marks);
+        #[cfg(feature = "linux-splice")]
+        std::mem::swap(&mut self.pipe_hold, &mut other.pipe_hold);
+        self.debug_check_invariants();
+        other.debug_check_invariants();
+        Ok(())
     }
-}
 
-#[derive(Debug)]
-pub enum BufError {
-    TooSmall,
-}
+    /// How many bytes the buffer can hold, mapped yet or not. Zero for the `Default`
+    /// placeholder.
+    pub fn capacity(&self) -> usize {
+        match self.lazy {
+            Some((size, _)) => size.get(),
+            None => self.buf_size,
+        }
+    }
 
-impl Display for BufError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::TooSmall => write!(f, "Not enough buffer space!"),
+    /// How many unread bytes are in the buffer.
+    pub fn len(&self) -> usize {
+        self.contents_size()
+    }
+
+    #[inline]
+    fn contents_size(&self) -> usize {
+        (self.bytes_written - self.bytes_read) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contents_size() == 0
+    }
+
+    /// Whether a write of even one byte would fail for lack of room. Always true for the
+    /// `Default` placeholder, and false for a lazy ring that isn't mapped yet.
+    pub fn is_full(&self) -> bool {
+        self.free_space() == 0 && self.lazy.is_none()
+    }
+
+    /// How many bytes a `write` can take right now. Zero for a lazy ring until it's mapped.
+    pub fn free_space(&self) -> usize {
+        index::free_space(self.contents_size() + self.reserved(), self.buf_size)
+    }
+
+    /// Consumed bytes right behind the head that a pipe may still be reading, which
+    /// `splice_to_pipe` keeps out of the free space.
+    fn reserved(&self) -> usize {
+        #[cfg(feature = "linux-splice")]
+        if let Some(hold) = &self.pipe_hold {
+            return (self.bytes_read - hold.floor) as usize;
         }
+        0
     }
-}
-impl ErrTrait for BufError {}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Start of the mirrored mapping, for running your own codecs over it. The pending bytes are
+    /// the `len()` bytes at `read_offset()` and the free space the `capacity() - len()` bytes at
+    /// `write_offset()`; thanks to the mirror both are contiguous. Dangling for the `Default`
+    /// placeholder.
+    ///
+    /// The pointer stays valid until the ring is dropped, shrunk or has its contents swapped.
+    /// Only write through it into the free space, and publish what you wrote with
+    /// `advance_write`.
+    pub fn data_ptr(&self) -> *mut u8 {
+        self.buf
+    }
 
-    #[test]
-    fn simple_buf() {
-        let mut buf = RingBuf::new(1).expect("Creation should work.");
-        buf.write(b"This is my string. There are many like it, but this one is mine.")
-            .expect("Not writing too far, should be okay.");
-        let sub_str = buf
-            .read(b"This is my string.".len())
-            .expect("Taking what is available.");
-        // buf.write(b"Okay sir");
-        assert_eq!(sub_str, b"This is my string.");
-        assert_eq!(
-            buf.contents_size,
-            b" There are many like it, but this one is mine.".len()
+    /// Length of the whole mapping, i.e. both views: `2 * capacity()`.
+    pub fn mirror_len(&self) -> usize {
+        2 * self.buf_size
+    }
+
+    /// Where the oldest unread byte sits, in `0..capacity()`.
+    pub fn read_offset(&self) -> usize {
+        self.head
+    }
+
+    /// Where the next written byte goes, in `0..capacity()`.
+    pub fn write_offset(&self) -> usize {
+        self.tail
+    }
+
+    /// Marks the first `n` pending bytes as consumed, as if they had been `read`.
+    ///
+    /// # Safety
+    /// `n` must be at most `len()`. Debug builds check this; release builds corrupt the ring.
+    pub unsafe fn advance_read(&mut self, n: usize) {
+        debug_assert!(
+            n <= self.contents_size(),
+            "advance_read({n}) past the {} pending bytes",
+            self.contents_size()
         );
-        buf.write(b" I love my substring.")
-            .expect("Continuing to write");
+        self.advance_head(n);
     }
 
-    #[test]
-    fn page_wrap() {
-        // TODO: Support non-4K page sizes.
-        let mut buf = RingBuf::new(1).expect("Creation should work.");
-        buf.write(&[1; 4096]).expect("Should fit in the buffer.");
-        let _lotsa_ones = buf.read(2048).expect("Should be available.");
-        assert_eq!(buf.head, 2048);
-        assert_eq!(buf.tail, 0);
-        buf.write(&[2; 4096])
-            .expect_err("We can't fit more than one page in this buffer.");
-        buf.write(&[2; 2048]).expect(
-            "Failure to write shouldn't affect our buffer. Also, there should be enough space.",
+    /// Publishes `n` bytes the caller has already written at `write_offset()`, as if they had
+    /// been `write`n. Forgets any window from `writable_slice`.
+    ///
+    /// # Safety
+    /// `n` must be at most `capacity() - len()`, and those bytes should have been initialized
+    /// through `data_ptr()`. Debug builds check the bound; release builds corrupt the ring.
+    pub unsafe fn advance_write(&mut self, n: usize) {
+        debug_assert!(
+            n <= self.free_space(),
+            "advance_write({n}) past the {} free bytes",
+            self.free_space()
         );
-        let _more_ones = buf.read(1024).expect("Business as usual");
-        let wrapping = buf.read(2048).expect("I trust my MMU.");
-        let should_have_read = { 
-            let mut scratch = [0; 2048];
-            let (before_page_end, after_page_end) = scratch.split_at_mut(1024);
-            before_page_end.copy_from_slice(&[1; 1024]);
-            after_page_end.copy_from_slice(&[2; 1024]);
-            scratch
-        };
-        assert_eq!(wrapping, should_have_read);
+        self.advance_tail(n);
+    }
+
+    fn check_fits(&mut self, n: usize) -> Result<()> {
+        self.ensure_mapped()?;
+        self.reclaim();
+        self.scrub();
+        if self.buf_size == 0 {
+            return Err(BufError::ZeroCapacity.into());
+        }
+        if !index::fits(n, self.free_space()) {
+            self.interval.drops += 1;
+            self.counters.rejected_writes += 1;
+            return Err(BufError::NotEnoughSpace {
+                requested: n,
+                available: self.free_space(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Fails with `BufError::NotEnoughData` unless at least `n` bytes are pending.
+    fn check_pending(&self, n: usize) -> Result<()> {
+        if n > self.contents_size() {
+            return Err(BufError::NotEnoughData {
+                requested: n,
+                available: self.contents_size(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Copies `raw` in at the tail and advances it. Thanks to the mirror mapping the destination
+    /// is always contiguous, even if it runs past the end of the first view.
+    ///
+    /// # Safety
+    /// The caller must have already checked that `raw` fits in the free space.
+    unsafe fn copy_in(&mut self, raw: &[u8]) {
+        self.copy_at(0, raw);
+        self.advance_write(raw.len());
+    }
+
+    /// Copies `raw` into the free region `offset` bytes past the tail, without publishing it.
+    ///
+    /// # Safety
+    /// `offset + raw.len()` must fit in the free space.
+    unsafe fn copy_at(&mut self, offset: usize, raw: &[u8]) {
+        let dst = self.data_ptr().add(self.write_offset() + offset);
+        // Anything `raw` could borrow from the ring (even through another mapping, like a
+        // cursor's) is pending, and the destination is free space.
+        std::ptr::copy_nonoverlapping(raw.as_ptr(), dst, raw.len());
+    }
+
+    fn options(&self) -> &MapOptions {
+        static DEFAULT: OnceLock<MapOptions> = OnceLock::new();
+        self.mirror
+            .as_ref()
+            .map_or_else(|| DEFAULT.get_or_init(MapOptions::default), |m| &m.options)
+    }
+
+    /// `Mirror::sync` for the ring's mirror, if it has one.
+    ///
+    /// # Safety
+    /// As for `Mirror::sync`.
+    #[inline]
+    unsafe fn sync(&self, offset: usize, len: usize) {
+        if let Some(mirror) = &self.mirror {
+            mirror.sync(offset, len);
+        }
+    }
+
+    /// Whether consumed bytes get overwritten, by `RingBufBuilder::zeroize` or `debug_fill`.
+    fn scrubbing(&self) -> bool {
+        let options = self.options();
+        options.zeroize || options.debug_fill
+    }
+
+    /// With the `poison` feature, panics if `bytes` about to be handed out are a long run of
+    /// nothing but poison: published without ever being written, most likely. Compiles to
+    /// nothing without it.
+    #[inline]
+    fn assert_not_poison(&self, bytes: &[u8]) {
+        if cfg!(feature = "poison")
+            && bytes.len() >= SUSPICIOUS_POISON_RUN
+            && self.options().debug_fill
+            && bytes.iter().all(|&b| b == POISON)
+        {
+            panic!(
+                "about to hand out {} bytes of poison; were they ever written?",
+                bytes.len()
+            );
+        }
+    }
+
+    /// Overwrites consumed bytes that are still waiting for it: with the poison pattern in
+    /// debug-fill mode, otherwise with zeros.
+    fn scrub(&mut self) {
+        if self.unscrubbed == 0 {
+            return;
+        }
+        // The mirror makes the bytes behind the head contiguous when viewed from the second copy.
+        self.scrub_range(self.head + self.buf_size - self.unscrubbed, self.unscrubbed);
+        self.unscrubbed = 0;
+    }
+
+    /// Scrubs the `len` bytes at `start`, which may run into the second view.
+    fn scrub_range(&self, start: usize, len: usize) {
+        unsafe {
+            if self.options().debug_fill {
+                std::ptr::write_bytes(self.buf.add(start), POISON, len);
+            } else {
+                mirror::zero_volatile(self.buf.add(start), len);
+            }
+            self.sync(start, len);
+        }
+    }
+
+    /// Forgets the `writable_slice` window, if any. In debug-fill mode, re-poisons whatever the
+    /// caller may have scribbled on past the first `used` bytes, which are about to be published.
+    fn close_write_window(&mut self, used: usize) {
+        let Some(window) = self.write_window.take() else {
+            return;
+        };
+        if self.options().debug_fill && window > used {
+            unsafe {
+                std::ptr::write_bytes(self.buf.add(self.tail + used), POISON, window - used);
+                self.sync(self.tail + used, window - used);
+            }
+        }
+    }
+
+    /// Discards everything pending and moves both indices back to the start, as if the ring had
+    /// just been built, so it can be reused instead of mapped afresh. O(1), unless the ring was
+    /// built with `RingBufBuilder::zeroize` or `debug_fill` and the discarded bytes need
+    /// scrubbing. The indices stay put while a pipe still holds bytes from `splice_to_pipe`.
+    pub fn clear(&mut self) {
+        self.detach_cursors();
+        unsafe { self.advance_read(self.contents_size()) };
+        self.scrub();
+        self.close_write_window(0);
+        self.reclaim();
+        if self.reserved() == 0 {
+            self.head = 0;
+            self.tail = 0;
+        }
+        self.debug_check_invariants();
+    }
+
+    /// `clear`, and also hands the physical pages back to the kernel, for rings that are going to
+    /// sit idle for a while. They come back zeroed as the ring is written to again. Only Linux
+    /// rings with an fd release anything; heap rings are just cleared.
+    ///
+    /// The ring is cleared even if releasing fails, e.g. for a posix shm fd on a filesystem that
+    /// can't punch holes.
+    pub fn clear_and_release(&mut self) -> Result<()> {
+        self.clear();
+        let Some(mirror) = &self.mirror else {
+            return Ok(());
+        };
+        let released = mirror.release();
+        if self.options().debug_fill {
+            // The released pages read as zeros, which would look like stray writes.
+            unsafe {
+                std::ptr::write_bytes(self.buf, POISON, self.buf_size);
+                self.sync(0, self.buf_size);
+            }
+        }
+        self.debug_check_invariants();
+        released
+    }
+
+    /// Discards everything pending and zeroes the whole buffer, whether or not the ring was built
+    /// with `RingBufBuilder::zeroize`. (Debug-fill rings get the poison pattern put back after.)
+    /// Bytes a pipe still holds from `splice_to_pipe` are left alone.
+    pub fn wipe(&mut self) {
+        self.detach_cursors();
+        unsafe { self.advance_read(self.contents_size()) };
+        self.close_write_window(0);
+        self.unscrubbed = 0;
+        self.reclaim();
+        if self.reserved() > 0 {
+            // Only the free space, which ends where the pipe's bytes start.
+            self.scrub_range(self.tail, self.free_space());
+        } else if self.buf_size > 0 {
+            unsafe { mirror::zero_volatile(self.buf, self.buf_size) };
+            if self.options().debug_fill {
+                unsafe { std::ptr::write_bytes(self.buf, POISON, self.buf_size) };
+            }
+            unsafe { self.sync(0, self.buf_size) };
+        }
+        self.debug_check_invariants();
+    }
+
+    /// Panics if the ring's bookkeeping doesn't add up: an index out of range, more pending than
+    /// fits, or a tail that isn't `len()` bytes past the head. For rings built with
+    /// `RingBufBuilder::debug_fill` it also checks that every free byte still holds the poison
+    /// pattern, apart from an open `writable_slice` window and bytes whose scrubbing is still
+    /// deferred, which catches stray writes into the free space.
+    ///
+    /// Every mutating method calls this when it's done in debug builds, or in any build with the
+    /// `paranoid` feature.
+    pub fn check_invariants(&self) {
+        let cap = self.buf_size;
+        assert!(
+            self.contents_size() <= cap,
+            "{} bytes pending in a ring of {cap}",
+            self.contents_size()
+        );
+        if cap == 0 {
+            assert_eq!(
+                (self.head, self.tail),
+                (0, 0),
+                "Indices of an unmapped ring"
+            );
+            return;
+        }
+        assert!(self.head < cap, "head {} out of range 0..{cap}", self.head);
+        assert!(self.tail < cap, "tail {} out of range 0..{cap}", self.tail);
+        assert_eq!(
+            self.tail,
+            index::advance(self.head, self.contents_size(), cap),
+            "tail doesn't match head {} + {} pending",
+            self.head,
+            self.contents_size()
+        );
+
+        if self.options().debug_fill {
+            let window = self.write_window.unwrap_or(0);
+            // Deferred scrubbing only reaches into the free space past any bytes held for a pipe.
+            let poisoned =
+                self.free_space() - window - self.unscrubbed.saturating_sub(self.reserved());
+            let free =
+                unsafe { std::slice::from_raw_parts(self.buf.add(self.tail + window), poisoned) };
+            if let Some(stray) = free.iter().position(|&b| b != POISON) {
+                panic!(
+                    "free byte at offset {} was overwritten",
+                    index::advance(self.tail, window + stray, cap)
+                );
+            }
+        }
+    }
+
+    /// `check_invariants`, when debug assertions or the `paranoid` feature are on.
+    #[inline]
+    fn debug_check_invariants(&self) {
+        if cfg!(any(debug_assertions, feature = "paranoid")) {
+            self.check_invariants();
+        }
+    }
+
+    /// Marks `n` more bytes after the tail as written.
+    fn advance_tail(&mut self, n: usize) {
+        if n == 0 {
+            return;
+        }
+        unsafe { self.sync(self.tail, n) };
+        // Any outstanding window now starts at the wrong place.
+        self.close_write_window(n);
+        if self.age_marks.len() < MAX_AGE_MARKS {
+            self.age_marks
+                .push_back((self.bytes_written, self.clock.now()));
+        }
+        self.counters.wraps += u64::from(self.tail + n >= self.buf_size);
+        self.tail = index::advance(self.tail, n, self.buf_size);
+        self.bytes_written += n as u64;
+        // Raw writers may have filled in part of what was waiting to be scrubbed.
+        self.unscrubbed = self.unscrubbed.min(self.free_space() + self.reserved());
+        if let Some(subscribers) = &self.subscribers {
+            subscribers.publish(self.bytes_written);
+        }
+        self.interval.bytes_in += n as u64;
+        self.interval.ops_in += 1;
+        self.interval.max_fill = self.interval.max_fill.max(self.contents_size());
+        self.counters.bytes_written += n as u64;
+        self.counters.high_water = self.counters.high_water.max(self.contents_size());
+        self.debug_check_invariants();
+    }
+
+    /// Marks `n` bytes at the head as consumed.
+    fn advance_head(&mut self, n: usize) {
+        if n == 0 {
+            return;
+        }
+        // Whatever a borrowed read left behind can't be borrowed anymore.
+        self.scrub();
+        if self.scrubbing() {
+            self.unscrubbed = n;
+        }
+        self.head = index::advance(self.head, n, self.buf_size);
+        self.bytes_read += n as u64;
+        if let Some(subscribers) = &self.subscribers {
+            subscribers.reclaimed(self.bytes_read);
+        }
+        self.interval.bytes_out += n as u64;
+        self.interval.ops_out += 1;
+        self.counters.bytes_read += n as u64;
+        if self.contents_size() == 0 {
+            self.age_marks.clear();
+        }
+        while self
+            .age_marks
+            .get(1)
+            .is_some_and(|&(pos, _)| pos <= self.bytes_read)
+        {
+            self.age_marks.pop_front();
+        }
+        self.debug_check_invariants();
+    }
+
+    /// How long the oldest unread byte has been sitting in the buffer, or `None` if it's empty.
+    /// Useful for spotting a consumer that has stopped keeping up.
+    pub fn oldest_data_age(&self) -> Option<Duration> {
+        if self.contents_size() == 0 {
+            return None;
+        }
+        let &(_, written_at) = self.age_marks.front()?;
+        Some(self.clock.now().saturating_duration_since(written_at))
+    }
+
+    /// A snapshot of the buffer's bookkeeping.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            oldest_data_age: self.oldest_data_age(),
+            thp_backed: self.mirror.as_ref().is_some_and(Mirror::thp_backed),
+            bytes_written: self.counters.bytes_written,
+            bytes_read: self.counters.bytes_read,
+            high_water: self.counters.high_water,
+            rejected_writes: self.counters.rejected_writes,
+            wraps: self.counters.wraps,
+            dropped_frames: self.counters.dropped_frames,
+            expired_frames: 0,
+        }
+    }
+
+    /// Starts the counters in `stats` over, with the high-water mark at what's pending now.
+    /// Leaves `stats_interval` alone.
+    pub fn reset_stats(&mut self) {
+        self.counters = Counters {
+            high_water: self.contents_size(),
+            ..Counters::default()
+        };
+    }
+
+    /// Traffic since the previous call (or since the ring was created), for computing rates.
+    /// Starts a new interval; the lifetime totals are unaffected. Transfers of zero bytes don't
+    /// count as operations.
+    pub fn stats_interval(&mut self) -> IntervalStats {
+        let now = self.clock.now();
+        let fresh = IntervalStats::starting_at(now, self.contents_size());
+        let mut finished = std::mem::replace(&mut self.interval, fresh);
+        finished.elapsed = now.saturating_duration_since(finished.started_at);
+        finished
+    }
+
+    /// Replaces the time source used for age tracking and interval stats, mostly so tests can use
+    /// a `MockClock`. The current interval restarts from the new clock's "now".
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Arc::new(clock);
+        self.interval.started_at = self.clock.now();
+    }
+
+    /// Consumes the next `num_bytes` and returns a view of them, contiguous even across the wrap.
+    /// The view keeps the ring mutably borrowed, so nothing can write over those bytes while it's
+    /// alive:
+    ///
+    /// ```compile_fail,E0499
+    /// # use borrow_checker_demo::ringbuf::RingBuf;
+    /// let mut buf = RingBuf::new(1).unwrap();
+    /// buf.write(b"This is my string.").unwrap();
+    /// let sub_str = buf.read(18).unwrap();
+    /// buf.write(b"Okay sir").unwrap(); // Could land on the bytes `sub_str` points at.
+    /// assert_eq!(sub_str, b"This is my string.");
+    /// ```
+    pub fn read(&mut self, num_bytes: usize) -> Result<&[u8]> {
+        self.read_mut(num_bytes).map(|view| &*view)
+    }
+
+    /// Like `read`, but hands out a mutable view for callers that transform the bytes in place
+    /// (e.g. decrypting or byte-swapping) before using them.
+    pub fn read_mut(&mut self, num_bytes: usize) -> Result<&mut [u8]> {
+        self.check_pending(num_bytes)?;
+
+        unsafe {
+            let view =
+                std::slice::from_raw_parts_mut(self.data_ptr().add(self.read_offset()), num_bytes);
+            self.assert_not_poison(view);
+            self.advance_read(num_bytes);
+            Ok(view)
+        }
+    }
+
+    #[deprecated(note = "`read` now returns `&[u8]`; use `read_mut` if you need to mutate")]
+    pub fn read_legacy(&mut self, num_bytes: usize) -> Result<&mut [u8]> {
+        self.read_mut(num_bytes)
+    }
+
+    /// Writes `payload` as one message: its length as a little-endian `u32`, then the payload
+    /// itself. All of it goes in or none does, as with `write_all_slices`. Fails with
+    /// `BufError::FrameTooLarge` for a message that wouldn't fit even in an empty ring, or of
+    /// 2 GiB less two bytes or more, whose length would look like a checked frame's or the end
+    /// marker's.
+    ///
+    /// On a ring built with `RingBufBuilder::overwrite`, makes room the way `write_msg_evicting`
+    /// does instead of failing.
+    pub fn write_msg(&mut self, payload: &[u8]) -> Result<()> {
+        let len = frame_len(payload, MSG_HEADER_LEN, self.capacity())?;
+        if self.overwrite {
+            self.evict_frames(MSG_HEADER_LEN + payload.len(), &mut |_, _| {})?;
+        }
+        self.write_all_slices(&[&len.to_le_bytes(), payload])
+    }
+
+    /// `write_msg` that makes room by dropping whole messages, oldest first, rather than cutting
+    /// one in half the way `write_overwriting` would. `on_drop` gets each victim's header:
+    /// whether it was checked, and its payload length. Returns how many were dropped; they're
+    /// also counted in `Stats::dropped_frames`.
+    ///
+    /// Only for rings holding nothing but messages, since the lengths are walked from the head.
+    /// Drops nothing, and fails like `write_msg`, if dropping every complete message still
+    /// wouldn't make room (say the last one is only half written) or the ring has cursors from
+    /// `subscribe`.
+    pub fn write_msg_evicting(
+        &mut self,
+        payload: &[u8],
+        mut on_drop: impl FnMut(bool, usize),
+    ) -> Result<usize> {
+        let len = frame_len(payload, MSG_HEADER_LEN, self.capacity())?;
+        let dropped = self.evict_frames(MSG_HEADER_LEN + payload.len(), &mut on_drop)?;
+        self.write_all_slices(&[&len.to_le_bytes(), payload])?;
+        Ok(dropped)
+    }
+
+    /// The payload of the next message from `write_msg`, consumed, or `None` (consuming nothing)
+    /// until all of it has arrived. Fails with `BufError::FrameTooLarge`, also consuming nothing,
+    /// if the length in the frame is more than the ring could ever hold, with
+    /// `BufError::MixedFrames` if the frame was written by `write_msg_checked`, and with
+    /// `BufError::EndOfStream` from the end marker on; see `recv_msg` for that as a value.
+    pub fn read_msg(&mut self) -> Result<Option<&[u8]>> {
+        let len = match self.next_frame()? {
+            None => return Ok(None),
+            Some(Frame::End) => return Err(BufError::EndOfStream.into()),
+            Some(Frame::Msg { checked: true, .. } | Frame::Timed { .. }) => {
+                return Err(BufError::MixedFrames.into())
+            }
+            Some(Frame::Msg { len, .. }) => len,
+        };
+        if self.contents_size() < MSG_HEADER_LEN + len {
+            return Ok(None);
+        }
+        self.consume(MSG_HEADER_LEN)?;
+        self.read(len).map(Some)
+    }
+
+    /// `read_msg` that returns the end marker from `write_msg_end` (or `Producer::finish`) as
+    /// `MsgEvent::End` rather than an error, so a clean end of the stream stands out from one that
+    /// just stops. The marker is never consumed: every call from then on returns it again.
+    pub fn recv_msg(&mut self) -> Result<Option<MsgEvent<&[u8]>>> {
+        match self.read_msg() {
+            Ok(msg) => Ok(msg.map(MsgEvent::Data)),
+            Err(Error::Ours(BufError::EndOfStream)) => Ok(Some(MsgEvent::End)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Ends the stream of messages with a marker that `recv_msg` returns as `MsgEvent::End`, so
+    /// the reader knows nothing was lost after the last one. It takes the room of an empty
+    /// message and isn't one: nothing behind it is ever read, and on an overwrite ring it makes
+    /// room like `write_msg` but is never dropped itself.
+    pub fn write_msg_end(&mut self) -> Result<()> {
+        if self.overwrite {
+            self.evict_frames(MSG_HEADER_LEN, &mut |_, _| {})?;
+        }
+        self.write_all_slices(&[&END_FRAME.to_le_bytes()])
+    }
+
+    /// `read_msg` copied into `buf`, which ends up holding just the payload, so one buffer can
+    /// serve a whole receive loop without an allocation per message. `buf` only grows for a
+    /// payload bigger than its capacity. Returns the payload's length, or `None` (or an error)
+    /// exactly when `read_msg` would, leaving `buf` as it was.
+    pub fn read_msg_into(&mut self, buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        let Some(payload) = self.read_msg()? else {
+            return Ok(None);
+        };
+        buf.clear();
+        buf.extend_from_slice(payload);
+        Ok(Some(payload.len()))
+    }
+
+    /// `write_msg` with a CRC-32 of `payload` after the length, for rings another program
+    /// writes into, so `read_msg_checked` can tell a corrupt frame from a good one. The length
+    /// has its top bit set to mark the frame as checked. Evicts on an overwrite ring, as
+    /// `write_msg` does.
+    pub fn write_msg_checked(&mut self, payload: &[u8]) -> Result<()> {
+        let len = frame_len(payload, CHECKED_MSG_HEADER_LEN, self.capacity())?;
+        if self.overwrite {
+            self.evict_frames(CHECKED_MSG_HEADER_LEN + payload.len(), &mut |_, _| {})?;
+        }
+        let crc = crc::crc32(payload);
+        self.write_all_slices(&[
+            &(len | CHECKED_FRAME).to_le_bytes(),
+            &crc.to_le_bytes(),
+            payload,
+        ])
+    }
+
+    /// `read_msg` for frames from `write_msg_checked`. The checksum is worked out over the
+    /// payload where it lies in the ring, wrapped or not. If it doesn't match, fails with
+    /// `BufError::CorruptFrame` and consumes nothing; `skip_frame` gets past the bad frame. Fails
+    /// with `BufError::MixedFrames` if the frame was written by plain `write_msg`, and with
+    /// `BufError::EndOfStream` at the end marker.
+    pub fn read_msg_checked(&mut self) -> Result<Option<&[u8]>> {
+        let len = match self.next_frame()? {
+            None => return Ok(None),
+            Some(Frame::End) => return Err(BufError::EndOfStream.into()),
+            Some(Frame::Msg { checked: false, .. } | Frame::Timed { .. }) => {
+                return Err(BufError::MixedFrames.into())
+            }
+            Some(Frame::Msg { len, .. }) => len,
+        };
+        if self.contents_size() < CHECKED_MSG_HEADER_LEN + len {
+            return Ok(None);
+        }
+        let stored = self.peek_at(MSG_HEADER_LEN, size_of::<u32>())?;
+        let expected = u32::from_le_bytes(stored.try_into().expect("Four bytes."));
+        let actual = crc::crc32(self.peek_at(CHECKED_MSG_HEADER_LEN, len)?);
+        if actual != expected {
+            return Err(BufError::CorruptFrame { expected, actual }.into());
+        }
+        self.consume(CHECKED_MSG_HEADER_LEN)?;
+        self.read(len).map(Some)
+    }
+
+    /// Drops the next message, checked or not, without looking at its payload, e.g. to get past
+    /// one `read_msg_checked` found corrupt. Returns `false` and drops nothing until all of it has
+    /// arrived, and at the end marker, which stays put. A length that's corrupt too fails with
+    /// `BufError::FrameTooLarge`, or gets the stream out of step; after that only `clear` makes
+    /// sense of the ring again.
+    pub fn skip_frame(&mut self) -> Result<bool> {
+        let Some(frame @ (Frame::Msg { .. } | Frame::Timed { .. })) = self.next_frame()? else {
+            return Ok(false);
+        };
+        if self.contents_size() < frame.size() {
+            return Ok(false);
+        }
+        self.consume(frame.size())?;
+        Ok(true)
+    }
+
+    /// The messages that have fully arrived, oldest first, to rewrite in place before they're
+    /// read: `read_msg` and friends later see whatever was written through `FrameMut`. Consumes
+    /// nothing. Holding the ring mutably keeps anything else from reading while frames are out.
+    /// Stops early at a length too big to be a frame that's arrived, and at the end marker.
+    pub fn iter_frames_mut(&mut self) -> FramesMut<'_> {
+        let rest = unsafe {
+            std::slice::from_raw_parts_mut(
+                self.data_ptr().add(self.read_offset()),
+                self.contents_size(),
+            )
+        };
+        FramesMut {
+            rest,
+            mirror: self.mirror.as_ref(),
+            offset: self.read_offset(),
+        }
+    }
+
+    /// Drops whole frames from the head until `need` bytes are free, reporting each to
+    /// `on_drop`. Works out how many that takes before dropping any, so it either makes the room
+    /// or changes nothing.
+    fn evict_frames(&mut self, need: usize, on_drop: &mut dyn FnMut(bool, usize)) -> Result<usize> {
+        self.ensure_mapped()?;
+        self.reclaim();
+        let mut victims = Vec::new();
+        let mut freed = 0;
+        while self.free_space() + freed < need {
+            if self.has_cursors() {
+                break;
+            }
+            // The end marker is never dropped, nor anything behind it.
+            let Some(frame @ Frame::Msg { checked, len }) = self.frame_at(freed)? else {
+                break;
+            };
+            if self.contents_size() - freed < frame.size() {
+                break;
+            }
+            victims.push((checked, len));
+            freed += frame.size();
+        }
+        if self.free_space() + freed < need {
+            // Let the write fail as usual.
+            return Ok(0);
+        }
+        self.consume(freed)?;
+        self.counters.dropped_frames += victims.len() as u64;
+        for &(checked, len) in &victims {
+            on_drop(checked, len);
+        }
+        Ok(victims.len())
+    }
+
+    /// The next frame's header, or `None` until all of its length has arrived. Fails with
+    /// `BufError::FrameTooLarge` if it could never fit.
+    fn next_frame(&self) -> Result<Option<Frame>> {
+        self.frame_at(0)
+    }
+
+    /// `next_frame` for the frame starting `offset` bytes past the head.
+    fn frame_at(&self, offset: usize) -> Result<Option<Frame>> {
+        let Ok(header) = self.peek_at(offset, MSG_HEADER_LEN) else {
+            return Ok(None);
+        };
+        Frame::parse(header, self.capacity()).map(Some)
+    }
+
+    /// Writes the bytes of `value`. `Pod` is what makes that a complete copy of it, with nothing
+    /// owned left behind or duplicated. Never evicts, even on a ring built with
+    /// `RingBufBuilder::overwrite`, since that could cut an earlier value in half; fails with
+    /// `BufError::NotEnoughSpace` instead.
+    pub fn write_typed<T: Pod>(&mut self, value: T) -> Result<()> {
+        let as_bytes = as_u8_slice(&value);
+        if self.typed_checks {
+            self.write_all_slices(&[&typetag::tag_for::<T>(), as_bytes])
+        } else if self.overwrite {
+            self.write_parts(&[as_bytes])
+        } else {
+            self.push_small(as_bytes)
+        }
+    }
+
+    /// Takes the next value out of the ring. It's copied out, so it doesn't matter where in the
+    /// ring it landed or how it's aligned there. Fails with `BufError::NotEnoughData` and
+    /// consumes nothing if the whole value isn't there yet.
+    ///
+    /// With typed checks on, fails with `BufError::TypeMismatch` if the next value was written as
+    /// some other type, and consumes nothing. Reading a tagged value with checks off (or the
+    /// other way around) fails with `BufError::MixedTypeChecks`, in any build. With checks off
+    /// the tag is recognized from the bytes alone: a bare value that happens to start with the
+    /// tag magic byte, three zero bytes and `T`'s size as a `u32` is taken for a tag too, and
+    /// rejected the same way. `read` still gets such bytes out.
+    pub fn read_typed<T: Pod>(&mut self) -> Result<T> {
+        let tag = if self.typed_checks {
+            typetag::check::<T>(self.pending())?;
+            typetag::TAG_LEN
+        } else if typetag::looks_tagged(self.pending(), size_of::<T>()) {
+            return Err(BufError::MixedTypeChecks.into());
+        } else {
+            0
+        };
+        self.check_pending(tag + size_of::<T>())?;
+        let raw = &self.pending()[tag..tag + size_of::<T>()];
+        // SAFETY: `raw` is exactly `size_of::<T>()` bytes, and any bytes make a valid `Pod`.
+        let value = unsafe { raw.as_ptr().cast::<T>().read_unaligned() };
+        self.consume(tag + size_of::<T>())?;
+        Ok(value)
+    }
+
+    /// Writes every value in `data` back to back in one copy, or none of them if they don't all
+    /// fit, for blocks of samples where `write_typed` per value would be slow. With typed checks
+    /// on, each value goes in behind its own tag, just as `write_typed` would write it, so the
+    /// two can be mixed freely. Like `write_typed`, never evicts.
+    pub fn write_slice<T: Pod>(&mut self, data: &[T]) -> Result<()> {
+        if !self.typed_checks {
+            return self.write_parts(&[slice_as_u8_slice(data)]);
+        }
+        let tag = typetag::tag_for::<T>();
+        let parts: Vec<&[u8]> = data
+            .iter()
+            .flat_map(|value| [&tag[..], as_u8_slice(value)])
+            .collect();
+        self.write_all_slices(&parts)
+    }
+
+    /// Fills `dst` with the next `dst.len()` values and consumes them. Fails with
+    /// `BufError::NotEnoughData` and consumes nothing unless all of them are there, so `dst` never
+    /// ends up with a value half-read. The bytes are copied out, so it doesn't matter how the
+    /// ring's offset lines up with `T`'s alignment. Type checks work as for `read_typed`, on
+    /// every value, and a mismatch anywhere consumes nothing.
+    pub fn read_slice_into<T: Pod>(&mut self, dst: &mut [T]) -> Result<()> {
+        if !self.typed_checks {
+            if typetag::looks_tagged(self.pending(), size_of::<T>()) {
+                return Err(BufError::MixedTypeChecks.into());
+            }
+            return self.read_exact_into(slice_as_u8_slice_mut(dst));
+        }
+        let stride = typetag::TAG_LEN + size_of::<T>();
+        // A long enough `dst` of zero-sized values can't have been written, tags and all.
+        let total = dst.len().saturating_mul(stride);
+        self.check_pending(total)?;
+        let pending = self.pending();
+        for i in 0..dst.len() {
+            typetag::check::<T>(&pending[i * stride..])?;
+        }
+        for (i, slot) in dst.iter_mut().enumerate() {
+            let raw = &pending[i * stride + typetag::TAG_LEN..(i + 1) * stride];
+            // SAFETY: As in `read_typed`.
+            *slot = unsafe { raw.as_ptr().cast::<T>().read_unaligned() };
+        }
+        self.consume(total)
+    }
+}
+
+/// Where the ring stands, not what's in it: the pending bytes could run to megabytes.
+impl std::fmt::Debug for RingBuf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RingBuf")
+            .field("head", &self.head)
+            .field("tail", &self.tail)
+            .field("len", &self.contents_size())
+            .field("capacity", &self.capacity())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for RingBuf {
+    /// An empty, zero-capacity ring that owns no mapping or fd, so it's free to create. Writes
+    /// fail with `BufError::ZeroCapacity` and reads see an empty buffer. Handy as the thing left
+    /// behind by `std::mem::take`.
+    fn default() -> Self {
+        Self {
+            buf: std::ptr::NonNull::dangling().as_ptr(),
+            buf_size: 0,
+            mirror: None,
+            lazy: None,
+            head: 0,
+            tail: 0,
+            write_window: None,
+            unscrubbed: 0,
+            bytes_written: 0,
+            bytes_read: 0,
+            age_marks: VecDeque::new(),
+            clock: Arc::new(SystemClock),
+            interval: IntervalStats::starting_at(Instant::now(), 0),
+            counters: Counters::default(),
+            typed_checks: cfg!(feature = "typed-checks"),
+            overwrite: false,
+            subscribers: None,
+            #[cfg(feature = "linux-splice")]
+            pipe_hold: None,
+        }
+    }
+}
+
+/// For code that's generic over writers. Like `write_up_to`, this takes as much of `data` as
+/// fits and reports a short write rather than failing, so `write_all` into a full ring fails with
+/// `WriteZero`. Rings built with `RingBufBuilder::overwrite` take all of `data` instead, evicting
+/// as `RingBuf::write` does, so a writer never stalls on them.
+impl Write for RingBuf {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.ensure_mapped()?;
+        if self.overwrite {
+            self.write_overwriting(data)?;
+            return Ok(data.len());
+        }
+        Ok(self.write_up_to(data))
+    }
+
+    /// Takes the slices in order until one doesn't fit in full.
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.ensure_mapped()?;
+        if self.overwrite {
+            return bufs
+                .iter()
+                .try_fold(0, |n, buf| Ok(n + Write::write(self, buf)?));
+        }
+        let mut n = 0;
+        for buf in bufs {
+            let written = self.write_up_to(buf);
+            n += written;
+            if written < buf.len() {
+                break;
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// For code that's generic over readers. `read_up_to`, which can't fail.
+impl Read for RingBuf {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        Ok(self.read_up_to(out))
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        Ok(RingBuf::read_vectored(self, bufs))
+    }
+}
+
+/// For line-oriented code, e.g. `read_line` and `lines`. `fill_buf` is everything pending, in
+/// one slice even across the wrap, and an empty ring reads as EOF, so a line that isn't complete
+/// yet comes back as it is.
+impl BufRead for RingBuf {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        Ok(self.pending())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        RingBuf::consume(self, amt).expect("Consumed past what `fill_buf` returned.");
+    }
+}
+
+/// For formatting straight into the ring with `write!`, without a `String` in between. Each
+/// `write!` is one record: the formatter's pieces are staged in the free space and only published
+/// once they've all fit, so running out of room partway through leaves nothing behind and fails
+/// with `fmt::Error`. A ring built with `RingBufBuilder::overwrite` makes room for each piece by
+/// evicting the oldest pending bytes, as `write_overwriting` does, so it only fails for a record
+/// bigger than the whole ring or one that would evict what a cursor hasn't read; whatever the
+/// earlier pieces evicted stays evicted. (With `std::io::Write` in scope as well, `write!` has to be spelled
+/// `std::fmt::Write::write_fmt(&mut ring, format_args!(...))`.)
+impl std::fmt::Write for RingBuf {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        self.write(s.as_bytes()).map_err(|_| std::fmt::Error)
+    }
+
+    fn write_fmt(&mut self, args: std::fmt::Arguments<'_>) -> std::fmt::Result {
+        self.check_fits(0).map_err(|_| std::fmt::Error)?;
+        self.close_write_window(0);
+        // Held open as a window so a failed run's bytes get re-poisoned in debug-fill mode.
+        self.write_window = Some(0);
+        let mut staged = Staged { ring: self, len: 0 };
+        if std::fmt::write(&mut staged, args).is_err() {
+            self.close_write_window(0);
+            return Err(std::fmt::Error);
+        }
+        let len = staged.len;
+        unsafe { self.advance_write(len) };
+        Ok(())
+    }
+}
+
+/// Panics if the ring fills up before the iterator runs out, or can't be written to at all. See
+/// `RingBuf::try_extend` for a version that stops at a full ring instead.
+impl Extend<u8> for RingBuf {
+    fn extend<I: IntoIterator<Item = u8>>(&mut self, iter: I) {
+        let mut iter = iter.into_iter();
+        self.try_extend(iter.by_ref())
+            .expect("Extended a ring that can't be written to.");
+        assert!(
+            iter.next().is_none(),
+            "Extended a ring past its free space."
+        );
+    }
+}
+
+impl<'a> Extend<&'a u8> for RingBuf {
+    fn extend<I: IntoIterator<Item = &'a u8>>(&mut self, iter: I) {
+        self.extend(iter.into_iter().copied());
+    }
+}
+
+/// What `RingBuf`'s `fmt::Write::write_fmt` formats into: the ring's free space, `len` bytes of
+/// which are filled in but not published yet.
+struct Staged<'a> {
+    ring: &'a mut RingBuf,
+    len: usize,
+}
+
+impl Staged<'_> {
+    /// Evicts the oldest pending bytes until `more` fit after what's staged, like
+    /// `RingBuf::write_overwriting`. The staged bytes are all in the free space, so they're safe.
+    fn evict_for(&mut self, more: usize) -> std::fmt::Result {
+        let need = self.len.saturating_add(more);
+        let short = need.saturating_sub(self.ring.free_space());
+        if short == 0 {
+            return Ok(());
+        }
+        if need > self.ring.capacity() || self.ring.has_cursors() {
+            return Err(std::fmt::Error);
+        }
+        self.ring.consume(short).map_err(|_| std::fmt::Error)
+    }
+}
+
+impl std::fmt::Write for Staged<'_> {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        if self.ring.overwrite {
+            self.evict_for(s.len())?;
+        }
+        if !index::fits(s.len(), self.ring.free_space() - self.len) {
+            return Err(std::fmt::Error);
+        }
+        unsafe { self.ring.copy_at(self.len, s.as_bytes()) };
+        self.len += s.len();
+        self.ring.write_window = Some(self.len);
+        Ok(())
+    }
+}
+
+/// The length prefix of a `RingBuf::write_msg` frame.
+const MSG_HEADER_LEN: usize = size_of::<u32>();
+
+/// The length prefix and CRC of a `RingBuf::write_msg_checked` frame.
+const CHECKED_MSG_HEADER_LEN: usize = 2 * size_of::<u32>();
+
+/// Set in the length prefix of a checked frame.
+const CHECKED_FRAME: u32 = 1 << 31;
+
+/// The length prefix and send time of a `Producer::write_msg_timed` frame.
+const TIMED_MSG_HEADER_LEN: usize = size_of::<u32>() + size_of::<u64>();
+
+/// Set in the length prefix of a timed frame. `frame_len` keeps every payload under it, so no
+/// other frame's length has it set.
+const TIMED_FRAME: u32 = 1 << 30;
+
+/// What `RingBuf::write_msg_end` writes in place of a length prefix. Would be a checked frame of
+/// 2 GiB less two bytes, which `frame_len` turns away.
+const END_FRAME: u32 = u32::MAX - 1;
+
+/// A frame's header, as read from the ring.
+#[derive(Clone, Copy)]
+enum Frame {
+    /// A message from `write_msg`, or `write_msg_checked` if `checked`, of `len` bytes.
+    Msg { checked: bool, len: usize },
+    /// A message from `Producer::write_msg_timed`, of `len` bytes.
+    Timed { len: usize },
+    /// The marker from `write_msg_end`.
+    End,
+}
+
+impl Frame {
+    /// Makes sense of the first `MSG_HEADER_LEN` bytes of a frame in a ring of `capacity`. Fails
+    /// with `BufError::FrameTooLarge` for a message that could never fit.
+    fn parse(word: &[u8], capacity: usize) -> Result<Self> {
+        let word = u32::from_le_bytes(word.try_into().expect("Four bytes."));
+        if word == END_FRAME {
+            return Ok(Self::End);
+        }
+        let checked = word & CHECKED_FRAME != 0;
+        let frame = if !checked && word & TIMED_FRAME != 0 {
+            Self::Timed {
+                len: (word & !TIMED_FRAME) as usize,
+            }
+        } else {
+            Self::Msg {
+                checked,
+                len: (word & !CHECKED_FRAME) as usize,
+            }
+        };
+        if frame.size() > capacity {
+            return Err(BufError::FrameTooLarge.into());
+        }
+        Ok(frame)
+    }
+
+    /// Header and payload together.
+    fn size(self) -> usize {
+        match self {
+            Self::Msg { len, .. } | Self::Timed { len } => self.header_len() + len,
+            Self::End => MSG_HEADER_LEN,
+        }
+    }
+
+    /// Where the payload starts.
+    fn header_len(self) -> usize {
+        match self {
+            Self::Msg { checked: true, .. } => CHECKED_MSG_HEADER_LEN,
+            Self::Msg { checked: false, .. } | Self::End => MSG_HEADER_LEN,
+            Self::Timed { .. } => TIMED_MSG_HEADER_LEN,
+        }
+    }
+}
+
+/// `payload`'s length for a frame header, if a frame with a header of `header` bytes around it
+/// could ever fit in a ring of `capacity`.
+fn frame_len(payload: &[u8], header: usize, capacity: usize) -> Result<u32> {
+    match u32::try_from(payload.len()) {
+        Ok(len) if len < TIMED_FRAME && payload.len() <= capacity.saturating_sub(header) => Ok(len),
+        _ => Err(BufError::FrameTooLarge.into()),
+    }
+}
+
+/// What `RingBuf::recv_msg` and friends found: a message, or the end of the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsgEvent<T> {
+    Data(T),
+    /// The writer ended the stream on purpose, with `RingBuf::write_msg_end` or
+    /// `Producer::finish`.
+    End,
+}
+
+/// See `RingBuf::peek_iter`. A concrete type rather than `impl Iterator` so the borrow of the
+/// ring ends at the iterator's last use instead of at the end of the scope.
+pub type PeekIter<'a> = std::iter::Copied<std::slice::Iter<'a, u8>>;
+
+impl<'a> IntoIterator for &'a RingBuf {
+    type Item = u8;
+    type IntoIter = PeekIter<'a>;
+
+    /// `peek_iter`, so `for byte in &ring` walks the pending bytes without consuming them.
+    fn into_iter(self) -> PeekIter<'a> {
+        self.peek_iter()
+    }
+}
+
+/// See `RingBuf::drain`. Holds the ring mutably, so nothing else can read or write while bytes
+/// are still coming out of it.
+pub struct Drain<'a> {
+    ring: &'a mut RingBuf,
+    // What's left to yield, as offsets from the head. The ring itself doesn't move until drop.
+    front: usize,
+    back: usize,
+    len: usize,
+}
+
+impl Iterator for Drain<'_> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.front == self.back {
+            return None;
+        }
+        self.front += 1;
+        Some(self.ring.pending()[self.front - 1])
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.back - self.front;
+        (left, Some(left))
+    }
+}
+
+impl DoubleEndedIterator for Drain<'_> {
+    fn next_back(&mut self) -> Option<u8> {
+        if self.front == self.back {
+            return None;
+        }
+        self.back -= 1;
+        Some(self.ring.pending()[self.back])
+    }
+}
+
+impl ExactSizeIterator for Drain<'_> {}
+
+impl std::iter::FusedIterator for Drain<'_> {}
+
+impl Drop for Drain<'_> {
+    fn drop(&mut self) {
+        self.ring.consume(self.len).expect("Checked by `drain`.");
+    }
+}
+
+/// See `RingBuf::iter_frames_mut`.
+pub struct FramesMut<'a> {
+    // The pending bytes not yet handed out, starting at a frame boundary.
+    rest: &'a mut [u8],
+    mirror: Option<&'a Mirror>,
+    // Where `rest` starts in the mapping.
+    offset: usize,
+}
+
+impl<'a> Iterator for FramesMut<'a> {
+    type Item = FrameMut<'a>;
+
+    fn next(&mut self) -> Option<FrameMut<'a>> {
+        let word = self.rest.get(..MSG_HEADER_LEN)?;
+        let word = u32::from_le_bytes(word.try_into().expect("Four bytes."));
+        if word == END_FRAME {
+            return None;
+        }
+        let (header, len) = if word & CHECKED_FRAME != 0 {
+            (CHECKED_MSG_HEADER_LEN, word & !CHECKED_FRAME)
+        } else {
+            (MSG_HEADER_LEN, word)
+        };
+        if self.rest.len() < header + len as usize {
+            return None;
+        }
+        let (frame, rest) = std::mem::take(&mut self.rest).split_at_mut(header + len as usize);
+        self.rest = rest;
+        let offset = self.offset;
+        self.offset += frame.len();
+        let (header, payload) = frame.split_at_mut(header);
+        Some(FrameMut {
+            header,
+            payload,
+            touched: false,
+            mirror: self.mirror,
+            offset,
+        })
+    }
+}
+
+impl std::iter::FusedIterator for FramesMut<'_> {}
+
+/// One message still in the ring, from `RingBuf::iter_frames_mut`. Its payload can be changed but
+/// not resized. If the payload was handed out mutably, a checked frame gets its CRC redone on
+/// drop, so `read_msg_checked` still accepts it, and a `BackendKind::Heap` ring copies the frame
+/// to its other view.
+pub struct FrameMut<'a> {
+    header: &'a mut [u8],
+    payload: &'a mut [u8],
+    touched: bool,
+    mirror: Option<&'a Mirror>,
+    // Where the header starts in the mapping.
+    offset: usize,
+}
+
+impl FrameMut<'_> {
+    /// Whether it was written by `write_msg_checked`.
+    pub fn is_checked(&self) -> bool {
+        self.header.len() == CHECKED_MSG_HEADER_LEN
+    }
+
+    /// The payload's length, as recorded in the header.
+    pub fn len(&self) -> usize {
+        self.payload.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.payload.is_empty()
+    }
+
+    /// The CRC stored in a checked frame's header, as it was before any changes.
+    pub fn stored_crc(&self) -> Option<u32> {
+        let crc = self.header.get(MSG_HEADER_LEN..)?;
+        Some(u32::from_le_bytes(crc.try_into().expect("Four bytes.")))
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        self.payload
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        self.touched = true;
+        self.payload
+    }
+}
+
+impl Drop for FrameMut<'_> {
+    fn drop(&mut self) {
+        if !self.touched {
+            return;
+        }
+        if self.is_checked() {
+            let crc = crc::crc32(self.payload);
+            self.header[MSG_HEADER_LEN..].copy_from_slice(&crc.to_le_bytes());
+        }
+        if let Some(mirror) = self.mirror {
+            // The frame is pending, so it's within a view's length of the head.
+            unsafe { mirror.sync(self.offset, self.header.len() + self.payload.len()) };
+        }
+    }
+}
+
+/// A copy of (part of) a ring's pending data, plus where the ring stood when it was taken. See
+/// `RingBuf::freeze`.
+#[derive(Debug, Clone)]
+pub struct FrozenSnapshot {
+    pub data: Arc<[u8]>,
+    /// Where `data` started, as an offset from the head at the time.
+    pub offset: usize,
+    pub head: usize,
+    pub tail: usize,
+    /// Bytes pending at the time, which is more than `data.len()` for `freeze_range`.
+    pub len: usize,
+    pub bytes_written: u64,
+    pub bytes_read: u64,
+}
+
+/// What `RingBuf::fill_from` did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Filled {
+    /// How many bytes came in.
+    pub bytes: usize,
+    pub stop: FillStop,
+}
+
+/// Why `RingBuf::fill_from` stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FillStop {
+    /// The source reported end-of-file.
+    Eof,
+    /// The ring ran out of free space.
+    Full,
+    /// The requested maximum was reached.
+    Max,
+}
+
+/// See `RingBuf::stats`.
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    pub oldest_data_age: Option<Duration>,
+    /// Whether the kernel is currently backing any of the ring with transparent huge pages. Only
+    /// ever true for rings built with `RingBufBuilder::transparent_huge_pages(true)`, and only
+    /// once the memory has been touched.
+    pub thp_backed: bool,
+    /// Bytes written and read since the ring was made or `reset_stats` was last called.
+    pub bytes_written: u64,
+    pub bytes_read: u64,
+    /// The most that has been pending at once, over the same span.
+    pub high_water: usize,
+    /// Writes turned away for lack of space.
+    pub rejected_writes: u64,
+    /// How many times the tail has wrapped around to the start of the ring.
+    pub wraps: u64,
+    /// Whole messages dropped to make room, by `write_msg_evicting` or `write_msg` on an
+    /// overwrite ring.
+    pub dropped_frames: u64,
+    /// Messages a `Consumer` skipped for being older than its `set_ttl`.
+    pub expired_frames: u64,
+}
+
+/// The counters behind `Stats`, bumped on the write and read paths.
+#[derive(Default)]
+struct Counters {
+    bytes_written: u64,
+    bytes_read: u64,
+    high_water: usize,
+    rejected_writes: u64,
+    wraps: u64,
+    dropped_frames: u64,
+}
+
+/// See `RingBuf::stats_interval`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IntervalStats {
+    /// When the interval began, according to the ring's clock.
+    pub started_at: Instant,
+    /// How long the interval lasted.
+    pub elapsed: Duration,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub ops_in: u64,
+    pub ops_out: u64,
+    /// Writes turned away for lack of space.
+    pub drops: u64,
+    /// The fullest the ring got, counting what was already in it when the interval began.
+    pub max_fill: usize,
+}
+
+impl IntervalStats {
+    fn starting_at(started_at: Instant, fill: usize) -> Self {
+        Self {
+            started_at,
+            elapsed: Duration::ZERO,
+            bytes_in: 0,
+            bytes_out: 0,
+            ops_in: 0,
+            ops_out: 0,
+            drops: 0,
+            max_fill: fill,
+        }
+    }
+}
+
+/// Read-only access to the pending data of a `RingBuf`, see `RingBuf::viewer`. Offsets are
+/// relative to the oldest unread byte.
+#[derive(Clone, Copy)]
+pub struct RingViewer<'a> {
+    pending: &'a [u8],
+}
+
+impl<'a> RingViewer<'a> {
+    /// The pending bytes in `range`. Views may overlap and live as long as the viewer's borrow of
+    /// the ring does.
+    pub fn view(&self, range: impl RangeBounds<usize>) -> Result<&'a [u8]> {
+        let range = resolve_range(range, self.pending.len())?;
+        Ok(&self.pending[range])
+    }
+
+    /// Splits the pending data in two at `at`, e.g. into a header and a payload.
+    pub fn split_view(&self, at: usize) -> Result<(&'a [u8], &'a [u8])> {
+        if at > self.pending.len() {
+            return Err(BufError::NotEnoughData {
+                requested: at,
+                available: self.pending.len(),
+            }
+            .into());
+        }
+        Ok(self.pending.split_at(at))
+    }
+}
+
+/// Turns `range` into concrete offsets into `len` pending bytes, or an error if it doesn't fit.
+fn resolve_range(range: impl RangeBounds<usize>, len: usize) -> Result<Range<usize>> {
+    let start = match range.start_bound() {
+        Bound::Included(&start) => start,
+        Bound::Excluded(&start) => start.saturating_add(1),
+        Bound::Unbounded => 0,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(&end) => end.saturating_add(1),
+        Bound::Excluded(&end) => end,
+        Bound::Unbounded => len,
+    };
+    if start > end || end > len {
+        return Err(BufError::NotEnoughData {
+            requested: start.max(end),
+            available: len,
+        }
+        .into());
+    }
+    Ok(start..end)
+}
+
+fn memchr(b: u8, haystack: &[u8]) -> Option<usize> {
+    let found = unsafe {
+        libc::memchr(
+            haystack.as_ptr() as *const std::ffi::c_void,
+            b as libc::c_int,
+            haystack.len(),
+        )
+    };
+    (!found.is_null()).then(|| found as usize - haystack.as_ptr() as usize)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn memrchr(b: u8, haystack: &[u8]) -> Option<usize> {
+    let found = unsafe {
+        libc::memrchr(
+            haystack.as_ptr() as *const std::ffi::c_void,
+            b as libc::c_int,
+            haystack.len(),
+        )
+    };
+    (!found.is_null()).then(|| found as usize - haystack.as_ptr() as usize)
+}
+
+/// libc only has `memrchr` on glibc and bionic.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn memrchr(b: u8, haystack: &[u8]) -> Option<usize> {
+    haystack.iter().rposition(|&x| x == b)
+}
+
+fn as_u8_slice<T: Pod>(value: &T) -> &[u8] {
+    slice_as_u8_slice(std::slice::from_ref(value))
+}
+
+fn slice_as_u8_slice<T: Pod>(values: &[T]) -> &[u8] {
+    // SAFETY: `Pod` types have no padding, so every byte is initialized.
+    unsafe { std::slice::from_raw_parts(values.as_ptr().cast::<u8>(), size_of_val(values)) }
+}
+
+fn slice_as_u8_slice_mut<T: Pod>(values: &mut [T]) -> &mut [u8] {
+    // SAFETY: As above, and any bytes written through the result make valid `Pod` values.
+    unsafe { std::slice::from_raw_parts_mut(values.as_mut_ptr().cast::<u8>(), size_of_val(values)) }
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    Nix(Errno),
+    Ours(BufError),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Nix(e) => write!(f, "{e}"),
+            Self::Ours(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl ErrTrait for Error {
+    fn source(&self) -> Option<&(dyn ErrTrait + 'static)> {
+        match self {
+            Self::Nix(e) => Some(e),
+            Self::Ours(e) => Some(e),
+        }
+    }
+}
+
+impl From<Errno> for Error {
+    fn from(value: Errno) -> Self {
+        Self::Nix(value)
+    }
+}
+
+impl From<BufError> for Error {
+    fn from(value: BufError) -> Self {
+        Self::Ours(value)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(value: Error) -> Self {
+        let kind = match &value {
+            Error::Nix(e) => return io::Error::from(*e),
+            Error::Ours(e) => match e {
+                BufError::NotEnoughSpace { .. }
+                | BufError::NotEnoughData { .. }
+                | BufError::Throttled { .. } => io::ErrorKind::WouldBlock,
+                BufError::HugePagesUnavailable | BufError::MemoryLockLimit => {
+                    io::ErrorKind::OutOfMemory
+                }
+                BufError::TypeMismatch { .. }
+                | BufError::MixedTypeChecks
+                | BufError::CorruptFrame { .. }
+                | BufError::MixedFrames
+                | BufError::Lagged
+                | BufError::VersionMismatch { .. } => io::ErrorKind::InvalidData,
+                BufError::TimedOut => io::ErrorKind::TimedOut,
+                BufError::Disconnected => io::ErrorKind::BrokenPipe,
+                BufError::EndOfStream => io::ErrorKind::UnexpectedEof,
+                BufError::HandshakeDisconnected => io::ErrorKind::ConnectionAborted,
+                BufError::Incomplete { .. } => io::ErrorKind::WriteZero,
+                BufError::UnknownPageSize => io::ErrorKind::Unsupported,
+                BufError::Poisoned => io::ErrorKind::Other,
+                BufError::ZeroCapacity
+                | BufError::CapacityOverflow
+                | BufError::CapacityMismatch
+                | BufError::InvalidName
+                | BufError::FrameTooLarge
+                | BufError::UnalignedCapacity { .. }
+                | BufError::IncompatibleOptions(_) => io::ErrorKind::InvalidInput,
+            },
+        };
+        io::Error::new(kind, value)
+    }
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum BufError {
+    /// A write needed `requested` bytes of free space but only `available` were free.
+    NotEnoughSpace {
+        requested: usize,
+        available: usize,
+    },
+    /// A read wanted `requested` pending bytes but only `available` were there.
+    NotEnoughData {
+        requested: usize,
+        available: usize,
+    },
+    ZeroCapacity,
+    /// The asked-for size doesn't fit in a `usize` once rounded up and mirrored.
+    CapacityOverflow,
+    CapacityMismatch,
+    /// `read_typed` found a value written as another type. `found` names it if this process has
+    /// written that type with checks on too, and gives the hash of its name otherwise.
+    TypeMismatch {
+        expected: &'static str,
+        found: Cow<'static, str>,
+    },
+    /// A typed value was written with typed checks on and read with them off, or vice versa.
+    MixedTypeChecks,
+    /// The system wouldn't say what its page size is.
+    UnknownPageSize,
+    /// A name given to `RingBufBuilder::name` has a NUL in it or is too long.
+    InvalidName,
+    /// A message frame claims to be longer than the ring could ever hold, so it will never be
+    /// complete. Either a corrupt length, or a ring too small for the messages sent through it.
+    FrameTooLarge,
+    /// `read_msg_checked` found a frame whose payload doesn't match the CRC-32 `expected` in its
+    /// header.
+    CorruptFrame {
+        expected: u32,
+        actual: u32,
+    },
+    /// `read_msg` found a frame from `write_msg_checked`, or the other way around.
+    MixedFrames,
+    /// The kernel has no hugetlb pages to give, or no hugetlb support at all. See
+    /// `RingBufBuilder::huge_pages`.
+    HugePagesUnavailable,
+    /// Locking the ring into memory would go over `RLIMIT_MEMLOCK`. See
+    /// `RingBufBuilder::lock_memory`.
+    MemoryLockLimit,
+    /// A blocking call ran out of time.
+    TimedOut,
+    /// The other half of a split ring was dropped while this one was waiting on it.
+    Disconnected,
+    /// `Producer::write_all_blocking` got only the first `written` bytes in before the consumer
+    /// went away or it ran out of time.
+    Incomplete {
+        written: usize,
+    },
+    /// `RingBufBuilder::capacity_bytes` asked for a size that isn't a multiple of the page size
+    /// (or of 2 MiB, with huge pages).
+    UnalignedCapacity {
+        capacity: usize,
+        granularity: usize,
+    },
+    /// Two `RingBufBuilder` options that can't both be had, named in the message.
+    IncompatibleOptions(&'static str),
+    /// A thread panicked while it held a `SharedRingBuf`'s lock, so the ring may be
+    /// half-updated.
+    Poisoned,
+    /// `read_msg` and friends reached the end marker from `RingBuf::write_msg_end` or
+    /// `Producer::finish`. `recv_msg` returns it as `MsgEvent::End` instead.
+    EndOfStream,
+    /// A pinned `ReadCursor` read more than its budget past the pin, and the ring has started
+    /// writing over what it pinned.
+    Lagged,
+    /// The other end of a `RingListener` handshake speaks version `theirs` of it, not `ours`.
+    VersionMismatch {
+        ours: u16,
+        theirs: u16,
+    },
+    /// The other end of a `RingListener` handshake went away before it was over.
+    HandshakeDisconnected,
+    /// A `RateLimitedProducer` is out of tokens for the write until `retry_after` from now.
+    Throttled {
+        retry_after: Duration,
+    },
+}
+
+impl Display for BufError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotEnoughSpace {
+                requested,
+                available,
+            } => write!(
+                f,
+                "Not enough buffer space ({requested} bytes wanted, {available} free)!"
+            ),
+            Self::NotEnoughData {
+                requested,
+                available,
+            } => write!(
+                f,
+                "Not enough buffered data ({requested} bytes wanted, {available} pending)!"
+            ),
+            Self::ZeroCapacity => write!(f, "Buffer has no capacity!"),
+            Self::CapacityOverflow => write!(f, "Buffer size overflows!"),
+            Self::CapacityMismatch => write!(f, "Buffers have different capacities!"),
+            Self::TypeMismatch { expected, found } => {
+                write!(f, "Expected a {expected} but found a {found}!")
+            }
+            Self::MixedTypeChecks => {
+                write!(f, "Typed checks are on for one end of the buffer only!")
+            }
+            Self::UnknownPageSize => write!(f, "Couldn't determine the page size!"),
+            Self::InvalidName => write!(f, "Invalid memfd name!"),
+            Self::FrameTooLarge => write!(f, "Message frame is bigger than the buffer!"),
+            Self::CorruptFrame { expected, actual } => write!(
+                f,
+                "Message frame is corrupt (CRC {actual:#010x}, expected {expected:#010x})!"
+            ),
+            Self::MixedFrames => write!(f, "Checked and unchecked message frames are mixed!"),
+            Self::HugePagesUnavailable => write!(f, "No huge pages available!"),
+            Self::MemoryLockLimit => write!(f, "Over the locked memory limit!"),
+            Self::TimedOut => write!(f, "Timed out waiting on the buffer!"),
+            Self::Disconnected => write!(f, "The other end of the buffer is gone!"),
+            Self::Incomplete { written } => {
+                write!(f, "Only {written} bytes made it into the buffer!")
+            }
+            Self::UnalignedCapacity {
+                capacity,
+                granularity,
+            } => write!(
+                f,
+                "Capacity of {capacity} bytes isn't a multiple of {granularity}!"
+            ),
+            Self::IncompatibleOptions(which) => write!(f, "Can't combine {which}!"),
+            Self::Poisoned => write!(f, "A thread panicked while holding the buffer's lock!"),
+            Self::EndOfStream => write!(f, "The stream of messages has ended!"),
+            Self::Lagged => write!(f, "Pinned data was overwritten!"),
+            Self::VersionMismatch { ours, theirs } => {
+                write!(f, "Handshake version {theirs} doesn't match ours ({ours})!")
+            }
+            Self::HandshakeDisconnected => write!(f, "The other end left mid-handshake!"),
+            Self::Throttled { retry_after } => {
+                write!(f, "Rate limited for another {retry_after:?}!")
+            }
+        }
+    }
+}
+impl ErrTrait for BufError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The page size the tests are running with.
+    fn page() -> usize {
+        page_size().expect("Page size lookup should work.")
+    }
+
+    #[test]
+    fn simple_buf() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        buf.write(b"This is my string. There are many like it, but this one is mine.")
+            .expect("Not writing too far, should be okay.");
+        let sub_str = buf
+            .read(b"This is my string.".len())
+            .expect("Taking what is available.");
+        // buf.write(b"Okay sir");
+        assert_eq!(sub_str, b"This is my string.");
+        assert_eq!(
+            buf.contents_size(),
+            b" There are many like it, but this one is mine.".len()
+        );
+        buf.write(b" I love my substring.")
+            .expect("Continuing to write");
+    }
+
+    #[test]
+    fn sized_by_the_real_page_size() {
+        let page = page();
+        assert!(page.is_power_of_two() && page >= 4096);
+        assert_eq!(page, unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize);
+        let buf = RingBuf::new(3).expect("Creation should work.");
+        assert_eq!(buf.capacity(), 3 * page);
+        assert_eq!(buf.data_ptr() as usize % page, 0);
+        assert!(matches!(
+            RingBuf::new(usize::MAX),
+            Err(Error::Ours(BufError::CapacityOverflow))
+        ));
+    }
+
+    #[test]
+    fn page_wrap() {
+        let (page, half, quarter) = (page(), page() / 2, page() / 4);
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        assert_eq!(buf.capacity(), page);
+        buf.write(&vec![1; page])
+            .expect("Should fit in the buffer.");
+        let _lotsa_ones = buf.read(half).expect("Should be available.");
+        assert_eq!(buf.head, half);
+        assert_eq!(buf.tail, 0);
+        buf.write(&vec![2; page])
+            .expect_err("We can't fit more than one page in this buffer.");
+        buf.write(&vec![2; half]).expect(
+            "Failure to write shouldn't affect our buffer. Also, there should be enough space.",
+        );
+        let _more_ones = buf.read(quarter).expect("Business as usual");
+        let wrapping = buf.read(half).expect("I trust my MMU.");
+        let should_have_read = {
+            let mut scratch = vec![0; half];
+            let (before_page_end, after_page_end) = scratch.split_at_mut(quarter);
+            before_page_end.fill(1);
+            after_page_end.fill(2);
+            scratch
+        };
+        assert_eq!(wrapping, should_have_read);
+    }
+
+    #[test]
+    fn read_mut_in_place() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        buf.write(b"shout").expect("Should fit.");
+        let view = buf.read_mut(5).expect("Should be available.");
+        view.make_ascii_uppercase();
+        assert_eq!(view, b"SHOUT");
+        buf.read(1).expect_err("Everything was consumed.");
+    }
+
+    #[test]
+    fn overlapping_views_across_wrap() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        park_at(&mut buf, page() - 6);
+        buf.write(b"HDR:0123456789").expect("Should fit.");
+
+        let viewer = buf.viewer();
+        let header = viewer.view(..4).expect("In range.");
+        let framed = viewer.view(2..).expect("In range.");
+        // Both views are alive at once and overlap, and the second one crosses the wrap.
+        assert_eq!(header, b"HDR:");
+        assert_eq!(framed, b"R:0123456789");
+
+        let (head, payload) = viewer.split_view(4).expect("In range.");
+        assert_eq!(head, header);
+        assert_eq!(payload, b"0123456789");
+
+        viewer.view(10..15).expect_err("Past the pending data.");
+        viewer.split_view(15).expect_err("Past the pending data.");
+        // Viewing doesn't consume anything.
+        assert_eq!(buf.contents_size(), 14);
+    }
+
+    #[test]
+    fn oldest_data_age() {
+        let clock = MockClock::new();
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        buf.set_clock(clock.clone());
+        assert_eq!(buf.oldest_data_age(), None);
+
+        buf.write(&[1; 100]).expect("Should fit.");
+        clock.advance(Duration::from_millis(10));
+        buf.write(&[2; 100]).expect("Should fit.");
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(buf.oldest_data_age(), Some(Duration::from_millis(15)));
+
+        // Partially consuming the first write leaves its bytes as the oldest.
+        buf.read(60).expect("Should be available.");
+        assert_eq!(buf.oldest_data_age(), Some(Duration::from_millis(15)));
+        assert_eq!(buf.stats().oldest_data_age, Some(Duration::from_millis(15)));
+
+        // Once the head is into the second write, that's what counts.
+        buf.read(50).expect("Should be available.");
+        assert_eq!(buf.oldest_data_age(), Some(Duration::from_millis(5)));
+
+        buf.read(90).expect("Should be available.");
+        assert_eq!(buf.oldest_data_age(), None);
+
+        buf.write(&[3; 10]).expect("Should fit.");
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(buf.oldest_data_age(), Some(Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn oldest_data_age_with_marks_exhausted() {
+        let clock = MockClock::new();
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        buf.set_clock(clock.clone());
+        for _ in 0..MAX_AGE_MARKS + 10 {
+            buf.write(&[0; 8]).expect("Should fit.");
+            clock.advance(Duration::from_millis(1));
+        }
+        // Drain everything the marks cover. The unmarked writes after them are reported as the
+        // age of the last mark, i.e. too old rather than too young.
+        buf.read(MAX_AGE_MARKS * 8).expect("Should be available.");
+        assert_eq!(buf.oldest_data_age(), Some(Duration::from_millis(11)));
+    }
+
+    #[test]
+    fn capacity_in_bytes() {
+        let page = page();
+        for (min_bytes, capacity) in [(1, page), (page, page), (page + 1, 2 * page)] {
+            let buf = RingBuf::with_capacity(min_bytes).expect("Creation should work.");
+            assert_eq!(buf.capacity(), capacity, "asked for {min_bytes}");
+        }
+        for result in [RingBuf::new(0), RingBuf::with_capacity(0)] {
+            assert!(matches!(result, Err(Error::Ours(BufError::ZeroCapacity))));
+        }
+        for result in [RingBuf::new(usize::MAX), RingBuf::with_capacity(usize::MAX)] {
+            assert!(matches!(
+                result,
+                Err(Error::Ours(BufError::CapacityOverflow))
+            ));
+        }
+    }
+
+    #[test]
+    fn absurd_sizes_fail_without_leaking() {
+        let leaks = LeakCheck::new();
+        // Rounds up and doubles without overflowing, so with a memfd backend this only fails once
+        // the memfd exists and the mapping is under way.
+        let err = RingBuf::with_capacity(1 << 62).expect_err("Nothing is that big.");
+        assert!(matches!(
+            io::Error::from(err).kind(),
+            io::ErrorKind::OutOfMemory | io::ErrorKind::InvalidInput
+        ));
+        leaks.finish().expect("Nothing leaked.");
+    }
+
+    #[test]
+    fn errors_as_io_errors() {
+        let mut buf = RingBuf::new(1).unwrap();
+        let cap = buf.capacity();
+        let full = buf.write(&vec![0; cap + 1]).unwrap_err();
+        assert_eq!(
+            full.to_string(),
+            format!(
+                "Not enough buffer space ({} bytes wanted, {cap} free)!",
+                cap + 1
+            )
+        );
+        assert_eq!(io::Error::from(full).kind(), io::ErrorKind::WouldBlock);
+        let empty = buf.read(1).unwrap_err();
+        assert!(matches!(
+            empty,
+            Error::Ours(BufError::NotEnoughData {
+                requested: 1,
+                available: 0
+            })
+        ));
+        assert_eq!(io::Error::from(empty).kind(), io::ErrorKind::WouldBlock);
+
+        let errno = io::Error::from(Error::Nix(Errno::ENOMEM));
+        assert_eq!(errno.raw_os_error(), Some(libc::ENOMEM));
+        let typed = io::Error::from(Error::from(BufError::MixedTypeChecks));
+        assert_eq!(typed.kind(), io::ErrorKind::InvalidData);
+        assert!(typed.into_inner().unwrap().is::<Error>());
+    }
+
+    #[test]
+    fn take_leaves_placeholder() {
+        let _leaks = LeakCheck::new();
+        let mut filling = RingBuf::new(1).expect("Creation should work.");
+        filling.write(b"frame one").expect("Should fit.");
+
+        let mut draining = std::mem::take(&mut filling);
+        assert_eq!(filling.capacity(), 0);
+        assert!(matches!(
+            filling.write(b"x"),
+            Err(Error::Ours(BufError::ZeroCapacity))
+        ));
+        assert!(matches!(
+            filling.write_all_slices(&[]),
+            Err(Error::Ours(BufError::ZeroCapacity))
+        ));
+        filling.read(1).expect_err("Placeholder is empty.");
+        assert_eq!(filling.read(0).expect("Nothing to read is fine."), b"");
+        assert!(filling.viewer().view(..).expect("Empty view.").is_empty());
+
+        assert_eq!(draining.read(9).expect("Moved over intact."), b"frame one");
+        // Dropping the placeholder must not try to unmap anything.
+        drop(filling);
+    }
+
+    #[test]
+    fn swap_contents() {
+        let _leaks = LeakCheck::new();
+        let mut a = RingBuf::new(1).expect("Creation should work.");
+        let mut b = RingBuf::new(1).expect("Creation should work.");
+        park_at(&mut a, page() - 96);
+        a.write(b"wrapped in a").expect("Should fit.");
+        b.write(b"in b").expect("Should fit.");
+
+        a.swap_contents(&mut b).expect("Same capacity.");
+        assert_eq!(a.read(4).expect("b's data."), b"in b");
+        assert_eq!(b.read(12).expect("a's data."), b"wrapped in a");
+        a.read(1).expect_err("Only b's data came over.");
+
+        let mut bigger = RingBuf::new(2).expect("Creation should work.");
+        assert!(matches!(
+            a.swap_contents(&mut bigger),
+            Err(Error::Ours(BufError::CapacityMismatch))
+        ));
+    }
+
+    #[test]
+    fn moves_to_another_thread() {
+        let leaks = LeakCheck::new();
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        park_at(&mut buf, page() - 4);
+        buf.write(b"wrapped!").expect("Should fit.");
+        let mut other = RingBuf::new(1).expect("Creation should work.");
+        other.write(b"swapped").expect("Should fit.");
+        // The pointers are into the mappings, not the structs, so this mustn't disturb them.
+        std::mem::swap(&mut buf, &mut other);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let worker = std::thread::spawn(move || {
+            tx.send(buf.read(7).expect("Moved over intact.").to_vec())
+                .unwrap();
+            tx.send(other.read(8).expect("Moved over intact.").to_vec())
+                .unwrap();
+            other.write(b"from the worker").expect("Should fit.");
+            other
+        });
+        assert_eq!(rx.recv().unwrap(), b"swapped");
+        assert_eq!(rx.recv().unwrap(), b"wrapped!");
+        let mut back = worker.join().expect("Worker shouldn't panic.");
+        assert_eq!(
+            back.read(15).expect("Moved back intact."),
+            b"from the worker"
+        );
+        back.check_invariants();
+        drop(back);
+        leaks
+            .finish()
+            .expect("Both rings unmapped, one on each thread.");
+    }
+
+    #[test]
+    fn shrink_wrapped() {
+        let _leaks = LeakCheck::new();
+        let page = page();
+        let mut buf = RingBuf::new(4).expect("Creation should work.");
+        park_at(&mut buf, 4 * page - 1000);
+        buf.write(&[0; 1000]).expect("Should fit.");
+        buf.write(&[1; 2000]).expect("Wraps past the end.");
+        assert!(buf.write_offset() < buf.read_offset());
+
+        buf.shrink_to(2999)
+            .expect_err("Would have to drop pending data.");
+        assert_eq!(buf.capacity(), 4 * page);
+        assert_eq!(buf.len(), 3000);
+
+        buf.shrink_to(3000).expect("Exactly what's pending.");
+        assert_eq!(buf.capacity(), page);
+        assert_eq!(buf.len(), 3000);
+        assert_eq!(buf.read(1000).expect("Old data first."), &[0; 1000]);
+        assert_eq!(buf.read(2000).expect("Then the wrapped data."), &[1; 2000]);
+
+        // Still a working ring afterwards, wrap included.
+        park_at(&mut buf, page - 96);
+        buf.write(&[3; 300]).expect("Should fit.");
+        assert_eq!(buf.read(300).expect("Should be available."), &[3; 300]);
+    }
+
+    #[test]
+    fn shrink_to_fit() {
+        let _leaks = LeakCheck::new();
+        let page = page();
+        let mut buf = RingBuf::new(3).expect("Creation should work.");
+        buf.write(&vec![5; page + 1]).expect("Should fit.");
+        buf.shrink_to_fit().expect("Two pages will do.");
+        assert_eq!(buf.capacity(), 2 * page);
+        buf.shrink_to(3 * page).expect("Never grows.");
+        assert_eq!(buf.capacity(), 2 * page);
+        assert_eq!(
+            buf.read(page + 1).expect("All still there."),
+            &vec![5; page + 1][..]
+        );
+
+        buf.shrink_to_fit().expect("Empty rings keep one page.");
+        assert_eq!(buf.capacity(), page);
+    }
+
+    #[test]
+    fn stats_interval() {
+        let clock = MockClock::new();
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        buf.set_clock(clock.clone());
+        // 96 bytes short of full after the first two writes.
+        let fill = page() - 96;
+
+        buf.write(&vec![0; fill - 3000]).expect("Should fit.");
+        buf.write_all_slices(&[&[0; 2000], &[0; 1000]])
+            .expect("Should fit.");
+        buf.write(&[0; 200]).expect_err("Doesn't fit.");
+        buf.read(500).expect("Should be available.");
+        clock.advance(Duration::from_millis(250));
+
+        let first = buf.stats_interval();
+        assert_eq!(first.elapsed, Duration::from_millis(250));
+        assert_eq!(first.bytes_in, fill as u64);
+        assert_eq!(first.ops_in, 2);
+        assert_eq!(first.bytes_out, 500);
+        assert_eq!(first.ops_out, 1);
+        assert_eq!(first.drops, 1);
+        assert_eq!(first.max_fill, fill);
+
+        buf.read(3000).expect("Should be available.");
+        buf.read(0).expect("Reading nothing is fine.");
+        buf.write(&[0; 10]).expect("Should fit.");
+        clock.advance(Duration::from_secs(1));
+
+        let second = buf.stats_interval();
+        assert_eq!(second.started_at, first.started_at + first.elapsed);
+        assert_eq!(second.elapsed, Duration::from_secs(1));
+        assert_eq!(second.bytes_in, 10);
+        assert_eq!(second.ops_in, 1);
+        assert_eq!(second.bytes_out, 3000);
+        assert_eq!(second.ops_out, 1);
+        assert_eq!(second.drops, 0);
+        // What was left over from the first interval counts.
+        assert_eq!(second.max_fill, fill - 500);
+
+        // The lifetime totals keep counting across intervals.
+        assert_eq!(buf.bytes_written, fill as u64 + 10);
+        assert_eq!(buf.bytes_read, 3500);
+    }
+
+    #[test]
+    fn lifetime_stats() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        let cap = buf.capacity();
+        buf.write(&[0; 1000]).expect("Should fit.");
+        buf.write(&vec![0; cap]).expect_err("Doesn't fit.");
+        buf.read(600).expect("Should be available.");
+        // Fills the ring exactly, running past the end.
+        buf.write(&vec![0; cap - 400]).expect("Should fit.");
+        buf.write(&[0]).expect_err("Full.");
+        buf.read(cap).expect("Should be available.");
+        buf.write(&[0; 10]).expect("Should fit.");
+
+        let stats = buf.stats();
+        assert_eq!(stats.bytes_written, cap as u64 + 610);
+        assert_eq!(stats.bytes_read, cap as u64 + 600);
+        assert_eq!(stats.high_water, cap);
+        assert_eq!(stats.rejected_writes, 2);
+        assert_eq!(stats.wraps, 1);
+
+        // Starts over from what's pending, without touching the interval stats.
+        buf.reset_stats();
+        buf.write(&[0; 20]).expect("Should fit.");
+        let stats = buf.stats();
+        assert_eq!(
+            (stats.bytes_written, stats.bytes_read, stats.high_water),
+            (20, 0, 30)
+        );
+        assert_eq!((stats.rejected_writes, stats.wraps), (0, 0));
+        assert_eq!(buf.stats_interval().drops, 2);
+
+        // Ending right at the end of the ring counts as a wrap too.
+        buf.write(&vec![0; cap - 630]).expect("Should fit.");
+        assert_eq!(buf.stats().wraps, 1);
+    }
+
+    #[test]
+    fn read_into_a_vecs_spare_capacity() {
+        let mut buf = RingBuf::new(1).unwrap();
+        let cap = buf.capacity();
+        let stream: Vec<u8> = (0..3 * cap).map(|i| (i * 7) as u8).collect();
+        let mut drained = Vec::with_capacity(100);
+        let mut written = 0;
+        while drained.len() < stream.len() {
+            written += buf.write_up_to(&stream[written..]);
+            // More room than is pending, and less, so the ring wraps at every point.
+            drained.reserve(777);
+            let spare = drained.spare_capacity_mut();
+            let limit = spare.len().min(1000);
+            let got = buf.read_into_uninit(&mut spare[..limit]).len();
+            unsafe { drained.set_len(drained.len() + got) };
+        }
+        assert_eq!(drained, stream);
+        assert!(buf
+            .read_into_uninit(drained.spare_capacity_mut())
+            .is_empty());
+
+        // Only the prefix is touched when fewer bytes are pending than `dst` has room for.
+        buf.write(b"abc").unwrap();
+        let mut dst = [MaybeUninit::new(b'-'); 8];
+        assert_eq!(buf.read_into_uninit(&mut dst), b"abc");
+        let dst = dst.map(|b| unsafe { b.assume_init() });
+        assert_eq!(&dst, b"abc-----");
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn from_slice_and_copy_to_vec_round_trip() {
+        let page = page();
+        for len in [0, 1, page - 1, page, page + 1, 3 * page] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let buf = RingBuf::from_slice(&data).expect("Creation should work.");
+            assert_eq!(buf.capacity(), len.max(1).next_multiple_of(page), "{len}");
+            assert_eq!(buf.copy_to_vec(), data, "{len}");
+        }
+
+        // Straddling the end of the ring, and left pending afterwards.
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        park_at(&mut buf, page - 3);
+        buf.write(b"straddles").expect("Fits.");
+        assert_eq!(buf.copy_to_vec(), b"straddles");
+        assert_eq!(buf.read(9).expect("Still pending."), b"straddles");
+    }
+
+    #[test]
+    fn slices_split_at_the_end_of_the_ring() {
+        let page = page();
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        let empty: &[u8] = &[];
+        assert_eq!(buf.as_slices(), (empty, empty));
+        let (first, second) = buf.spare_capacity_slices();
+        assert_eq!((first.len(), second.len()), (page, 0));
+
+        // Head at zero: nothing wraps.
+        buf.write(b"hello").expect("Fits.");
+        assert_eq!(buf.as_slices(), (&b"hello"[..], empty));
+
+        // Head on the last byte: all but one byte wraps.
+        park_at(&mut buf, page - 1);
+        buf.write(b"hello").expect("Fits.");
+        assert_eq!(buf.as_slices(), (&b"h"[..], &b"ello"[..]));
+        let (first, second) = buf.spare_capacity_slices();
+        assert_eq!((first.len(), second.len()), (page - 5, 0));
+
+        // Free space that wraps, filled through both halves and committed in one go.
+        park_at(&mut buf, 100);
+        buf.write(&[0; 100]).expect("Fits.");
+        let (first, second) = buf.spare_capacity_slices();
+        assert_eq!((first.len(), second.len()), (page - 200, 100));
+        first.fill(1);
+        second[..10].fill(2);
+        buf.commit_write(page - 190);
+        let (first, second) = buf.as_slices();
+        assert_eq!((first.len(), second.len()), (page - 100, 10));
+        assert_eq!(first.len() + second.len(), buf.len());
+        assert!(second.iter().all(|&b| b == 2));
+        assert_eq!(buf.free_space(), 90);
+
+        assert_eq!(RingBuf::default().as_slices(), (empty, empty));
+    }
+
+    #[test]
+    fn peek_iter_then_consume() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        park_at(&mut buf, page() - 6);
+        buf.write(b"GET /index.html\r\nHost")
+            .expect("Wraps past the end.");
+
+        let scan = buf.peek_iter();
+        assert_eq!(scan.len(), 21);
+        // First pass: find the end of the request line.
+        let line_end = scan
+            .clone()
+            .zip(scan.clone().skip(1))
+            .position(|pair| pair == (b'\r', b'\n'))
+            .expect("There's a CRLF in there.")
+            + 2;
+        // Second pass over the same bytes: count the spaces in that line.
+        assert_eq!(
+            scan.clone().take(line_end).filter(|&b| b == b' ').count(),
+            1
+        );
+        assert_eq!(scan.clone().next_back(), Some(b't'));
+
+        buf.consume(line_end).expect("Only what was scanned.");
+        assert_eq!(buf.peek_iter().collect::<Vec<_>>(), b"Host");
+        buf.consume(5).expect_err("Only four bytes left.");
+        assert_eq!(buf.len(), 4);
+    }
+
+    #[test]
+    fn rfind_last_match_wins() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        park_at(&mut buf, page() - 96);
+        // Three sync markers; the last one starts 4 bytes before the end of the buffer and
+        // finishes after the wrap.
+        buf.write(b"SYNCaaa").expect("Should fit.");
+        buf.write(&[b'x'; 40]).expect("Should fit.");
+        buf.write(b"SYNCbbb").expect("Should fit.");
+        buf.write(&[b'y'; 38]).expect("Should fit.");
+        buf.write(b"SYNClatest").expect("Wraps past the end.");
+        assert_eq!(buf.read_offset() + 92, page() - 4);
+
+        assert_eq!(buf.rfind(b"SYNC"), Some(92));
+        assert_eq!(buf.rfind(b"SYNCb"), Some(47));
+        assert_eq!(buf.rfind(b"NCla"), Some(94));
+        assert_eq!(buf.rfind(b"nope"), None);
+        assert_eq!(buf.rfind(b""), Some(102));
+        assert_eq!(buf.rfind_byte(b'S'), Some(92));
+        assert_eq!(buf.rfind_byte(b'y'), Some(91));
+        assert_eq!(buf.rfind_byte(b'z'), None);
+
+        assert_eq!(buf.skip_to_last(b"missing"), None);
+        assert_eq!(buf.len(), 102);
+        assert_eq!(buf.skip_to_last(b"SYNC"), Some(92));
+        assert_eq!(buf.read(10).expect("The latest frame."), b"SYNClatest");
+    }
+
+    #[test]
+    fn split_to_owned() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        park_at(&mut buf, page() - 96);
+        buf.write(&[1; 150]).expect("Wraps past the end.");
+        buf.write(b"rest").expect("Should fit.");
+
+        let front = buf.split_to(150).expect("Exactly what's there, wrapped.");
+        // Owned, so the ring is free to be written to while we hold on to it.
+        buf.write(b" and more").expect("Should fit.");
+        assert_eq!(front, vec![1; 150]);
+
+        buf.split_to(14).expect_err("Only 13 bytes pending.");
+        assert_eq!(buf.split_to(0).expect("Nothing is fine."), b"");
+        assert_eq!(buf.split_off_pending(), b"rest and more");
+        assert!(buf.is_empty());
+        assert_eq!(buf.split_off_pending(), b"");
+    }
+
+    #[test]
+    fn freeze_is_unaffected_by_later_traffic() {
+        let page = page();
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        buf.write(&vec![0; page - 6]).expect("Should fit.");
+        buf.read(page - 6).expect("Should be available.");
+        buf.write(b"audit me please").expect("Wraps past the end.");
+
+        let snapshot = buf.freeze();
+        let partial = buf.freeze_range(6..8).expect("In range.");
+        buf.freeze_range(10..20)
+            .expect_err("Past the pending data.");
+
+        buf.read(15).expect("Should be available.");
+        buf.write(&vec![9; page])
+            .expect("Overwrites where the snapshot came from.");
+
+        let handle = std::thread::spawn(move || {
+            assert_eq!(&*snapshot.data, b"audit me please");
+            assert_eq!(snapshot.offset, 0);
+            assert_eq!((snapshot.head, snapshot.tail), (page - 6, 9));
+            assert_eq!(snapshot.len, 15);
+            assert_eq!(snapshot.bytes_written, page as u64 + 9);
+            assert_eq!(snapshot.bytes_read, page as u64 - 6);
+        });
+        handle.join().expect("Snapshot checks passed.");
+
+        assert_eq!(&*partial.data, b"me");
+        assert_eq!(partial.offset, 6);
+        assert_eq!(partial.len, 15);
+    }
+
+    /// Hands out at most `chunk` bytes per `read` call, with an `Interrupted` before each one.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        chunk: usize,
+        interrupt: bool,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            let n = self.chunk.min(out.len()).min(self.data.len());
+            out[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn fill_from_cursor() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        park_at(&mut buf, page() - 96);
+
+        let data = (0..300u32).map(|i| i as u8).collect::<Vec<_>>();
+        let mut src = io::Cursor::new(&data);
+        let filled = buf.fill_from(&mut src, 100).expect("Cursors don't fail.");
+        assert_eq!(
+            filled,
+            Filled {
+                bytes: 100,
+                stop: FillStop::Max
+            }
+        );
+        let filled = buf
+            .fill_from(&mut src, usize::MAX)
+            .expect("Cursors don't fail.");
+        assert_eq!(
+            filled,
+            Filled {
+                bytes: 200,
+                stop: FillStop::Eof
+            }
+        );
+        assert_eq!(buf.split_off_pending(), data);
+    }
+
+    #[test]
+    fn read_from_one_read_at_a_time() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        let cap = buf.capacity();
+        park_at(&mut buf, cap - 96);
+        let data = (0..cap + 50).map(|i| i as u8).collect::<Vec<_>>();
+        let mut src = Trickle {
+            data: &data,
+            chunk: 200,
+            interrupt: false,
+        };
+        assert_eq!(
+            buf.read_from(&mut src).expect("Interrupts get retried."),
+            200
+        );
+        assert_eq!(buf.write_offset(), 104);
+        while buf.read_from(&mut src).unwrap() > 0 {}
+        assert!(buf.is_full());
+        // Full, so the source isn't even asked.
+        assert_eq!(src.data.len(), 50);
+        assert_eq!(buf.read_from(&mut src).unwrap(), 0);
+        assert_eq!(src.data.len(), 50);
+        assert_eq!(buf.split_off_pending(), data[..cap]);
+
+        assert_eq!(buf.read_from(&mut src).unwrap(), 50);
+        assert_eq!(buf.read_from(&mut src).expect("EOF is no error."), 0);
+        assert_eq!(buf.split_off_pending(), data[cap..]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn read_from_a_socket_across_the_wrap() {
+        use std::os::unix::net::UnixStream;
+        let (mut tx, mut rx) = UnixStream::pair().unwrap();
+        rx.set_nonblocking(true).unwrap();
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        let cap = buf.capacity();
+        park_at(&mut buf, cap - 10);
+
+        tx.write_all(b"straddles the end of the ring").unwrap();
+        assert_eq!(buf.read_from(&mut rx).unwrap(), 29);
+        assert!(buf.write_offset() < buf.read_offset());
+        assert_eq!(buf.peek(), b"straddles the end of the ring");
+        let err = buf.read_from(&mut rx).expect_err("Nothing more sent yet.");
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        drop(tx);
+        assert_eq!(buf.read_from(&mut rx).expect("Closed socket is EOF."), 0);
+    }
+
+    #[test]
+    fn fill_from_tiny_chunks() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        let data = [b"abc".as_slice(); 100].concat();
+        let mut src = Trickle {
+            data: &data,
+            chunk: 7,
+            interrupt: false,
+        };
+        let filled = buf
+            .fill_from(&mut src, usize::MAX)
+            .expect("Interrupts get retried.");
+        assert_eq!(filled.bytes, 300);
+        assert_eq!(filled.stop, FillStop::Eof);
+        assert_eq!(buf.split_off_pending(), data);
+    }
+
+    #[test]
+    fn fill_from_nearly_full() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        buf.write(&vec![0; page() - 6]).expect("Should fit.");
+        let mut src = io::Cursor::new([1; 100]);
+        let filled = buf
+            .fill_from(&mut src, usize::MAX)
+            .expect("Cursors don't fail.");
+        assert_eq!(
+            filled,
+            Filled {
+                bytes: 6,
+                stop: FillStop::Full
+            }
+        );
+        assert_eq!(src.position(), 6);
+        let filled = buf
+            .fill_from(&mut src, usize::MAX)
+            .expect("Cursors don't fail.");
+        assert_eq!(filled.bytes, 0);
+        assert_eq!(filled.stop, FillStop::Full);
+
+        let mut placeholder = RingBuf::default();
+        let filled = placeholder.fill_from(&mut src, 10).expect("Nothing to do.");
+        assert_eq!(filled.stop, FillStop::Full);
+    }
+
+    /// Accepts at most `per_call` bytes per `write`, and only `budget` bytes in total before
+    /// reporting `WouldBlock`.
+    struct Sip {
+        taken: Vec<u8>,
+        per_call: usize,
+        budget: usize,
+    }
+
+    impl Write for Sip {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            if self.budget == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = data.len().min(self.per_call).min(self.budget);
+            self.taken.extend_from_slice(&data[..n]);
+            self.budget -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn drain_to_short_writes() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        park_at(&mut buf, page() - 96);
+        let data = (0..200u32).map(|i| i as u8).collect::<Vec<_>>();
+        buf.write(&data).expect("Wraps past the end.");
+
+        let mut dst = Sip {
+            taken: Vec::new(),
+            per_call: 7,
+            budget: usize::MAX,
+        };
+        assert_eq!(buf.drain_to(&mut dst, 50).expect("Never fails."), 50);
+        assert_eq!(buf.len(), 150);
+        assert_eq!(
+            buf.drain_to(&mut dst, usize::MAX).expect("Never fails."),
+            150
+        );
+        assert_eq!(dst.taken, data);
+        assert_eq!(
+            buf.drain_to(&mut dst, usize::MAX).expect("Nothing to do."),
+            0
+        );
+    }
+
+    #[test]
+    fn write_to_consumes_only_what_was_taken() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        park_at(&mut buf, page() - 10);
+        let data = (0..30u32).map(|i| i as u8).collect::<Vec<_>>();
+        buf.write(&data).expect("Wraps past the end.");
+
+        let mut dst = Sip {
+            taken: Vec::new(),
+            per_call: 7,
+            budget: 25,
+        };
+        // 7-byte sips, the second of which straddles the end of the page.
+        for (left, read_offset) in [(23, page() - 3), (16, 4), (9, 11), (5, 15)] {
+            assert!(buf.write_to(&mut dst).expect("Within budget.") > 0);
+            assert_eq!((buf.len(), buf.read_offset()), (left, read_offset));
+        }
+        let err = buf.write_to(&mut dst).expect_err("Out of budget.");
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(buf.peek(), &data[25..]);
+
+        dst.budget = usize::MAX;
+        assert_eq!(buf.drain_to(&mut dst, usize::MAX).expect("Never fails."), 5);
+        assert_eq!(dst.taken, data);
+        assert_eq!(buf.write_to(&mut dst).expect("Nothing to do."), 0);
+    }
+
+    #[test]
+    fn peek_then_consume_across_the_wrap() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        park_at(&mut buf, page() - 5);
+        // Length-prefixed messages, the first of which straddles the end of the page.
+        buf.write(b"\x05hello\x03abc\x04wo").expect("Should fit.");
+        let mut messages = Vec::new();
+        while let Some(&len) = buf.peek().first() {
+            let Ok(body) = buf.peek_at(1, len as usize) else {
+                break;
+            };
+            messages.push(body.to_vec());
+            buf.consume(1 + len as usize).expect("Just peeked at it.");
+        }
+        assert_eq!(messages, [&b"hello"[..], b"abc"]);
+        // The incomplete one stays put until the rest of it arrives.
+        assert_eq!(buf.peek(), b"\x04wo");
+        buf.write(b"rd").expect("Should fit.");
+        assert_eq!(buf.peek_at(1, 4).expect("Complete now."), b"word");
+
+        assert!(buf.peek_at(5, 1).is_err());
+        assert!(buf.peek_at(1, usize::MAX).is_err());
+        assert_eq!(buf.peek_at(5, 0).expect("Empty, at the end."), b"");
+        buf.consume(6).expect_err("Only five bytes pending.");
+        assert_eq!(RingBuf::default().peek(), b"");
+    }
+
+    #[test]
+    fn io_copy_between_rings() {
+        let page = page();
+        let mut src = RingBuf::new(1).expect("Creation should work.");
+        let mut dst = RingBuf::new(2).expect("Creation should work.");
+        park_at(&mut src, page - 50);
+        park_at(&mut dst, 2 * page - 7);
+        let data: Vec<u8> = (0..page).map(|i| (i * 13) as u8).collect();
+        Write::write_all(&mut src, &data).expect("Exactly fills it, across the wrap.");
+
+        assert_eq!(
+            io::copy(&mut src, &mut dst).expect("Rings don't fail."),
+            page as u64
+        );
+        assert!(src.is_empty());
+        assert_eq!(dst.read(page).expect("All of it."), &data[..]);
+        assert_eq!(Read::read(&mut src, &mut [0; 10]).expect("Empty."), 0);
+    }
+
+    #[test]
+    fn io_write_is_short_when_full() {
+        let page = page();
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        buf.write(&vec![1; page - 3]).expect("Should fit.");
+        assert_eq!(Write::write(&mut buf, b"abcdef").expect("Short."), 3);
+        assert_eq!(Write::write(&mut buf, b"gh").expect("Full."), 0);
+        let err = Write::write_all(&mut buf, b"ij").expect_err("Full.");
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+
+        let mut out = vec![0; page + 10];
+        assert_eq!(Read::read(&mut buf, &mut out).expect("Never fails."), page);
+        assert_eq!(&out[page - 3..page], b"abc");
+        write!(buf, "{}-{}", 4, 2).expect("Fits now.");
+        assert_eq!(buf.read(3).expect("Formatted."), b"4-2");
+
+        assert_eq!(
+            Write::write(&mut RingBuf::default(), b"x").expect("Full."),
+            0
+        );
+    }
+
+    #[test]
+    fn drain_to_would_block() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        buf.write(&[4; 100]).expect("Should fit.");
+        let mut dst = Sip {
+            taken: Vec::new(),
+            per_call: 16,
+            budget: 40,
+        };
+        let err = buf
+            .drain_to(&mut dst, usize::MAX)
+            .expect_err("The writer gives up part way.");
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        // What the writer took is gone from the ring, the rest is still there.
+        assert_eq!(dst.taken.len(), 40);
+        assert_eq!(buf.len(), 60);
+    }
+
+    #[test]
+    #[cfg_attr(
+        any(feature = "portable", not(mapped)),
+        ignore = "counts mappings and memfds"
+    )]
+    fn leak_check_catches_forget() {
+        let check = LeakCheck::new();
+        let kept = RingBuf::new(1).expect("Creation should work.");
+        let forgotten = RingBuf::new(1).expect("Creation should work.");
+        assert_eq!(
+            check.live(),
+            Live {
+                mappings: 2,
+                memfds: 2
+            }
+        );
+        drop(kept);
+        std::mem::forget(forgotten);
+        assert_eq!(
+            check.finish(),
+            Err(Live {
+                mappings: 1,
+                memfds: 1
+            })
+        );
+    }
+
+    #[test]
+    fn failed_construction_leaks_nothing() {
+        let _leaks = LeakCheck::new();
+        // Way more address space than we can reserve.
+        assert!(
+            Mirror::new(NonZeroUsize::new(1 << 60).unwrap()).is_err(),
+            "Too big to map."
+        );
+        assert_no_leaks();
+    }
+
+    /// Every OS call each backend makes, in order.
+    #[cfg(mapped)]
+    const ALL_OS_OPS: [(BackendKind, &[OsOp]); 2] = [
+        (
+            BackendKind::DoubleMap,
+            &[
+                shmem::CREATE_OP,
+                OsOp::Ftruncate,
+                OsOp::MapDouble,
+                OsOp::MapHigh,
+            ],
+        ),
+        (
+            BackendKind::Reserve,
+            &[
+                shmem::CREATE_OP,
+                OsOp::Ftruncate,
+                OsOp::Reserve,
+                OsOp::MapLow,
+                OsOp::MapHigh,
+            ],
+        ),
+    ];
+
+    /// Every backend, including the heap one that makes no OS calls.
+    #[cfg(mapped)]
+    const ALL_BACKENDS: [BackendKind; 3] = [
+        BackendKind::DoubleMap,
+        BackendKind::Reserve,
+        BackendKind::Heap,
+    ];
+    /// The only backend there is without `mapped`.
+    #[cfg(not(mapped))]
+    const ALL_BACKENDS: [BackendKind; 1] = [BackendKind::Heap];
+
+    #[test]
+    #[cfg(mapped)]
+    fn new_fails_cleanly_at_every_os_call() {
+        for (backend, ops) in ALL_OS_OPS {
+            for &op in ops {
+                let _leaks = LeakCheck::new();
+                inject_failure(op, Errno::ENOMEM, 0);
+                assert!(
+                    matches!(
+                        RingBuf::builder().backend(backend).build(),
+                        Err(Error::Nix(Errno::ENOMEM))
+                    ),
+                    "Injected failure at {op:?} should surface."
+                );
+                assert_no_leaks();
+            }
+            // Each injection fires once.
+            RingBuf::builder()
+                .backend(backend)
+                .build()
+                .expect("Nothing injected any more.");
+        }
+    }
+
+    #[test]
+    #[cfg(mapped)]
+    #[cfg_attr(feature = "portable", ignore = "injects OS call failures")]
+    fn double_map_failure_falls_back() {
+        let _leaks = LeakCheck::new();
+        let buf = RingBuf::new(1).expect("Creation should work.");
+        assert_eq!(buf.backend_kind(), Some(BackendKind::DoubleMap));
+
+        inject_failure(OsOp::MapDouble, Errno::EINVAL, 0);
+        let mut buf = RingBuf::new(1).expect("Falls back to reserving.");
+        assert_eq!(buf.backend_kind(), Some(BackendKind::Reserve));
+        park_at(&mut buf, page() - 96);
+        buf.write(b"wrapped").expect("Wraps past the end.");
+        assert_eq!(buf.read(7).expect("Should be available."), b"wrapped");
+        assert_eq!(RingBuf::default().backend_kind(), None);
+    }
+
+    #[test]
+    fn every_backend_behaves_the_same() {
+        for backend in ALL_BACKENDS {
+            let _leaks = LeakCheck::new();
+            let mut buf = RingBuf::builder()
+                .pages(2)
+                .backend(backend)
+                .build()
+                .expect("Creation should work.");
+            assert_eq!(buf.backend_kind(), Some(backend));
+            for i in 0..50usize {
+                let data: Vec<u8> = (0..(i * 331) % (2 * page()))
+                    .map(|j| (i ^ j) as u8)
+                    .collect();
+                buf.write(&data).expect("Always consumed below.");
+                assert_eq!(buf.read(data.len()).expect("Just written."), &data[..]);
+            }
+            let page = page();
+            buf.write(&vec![1; page + 904]).expect("Should fit.");
+            buf.shrink_to(page).expect_err("Too much pending.");
+            buf.consume(2000).expect("Should be available.");
+            buf.shrink_to_fit().expect("Shrinking should work.");
+            assert_eq!(buf.backend_kind(), Some(backend));
+            assert_eq!(
+                buf.read(page - 1096).expect("Survived the shrink."),
+                &vec![1; page - 1096][..]
+            );
+        }
+    }
+
+    #[test]
+    fn heap_views_stay_in_sync() {
+        let leaks = LeakCheck::new();
+        let mut buf = RingBuf::builder()
+            .backend(BackendKind::Heap)
+            .debug_fill(true)
+            .build()
+            .unwrap();
+        assert_eq!(buf.backend_kind(), Some(BackendKind::Heap));
+        assert_eq!(buf.fd_source(), None);
+        assert_eq!(leaks.live(), Live::default());
+        let cap = buf.capacity();
+        let views_match = |buf: &RingBuf| {
+            let both = unsafe { std::slice::from_raw_parts(buf.data_ptr(), 2 * cap) };
+            assert_eq!(both[..cap], both[cap..]);
+        };
+
+        let mut tap = buf.try_clone_reader().unwrap();
+        park_at(&mut buf, cap - 3);
+        buf.write(b"across").unwrap();
+        views_match(&buf);
+        let window = buf.writable_slice(10).unwrap();
+        window[..4].copy_from_slice(b" the");
+        buf.commit_write(4);
+        views_match(&buf);
+        unsafe {
+            buf.data_ptr().add(buf.write_offset()).write(b'!');
+            buf.advance_write(1);
+        }
+        assert_eq!(buf.read(11).unwrap(), b"across the!");
+        buf.write(b"x").unwrap();
+        views_match(&buf);
+        tap.refresh(&buf);
+        assert_eq!(tap.peek().last(), Some(&b'x'));
+
+        let (mut producer, mut consumer) = buf.split().unwrap();
+        producer.write(&vec![7; cap - 1]).unwrap();
+        assert_eq!(consumer.read_into(&mut vec![0; cap]), cap);
+        producer.write(b"seam").unwrap();
+        assert_eq!(consumer.peek(), b"seam");
+    }
+
+    #[test]
+    #[cfg(mapped)]
+    #[cfg_attr(feature = "portable", ignore = "injects OS call failures")]
+    fn injected_failure_after_n_calls() {
+        let _leaks = LeakCheck::new();
+        inject_failure(OsOp::MapHigh, Errno::EAGAIN, 2);
+        let _first = RingBuf::new(1).expect("First call goes through.");
+        let _second = RingBuf::new(1).expect("Second call goes through.");
+        assert!(matches!(RingBuf::new(1), Err(Error::Nix(Errno::EAGAIN))));
+
+        inject_failure(OsOp::Reserve, Errno::EAGAIN, 0);
+        clear_injected_failures();
+        RingBuf::new(1).expect("The injection was cleared.");
+    }
+
+    #[test]
+    #[cfg(mapped)]
+    fn shrink_failure_leaves_ring_usable() {
+        for (backend, ops) in ALL_OS_OPS {
+            for &op in ops {
+                shrink_failure_at(backend, op);
+            }
+        }
+    }
+
+    #[cfg(mapped)]
+    fn shrink_failure_at(backend: BackendKind, op: OsOp) {
+        let _leaks = LeakCheck::new();
+        let mut buf = RingBuf::builder()
+            .pages(2)
+            .backend(backend)
+            .build()
+            .expect("Creation should work.");
+        park_at(&mut buf, 2 * page() - 1000);
+        buf.write(&[0; 1000]).expect("Should fit.");
+        buf.write(&[1; 1500]).expect("Wraps past the end.");
+
+        inject_failure(op, Errno::ENOMEM, 0);
+        assert!(matches!(
+            buf.shrink_to_fit(),
+            Err(Error::Nix(Errno::ENOMEM))
+        ));
+        assert_eq!(buf.capacity(), 2 * page());
+        assert_eq!(buf.read(1000).expect("Untouched."), &[0; 1000]);
+        buf.write(&[2; 5000]).expect("Still writable.");
+        assert_eq!(buf.read(1500).expect("Untouched."), &[1; 1500]);
+        assert_eq!(buf.read(5000).expect("Still readable."), &[2; 5000]);
+    }
+
+    #[test]
+    fn write_all_slices_exact_fit() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        buf.write(&[0; 100]).expect("Should fit.");
+        let header = [1; 96];
+        let body = vec![2; page() - 196];
+        buf.write_all_slices(&[&header, &[], &body])
+            .expect("Header and body fill the buffer exactly.");
+        assert_eq!(buf.len(), page());
+        assert_eq!(buf.write_offset(), buf.read_offset());
+    }
+
+    #[test]
+    fn write_all_slices_one_byte_too_big() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        buf.write(&[0; 100]).expect("Should fit.");
+        let header = [1; 97];
+        let body = vec![2; page() - 196];
+        buf.write_all_slices(&[&header, &body])
+            .expect_err("One byte more than the free space.");
+        // Nothing from the failed call should have made it in, not even the header.
+        assert_eq!(buf.len(), 100);
+        assert_eq!(buf.write_offset(), 100);
+    }
+
+    #[test]
+    fn write_all_slices_wraps() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        park_at(&mut buf, page() - 96);
+        buf.write_all_slices(&[b"head", &[], &[3; 200], b"tail"])
+            .expect("Plenty of room once the first write is consumed.");
+        assert_eq!(buf.write_offset(), 208 - 96);
+
+        let read = buf.read(208).expect("Everything we just wrote.");
+        assert_eq!(&read[..4], b"head");
+        assert_eq!(&read[4..204], &[3; 200]);
+        assert_eq!(&read[204..], b"tail");
+    }
+
+    #[test]
+    fn vectored_exact_fit_across_the_wrap() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        park_at(&mut buf, page() - 96);
+        buf.write(&[0; 100]).expect("Should fit.");
+        let body = vec![2; page() - 196];
+        let bufs = [
+            IoSlice::new(b"head"),
+            IoSlice::new(&[]),
+            IoSlice::new(&body),
+            IoSlice::new(&[]),
+            IoSlice::new(&[1; 92]),
+        ];
+        buf.write_vectored(&[IoSlice::new(&[])])
+            .expect("Nothing to write is fine.");
+        buf.write_vectored(&[IoSlice::new(&vec![9; page()])])
+            .expect_err("Bigger than the free space.");
+        buf.write_vectored(&bufs)
+            .expect("Fills the free space exactly.");
+        assert!(buf.is_full());
+
+        buf.consume(100).expect("The filler.");
+        let (mut head, mut none, mut rest) = ([0; 4], [0; 0], vec![0; page()]);
+        let mut outs = [
+            IoSliceMut::new(&mut head),
+            IoSliceMut::new(&mut none),
+            IoSliceMut::new(&mut rest),
+        ];
+        assert_eq!(buf.read_vectored(&mut outs), page() - 100);
+        assert_eq!(&head, b"head");
+        assert_eq!(rest[..body.len()], body);
+        assert_eq!(rest[body.len()..page() - 104], [1; 92]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn io_vectored_writes_stop_at_the_first_short_slice() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        let cap = buf.capacity();
+        let big = vec![2; cap];
+        let bufs = [
+            IoSlice::new(&[1; 100]),
+            IoSlice::new(&[]),
+            IoSlice::new(&big),
+            IoSlice::new(&[3; 10]),
+        ];
+        assert_eq!(Write::write_vectored(&mut buf, &bufs).unwrap(), cap);
+        let mut out = vec![0; cap];
+        assert_eq!(
+            Read::read_vectored(&mut buf, &mut [IoSliceMut::new(&mut out)]).unwrap(),
+            cap
+        );
+        assert_eq!(out[..100], [1; 100]);
+        assert!(out[100..].iter().all(|&b| b == 2));
+    }
+
+    #[test]
+    fn extend_from_iterators() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        let cap = buf.capacity();
+        park_at(&mut buf, cap - 5);
+        assert_eq!(buf.try_extend(std::iter::empty()).unwrap(), 0);
+        assert_eq!(buf.try_extend((0..10).map(|i| i as u8)).unwrap(), 10);
+        assert_eq!(buf.write_offset(), 5);
+
+        let mut long = (0..).map(|i| (i % 251) as u8);
+        assert_eq!(buf.try_extend(long.by_ref()).unwrap(), cap - 10);
+        assert!(buf.is_full());
+        assert_eq!(long.next(), Some(((cap - 10) % 251) as u8));
+        assert_eq!(buf.try_extend(long.by_ref()).unwrap(), 0);
+
+        buf.consume(cap).unwrap();
+        let exact: Vec<u8> = (0..cap).map(|i| (i * 7) as u8).collect();
+        buf.extend(&exact);
+        assert_eq!(buf.read(cap).unwrap(), &exact[..]);
+        buf.extend(exact.iter().take(3).copied());
+        assert_eq!(buf.read(3).unwrap(), &exact[..3]);
+
+        assert!(matches!(
+            RingBuf::default().try_extend([1]),
+            Err(Error::Ours(BufError::ZeroCapacity))
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "past its free space")]
+    fn extend_past_the_free_space() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        let cap = buf.capacity();
+        buf.extend(&vec![1; cap + 1]);
+    }
+
+    #[test]
+    fn writable_slice_spans_wrap() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        park_at(&mut buf, page() - 96);
+
+        let window = buf.writable_slice(300).expect("Plenty of free space.");
+        assert_eq!(window.len(), 300);
+        window.copy_from_slice(&[7; 300]);
+        buf.commit_write(300);
+        assert_eq!(buf.write_offset(), 300 - 96);
+        assert_eq!(buf.read(300).expect("Just committed."), &[7; 300]);
+
+        buf.writable_slice(page() + 1)
+            .expect_err("Can't ask for more than the whole buffer.");
+    }
+
+    #[test]
+    fn writable_slice_filled_from_a_file() {
+        let path = std::env::temp_dir().join(format!("ringbuf-fill-{}", std::process::id()));
+        let data: Vec<u8> = (0..3000u32).map(|i| (i * 7) as u8).collect();
+        std::fs::write(&path, &data).expect("Temp dir should be writable.");
+        let mut file = std::fs::File::open(&path).expect("Just written.");
+        std::fs::remove_file(&path).expect("Still open, so fine to unlink.");
+
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        park_at(&mut buf, page() - 1000);
+        buf.write(b"unread").expect("Should fit.");
+        // All of the free space, which wraps and stops short of the unread bytes.
+        let free = buf.capacity() - buf.len();
+        let window = buf.writable_slice(free).expect("Exactly the free space.");
+        let n = file.read(window).expect("Files don't fail.");
+        assert_eq!(n, data.len());
+        buf.commit_write(n);
+
+        assert_eq!(buf.read(6).expect("Untouched."), b"unread");
+        assert_eq!(buf.read(n).expect("Straight from the file."), &data[..]);
+        buf.writable_slice(0).expect("Nothing is fine.");
+        buf.commit_write(0);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn writable_slice_interleaved_with_writes() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        buf.write(b"one ").expect("Should fit.");
+
+        let window = buf.writable_slice(16).expect("Should fit.");
+        window[..4].copy_from_slice(b"two ");
+        // Only part of the window ends up being used.
+        buf.commit_write(4);
+
+        buf.write(b"three").expect("Should fit.");
+        assert_eq!(buf.read(13).expect("All of it."), b"one two three");
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "without a preceding writable_slice")]
+    fn commit_write_without_slice() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        buf.commit_write(1);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "without a preceding writable_slice")]
+    fn commit_write_after_interleaved_write() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        buf.writable_slice(8).expect("Should fit.");
+        buf.write(b"oops").expect("Should fit.");
+        buf.commit_write(8);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "committed 9 bytes from a window of 8")]
+    fn commit_write_more_than_requested() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        buf.writable_slice(8).expect("Should fit.");
+        buf.commit_write(9);
+    }
+
+    /// `write` rebuilt on top of the raw-parts API.
+    fn raw_write(buf: &mut RingBuf, data: &[u8]) -> bool {
+        if data.len() > buf.capacity() - buf.len() {
+            return false;
+        }
+        unsafe {
+            let dst = buf.data_ptr().add(buf.write_offset());
+            std::ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len());
+            buf.advance_write(data.len());
+        }
+        true
+    }
+
+    /// `read` rebuilt on top of the raw-parts API, copying out.
+    fn raw_read(buf: &mut RingBuf, n: usize) -> Option<Vec<u8>> {
+        if n > buf.len() {
+            return None;
+        }
+        unsafe {
+            let src = buf.data_ptr().add(buf.read_offset());
+            let out = std::slice::from_raw_parts(src, n).to_vec();
+            buf.advance_read(n);
+            Some(out)
+        }
+    }
+
+    #[test]
+    fn raw_parts_match_builtins() {
+        let mut raw = RingBuf::new(1).expect("Creation should work.");
+        let mut builtin = RingBuf::new(1).expect("Creation should work.");
+        assert_eq!(raw.mirror_len(), 2 * page());
+
+        // Uneven sizes so the offsets wrap at different points each lap.
+        for i in 0..200usize {
+            let data: Vec<u8> = (0..(i * 37) % 3000).map(|j| (i + j) as u8).collect();
+            let fits = raw_write(&mut raw, &data);
+            assert_eq!(fits, builtin.write(&data).is_ok());
+            let n = (i * 53) % 3500;
+            let got = raw_read(&mut raw, n);
+            assert_eq!(got.as_deref(), builtin.read(n).ok());
+
+            assert_eq!(raw.read_offset(), builtin.read_offset());
+            assert_eq!(raw.write_offset(), builtin.write_offset());
+            assert_eq!(raw.len(), builtin.len());
+            assert!(raw.read_offset() < raw.capacity());
+            assert!(raw.write_offset() < raw.capacity());
+        }
+        assert_eq!(raw.split_off_pending(), builtin.split_off_pending());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "advance_read(1) past the 0 pending bytes")]
+    fn advance_read_past_pending() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        unsafe { buf.advance_read(1) };
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "free bytes")]
+    fn advance_write_past_free_space() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        unsafe { buf.advance_write(page() + 1) };
+    }
+
+    /// Empties `buf` and moves both offsets to `at`.
+    fn park_at(buf: &mut RingBuf, at: usize) {
+        buf.consume(buf.len()).expect("Available.");
+        let n = (at + buf.capacity() - buf.write_offset()) % buf.capacity();
+        unsafe {
+            buf.advance_write(n);
+            buf.advance_read(n);
+        }
+    }
+
+    fn push_small_at_every_offset<const N: usize>(buf: &mut RingBuf) {
+        let cap = buf.capacity();
+        let bytes: [u8; N] = std::array::from_fn(|i| i as u8 + 1);
+        for at in 0..cap {
+            park_at(buf, at);
+            let (before, after) = ((at + cap - 1) % cap, (at + N) % cap);
+            unsafe {
+                *buf.data_ptr().add(before) = 0xee;
+                *buf.data_ptr().add(after) = 0xee;
+            }
+            buf.push_bytes_small(bytes).expect("Fits.");
+            assert_eq!(buf.write_offset(), (at + N) % cap);
+            assert_eq!(
+                buf.read(N).expect("Just written."),
+                &bytes[..],
+                "N = {N} at {at}"
+            );
+            unsafe {
+                assert_eq!(*buf.data_ptr().add(before), 0xee, "N = {N} at {at}");
+                assert_eq!(*buf.data_ptr().add(after), 0xee, "N = {N} at {at}");
+            }
+        }
+    }
+
+    #[test]
+    fn push_bytes_small_at_every_offset() {
+        // The sentinels around each write would count as stray writes into the free space.
+        let mut buf = RingBuf::builder()
+            .debug_fill(false)
+            .build()
+            .expect("Creation should work.");
+        macro_rules! every_n {
+            ($($n:literal)*) => { $(push_small_at_every_offset::<$n>(&mut buf);)* };
+        }
+        every_n!(1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16);
+    }
+
+    #[test]
+    fn push_bytes_small_matches_write() {
+        let mut fast = debug_filled();
+        let mut slow = debug_filled();
+        for i in 0..3000u64 {
+            let record = i.to_le_bytes();
+            let fits = fast.push_bytes_small(record).is_ok();
+            assert_eq!(fits, slow.write(&record).is_ok());
+            if i % 3 == 0 {
+                let n = fast.len().min(20);
+                assert_eq!(fast.read(n).ok(), slow.read(n).ok());
+            }
+            assert_eq!(fast.write_offset(), slow.write_offset());
+        }
+        let (fast_stats, slow_stats) = (fast.stats_interval(), slow.stats_interval());
+        assert_eq!(
+            (fast_stats.bytes_in, fast_stats.ops_in, fast_stats.drops),
+            (slow_stats.bytes_in, slow_stats.ops_in, slow_stats.drops)
+        );
+        assert_eq!(fast_stats.max_fill, slow_stats.max_fill);
+        assert_eq!(fast.split_off_pending(), slow.split_off_pending());
+
+        // The slow cases fall back to `write`.
+        let mut lazy = RingBuf::builder().lazy(true).build().expect("Lazy.");
+        lazy.push_bytes_small(*b"first").expect("Maps on demand.");
+        assert_eq!(lazy.read(5).expect("Written."), b"first");
+        assert!(matches!(
+            RingBuf::default().push_bytes_small([1]),
+            Err(Error::Ours(BufError::ZeroCapacity))
+        ));
+    }
+
+    fn debug_filled() -> RingBuf {
+        RingBuf::builder()
+            .debug_fill(true)
+            .build()
+            .expect("Creation should work.")
+    }
+
+    #[test]
+    fn invariants_hold_through_normal_traffic() {
+        let mut buf = debug_filled();
+        buf.check_invariants();
+        for i in 0..100usize {
+            buf.write(&vec![i as u8; (i * 41) % 3000]).expect("Fits.");
+            let window = buf.writable_slice(200).expect("Fits.");
+            window.fill(0xee);
+            buf.commit_write(50);
+            buf.check_invariants();
+            let n = buf.len();
+            // A borrowed read, poisoned on the next call, then a copying one.
+            buf.read(n / 2).expect("Available.");
+            buf.split_off_pending();
+            buf.check_invariants();
+        }
+        buf.wipe();
+        buf.check_invariants();
+        RingBuf::default().check_invariants();
+    }
+
+    #[test]
+    fn consumed_bytes_are_poisoned() {
+        let mut buf = debug_filled();
+        buf.write(b"stale").expect("Fits.");
+        buf.consume(5).expect("Available.");
+        let raw = unsafe { std::slice::from_raw_parts(buf.data_ptr(), 5) };
+        assert_eq!(raw, &[POISON; 5]);
+    }
+
+    #[test]
+    fn stale_slices_show_poison_after_the_next_write() {
+        let mut buf = debug_filled();
+        let cap = buf.capacity();
+        // Across the wrap, which the mirror makes contiguous for the poisoning too.
+        park_at(&mut buf, cap - 5);
+        buf.write(b"GET /index.html").expect("Fits.");
+        let stale = buf.read(15).expect("Available.").as_ptr();
+        // What a parser holding on to that slice would see once the ring has moved on.
+        buf.write(b"next").expect("Fits.");
+        let seen = unsafe { std::slice::from_raw_parts(stale, 15) };
+        assert_eq!(seen, &[POISON; 15]);
+    }
+
+    #[test]
+    #[cfg(feature = "poison")]
+    #[should_panic(expected = "about to hand out 32 bytes of poison")]
+    fn handing_out_unwritten_bytes_panics() {
+        // The feature turns debug-fill on for plain rings too.
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        unsafe { buf.advance_write(32) };
+        let _ = buf.read(32);
+    }
+
+    #[test]
+    #[should_panic(expected = "free byte at offset 100 was overwritten")]
+    fn checker_catches_stray_write_into_free_space() {
+        let mut buf = debug_filled();
+        buf.write(&[1; 10]).expect("Fits.");
+        unsafe { *buf.data_ptr().add(100) = 0 };
+        buf.check_invariants();
+    }
+
+    #[test]
+    #[should_panic(expected = "free byte at offset 89 was overwritten")]
+    fn checker_catches_stray_write_behind_the_head() {
+        let mut buf = debug_filled();
+        buf.write(&[1; 100]).expect("Fits.");
+        buf.consume(100).expect("Available.");
+        // Just behind the head, through the second view.
+        unsafe { *buf.data_ptr().add(buf.read_offset() + buf.capacity() - 11) = 0 };
+        buf.check_invariants();
+    }
+
+    #[test]
+    #[should_panic(expected = "tail doesn't match head")]
+    fn checker_catches_inconsistent_indices() {
+        let mut buf = RingBuf::new(1).expect("Creation should work.");
+        buf.write(b"abc").expect("Fits.");
+        buf.tail = 7;
+        buf.check_invariants();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "was overwritten")]
+    fn mutating_calls_check_invariants() {
+        let mut buf = debug_filled();
+        // The write itself is fine; the check at the end of it finds the stray byte.
+        unsafe { *buf.data_ptr().add(20) = 0 };
+        buf.write(b"any write").expect("Fits.");
+    }
+
+    /// Capacities, in pages, for the wrap suite: powers of two and not.
+    const WRAP_PAGES: [usize; 5] = [1, 2, 3, 5, 8];
+
+    /// Runs `check` on a fresh ring for every capacity in `WRAP_PAGES` and every backend.
+    fn for_each_wrap_ring(mut check: impl FnMut(&mut RingBuf)) {
+        for backend in ALL_BACKENDS {
+            for pages in WRAP_PAGES {
+                let _leaks = LeakCheck::new();
+                let mut buf = RingBuf::builder()
+                    .pages(pages)
+                    .backend(backend)
+                    .build()
+                    .expect("Creation should work.");
+                assert_eq!(buf.capacity(), pages * page());
+                check(&mut buf);
+            }
+        }
+    }
+
+    /// Offsets where the index math is most likely to go wrong.
+    fn boundary_offsets(cap: usize) -> Vec<usize> {
+        let page = page();
+        let mut offsets = vec![0, 1, 2, 7, page - 1, page, page + 1];
+        offsets.extend([cap / 2, cap / 3, cap - 16, cap - 8, cap - 2, cap - 1]);
+        offsets.retain(|&at| at < cap);
+        offsets
+    }
+
+    #[test]
+    fn exact_fit_at_every_boundary() {
+        for_each_wrap_ring(|buf| {
+            let cap = buf.capacity();
+            let data: Vec<u8> = (0..cap).map(|i| (i % 251) as u8).collect();
+            for at in boundary_offsets(cap) {
+                park_at(buf, at);
+                buf.write(&data).expect("Exactly the capacity fits.");
+                assert_eq!(buf.len(), cap);
+                assert_eq!(buf.write_offset(), at, "cap {cap} at {at}");
+                assert!(buf.write(b"x").is_err());
+                assert!(buf.writable_slice(1).is_err());
+                assert_eq!(buf.read(cap).expect("All of it."), &data[..]);
+                assert!(buf.is_empty());
+                assert_eq!(buf.read_offset(), at);
+
+                // One short, then topped up to exactly full by a second write.
+                buf.write(&data[..cap - 1]).expect("Fits.");
+                buf.write(&data[cap - 1..]).expect("Exactly fills it.");
+                assert!(buf.write(b"x").is_err());
+                assert_eq!(buf.split_off_pending(), data);
+
+                assert!(buf.write(&vec![0; cap + 1]).is_err());
+                assert!(buf.is_empty());
+            }
+        });
+    }
+
+    #[test]
+    fn reads_ending_exactly_at_the_boundary() {
+        for_each_wrap_ring(|buf| {
+            let cap = buf.capacity();
+            for n in [1, 2, 8, page() - 1, page(), cap - 1, cap] {
+                let data: Vec<u8> = (0..n).map(|i| (i * 7 + n) as u8).collect();
+                // Ends exactly at the end of the first view, so both offsets land on zero.
+                park_at(buf, cap - n);
+                buf.write(&data).expect("Fits.");
+                assert_eq!(buf.write_offset(), 0, "cap {cap}, n {n}");
+                assert_eq!(buf.read(n).expect("Available."), &data[..]);
+                assert_eq!(buf.read_offset(), 0, "cap {cap}, n {n}");
+
+                // Starts exactly at the boundary.
+                buf.write(&data).expect("Fits.");
+                assert_eq!(buf.split_to(n).expect("Available."), data);
+                assert_eq!(buf.read_offset(), n % cap);
+
+                // Through the viewer, ending one short of and exactly at the boundary.
+                park_at(buf, cap - n);
+                buf.write(&data).expect("Fits.");
+                let viewer = buf.viewer();
+                assert_eq!(viewer.view(..n - 1).expect("In range."), &data[..n - 1]);
+                assert_eq!(viewer.view(n - 1..).expect("In range."), &data[n - 1..]);
+                buf.consume(n).expect("Available.");
+            }
+        });
+    }
+
+    /// FNV-1a, rolled one chunk at a time.
+    fn roll(hash: u64, bytes: &[u8]) -> u64 {
+        bytes
+            .iter()
+            .fold(hash, |h, &b| (h ^ b as u64).wrapping_mul(0x100_0000_01b3))
+    }
+
+    #[test]
+    fn thousands_of_wraps_keep_the_stream_intact() {
+        for_each_wrap_ring(|buf| {
+            let cap = buf.capacity();
+            let (mut written, mut read) = (0xcbf2_9ce4_8422_2325u64, 0xcbf2_9ce4_8422_2325u64);
+            let mut total = 0;
+            let source: Vec<u8> = (0..2 * cap).map(|j| (j % 253) as u8).collect();
+            let mut i = 0usize;
+            // Chunk sizes stepping by a prime so the offsets don't fall into a short cycle.
+            while total < 2000 * cap {
+                let n = 1 + (i * 4099) % cap;
+                let chunk = &source[i % cap..][..n];
+                buf.write(chunk).expect("Drained every time.");
+                written = roll(written, chunk);
+                // Full drains, alternately in two reads and one.
+                if i.is_multiple_of(2) {
+                    let half = n / 2;
+                    read = roll(read, buf.read(half).expect("Available."));
+                    read = roll(read, buf.read(n - half).expect("Available."));
+                } else {
+                    read = roll(read, &buf.split_off_pending());
+                }
+                assert!(buf.is_empty());
+                assert_eq!(buf.read_offset(), buf.write_offset());
+                total += n;
+                i += 1;
+            }
+            assert_eq!(written, read, "cap {cap}");
+            assert_eq!(buf.read_offset(), total % cap);
+        });
+    }
+
+    #[test]
+    fn random_operations_match_a_vecdeque() {
+        // xorshift64, so every run does the same thing.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move |below: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % below as u64) as usize
+        };
+        for_each_wrap_ring(|buf| {
+            let cap = buf.capacity();
+            let mut model = VecDeque::new();
+            let mut written = 0usize;
+            for step in 0..3000 {
+                // Mostly sizes around the wrap-prone small end, now and then up to the whole ring.
+                let n = if next(8) == 0 {
+                    next(cap + 2)
+                } else {
+                    next(300)
+                };
+                let chunk: Vec<u8> = (written..written + n).map(|i| i as u8).collect();
+                match next(8) {
+                    0 | 1 => {
+                        let fits = model.len() + n <= cap;
+                        assert_eq!(buf.write(&chunk).is_ok(), fits, "step {step}");
+                        if fits {
+                            model.extend(&chunk);
+                            written += n;
+                        }
+                    }
+                    2 => {
+                        let took = buf.write_up_to(&chunk);
+                        assert_eq!(took, n.min(cap - model.len()), "step {step}");
+                        model.extend(&chunk[..took]);
+                        written += took;
+                    }
+                    3 => {
+                        buf.write_overwriting(&chunk).unwrap();
+                        model.extend(&chunk);
+                        written += n;
+                        let excess = model.len().saturating_sub(cap);
+                        model.drain(..excess);
+                    }
+                    4 => match buf.read(n) {
+                        Ok(bytes) => {
+                            assert_eq!(bytes, model.drain(..n).collect::<Vec<_>>(), "step {step}")
+                        }
+                        Err(_) => assert!(n > model.len(), "step {step}"),
+                    },
+                    5 => {
+                        let mut out = vec![0; n];
+                        let got = buf.read_up_to(&mut out);
+                        assert_eq!(got, n.min(model.len()), "step {step}");
+                        assert_eq!(out[..got], model.drain(..got).collect::<Vec<_>>());
+                    }
+                    6 => {
+                        let n = n.min(model.len());
+                        buf.consume(n).unwrap();
+                        model.drain(..n);
+                    }
+                    _ if step % 50 == 0 => {
+                        buf.clear();
+                        model.clear();
+                    }
+                    _ => {}
+                }
+                assert_eq!(buf.peek(), model.make_contiguous(), "step {step}");
+                assert_eq!(buf.len(), model.len());
+                assert_eq!(buf.free_space(), cap - model.len());
+                assert_eq!(buf.is_empty(), model.is_empty());
+                assert_eq!(buf.is_full(), model.len() == cap);
+            }
+        });
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[repr(C)]
+    struct Sample {
+        timestamp: u64,
+        value: f64,
+        channel: u32,
+        flags: u32,
+    }
+
+    unsafe impl Pod for Sample {}
+
+    #[test]
+    fn typed_values_at_odd_offsets() {
+        let mut buf = RingBuf::new(1).unwrap();
+        let sample = |i: u64| Sample {
+            timestamp: 0x0102_0304_0506_0708u64.wrapping_mul(i),
+            value: i as f64 / 3.0,
+            channel: i as u32,
+            flags: !(i as u32),
+        };
+        let mut misaligned = 0;
+        for i in 0..1000 {
+            misaligned += usize::from(!buf.write_offset().is_multiple_of(align_of::<Sample>()));
+            buf.write_typed(sample(i)).unwrap();
+            assert_eq!(buf.read_typed::<Sample>().unwrap(), sample(i));
+            assert!(buf.is_empty());
+            // Three bytes of filler keep moving the next sample to a new alignment.
+            buf.write(&[0; 3]).unwrap();
+            buf.consume(3).unwrap();
+        }
+        assert!(misaligned > 800, "only {misaligned} misaligned");
+    }
+
+    #[test]
+    fn typed_read_of_a_partial_value() {
+        let mut buf = RingBuf::builder().typed_checks(false).build().unwrap();
+        buf.write(&7u64.to_ne_bytes()[..5]).unwrap();
+        assert!(matches!(
+            buf.read_typed::<u64>(),
+            Err(Error::Ours(BufError::NotEnoughData {
+                requested: 8,
+                available: 5
+            }))
+        ));
+        assert_eq!(buf.len(), 5);
+        buf.write(&7u64.to_ne_bytes()[5..]).unwrap();
+        assert_eq!(buf.read_typed::<u64>().unwrap(), 7);
+    }
+
+    #[test]
+    fn typed_values_across_the_boundary() {
+        for_each_wrap_ring(|buf| {
+            let cap = buf.capacity();
+            for typed_checks in [false, true] {
+                buf.typed_checks = typed_checks;
+                let tag = if typed_checks { typetag::TAG_LEN } else { 0 };
+                // Every offset straddling the end, aligned or not.
+                for back in 0..=24 {
+                    park_at(buf, cap - back);
+                    buf.write_typed(*b"twelve bytes").expect("Fits.");
+                    assert_eq!(buf.len(), tag + 12);
+                    assert_eq!(
+                        &buf.read_typed::<[u8; 12]>().expect("Same type."),
+                        b"twelve bytes"
+                    );
+                    park_at(buf, cap - back);
+                    buf.write_typed(0x0123_4567_89ab_cdefu64).expect("Fits.");
+                    assert_eq!(
+                        buf.read_typed::<u64>().expect("Same type."),
+                        0x0123_4567_89ab_cdef
+                    );
+                    assert!(buf.is_empty());
+                }
+                // Values filling the ring exactly.
+                park_at(buf, cap - 8);
+                let count = (cap / (tag + 8)) as u64;
+                for v in 0..count {
+                    buf.write_typed(v).expect("Fits.");
+                }
+                for v in 0..count {
+                    assert_eq!(buf.read_typed::<u64>().expect("Same type."), v);
+                }
+            }
+        });
+    }
+
+    /// Twelve bytes with no padding, so values of it land at every alignment in the ring.
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    #[repr(C)]
+    struct Triple {
+        x: u32,
+        y: u16,
+        z: u16,
+        w: f32,
+    }
+
+    unsafe impl Pod for Triple {}
+
+    #[test]
+    fn pod_slices_across_the_boundary() {
+        let frames: Vec<f32> = (0..100).map(|i| i as f32 * 0.25 - 7.0).collect();
+        let triples: Vec<Triple> = (0..50)
+            .map(|i| Triple {
+                x: 0x0102_0304 * i,
+                y: i as u16,
+                z: !(i as u16),
+                w: i as f32 / 3.0,
+            })
+            .collect();
+        for_each_wrap_ring(|buf| {
+            let cap = buf.capacity();
+            for typed_checks in [false, true] {
+                buf.typed_checks = typed_checks;
+                // Straddling the end at every offset, aligned or not.
+                for back in (0..=64).chain([cap / 2 + 1]) {
+                    park_at(buf, cap - back);
+                    buf.write_slice(&frames).expect("Fits.");
+                    buf.write_slice(&triples).expect("Fits.");
+                    let mut frames_out = vec![0.0f32; frames.len()];
+                    buf.read_slice_into(&mut frames_out).expect("Same type.");
+                    assert_eq!(frames_out, frames, "back {back}");
+                    let mut triples_out = vec![Triple::default(); triples.len()];
+                    buf.read_slice_into(&mut triples_out).expect("Same type.");
+                    assert_eq!(triples_out, triples, "back {back}");
+                    assert!(buf.is_empty());
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn pod_writes_never_evict() {
+        for typed_checks in [false, true] {
+            let mut buf = RingBuf::builder()
+                .overwrite(true)
+                .typed_checks(typed_checks)
+                .build()
+                .unwrap();
+            let stride = if typed_checks { typetag::TAG_LEN } else { 0 } + 12;
+            let count = buf.capacity() / stride;
+            let triples: Vec<Triple> = (0..count as u32)
+                .map(|i| Triple {
+                    x: i,
+                    ..Triple::default()
+                })
+                .collect();
+            buf.write_slice(&triples).unwrap();
+            let len = buf.len();
+            // Evicting bytes to make room would cut the oldest value in half.
+            assert!(matches!(
+                buf.write_slice(&triples[..1]),
+                Err(Error::Ours(BufError::NotEnoughSpace { .. }))
+            ));
+            assert!(matches!(
+                buf.write_typed(Triple::default()),
+                Err(Error::Ours(BufError::NotEnoughSpace { .. }))
+            ));
+            assert_eq!(buf.len(), len);
+            let mut out = vec![Triple::default(); count];
+            buf.read_slice_into(&mut out).unwrap();
+            assert_eq!(out, triples);
+        }
+    }
+
+    #[test]
+    fn pod_slice_reads_are_all_or_nothing() {
+        for typed_checks in [false, true] {
+            let mut buf = RingBuf::builder()
+                .typed_checks(typed_checks)
+                .build()
+                .unwrap();
+            let tag = if typed_checks { typetag::TAG_LEN } else { 0 };
+            buf.write_slice(&[1.5f32, 2.5, 3.5]).unwrap();
+            assert_eq!(buf.len(), 3 * (tag + 4));
+
+            // One value short, or a whole one plus part of the next: nothing is taken.
+            let mut out = [0.0f32; 4];
+            assert!(matches!(
+                buf.read_slice_into(&mut out),
+                Err(Error::Ours(BufError::NotEnoughData { .. }))
+            ));
+            assert_eq!(out, [0.0; 4]);
+            assert_eq!(buf.len(), 3 * (tag + 4));
+
+            // Element by element, and mixed with `write_typed`.
+            assert_eq!(buf.read_typed::<f32>().unwrap(), 1.5);
+            buf.write_typed(4.5f32).unwrap();
+            let mut out = [0.0f32; 3];
+            buf.read_slice_into(&mut out).unwrap();
+            assert_eq!(out, [2.5, 3.5, 4.5]);
+
+            buf.write_slice::<Triple>(&[]).unwrap();
+            buf.read_slice_into::<Triple>(&mut []).unwrap();
+            assert!(buf.is_empty());
+        }
+
+        // Zero-sized values with their tags add up to more than could ever be pending.
+        let mut buf = RingBuf::builder().typed_checks(true).build().unwrap();
+        buf.write_slice(&[[0u8; 0]; 3]).unwrap();
+        assert_eq!(buf.len(), 3 * typetag::TAG_LEN);
+        let mut huge = vec![[0u8; 0]; usize::MAX / 2];
+        assert!(matches!(
+            buf.read_slice_into(&mut huge),
+            Err(Error::Ours(BufError::NotEnoughData { .. }))
+        ));
+        buf.read_slice_into(&mut huge[..3]).unwrap();
+        assert!(buf.is_empty());
+
+        // A wrong type anywhere in the slice consumes nothing.
+        let mut buf = RingBuf::builder().typed_checks(true).build().unwrap();
+        buf.write_slice(&[1u32, 2]).unwrap();
+        buf.write_typed(3.0f32).unwrap();
+        let mut out = [0u32; 3];
+        assert!(matches!(
+            buf.read_slice_into(&mut out),
+            Err(Error::Ours(BufError::TypeMismatch { .. }))
+        ));
+        assert_eq!(buf.len(), 3 * (typetag::TAG_LEN + 4));
+    }
+
+    #[test]
+    fn io_write_evicts_on_overwrite_rings() {
+        let mut buf = RingBuf::builder().overwrite(true).build().unwrap();
+        let cap = buf.capacity();
+        let input: Vec<u8> = (0..3 * cap + 17).map(|i| (i * 13) as u8).collect();
+        // `io::copy` keeps calling `write` until everything has been taken.
+        io::copy(&mut &input[..], &mut buf).unwrap();
+        assert_eq!(buf.peek(), &input[input.len() - cap..]);
+
+        let (a, b) = input.split_at(cap / 2);
+        let n = Write::write_vectored(&mut buf, &[IoSlice::new(a), IoSlice::new(b)]).unwrap();
+        assert_eq!(n, input.len());
+        assert_eq!(buf.peek(), &input[input.len() - cap..]);
+
+        // A plain ring still reports the short write.
+        let mut plain = RingBuf::new(1).unwrap();
+        assert_eq!(Write::write(&mut plain, &input).unwrap(), cap);
+        assert_eq!(
+            plain.write_all(b"x").unwrap_err().kind(),
+            io::ErrorKind::WriteZero
+        );
+    }
+
+    #[test]
+    fn overwriting_keeps_the_latest_bytes() {
+        for backend in ALL_BACKENDS {
+            let _leaks = LeakCheck::new();
+            let mut buf = RingBuf::builder()
+                .pages(1)
+                .backend(backend)
+                .zeroize(true)
+                .build()
+                .unwrap();
+            let cap = buf.capacity();
+            let mut input = Vec::new();
+            // Odd sizes, so the ring wraps everywhere, plus the odd write bigger than all of it.
+            for (i, len) in (0..60)
+                .map(|i| (i, (i * 977 + 13) % (cap + cap / 2)))
+                .chain([(60, 0)])
+            {
+                let chunk: Vec<u8> = (0..len).map(|j| (i * 31 + j * 7) as u8).collect();
+                let before = buf.len();
+                let evicted = buf.write_overwriting(&chunk).unwrap();
+                assert_eq!(
+                    evicted,
+                    (before + len.min(cap)).saturating_sub(cap),
+                    "chunk {i}"
+                );
+                input.extend_from_slice(&chunk);
+                let kept = input.len().min(cap);
+                assert_eq!(buf.peek(), &input[input.len() - kept..], "chunk {i}");
+                if i % 7 == 0 {
+                    buf.consume(buf.len() / 3).unwrap();
+                    input.drain(..input.len() - buf.len());
+                }
+            }
+            assert!(matches!(
+                RingBuf::default().write_overwriting(b"x"),
+                Err(Error::Ours(BufError::ZeroCapacity))
+            ));
+        }
+    }
+
+    #[test]
+    fn partial_writes_and_reads_across_the_wrap() {
+        let mut buf = RingBuf::new(1).unwrap();
+        let cap = buf.capacity();
+        assert_eq!(buf.write_up_to(&vec![0; cap - 10]), cap - 10);
+        buf.consume(cap - 20).unwrap();
+        // Ten pending just before the end, so what fits runs across it.
+        let input: Vec<u8> = (0..cap).map(|i| (i % 253) as u8).collect();
+        assert_eq!(buf.write_up_to(&input), cap - 10);
+        assert_eq!(&buf.peek()[10..], &input[..cap - 10]);
+        assert_eq!(buf.write_up_to(b"full"), 0);
+        assert_eq!(buf.write_up_to(b""), 0);
+
+        let mut out = vec![0; 16];
+        assert_eq!(buf.read_up_to(&mut out), 16);
+        assert_eq!(&out[10..], &input[..6]);
+        let mut rest = vec![0; cap];
+        assert_eq!(buf.read_up_to(&mut rest), cap - 16);
+        assert_eq!(&rest[..cap - 16], &input[6..cap - 10]);
+        assert_eq!(buf.read_up_to(&mut out), 0);
+
+        assert_eq!(RingBuf::default().write_up_to(b"x"), 0);
+        assert_eq!(RingBuf::default().read_up_to(&mut out), 0);
+    }
+
+    #[test]
+    fn read_exact_into_across_the_wrap() {
+        let mut buf = RingBuf::new(1).unwrap();
+        let cap = buf.capacity();
+        buf.write(&vec![0; cap - 3]).unwrap();
+        buf.consume(cap - 3).unwrap();
+        buf.write(b"wrapped!").unwrap();
+
+        let mut out = [0; 8];
+        assert!(matches!(
+            buf.read_exact_into(&mut [0; 9]),
+            Err(Error::Ours(BufError::NotEnoughData {
+                requested: 9,
+                available: 8
+            }))
+        ));
+        assert_eq!(buf.len(), 8);
+        buf.read_exact_into(&mut []).unwrap();
+        buf.read_exact_into(&mut out[..5]).unwrap();
+        // Nothing borrowed, so writing right after is fine.
+        buf.write(&out[..5]).unwrap();
+        buf.read_exact_into(&mut out).unwrap();
+        assert_eq!(&out, b"ed!wrapp");
+        assert!(buf.is_empty());
+        RingBuf::default().read_exact_into(&mut []).unwrap();
+    }
+
+    #[test]
+    fn drain_across_the_wrap() {
+        let mut buf = RingBuf::new(1).unwrap();
+        let cap = buf.capacity();
+        buf.write(&vec![0; cap - 4]).unwrap();
+        buf.consume(cap - 4).unwrap();
+        buf.write(&(0..16).collect::<Vec<u8>>()).unwrap();
+
+        let mut drain = buf.drain(10).unwrap();
+        assert_eq!(drain.len(), 10);
+        assert_eq!(
+            drain.by_ref().take(6).collect::<Vec<_>>(),
+            [0, 1, 2, 3, 4, 5]
+        );
+        assert_eq!(drain.next_back(), Some(9));
+        assert_eq!(drain.len(), 3);
+        // Dropped halfway through, and the rest of the ten go anyway.
+        drop(drain);
+        assert_eq!(buf.peek(), (10..16).collect::<Vec<u8>>());
+        assert_eq!(buf.read_offset(), 6);
+        buf.check_invariants();
+
+        assert!(matches!(
+            buf.drain(7),
+            Err(Error::Ours(BufError::NotEnoughData { .. }))
+        ));
+        assert_eq!((&buf).into_iter().next_back(), Some(15));
+        assert_eq!((&buf).into_iter().len(), 6);
+        assert_eq!(buf.drain(6).unwrap().sum::<u8>(), (10..16).sum());
+        assert!(buf.is_empty());
+        assert_eq!(buf.drain(0).unwrap().next(), None);
+    }
+
+    #[test]
+    fn read_until_at_the_wrap() {
+        let mut buf = RingBuf::new(1).unwrap();
+        let cap = buf.capacity();
+        // Newline on the last byte of the first view, then on the first byte of the second.
+        for newline_at in [cap - 1, cap] {
+            let filler = (newline_at - 4 + cap - buf.read_offset()) % cap;
+            buf.write(&vec![b'-'; filler]).unwrap();
+            buf.consume(filler).unwrap();
+            buf.write(b"line").unwrap();
+            assert_eq!(buf.write_offset(), newline_at % cap);
+            assert_eq!(buf.read_until(b'\n').unwrap(), None);
+            assert_eq!(buf.len(), 4);
+            buf.write(b"\nrest").unwrap();
+            assert_eq!(buf.read_until(b'\n').unwrap(), Some(&b"line\n"[..]));
+            assert_eq!(buf.peek(), b"rest");
+            buf.consume(4).unwrap();
+        }
+
+        buf.write(b"one\ntwo\nthree\nfour").unwrap();
+        let mut line = String::new();
+        assert_eq!(buf.read_line(&mut line).unwrap(), 4);
+        assert_eq!(line, "one\n");
+        let lines: Vec<String> = (&mut buf).lines().map(|l| l.unwrap()).collect();
+        // The unfinished one too, since the empty ring reads as EOF.
+        assert_eq!(lines, ["two", "three", "four"]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let mut buf = RingBuf::new(1).unwrap();
+        let cap = buf.capacity();
+        let mut rng = 0x2545_f491_4f6c_dd1du64;
+        let mut next = |bound: usize| {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            rng as usize % bound
+        };
+        let mut sent = VecDeque::new();
+        let (mut written, mut read, mut wrapped_headers) = (0, 0, 0);
+        while read < 20_000 {
+            // Fill up with messages of random sizes, a good share of them empty.
+            loop {
+                let len = if next(5) == 0 { 0 } else { next(cap / 8) };
+                let payload: Vec<u8> = (0..len).map(|i| (written + i) as u8).collect();
+                match buf.write_msg(&payload) {
+                    Ok(()) => sent.push_back(payload),
+                    Err(Error::Ours(BufError::NotEnoughSpace { .. })) => break,
+                    Err(e) => panic!("{e}"),
+                }
+                written += 1;
+            }
+            for _ in 0..=next(sent.len()) {
+                wrapped_headers += usize::from(buf.read_offset() + MSG_HEADER_LEN > cap);
+                let expected = sent.pop_front().unwrap();
+                assert_eq!(
+                    buf.read_msg().unwrap(),
+                    Some(&expected[..]),
+                    "message {read}"
+                );
+                read += 1;
+            }
+        }
+        assert!(wrapped_headers > 0);
+
+        while let Some(payload) = sent.pop_front() {
+            assert_eq!(buf.read_msg().unwrap(), Some(&payload[..]));
+        }
+        assert_eq!(buf.read_msg().unwrap(), None);
+
+        // A frame that hasn't all arrived yet, with its header across the wrap.
+        let filler = (2 * cap - 2 - buf.read_offset()) % cap;
+        buf.write(&vec![0; filler]).unwrap();
+        buf.consume(filler).unwrap();
+        buf.write(&5u32.to_le_bytes()[..3]).unwrap();
+        assert_eq!(buf.read_msg().unwrap(), None);
+        buf.write(&[0, b'h', b'e']).unwrap();
+        assert_eq!(buf.read_msg().unwrap(), None);
+        assert_eq!(buf.len(), 6);
+        buf.write(b"llo").unwrap();
+        assert_eq!(buf.read_msg().unwrap(), Some(&b"hello"[..]));
+
+        // A length that could never complete is an error rather than a `None` forever.
+        buf.write(&u32::MAX.to_le_bytes()).unwrap();
+        assert!(matches!(
+            buf.read_msg(),
+            Err(Error::Ours(BufError::FrameTooLarge))
+        ));
+        assert_eq!(buf.len(), 4);
+        assert!(matches!(
+            buf.write_msg(&vec![0; cap - 3]),
+            Err(Error::Ours(BufError::FrameTooLarge))
+        ));
+        assert_eq!(buf.len(), 4);
+    }
+
+    #[test]
+    fn messages_into_a_reused_buffer() {
+        let mut buf = RingBuf::new(1).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(buf.read_msg_into(&mut out).unwrap(), None);
+        buf.write_msg(&[7; 300]).unwrap();
+        assert_eq!(buf.read_msg_into(&mut out).unwrap(), Some(300));
+        assert_eq!(out, [7; 300]);
+        let (capacity, ptr) = (out.capacity(), out.as_ptr());
+
+        // Same-sized and smaller frames, wrapping many times over, never reallocate.
+        for i in 0..1000 {
+            let payload: Vec<u8> = (0..300 - i % 7).map(|j| (i + j) as u8).collect();
+            buf.write_msg(&payload).unwrap();
+            assert_eq!(buf.read_msg_into(&mut out).unwrap(), Some(payload.len()));
+            assert_eq!(out, payload);
+            assert_eq!((out.capacity(), out.as_ptr()), (capacity, ptr), "frame {i}");
+        }
+
+        // An empty frame empties the buffer; a partial or oversized one leaves it alone.
+        buf.write_msg(b"").unwrap();
+        assert_eq!(buf.read_msg_into(&mut out).unwrap(), Some(0));
+        assert!(out.is_empty());
+        out.extend_from_slice(b"kept");
+        buf.write(&3u32.to_le_bytes()).unwrap();
+        assert_eq!(buf.read_msg_into(&mut out).unwrap(), None);
+        buf.consume(4).unwrap();
+        buf.write(&u32::MAX.to_le_bytes()).unwrap();
+        assert!(matches!(
+            buf.read_msg_into(&mut out),
+            Err(Error::Ours(BufError::FrameTooLarge))
+        ));
+        assert_eq!(out, b"kept");
+        assert_eq!(buf.len(), 4);
+    }
+
+    #[test]
+    fn the_end_marker_ends_the_stream() {
+        let mut buf = RingBuf::new(1).unwrap();
+        buf.write_msg(b"one").unwrap();
+        buf.write_msg(b"").unwrap();
+        buf.write_msg_end().unwrap();
+        // Everything before the marker comes first, empty messages included.
+        assert_eq!(buf.recv_msg().unwrap(), Some(MsgEvent::Data(&b"one"[..])));
+        assert_eq!(buf.recv_msg().unwrap(), Some(MsgEvent::Data(&b""[..])));
+        assert_eq!(buf.recv_msg().unwrap(), Some(MsgEvent::End));
+        assert_eq!(buf.recv_msg().unwrap(), Some(MsgEvent::End));
+        assert!(matches!(
+            buf.read_msg(),
+            Err(Error::Ours(BufError::EndOfStream))
+        ));
+        assert!(matches!(
+            buf.read_msg_checked(),
+            Err(Error::Ours(BufError::EndOfStream))
+        ));
+        assert!(!buf.skip_frame().unwrap());
+        assert_eq!(buf.iter_frames_mut().count(), 0);
+        assert_eq!(buf.len(), MSG_HEADER_LEN);
+
+        // On an overwrite ring the marker makes room like a message, but is never dropped.
+        let mut buf = RingBuf::builder().overwrite(true).build().unwrap();
+        let cap = buf.capacity();
+        buf.write_msg(&vec![1; cap - MSG_HEADER_LEN]).unwrap();
+        buf.write_msg_end().unwrap();
+        assert_eq!(buf.stats().dropped_frames, 1);
+        buf.write_msg(&vec![2; cap - 2 * MSG_HEADER_LEN + 1])
+            .expect_err("Only the marker is left to drop.");
+        assert_eq!(buf.recv_msg().unwrap(), Some(MsgEvent::End));
+    }
+
+    #[test]
+    fn overwrite_rings_drop_whole_messages() {
+        let mut buf = RingBuf::builder().overwrite(true).build().unwrap();
+        let cap = buf.capacity();
+        // Three frames filling all but 30 bytes, the second straddling the end of the ring.
+        park_at(&mut buf, cap - cap / 2);
+        let third = cap / 3 - 10 - MSG_HEADER_LEN;
+        let frames: Vec<Vec<u8>> = (0..3).map(|i| vec![i as u8; third]).collect();
+        for frame in &frames {
+            buf.write_msg(frame).unwrap();
+        }
+
+        // One more drops just the oldest.
+        buf.write_msg(&[3; 100]).unwrap();
+        assert_eq!(buf.stats().dropped_frames, 1);
+
+        // A checked frame wanting the whole ring drops all the rest.
+        let mut victims = Vec::new();
+        let big = vec![4; cap - CHECKED_MSG_HEADER_LEN];
+        assert_eq!(
+            buf.write_msg_evicting(b"x", |checked, len| victims.push((checked, len)))
+                .unwrap(),
+            0
+        );
+        buf.write_msg_checked(&big).unwrap();
+        assert_eq!(buf.stats().dropped_frames, 5);
+        assert_eq!(buf.read_msg_checked().unwrap().unwrap(), big);
+        assert!(buf.is_empty());
+        assert!(victims.is_empty());
+
+        // The walk stays in step when every frame wraps somewhere different.
+        let payload =
+            |i: usize| -> Vec<u8> { (0..100 + i * 13 % 400).map(|j| (i + j) as u8).collect() };
+        for i in 0..200 {
+            let payload = payload(i);
+            buf.write_msg_evicting(&payload, |checked, len| victims.push((checked, len)))
+                .unwrap();
+        }
+        let dropped = victims.len();
+        assert!(dropped > 0);
+        assert!(victims.iter().all(|&(checked, _)| !checked));
+        assert_eq!(buf.stats().dropped_frames, 5 + dropped as u64);
+        let mut seen = 0;
+        while let Some(msg) = buf.read_msg().unwrap() {
+            let i = dropped + seen;
+            assert_eq!(msg, payload(i));
+            seen += 1;
+        }
+        assert_eq!(dropped + seen, 200);
+    }
+
+    #[test]
+    fn frames_rewritten_in_place() {
+        for_each_wrap_ring(|buf| {
+            let cap = buf.capacity();
+            let frames: [Vec<u8>; 3] = [b"first".to_vec(), vec![2; 300], b"third".to_vec()];
+            // The middle frame's payload straddling the end of the ring.
+            park_at(buf, cap - 100);
+            buf.write_msg(&frames[0]).unwrap();
+            buf.write_msg_checked(&frames[1]).unwrap();
+            buf.write_msg(&frames[2]).unwrap();
+            // Half of a fourth, which isn't handed out.
+            buf.write(&10u32.to_le_bytes()).unwrap();
+
+            let mut seen = 0;
+            for (i, mut frame) in buf.iter_frames_mut().enumerate() {
+                assert_eq!(frame.payload(), frames[i]);
+                assert_eq!(frame.is_checked(), i == 1);
+                if i == 1 {
+                    assert_eq!(frame.stored_crc(), Some(crc::crc32(&frames[1])));
+                    frame.payload_mut()[0] = b'R';
+                    frame.payload_mut()[299] = b'R';
+                }
+                seen += 1;
+            }
+            assert_eq!(seen, 3);
+            assert_eq!(buf.len(), 9 + 308 + 9 + 4);
+
+            assert_eq!(buf.read_msg().unwrap().unwrap(), frames[0]);
+            let second = buf.read_msg_checked().unwrap().unwrap();
+            assert_eq!((second[0], second[299]), (b'R', b'R'));
+            assert_eq!(second[1..299], frames[1][1..299]);
+            assert_eq!(buf.read_msg().unwrap().unwrap(), frames[2]);
+            assert_eq!(buf.read_msg().unwrap(), None);
+
+            // The header straddling the end, so the edit goes in through the second view and
+            // comes back out through the first.
+            park_at(buf, cap - 2);
+            buf.write_msg(b"hello world").unwrap();
+            let mut frame = buf.iter_frames_mut().next().unwrap();
+            frame.payload_mut()[0] = b'J';
+            drop(frame);
+            assert_eq!(buf.read_msg().unwrap().unwrap(), b"Jello world");
+        });
+    }
+
+    #[test]
+    fn message_eviction_is_all_or_nothing() {
+        let mut buf = RingBuf::new(1).unwrap();
+        let cap = buf.capacity();
+        let first = vec![1; cap - 400];
+        buf.write_msg(&first).unwrap();
+        // Then a frame that's still being written, so dropping the first alone isn't enough.
+        buf.write(&500u32.to_le_bytes()).unwrap();
+        buf.write(&[0; 200]).unwrap();
+        assert!(matches!(
+            buf.write_msg_evicting(&vec![2; cap - 150], |_, _| unreachable!("Nothing to drop.")),
+            Err(Error::Ours(BufError::NotEnoughSpace { .. }))
+        ));
+        assert_eq!(buf.stats().dropped_frames, 0);
+        assert_eq!(buf.read_msg().unwrap().unwrap(), first);
+
+        // Without the overwrite flag, plain `write_msg` still just fails.
+        buf.clear();
+        buf.write_msg(&vec![1; cap - MSG_HEADER_LEN]).unwrap();
+        assert!(buf.write_msg(b"").is_err());
+        assert_eq!(buf.write_msg_evicting(b"", |_, _| ()).unwrap(), 1);
+        assert_eq!(buf.read_msg().unwrap().unwrap(), b"");
+    }
+
+    #[test]
+    fn checked_messages_catch_flipped_bits() {
+        for_each_wrap_ring(|buf| {
+            let cap = buf.capacity();
+            let payload: Vec<u8> = (0..200).map(|i| i as u8).collect();
+            // The header, the CRC and the payload each straddling the end in turn.
+            for back in [2, 6, 100] {
+                park_at(buf, cap - back);
+                buf.write_msg_checked(&payload).unwrap();
+                buf.write_msg_checked(b"next").unwrap();
+                assert_eq!(buf.read_msg_checked().unwrap(), Some(&payload[..]));
+                assert_eq!(buf.read_msg_checked().unwrap(), Some(&b"next"[..]));
+
+                // One bit of the payload, flipped in the mapping between write and read, is
+                // caught, and nothing is consumed.
+                park_at(buf, cap - back);
+                buf.write_msg_checked(&payload).unwrap();
+                buf.write_msg_checked(b"next").unwrap();
+                let len = buf.len();
+                unsafe { *buf.data_ptr().add(buf.read_offset() + 8 + 97) ^= 0x10 };
+                let Err(Error::Ours(BufError::CorruptFrame { expected, actual })) =
+                    buf.read_msg_checked()
+                else {
+                    panic!("Flipped bit not caught.");
+                };
+                assert_eq!(expected, crc::crc32(&payload));
+                assert_ne!(actual, expected);
+                assert_eq!(buf.len(), len);
+
+                // Skipping the bad frame gets the stream back in step.
+                assert!(buf.skip_frame().unwrap());
+                assert_eq!(buf.read_msg_checked().unwrap(), Some(&b"next"[..]));
+                assert!(buf.is_empty());
+            }
+        });
+
+        // A corrupt CRC is caught as well, and a frame is only skipped once it's all there.
+        let mut buf = RingBuf::new(1).unwrap();
+        buf.write_msg_checked(b"hello").unwrap();
+        unsafe { *buf.data_ptr().add(buf.read_offset() + 4) ^= 1 };
+        assert!(matches!(
+            buf.read_msg_checked(),
+            Err(Error::Ours(BufError::CorruptFrame { .. }))
+        ));
+        buf.consume(buf.len()).unwrap();
+        buf.write(&(5 | CHECKED_FRAME).to_le_bytes()).unwrap();
+        assert_eq!(buf.read_msg_checked().unwrap(), None);
+        assert!(!buf.skip_frame().unwrap());
+        buf.write(&[0; 9]).unwrap();
+        assert!(buf.skip_frame().unwrap());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn checked_and_unchecked_frames_dont_mix() {
+        let mut buf = RingBuf::new(1).unwrap();
+        let cap = buf.capacity();
+        buf.write_msg(b"plain").unwrap();
+        buf.write_msg_checked(b"checked").unwrap();
+        let len = buf.len();
+        assert!(matches!(
+            buf.read_msg_checked(),
+            Err(Error::Ours(BufError::MixedFrames))
+        ));
+        assert_eq!(buf.len(), len);
+        assert_eq!(buf.read_msg().unwrap(), Some(&b"plain"[..]));
+        assert!(matches!(
+            buf.read_msg(),
+            Err(Error::Ours(BufError::MixedFrames))
+        ));
+        assert_eq!(buf.read_msg_checked().unwrap(), Some(&b"checked"[..]));
+
+        // A checked frame has four more bytes of header to fit.
+        buf.write_msg(&vec![0; cap - 4]).unwrap();
+        buf.skip_frame().unwrap();
+        assert!(matches!(
+            buf.write_msg_checked(&vec![0; cap - 7]),
+            Err(Error::Ours(BufError::FrameTooLarge))
+        ));
+        buf.write_msg_checked(&vec![0; cap - 8]).unwrap();
+        assert_eq!(
+            buf.read_msg_checked().unwrap().map(<[u8]>::len),
+            Some(cap - 8)
+        );
+        let e = io::Error::from(Error::from(BufError::MixedFrames));
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn introspection() {
+        let mut buf = RingBuf::new(256).unwrap();
+        let cap = buf.capacity();
+        assert!(buf.is_empty() && !buf.is_full());
+        assert_eq!(buf.free_space(), cap);
+        buf.write(&vec![b'x'; cap - 3]).unwrap();
+        buf.consume(5).unwrap();
+        assert_eq!((buf.len(), buf.free_space()), (cap - 8, 8));
+        buf.write(&[b'x'; 8]).unwrap();
+        assert!(buf.is_full() && buf.free_space() == 0);
+
+        // Positions only, however much is pending.
+        let debug = format!("{buf:?}");
+        assert_eq!(
+            debug,
+            format!("RingBuf {{ head: 5, tail: 5, len: {cap}, capacity: {cap}, .. }}")
+        );
+
+        assert!(RingBuf::default().is_full());
+        let lazy = RingBuf::builder().lazy(true).build().unwrap();
+        assert!(!lazy.is_full() && lazy.free_space() == 0);
+    }
+
+    #[test]
+    fn clear_and_refill() {
+        for debug_fill in [false, true] {
+            let _leaks = LeakCheck::new();
+            let mut buf = RingBuf::builder()
+                .pages(4)
+                .debug_fill(debug_fill)
+                .build()
+                .unwrap();
+            let cap = buf.capacity();
+            buf.write(&vec![1; cap / 2]).unwrap();
+            buf.consume(cap / 4).unwrap();
+            buf.write(&vec![2; cap / 2]).unwrap();
+            buf.clear();
+            assert!(buf.is_empty());
+            assert_eq!((buf.read_offset(), buf.write_offset()), (0, 0));
+            buf.write(b"fresh").unwrap();
+            assert_eq!(buf.peek(), b"fresh");
+
+            buf.write(&vec![3; cap - 5]).unwrap();
+            buf.clear_and_release().unwrap();
+            assert!(buf.is_empty());
+            buf.write(b"after release").unwrap();
+            assert_eq!(buf.read(13).unwrap(), b"after release");
+            // The rest was released, not kept: zeros, or poison in debug-fill mode. Heap rings
+            // keep their memory as it was.
+            if buf.backend_kind() != Some(BackendKind::Heap) {
+                let fill = if debug_fill { POISON } else { 0 };
+                let rest = unsafe { std::slice::from_raw_parts(buf.data_ptr().add(13), cap - 13) };
+                assert!(rest.iter().all(|&b| b == fill));
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(all(mapped, target_os = "linux"))]
+    #[cfg_attr(feature = "portable", ignore = "Heap rings have no fd to release.")]
+    fn released_pages_go_back_to_the_kernel() {
+        // Poisoning would fault every page straight back in.
+        let mut buf = RingBuf::builder()
+            .pages(16)
+            .debug_fill(false)
+            .build()
+            .unwrap();
+        use nix::sys::stat::fstat;
+        use std::os::fd::AsRawFd;
+
+        let blocks = |buf: &RingBuf| {
+            let fd = buf.memfd().expect("Mapped rings have an fd.");
+            fstat(fd.as_raw_fd()).unwrap().st_blocks
+        };
+        buf.write(&vec![7; buf.capacity()]).unwrap();
+        let full = blocks(&buf);
+        assert!(full > 0);
+        buf.clear_and_release().unwrap();
+        assert_eq!(blocks(&buf), 0);
+        buf.write(&[7; 100]).unwrap();
+        assert!(blocks(&buf) > 0 && blocks(&buf) < full);
+        assert_eq!(buf.read(100).unwrap(), [7; 100]);
+
+        inject_failure(OsOp::PunchHole, Errno::EOPNOTSUPP, 0);
+        buf.write(b"gone anyway").unwrap();
+        assert!(buf.clear_and_release().is_err());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn grow_keeps_what_straddles_the_wrap() {
+        let _leaks = LeakCheck::new();
+        let mut buf = RingBuf::new(1).unwrap();
+        let cap = buf.capacity();
+        let input: Vec<u8> = (0..cap).map(|i| (i % 251) as u8).collect();
+        buf.write(&vec![0; cap - 100]).unwrap();
+        buf.consume(cap - 100).unwrap();
+        buf.write(&input[..300]).unwrap();
+        assert!(buf.write_offset() < buf.read_offset());
+
+        // A failed grow leaves the ring as it was.
+        #[cfg(mapped)]
+        {
+            inject_failure(OsOp::MapHigh, Errno::ENOMEM, 0);
+            inject_failure(OsOp::MapDouble, Errno::ENOMEM, 0);
+            if !cfg!(feature = "portable") {
+                assert!(buf.grow(4).is_err());
+            }
+            clear_injected_failures();
+        }
+        assert_eq!((buf.capacity(), buf.peek()), (cap, &input[..300]));
+
+        buf.grow(4).unwrap();
+        assert_eq!(buf.capacity(), 4 * cap);
+        assert_eq!(buf.peek(), &input[..300]);
+        buf.write(&input[300..]).unwrap();
+        buf.write(&input).unwrap();
+        assert_eq!(buf.read(cap).unwrap(), &input[..]);
+        assert_eq!(buf.read(cap).unwrap(), &input[..]);
+        buf.grow(2).unwrap();
+        assert_eq!(buf.capacity(), 4 * cap);
+
+        let mut placeholder = RingBuf::default();
+        placeholder.grow(1).unwrap();
+        placeholder.write(b"mapped now").unwrap();
+        assert_eq!(placeholder.capacity(), cap);
+    }
+
+    /// Away from `super::*`, whose `io::Write` would make `write!` ambiguous.
+    mod formatting {
+        use super::{debug_filled, page, park_at};
+        use crate::ringbuf::RingBuf;
+        use std::fmt::Write;
+
+        #[test]
+        fn formatted_records_are_never_torn() {
+            let mut buf = debug_filled();
+            park_at(&mut buf, page() - 10);
+            let mut records = 0;
+            while writeln!(buf, "temp={} ts={}", records % 40, 1_000_000 + records).is_ok() {
+                records += 1;
+            }
+            // The run that didn't fit left nothing behind, not even in the free space.
+            buf.check_invariants();
+            assert!(buf.free_space() < "temp=00 ts=0000000\n".len());
+
+            let text = std::str::from_utf8(buf.peek()).expect("Only whole records.");
+            assert_eq!(text.lines().count(), records);
+            for (i, line) in text.lines().enumerate() {
+                assert_eq!(line, format!("temp={} ts={}", i % 40, 1_000_000 + i));
+            }
+            assert!(text.ends_with('\n'));
+
+            buf.write_str("").expect("Nothing to write is fine.");
+            assert!(write!(RingBuf::default(), "{records}").is_err());
+        }
+
+        #[test]
+        fn overwrite_rings_make_room_for_records() {
+            let mut buf = RingBuf::builder()
+                .debug_fill(true)
+                .overwrite(true)
+                .build()
+                .expect("Creation should work.");
+            let mut all = String::new();
+            for i in 0..2000 {
+                writeln!(buf, "temp={} ts={}", i % 40, 1_000_000 + i).expect("Evicts to fit.");
+                all += &format!("temp={} ts={}\n", i % 40, 1_000_000 + i);
+            }
+            buf.check_invariants();
+            // Only the latest bytes are kept, the oldest record torn the way `write_overwriting`
+            // would tear it.
+            assert!(all.len() > 2 * buf.capacity());
+            assert_eq!(buf.len(), buf.capacity());
+            assert!(all.as_bytes().ends_with(buf.peek()));
+
+            // Bigger than the ring, so nothing is evicted for it.
+            let kept = buf.peek().to_vec();
+            assert!(write!(buf, "{}", "x".repeat(buf.capacity() + 1)).is_err());
+            // Nor for a cursor that hasn't read what would go.
+            let _cursor = buf.subscribe().unwrap();
+            assert!(writeln!(buf, "late").is_err());
+            buf.check_invariants();
+            assert_eq!(buf.peek(), kept);
+        }
     }
 }
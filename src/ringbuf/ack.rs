@@ -0,0 +1,332 @@
+//! At-least-once delivery of messages from a split ring: a message's space only goes back to the
+//! producer once it's been acknowledged.
+
+use super::{BufError, Consumer, Frame, Result, MSG_HEADER_LEN};
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fmt,
+    ops::{Deref, Range},
+};
+
+/// The most messages an `AckingConsumer` has out at once, one bit each.
+const WINDOW: usize = u64::BITS as usize;
+
+/// A `Consumer` that hands out messages (from `Producer::write_msg`, or `write_msg_timed`
+/// without a TTL) as `Delivery`s to be acknowledged one by one, in any order, once they've been
+/// dealt with. The ring only frees a message once it and every message before it have been
+/// acked, so unacked ones keep holding their space and a slow one can fill the ring up. A
+/// nacked one is delivered again, ahead of anything new. Made with `Consumer::acking`.
+///
+/// Nothing is consumed until it's acked, so `into_inner` (or the consumer's `from_fd` in a new
+/// process) starts over from the oldest unacked message, delivering again whatever came after
+/// it, acked or not.
+pub struct AckingConsumer {
+    consumer: RefCell<Consumer>,
+    window: RefCell<Window>,
+}
+
+/// The messages from the head on that have been delivered, one bit each.
+#[derive(Default)]
+struct Window {
+    // How long each message is, header included, oldest first.
+    sizes: VecDeque<usize>,
+    acked: u64,
+    // Nacked, and waiting to be delivered again.
+    nacked: u64,
+    // The sequence number of the message at the head, counting from zero.
+    base: u64,
+}
+
+impl Window {
+    /// Where the `i`th message starts, in bytes past the head.
+    fn offset(&self, i: usize) -> usize {
+        self.sizes.iter().take(i).sum()
+    }
+}
+
+impl Consumer {
+    /// Switches to acknowledged delivery. See `AckingConsumer`.
+    pub fn acking(self) -> AckingConsumer {
+        AckingConsumer {
+            consumer: RefCell::new(self),
+            window: RefCell::default(),
+        }
+    }
+}
+
+impl AckingConsumer {
+    /// The next message: one that was nacked, oldest first, or else the next new one. `None`
+    /// until a whole new one has arrived, and while 64 are out unacked. Fails with
+    /// `BufError::Disconnected` once the producer is gone and there's nothing left to deliver,
+    /// with `BufError::EndOfStream` at the marker from `Producer::finish`, and like
+    /// `RingBuf::read_msg` for a frame that could never fit or a checked one.
+    pub fn recv(&self) -> Result<Option<Delivery<'_>>> {
+        let mut window = self.window.borrow_mut();
+        if window.nacked != 0 {
+            let i = window.nacked.trailing_zeros() as usize;
+            window.nacked &= !(1 << i);
+            let start = window.offset(i);
+            let seq = window.base + i as u64;
+            return Ok(Some(self.delivery(seq, start..start + window.sizes[i])));
+        }
+        if window.sizes.len() == WINDOW {
+            return Ok(None);
+        }
+        let consumer = self.consumer.borrow();
+        // Checked first, so anything written before the producer went is seen below.
+        let producer_gone = consumer.producer_gone();
+        let start = window.offset(window.sizes.len());
+        let rest = &consumer.peek()[start..];
+        let frame = match rest.get(..MSG_HEADER_LEN) {
+            Some(header) => Frame::parse(header, consumer.capacity())?,
+            None if producer_gone => return Err(BufError::Disconnected.into()),
+            None => return Ok(None),
+        };
+        match frame {
+            Frame::End => return Err(BufError::EndOfStream.into()),
+            Frame::Msg { checked: true, .. } => return Err(BufError::MixedFrames.into()),
+            _ if rest.len() < frame.size() => {
+                return if producer_gone {
+                    Err(BufError::Disconnected.into())
+                } else {
+                    Ok(None)
+                };
+            }
+            Frame::Msg { .. } | Frame::Timed { .. } => {}
+        }
+        window.sizes.push_back(frame.size());
+        let seq = window.base + window.sizes.len() as u64 - 1;
+        Ok(Some(self.delivery(seq, start..start + frame.size())))
+    }
+
+    /// How many messages have been delivered and not acked yet, nacked ones included.
+    pub fn pending_unacked(&self) -> usize {
+        let window = self.window.borrow();
+        window.sizes.len() - window.acked.count_ones() as usize
+    }
+
+    /// Back to a plain `Consumer`, with every message from the oldest unacked one on still in
+    /// the ring, as if this one had never seen them.
+    pub fn into_inner(self) -> Consumer {
+        self.consumer.into_inner()
+    }
+
+    /// The delivery of message `seq`, found at `frame` bytes past the head.
+    fn delivery(&self, seq: u64, frame: Range<usize>) -> Delivery<'_> {
+        let consumer = self.consumer.borrow();
+        let frame = &consumer.peek()[frame];
+        let header = Frame::parse(&frame[..MSG_HEADER_LEN], consumer.capacity())
+            .expect("Parsed once already.")
+            .header_len();
+        let bytes = &frame[header..];
+        // SAFETY: The payload stays where it is, untouched, until the head moves past it, which
+        // only an ack of this very delivery can allow. The mapping lives as long as `self`.
+        let payload = unsafe { std::slice::from_raw_parts(bytes.as_ptr(), bytes.len()) };
+        Delivery {
+            acking: self,
+            seq,
+            payload,
+            settled: false,
+        }
+    }
+
+    /// Marks message `seq` acked, and frees every acked one at the head.
+    fn ack(&self, seq: u64) -> Result<()> {
+        let mut window = self.window.borrow_mut();
+        let i = (seq - window.base) as usize;
+        window.acked |= 1 << i;
+        let mut freed = 0;
+        while window.acked & 1 != 0 {
+            freed += window.sizes.pop_front().expect("Acked, so delivered.");
+            window.acked >>= 1;
+            window.nacked >>= 1;
+            window.base += 1;
+        }
+        self.consumer.borrow_mut().consume(freed)
+    }
+
+    fn nack(&self, seq: u64) {
+        let mut window = self.window.borrow_mut();
+        let i = (seq - window.base) as usize;
+        window.nacked |= 1 << i;
+    }
+}
+
+/// A message from `AckingConsumer::recv`, to `ack` once it's been dealt with or `nack` to have
+/// it delivered again. Dropping it without either counts as a nack. Derefs to the payload.
+pub struct Delivery<'a> {
+    acking: &'a AckingConsumer,
+    seq: u64,
+    payload: &'a [u8],
+    settled: bool,
+}
+
+impl Delivery<'_> {
+    /// The message. Borrowed from the delivery, since the ring may reuse its space once acked.
+    pub fn payload(&self) -> &[u8] {
+        self.payload
+    }
+
+    /// Done with the message. Its space goes back to the producer once every message before it
+    /// has been acked too.
+    pub fn ack(mut self) -> Result<()> {
+        self.settled = true;
+        self.acking.ack(self.seq)
+    }
+
+    /// Not done with the message: `recv` delivers it again, ahead of anything new.
+    pub fn nack(mut self) {
+        self.settled = true;
+        self.acking.nack(self.seq);
+    }
+}
+
+impl Deref for Delivery<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.payload
+    }
+}
+
+impl fmt::Debug for Delivery<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Delivery")
+            .field("seq", &self.seq)
+            .field("payload", &self.payload)
+            .finish()
+    }
+}
+
+impl Drop for Delivery<'_> {
+    fn drop(&mut self) {
+        if !self.settled {
+            self.acking.nack(self.seq);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Error, RingBuf};
+    use super::*;
+
+    fn ring() -> (super::super::Producer, AckingConsumer) {
+        let (producer, consumer) = RingBuf::new(1).unwrap().split().unwrap();
+        (producer, consumer.acking())
+    }
+
+    #[test]
+    fn acks_out_of_order_free_space_in_order() {
+        let (mut producer, acking) = ring();
+        for msg in [&b"one"[..], b"two", b"three"] {
+            producer.write_msg(msg).unwrap();
+        }
+        let free = producer.free_space();
+        let one = acking.recv().unwrap().unwrap();
+        let two = acking.recv().unwrap().unwrap();
+        let three = acking.recv().unwrap().unwrap();
+        assert_eq!(
+            (&*one, &*two, &*three),
+            (&b"one"[..], &b"two"[..], &b"three"[..])
+        );
+        assert!(acking.recv().unwrap().is_none());
+        assert_eq!(acking.pending_unacked(), 3);
+
+        // Nothing before `one` is acked, so nothing is freed yet.
+        three.ack().unwrap();
+        two.ack().unwrap();
+        assert_eq!(acking.pending_unacked(), 1);
+        assert_eq!(producer.free_space(), free);
+
+        one.ack().unwrap();
+        assert_eq!(acking.pending_unacked(), 0);
+        assert_eq!(producer.free_space(), producer.capacity());
+    }
+
+    #[test]
+    fn nacked_messages_come_back_first() {
+        let (mut producer, acking) = ring();
+        for msg in [&b"a"[..], b"b", b"c"] {
+            producer.write_msg(msg).unwrap();
+        }
+        let a = acking.recv().unwrap().unwrap();
+        let b = acking.recv().unwrap().unwrap();
+        b.nack();
+        // Dropped without a word counts as a nack too.
+        drop(a);
+        assert_eq!(acking.pending_unacked(), 2);
+
+        let again = acking.recv().unwrap().unwrap();
+        assert_eq!(&*again, b"a");
+        again.ack().unwrap();
+        let again = acking.recv().unwrap().unwrap();
+        assert_eq!(&*again, b"b");
+        again.ack().unwrap();
+        let c = acking.recv().unwrap().unwrap();
+        assert_eq!(&*c, b"c");
+        c.ack().unwrap();
+        assert!(acking.recv().unwrap().is_none());
+
+        // Once the producer is gone and everything's been delivered, that's the end.
+        drop(producer);
+        assert!(matches!(
+            acking.recv(),
+            Err(Error::Ours(BufError::Disconnected))
+        ));
+    }
+
+    #[test]
+    fn a_restart_rereads_what_wasnt_acked() {
+        let (mut producer, acking) = ring();
+        for i in 0..5u8 {
+            producer.write_msg(&[i; 10]).unwrap();
+        }
+        producer.finish().unwrap();
+        let deliveries: Vec<_> = (0..5).map(|_| acking.recv().unwrap().unwrap()).collect();
+        assert!(matches!(
+            acking.recv(),
+            Err(Error::Ours(BufError::EndOfStream))
+        ));
+        // 0 and 1 are done with, 3 is too but 2 isn't, and 4 was still in the works.
+        let mut deliveries = deliveries.into_iter();
+        for (i, delivery) in deliveries.by_ref().take(4).enumerate() {
+            if i != 2 {
+                delivery.ack().unwrap();
+            } else {
+                std::mem::forget(delivery);
+            }
+        }
+        std::mem::forget(deliveries);
+
+        // Picked up again from the oldest unacked one.
+        let acking = acking.into_inner().acking();
+        assert_eq!(acking.pending_unacked(), 0);
+        for i in 2..5 {
+            let delivery = acking.recv().unwrap().unwrap();
+            assert_eq!(&*delivery, [i; 10]);
+            delivery.ack().unwrap();
+        }
+        assert!(matches!(
+            acking.recv(),
+            Err(Error::Ours(BufError::EndOfStream))
+        ));
+        assert_eq!(acking.into_inner().len(), MSG_HEADER_LEN);
+    }
+
+    #[test]
+    fn the_window_caps_what_is_out() {
+        let (mut producer, acking) = ring();
+        for i in 0..WINDOW + 1 {
+            producer.write_msg(&[i as u8]).unwrap();
+        }
+        let out: Vec<_> = (0..WINDOW)
+            .map(|_| acking.recv().unwrap().unwrap())
+            .collect();
+        assert!(acking.recv().unwrap().is_none());
+        let mut out = out.into_iter();
+        out.next().unwrap().ack().unwrap();
+        assert_eq!(&*acking.recv().unwrap().unwrap(), [WINDOW as u8]);
+    }
+}
@@ -0,0 +1,457 @@
+//! Read cursors that fan one ring's bytes out to several readers without copying them.
+
+use super::{
+    index,
+    mirror::{MapOptions, Mirror},
+    BufError, Result, RingBuf,
+};
+use std::sync::{
+    atomic::{fence, AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex, Weak,
+};
+
+/// What a ring shares with the cursors `RingBuf::subscribe` made on it.
+pub(crate) struct Subscribers {
+    /// The ring's `bytes_written`, stored with `Release` once the bytes are in place.
+    written: AtomicU64,
+    /// The ring's `bytes_read`, stored before it writes over anything behind it.
+    reclaimed: AtomicU64,
+    /// Set once the ring stops feeding its cursors, e.g. after a `clear`.
+    detached: AtomicBool,
+    /// What each live cursor holds back.
+    cursors: Mutex<Vec<Weak<Hold>>>,
+}
+
+/// How far the ring can reclaim for one cursor.
+struct Hold {
+    /// Where the cursor has released up to.
+    released: AtomicU64,
+    /// Where the cursor's pin is, or `UNPINNED`.
+    pinned: AtomicU64,
+    /// How far behind `released` the pin may hold the ring back.
+    budget: AtomicU64,
+}
+
+const UNPINNED: u64 = u64::MAX;
+
+impl Hold {
+    /// The stream position the ring mustn't reclaim past for this cursor.
+    fn floor(&self) -> u64 {
+        // Pairs with the cursor's release stores, so its reads of the bytes are done before the
+        // ring writes over them.
+        let released = self.released.load(Ordering::Acquire);
+        match self.pinned.load(Ordering::Acquire) {
+            UNPINNED => released,
+            pinned => {
+                let budget = self.budget.load(Ordering::Relaxed);
+                pinned.max(released.saturating_sub(budget))
+            }
+        }
+    }
+}
+
+impl Subscribers {
+    pub(crate) fn new(written: u64, read: u64) -> Self {
+        Self {
+            written: AtomicU64::new(written),
+            reclaimed: AtomicU64::new(read),
+            detached: AtomicBool::new(false),
+            cursors: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Lets the cursors see everything up to stream position `written`.
+    pub(crate) fn publish(&self, written: u64) {
+        self.written.store(written, Ordering::Release);
+    }
+
+    /// Tells pinned cursors the ring may write over everything before stream position `read`.
+    /// Call before writing over any of it.
+    pub(crate) fn reclaimed(&self, read: u64) {
+        self.reclaimed.store(read, Ordering::Relaxed);
+        // Pairs with the fence in `ReadCursor::check_pin`: a cursor that read bytes we go on to
+        // write over sees the new position once it's done.
+        fence(Ordering::Release);
+    }
+
+    /// Leaves every cursor empty for good.
+    pub(crate) fn detach(&self) {
+        self.detached.store(true, Ordering::Release);
+    }
+
+    /// How far the ring can reclaim for every live cursor, pins included, or `None` once they've
+    /// all been dropped.
+    pub(crate) fn slowest(&self) -> Option<u64> {
+        let mut cursors = self.cursors.lock().unwrap_or_else(|e| e.into_inner());
+        cursors.retain(|cursor| cursor.strong_count() > 0);
+        cursors
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|hold| hold.floor())
+            .min()
+    }
+}
+
+/// One of several readers of a ring, made by `RingBuf::subscribe`. Each cursor sees every byte
+/// written after it subscribed (and whatever was pending then) at its own pace, and the ring only
+/// reuses space once the slowest cursor has read past it; until then writes fail with
+/// `BufError::NotEnoughSpace`. Dropping a cursor stops it holding anything back.
+///
+/// A cursor has its own mapping of the ring's memfd and can go to another thread. The bytes of
+/// the last `read` stay reserved until the cursor's next `read` or `advance`, since the returned
+/// slice still points at them. To keep more than that, say to go over a run of frames twice,
+/// `pin` the cursor first.
+pub struct ReadCursor {
+    mirror: Mirror,
+    subscribers: Arc<Subscribers>,
+    // `released` is how far the ring may reclaim: `head`, or the start of the last `read` until
+    // the next call. A pin can hold it further back.
+    hold: Arc<Hold>,
+    // Offset of the next unread byte in `0..capacity`.
+    offset: usize,
+    // Stream position of the next unread byte, counted like the ring's `bytes_written`.
+    head: u64,
+    // Stream position and offset of the pin, if any.
+    pin: Option<(u64, usize)>,
+}
+
+// SAFETY: The mapping isn't tied to the thread that made it. The cursor only reads bytes the ring
+// has published with a release store of `written`, and the ring doesn't write over them until the
+// cursor has released them with a release store of its own.
+unsafe impl Send for ReadCursor {}
+
+impl ReadCursor {
+    pub(crate) fn new(ring: &RingBuf, subscribers: Arc<Subscribers>) -> Result<Self> {
+        let source = ring.mirror.as_ref().ok_or(BufError::ZeroCapacity)?;
+        let options = MapOptions {
+            read_only: true,
+            ..MapOptions::default()
+        };
+        let mirror = Mirror::remap(source, options)?;
+        let hold = Arc::new(Hold {
+            released: AtomicU64::new(ring.bytes_read),
+            pinned: AtomicU64::new(UNPINNED),
+            budget: AtomicU64::new(0),
+        });
+        subscribers
+            .cursors
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Arc::downgrade(&hold));
+        Ok(Self {
+            mirror,
+            subscribers,
+            hold,
+            offset: ring.head,
+            head: ring.bytes_read,
+            pin: None,
+        })
+    }
+
+    /// Everything this cursor hasn't read yet, without consuming it.
+    pub fn peek(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.mirror.ptr.add(self.offset), self.len()) }
+    }
+
+    /// Reads the next `num_bytes`, moving only this cursor. Fails with
+    /// `BufError::NotEnoughData` if fewer have been written.
+    pub fn read(&mut self, num_bytes: usize) -> Result<&[u8]> {
+        self.release();
+        self.check_pending(num_bytes)?;
+        let start = self.offset;
+        self.step(num_bytes);
+        unsafe {
+            Ok(std::slice::from_raw_parts(
+                self.mirror.ptr.add(start),
+                num_bytes,
+            ))
+        }
+    }
+
+    /// Skips the next `num_bytes` without looking at them.
+    pub fn advance(&mut self, num_bytes: usize) -> Result<()> {
+        self.check_pending(num_bytes)?;
+        self.step(num_bytes);
+        self.release();
+        Ok(())
+    }
+
+    /// Keeps everything this cursor reads from here on in the ring, so `pinned` can hand it back
+    /// as one slice that stays the same however much more gets written. The ring holds back at
+    /// most `budget` bytes behind what the cursor has read for the pin; past that it may write
+    /// over the oldest of them, after which `pinned` and `unpin` fail with `BufError::Lagged`.
+    /// Pinning an already pinned cursor moves the pin up to where it's read to.
+    pub fn pin(&mut self, budget: usize) {
+        self.release();
+        let hold = &self.hold;
+        hold.budget.store(budget as u64, Ordering::Relaxed);
+        // Never behind what the ring already saw us release, so there's nothing to race with.
+        hold.pinned.store(self.head, Ordering::Release);
+        self.pin = Some((self.head, self.offset));
+    }
+
+    /// Everything read since `pin`, contiguous even across the wrap, or empty if the cursor isn't
+    /// pinned. Fails with `BufError::Lagged` once the ring is free to write over any of it.
+    pub fn pinned(&self) -> Result<&[u8]> {
+        let Some((pos, offset)) = self.pin else {
+            return Ok(&[]);
+        };
+        self.check_pin(pos)?;
+        let len = (self.head - pos) as usize;
+        unsafe { Ok(std::slice::from_raw_parts(self.mirror.ptr.add(offset), len)) }
+    }
+
+    /// Lets the ring have the pinned bytes back. Fails with `BufError::Lagged` if it was already
+    /// free to write over them, so anything read from them since `pin` may be wrong; the cursor is
+    /// unpinned either way.
+    pub fn unpin(&mut self) -> Result<()> {
+        let Some((pos, _)) = self.pin.take() else {
+            return Ok(());
+        };
+        let checked = self.check_pin(pos);
+        // Release, so we're done reading the pinned bytes before the ring may reuse them.
+        self.hold.pinned.store(UNPINNED, Ordering::Release);
+        checked
+    }
+
+    /// Fails with `BufError::Lagged` if the ring may have written over anything from stream
+    /// position `pos` on.
+    fn check_pin(&self, pos: u64) -> Result<()> {
+        // Pairs with the fence in `Subscribers::reclaimed`: if any of the bytes we read had been
+        // written over, we see that the ring reclaimed them.
+        fence(Ordering::Acquire);
+        let subscribers = &self.subscribers;
+        if pos < subscribers.reclaimed.load(Ordering::Relaxed)
+            || self.head - pos > self.capacity() as u64
+            || (pos < self.head && subscribers.detached.load(Ordering::Acquire))
+        {
+            return Err(BufError::Lagged.into());
+        }
+        Ok(())
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.mirror.size
+    }
+
+    /// How many bytes this cursor has yet to read. Always zero once the ring has detached its
+    /// cursors; see `RingBuf::subscribe`.
+    pub fn len(&self) -> usize {
+        if self.subscribers.detached.load(Ordering::Acquire) {
+            return 0;
+        }
+        (self.subscribers.written.load(Ordering::Acquire) - self.head) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn check_pending(&self, n: usize) -> Result<()> {
+        let len = self.len();
+        if n > len {
+            return Err(BufError::NotEnoughData {
+                requested: n,
+                available: len,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, n: usize) {
+        self.offset = index::advance(self.offset, n, self.mirror.size);
+        self.head += n as u64;
+    }
+
+    /// Hands everything before `head` back to the ring, but for what a pin holds.
+    fn release(&self) {
+        self.hold.released.store(self.head, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Error, LeakCheck};
+    use super::*;
+
+    /// Bytes `from..from + n` of a stream where byte `i` is `i as u8`.
+    fn stream(from: usize, n: usize) -> Vec<u8> {
+        (from..from + n).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn cursors_at_different_rates_across_wraps() {
+        let leaks = LeakCheck::new();
+        let mut ring = RingBuf::new(1).unwrap();
+        let cap = ring.capacity();
+        let mut fast = ring.subscribe().unwrap();
+        let mut slow = ring.subscribe().unwrap();
+        let (mut written, mut fast_read, mut slow_read) = (0, 0, 0);
+        // Where the slow cursor's last `read` started, which it's still holding on to.
+        let mut slow_held = 0;
+
+        for round in 0..200 {
+            // Write as much as the slow cursor leaves room for, in odd-sized chunks.
+            loop {
+                let chunk = stream(written, 97);
+                match ring.write(&chunk) {
+                    Ok(()) => written += chunk.len(),
+                    Err(Error::Ours(BufError::NotEnoughSpace { available, .. })) => {
+                        assert_eq!(available, cap - (written - slow_held));
+                        break;
+                    }
+                    Err(e) => panic!("{e}"),
+                }
+            }
+            assert!(written - slow_held > cap - 97);
+
+            let n = fast.len();
+            assert_eq!(n, written - fast_read);
+            assert_eq!(fast.peek(), stream(fast_read, n));
+            fast.advance(n).unwrap();
+            fast_read += n;
+
+            // The slow one reads a little every third round, so the writer laps the ring many
+            // times over without ever changing a byte the slow one hadn't got to yet.
+            if round % 3 == 0 {
+                let n = slow.len().min(cap / 4);
+                assert_eq!(slow.read(n).unwrap(), stream(slow_read, n));
+                slow_held = slow_read;
+                slow_read += n;
+            }
+        }
+        assert!(written > 10 * cap, "only {written} bytes through");
+        assert!(matches!(
+            slow.read(slow.len() + 1),
+            Err(Error::Ours(BufError::NotEnoughData { .. }))
+        ));
+        drop((fast, slow, ring));
+        leaks.finish().expect("Cursors unmap their own views.");
+    }
+
+    #[test]
+    fn dropped_cursors_stop_holding_back_space() {
+        let mut ring = RingBuf::new(1).unwrap();
+        let cap = ring.capacity();
+        let mut reader = ring.subscribe().unwrap();
+        let idle = ring.subscribe().unwrap();
+        ring.write(&vec![1; cap]).unwrap();
+        reader.advance(cap).unwrap();
+        ring.write(b"x")
+            .expect_err("The idle cursor hasn't read anything.");
+
+        drop(idle);
+        ring.write(b"x")
+            .expect("Only the reader is left, and it's read everything.");
+        assert_eq!(reader.read(1).unwrap(), b"x");
+
+        // With no cursors left it's an ordinary ring again, holding whatever it hadn't reclaimed.
+        drop(reader);
+        assert_eq!(ring.read(ring.len()).unwrap(), b"x");
+
+        // Clearing detaches any cursors, and they stop holding anything back.
+        let cursor = ring.subscribe().unwrap();
+        ring.write(b"gone").unwrap();
+        assert_eq!(cursor.peek(), b"gone");
+        ring.clear();
+        assert!(cursor.is_empty());
+        ring.write(&vec![2; cap]).unwrap();
+        assert!(cursor.is_empty());
+    }
+
+    #[test]
+    fn a_pinned_view_holds_while_the_ring_wraps() {
+        let mut ring = RingBuf::new(1).unwrap();
+        let cap = ring.capacity();
+        let mut cursor = ring.subscribe().unwrap();
+        let (mut written, mut read) = (0, 0);
+
+        for _ in 0..20 {
+            cursor.pin(cap);
+            let start = read;
+            // A little at a time, with the ring filling whatever the pin leaves it in between.
+            for _ in 0..8 {
+                while ring.write(&stream(written, 97)).is_ok() {
+                    written += 97;
+                }
+                let n = cursor.len().min(cap / 16);
+                assert_eq!(cursor.read(n).unwrap(), stream(read, n));
+                read += n;
+                assert_eq!(cursor.pinned().unwrap(), stream(start, read - start));
+            }
+            cursor.unpin().expect("The budget covers the whole ring.");
+        }
+        assert!(written > 5 * cap, "only {written} bytes through");
+    }
+
+    #[test]
+    fn pins_past_their_budget_lag() {
+        let mut ring = RingBuf::new(1).unwrap();
+        let cap = ring.capacity();
+        let mut cursor = ring.subscribe().unwrap();
+        ring.write(&stream(0, cap)).unwrap();
+        cursor.pin(100);
+        cursor.advance(1000).unwrap();
+        assert_eq!(cursor.pinned().unwrap(), stream(0, 1000));
+
+        // The pin only holds on to the last 100 bytes the cursor read.
+        ring.write(&[0; 901])
+            .expect_err("The cursor read 1000 bytes, less the budget.");
+        ring.write(&[0; 900]).unwrap();
+        assert!(matches!(
+            cursor.pinned(),
+            Err(Error::Ours(BufError::Lagged))
+        ));
+        assert!(matches!(cursor.unpin(), Err(Error::Ours(BufError::Lagged))));
+
+        // Unpinned either way, and the unread bytes were never at risk.
+        assert!(cursor.pinned().unwrap().is_empty());
+        cursor.unpin().unwrap();
+        assert_eq!(cursor.read(cap - 1000).unwrap(), stream(1000, cap - 1000));
+    }
+
+    #[test]
+    fn unpinning_gives_the_space_back() {
+        let mut ring = RingBuf::new(1).unwrap();
+        let cap = ring.capacity();
+        let mut cursor = ring.subscribe().unwrap();
+        ring.write(&vec![1; cap]).unwrap();
+        cursor.pin(cap);
+        cursor.advance(cap).unwrap();
+        ring.write(b"x")
+            .expect_err("The pin holds everything the cursor read.");
+
+        cursor.unpin().unwrap();
+        ring.write(&vec![2; cap]).expect("All of it is free again.");
+        assert_eq!(cursor.read(cap).unwrap(), vec![2; cap]);
+    }
+
+    #[test]
+    fn a_cursor_on_another_thread() {
+        let mut ring = RingBuf::new(1).unwrap();
+        let mut cursor = ring.subscribe().unwrap();
+        let total = 10 * ring.capacity();
+        let reader = std::thread::spawn(move || {
+            let mut seen = 0;
+            while seen < total {
+                let n = cursor.len().min(4096);
+                if n == 0 {
+                    std::thread::yield_now();
+                }
+                assert_eq!(cursor.read(n).unwrap(), stream(seen, n));
+                seen += n;
+            }
+        });
+        let mut written = 0;
+        while written < total {
+            let chunk = stream(written, 300.min(total - written));
+            if ring.write(&chunk).is_ok() {
+                written += chunk.len();
+            } else {
+                std::thread::yield_now();
+            }
+        }
+        reader.join().expect("Reader shouldn't panic.");
+    }
+}
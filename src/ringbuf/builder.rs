@@ -0,0 +1,617 @@
+//! Construction options for `RingBuf` that don't fit in `RingBuf::new`.
+
+use super::{
+    mirror::{BackendKind, MapOptions, Mirror},
+    page_size, shmem, BufError, Result, RingBuf,
+};
+use std::num::NonZeroUsize;
+
+/// Configures a `RingBuf` before mapping it. Start from `RingBuf::builder()`.
+#[derive(Debug, Clone)]
+pub struct RingBufBuilder {
+    capacity: Capacity,
+    lazy: bool,
+    typed_checks: bool,
+    overwrite: bool,
+    // Checked and put into `options` by `build`.
+    name: Option<String>,
+    options: MapOptions,
+}
+
+/// Whichever of `pages` and `capacity_bytes` was called last.
+#[derive(Debug, Clone, Copy)]
+enum Capacity {
+    Pages(usize),
+    Bytes(usize),
+}
+
+impl Default for RingBufBuilder {
+    fn default() -> Self {
+        Self {
+            capacity: Capacity::Pages(1),
+            lazy: false,
+            typed_checks: cfg!(feature = "typed-checks"),
+            overwrite: false,
+            name: None,
+            options: MapOptions::default(),
+        }
+    }
+}
+
+impl RingBufBuilder {
+    /// Minimum capacity in pages, as in `RingBuf::new`, rounded up to 2 MiB for huge pages.
+    /// Defaults to one.
+    pub fn pages(mut self, num_pages: usize) -> Self {
+        self.capacity = Capacity::Pages(num_pages);
+        self
+    }
+
+    /// The exact capacity in bytes, instead of `pages`. Nothing gets rounded: `build` fails with
+    /// `BufError::UnalignedCapacity` unless it's a multiple of the page size, or of 2 MiB with
+    /// huge pages of either kind.
+    pub fn capacity_bytes(mut self, num_bytes: usize) -> Self {
+        self.capacity = Capacity::Bytes(num_bytes);
+        self
+    }
+
+    /// `true` rounds the capacity up to a multiple of 2 MiB, aligns the mapping to match and asks
+    /// the kernel to back it with transparent huge pages, which cuts TLB misses on big rings.
+    /// `false` asks the kernel never to. Either way it's only a hint: kernels without THP
+    /// support quietly hand out normal pages. See `Stats::thp_backed` for what actually happened.
+    pub fn transparent_huge_pages(mut self, enable: bool) -> Self {
+        self.options.thp = Some(enable);
+        self
+    }
+
+    /// Backs the ring with 2 MiB hugetlb pages from a `MFD_HUGETLB` memfd, rounding the capacity
+    /// up to match. Unlike `transparent_huge_pages` this isn't a hint: they come out of the pool
+    /// reserved in `/proc/sys/vm/nr_hugepages`, and if that's empty (or there's no memfd or
+    /// hugetlb support) building fails with `BufError::HugePagesUnavailable`, unless
+    /// `huge_pages_fallback` allows normal pages instead. Heap rings can't have them either.
+    pub fn huge_pages(mut self, enable: bool) -> Self {
+        self.options.fd.hugetlb = enable;
+        self
+    }
+
+    /// With `huge_pages`, builds the ring from normal pages when there are no huge ones rather
+    /// than failing, keeping the rounded-up capacity. `RingBuf::huge_pages` says which it got.
+    pub fn huge_pages_fallback(mut self, allow: bool) -> Self {
+        self.options.hugetlb_fallback = allow;
+        self
+    }
+
+    /// Locks both views into memory with `mlock` and faults every page in as soon as they're
+    /// mapped, so a real-time thread touching the ring never takes a page fault. They're unlocked
+    /// again on drop, and the new mapping from `RingBuf::grow` or `shrink_to` is locked too.
+    ///
+    /// Going over `RLIMIT_MEMLOCK` fails with `BufError::MemoryLockLimit`, so callers can decide
+    /// to carry on with `lock_memory(false)` instead.
+    pub fn lock_memory(mut self, enable: bool) -> Self {
+        self.options.mlock = enable;
+        self
+    }
+
+    /// Scrubs secrets out of the ring as soon as they're no longer needed: bytes consumed by a
+    /// copying read (`split_to`, `split_off_pending`, `read_typed`, `drain_to`, `consume`, ...)
+    /// are zeroed right away, and the whole mapping is zeroed before it's unmapped on drop or
+    /// shrink.
+    ///
+    /// Borrowed reads (`read`, `read_mut`) can't zero what they just handed out, so
+    /// their bytes are zeroed at the start of the next call that writes to or reads from the
+    /// ring instead. Don't hold on to secrets by keeping such a borrow around.
+    pub fn zeroize(mut self, enable: bool) -> Self {
+        self.options.zeroize = enable;
+        self
+    }
+
+    /// Fills free space with a poison pattern, refilling consumed bytes the same way zeroize
+    /// mode zeroes them, so `RingBuf::check_invariants` can catch stray writes into it. Costs a
+    /// pass over the consumed bytes on every read, plus a scan of the whole free space per
+    /// operation when the invariant checks are on. Meant for tests.
+    pub fn debug_fill(mut self, enable: bool) -> Self {
+        self.options.debug_fill = enable;
+        self
+    }
+
+    /// Builds the mapping with `backend` rather than the cheapest sequence the kernel accepts,
+    /// failing instead of falling back. Mostly for tests and benchmarks. Huge-page rings always
+    /// use `BackendKind::Reserve` or `BackendKind::Heap`, since `DoubleMap` can't align the
+    /// mapping.
+    pub fn backend(mut self, backend: BackendKind) -> Self {
+        self.options.backend = Some(backend);
+        self
+    }
+
+    /// Skips the memfd and mappings until the ring is first written to (or `ensure_mapped` is
+    /// called), for when most of the rings you preallocate never get used. Until then the ring
+    /// reports its configured `capacity()` but is empty, and building can't fail for OS reasons;
+    /// those errors come out of the first write instead.
+    pub fn lazy(mut self, enable: bool) -> Self {
+        self.lazy = enable;
+        self
+    }
+
+    /// Tags every `write_typed` value with a hash of its type's name and size, and makes
+    /// `read_typed` check the tag, to catch values being read back as the wrong type. This
+    /// changes what goes into the ring, so both ends have to agree. On by default with the
+    /// `typed-checks` feature.
+    pub fn typed_checks(mut self, enable: bool) -> Self {
+        self.typed_checks = enable;
+        self
+    }
+
+    /// Makes `write` (and `io::Write::write`) evict the oldest pending bytes when there isn't
+    /// room, like `RingBuf::write_overwriting`, rather than fail. For rings that only need to keep the
+    /// latest data, such as logs. Off by default. `write_msg` and `write_msg_checked` evict whole
+    /// messages instead, like `RingBuf::write_msg_evicting`. `write_typed` and `write_slice` still
+    /// fail rather than evict, since evicting bytes could cut a value in half.
+    pub fn overwrite(mut self, enable: bool) -> Self {
+        self.overwrite = enable;
+        self
+    }
+
+    /// What the memfd is called, to tell rings apart in `/proc/<pid>/fd` and
+    /// `/proc/<pid>/maps` (as `/memfd:<name>`). Defaults to `ringbuf`. `build` fails with
+    /// `BufError::InvalidName` if the name has a NUL in it or is over 249 bytes. The POSIX shm
+    /// fallback ignores it.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Whether the fd gets closed in child processes at `exec`. On by default, so rings don't
+    /// leak into every program you spawn; turn it off to hand one down deliberately.
+    pub fn cloexec(mut self, enable: bool) -> Self {
+        self.options.fd.cloexec = enable;
+        self
+    }
+
+    /// Seals the memfd's size (`F_SEAL_GROW | F_SEAL_SHRINK | F_SEAL_SEAL`) once it's set, so
+    /// nothing holding the fd can truncate the memory out from under the mapping. Only memfds
+    /// can be sealed, so this does nothing if `RingBuf::fd_source` says a fallback was used.
+    pub fn seal(mut self, enable: bool) -> Self {
+        self.options.fd.seal = enable;
+        self
+    }
+
+    /// Maps a ring as configured. Combinations that could never work fail before anything is
+    /// created: a `capacity_bytes` that isn't a multiple of the granularity, or huge pages with
+    /// `BackendKind::DoubleMap` (`BufError::IncompatibleOptions`). The builder isn't used up, so
+    /// it can stamp out any number of rings alike.
+    pub fn build(&self) -> Result<RingBuf> {
+        let mut options = self.options.clone();
+        if options.granularity()? != page_size()? && options.backend == Some(BackendKind::DoubleMap)
+        {
+            return Err(
+                BufError::IncompatibleOptions("huge pages with the DoubleMap backend").into(),
+            );
+        }
+        if let Some(name) = &self.name {
+            options.fd.name = shmem::fd_name(name)?;
+        }
+        let granularity = options.granularity()?;
+        let size = match self.capacity {
+            Capacity::Pages(pages) => pages
+                .checked_mul(page_size()?)
+                .and_then(|size| size.checked_next_multiple_of(granularity))
+                .ok_or(BufError::CapacityOverflow)?,
+            Capacity::Bytes(bytes) if bytes % granularity != 0 => {
+                return Err(BufError::UnalignedCapacity {
+                    capacity: bytes,
+                    granularity,
+                }
+                .into())
+            }
+            Capacity::Bytes(bytes) => bytes,
+        };
+        let size = NonZeroUsize::new(size).ok_or(BufError::ZeroCapacity)?;
+        let mut ring = if self.lazy {
+            RingBuf::lazy(size, options)
+        } else {
+            RingBuf::from_mirror(Mirror::with_options(size, options)?)
+        };
+        ring.typed_checks = self.typed_checks;
+        ring.overwrite = self.overwrite;
+        Ok(ring)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(mapped)]
+    use super::super::{clear_injected_failures, inject_failure, shmem, OsOp};
+    use super::super::{mirror::THP_SIZE, LeakCheck, Live};
+    use super::*;
+    #[cfg(mapped)]
+    use nix::errno::Errno;
+    use std::path::Path;
+
+    #[test]
+    fn defaults_match_new() {
+        let page = page_size().unwrap();
+        let _leaks = LeakCheck::new();
+        let ring = RingBuf::builder().pages(3).build().unwrap();
+        assert_eq!(ring.capacity(), 3 * page);
+        assert!(!ring.stats().thp_backed);
+        assert!(matches!(
+            RingBuf::builder().pages(0).build(),
+            Err(crate::ringbuf::Error::Ours(BufError::ZeroCapacity))
+        ));
+    }
+
+    #[test]
+    #[cfg_attr(
+        any(feature = "portable", not(mapped)),
+        ignore = "needs a real mapping for huge pages"
+    )]
+    fn transparent_huge_pages() {
+        let _leaks = LeakCheck::new();
+        let mut ring = RingBuf::builder()
+            .pages(1)
+            .transparent_huge_pages(true)
+            .build()
+            .unwrap();
+        assert_eq!(ring.capacity(), THP_SIZE);
+        let mirror = ring.mirror.as_ref().unwrap();
+        assert_eq!(mirror.ptr as usize % THP_SIZE, 0);
+        if Path::new("/sys/kernel/mm/transparent_hugepage").exists() {
+            assert!(mirror.thp_advised);
+        }
+
+        // Round-trip across the wrap.
+        let chunk: Vec<u8> = (0..=255).cycle().take(THP_SIZE / 2 + 7).collect();
+        for _ in 0..3 {
+            ring.write(&chunk).unwrap();
+            assert_eq!(ring.read(chunk.len()).unwrap(), &chunk[..]);
+        }
+        // Whether the kernel actually used huge pages depends on its settings; just make sure
+        // asking doesn't blow up.
+        let _ = ring.stats().thp_backed;
+
+        ring.shrink_to(0).unwrap();
+        assert_eq!(ring.capacity(), THP_SIZE);
+    }
+
+    #[test]
+    fn hugetlb_pages_or_a_clear_error() {
+        let _leaks = LeakCheck::new();
+        match RingBuf::builder().pages(1).huge_pages(true).build() {
+            Ok(mut ring) => {
+                assert!(ring.huge_pages());
+                assert_eq!(ring.capacity(), THP_SIZE);
+                assert_eq!(ring.backend_kind(), Some(BackendKind::Reserve));
+                let chunk: Vec<u8> = (0..=255).cycle().take(THP_SIZE / 2 + 7).collect();
+                for _ in 0..3 {
+                    ring.write(&chunk).unwrap();
+                    assert_eq!(ring.read(chunk.len()).unwrap(), &chunk[..]);
+                }
+            }
+            // No pages reserved, which is how most machines are set up.
+            Err(crate::ringbuf::Error::Ours(BufError::HugePagesUnavailable)) => {}
+            Err(e) => panic!("{e}"),
+        }
+
+        let mut ring = RingBuf::builder()
+            .pages(1)
+            .huge_pages(true)
+            .huge_pages_fallback(true)
+            .build()
+            .unwrap();
+        assert_eq!(ring.capacity(), THP_SIZE);
+        ring.write(b"either way").unwrap();
+        assert_eq!(ring.read(10).unwrap(), b"either way");
+    }
+
+    #[test]
+    #[cfg(mapped)]
+    #[cfg_attr(
+        feature = "portable",
+        ignore = "heap rings never have huge pages to lose"
+    )]
+    fn hugetlb_fallback_when_mapping_fails() {
+        let _leaks = LeakCheck::new();
+        // What `memfd_create` says on a kernel without hugetlbfs.
+        let no_pages = || inject_failure(OsOp::MemfdCreate, Errno::EINVAL, 0);
+        no_pages();
+        assert!(matches!(
+            RingBuf::builder().huge_pages(true).build(),
+            Err(crate::ringbuf::Error::Ours(BufError::HugePagesUnavailable))
+        ));
+        no_pages();
+        let ring = RingBuf::builder()
+            .huge_pages(true)
+            .huge_pages_fallback(true)
+            .build()
+            .unwrap();
+        assert!(!ring.huge_pages());
+        assert_eq!(ring.capacity(), THP_SIZE);
+
+        // Only ENOMEM and EINVAL mean there are no huge pages.
+        inject_failure(OsOp::MemfdCreate, Errno::EMFILE, 0);
+        assert!(matches!(
+            RingBuf::builder()
+                .huge_pages(true)
+                .huge_pages_fallback(true)
+                .build(),
+            Err(crate::ringbuf::Error::Nix(Errno::EMFILE))
+        ));
+    }
+
+    #[test]
+    #[cfg_attr(not(mapped), ignore = "heap-only builds can't lock memory")]
+    fn locked_into_memory() {
+        let _leaks = LeakCheck::new();
+        let mut ring = RingBuf::builder().lock_memory(true).build().unwrap();
+        assert!(ring.memory_locked());
+        ring.write(b"no faults").unwrap();
+        ring.grow(2).unwrap();
+        assert!(ring.memory_locked());
+        assert_eq!(ring.read(9).unwrap(), b"no faults");
+
+        let mut ring = RingBuf::new(1).unwrap();
+        assert!(!ring.memory_locked());
+        ring.lock_memory().unwrap();
+        assert!(ring.memory_locked());
+        ring.lock_memory().unwrap();
+    }
+
+    #[test]
+    #[cfg(mapped)]
+    fn lock_limit_is_its_own_error() {
+        let _leaks = LeakCheck::new();
+        // What `mlock` says over `RLIMIT_MEMLOCK`. Lowering the real limit would hit every other
+        // test in the process, and root isn't held to it anyway.
+        inject_failure(OsOp::Mlock, Errno::ENOMEM, 0);
+        assert!(matches!(
+            RingBuf::builder().pages(1 << 16).lock_memory(true).build(),
+            Err(crate::ringbuf::Error::Ours(BufError::MemoryLockLimit))
+        ));
+        let mut ring = RingBuf::new(1).unwrap();
+        inject_failure(OsOp::Mlock, Errno::EPERM, 0);
+        assert!(matches!(
+            ring.lock_memory(),
+            Err(crate::ringbuf::Error::Ours(BufError::MemoryLockLimit))
+        ));
+        assert!(!ring.memory_locked());
+        ring.write(b"still works").unwrap();
+    }
+
+    #[test]
+    fn huge_pages_opt_out() {
+        let page = page_size().unwrap();
+        let _leaks = LeakCheck::new();
+        let mut ring = RingBuf::builder()
+            .pages(2)
+            .transparent_huge_pages(false)
+            .build()
+            .unwrap();
+        assert_eq!(ring.capacity(), 2 * page);
+        ring.write(b"hello").unwrap();
+        assert_eq!(ring.read(5).unwrap(), b"hello");
+        assert!(!ring.stats().thp_backed);
+    }
+
+    /// The `len` bytes at `offset` straight out of the mapping.
+    fn raw_bytes(ring: &RingBuf, offset: usize, len: usize) -> Vec<u8> {
+        unsafe { std::slice::from_raw_parts(ring.data_ptr().add(offset), len).to_vec() }
+    }
+
+    #[test]
+    fn zeroize_copying_reads() {
+        let page = page_size().unwrap();
+        let _leaks = LeakCheck::new();
+        let mut ring = RingBuf::builder()
+            .zeroize(true)
+            .debug_fill(false)
+            .build()
+            .unwrap();
+        // Park the head near the end so the secret wraps.
+        ring.write(&vec![1; page - 4]).unwrap();
+        ring.consume(page - 4).unwrap();
+        assert_eq!(raw_bytes(&ring, 0, page), vec![0; page]);
+
+        let at = ring.write_offset();
+        ring.write(b"hunter2hunter2").unwrap();
+        assert_eq!(ring.split_to(7).unwrap(), b"hunter2");
+        assert_eq!(raw_bytes(&ring, at, 7), vec![0; 7]);
+        assert_eq!(ring.split_off_pending(), b"hunter2");
+        assert_eq!(raw_bytes(&ring, 0, page), vec![0; page]);
+
+        let mut sink = Vec::new();
+        ring.write(b"swordfish").unwrap();
+        ring.drain_to(&mut sink, 100).unwrap();
+        assert_eq!(sink, b"swordfish");
+        assert_eq!(raw_bytes(&ring, 0, page), vec![0; page]);
+    }
+
+    #[test]
+    fn zeroize_borrowed_reads_on_next_call() {
+        let _leaks = LeakCheck::new();
+        let mut ring = RingBuf::builder()
+            .zeroize(true)
+            .debug_fill(false)
+            .build()
+            .unwrap();
+        let at = ring.read_offset();
+        ring.write(b"secret").unwrap();
+        assert_eq!(ring.read(6).unwrap(), b"secret");
+        // Still there: the borrow could have been alive until just now.
+        assert_eq!(raw_bytes(&ring, at, 6), b"secret");
+        ring.write(b"x").unwrap();
+        assert_eq!(raw_bytes(&ring, at, 6), vec![0; 6]);
+        assert_eq!(ring.read(1).unwrap(), b"x");
+    }
+
+    #[test]
+    #[cfg_attr(
+        any(feature = "portable", not(mapped)),
+        ignore = "heap views are separate memory"
+    )]
+    fn zeroize_through_the_second_view_and_on_drop() {
+        let page = page_size().unwrap();
+        let mut ring = RingBuf::builder()
+            .zeroize(true)
+            .debug_fill(false)
+            .build()
+            .unwrap();
+        let at = ring.write_offset();
+        ring.write(b"token:unread").unwrap();
+        ring.consume(6).unwrap();
+        // Gone from the second view too, since it's the same pages, but what's still unread
+        // is left alone.
+        assert_eq!(raw_bytes(&ring, page + at, 6), vec![0; 6]);
+        assert_eq!(raw_bytes(&ring, page + at + 6, 6), b"unread");
+
+        // A reader with its own mapping of the memfd outlives the ring, and sees the pending
+        // bytes scrubbed when it goes.
+        let mut tap = ring.try_clone_reader().unwrap();
+        tap.refresh(&ring);
+        assert_eq!(tap.peek(), b"unread");
+        drop(ring);
+        assert_eq!(tap.peek(), vec![0; 6]);
+    }
+
+    #[test]
+    fn no_zeroize_by_default() {
+        let mut ring = RingBuf::builder().debug_fill(false).build().unwrap();
+        ring.write(b"plain").unwrap();
+        ring.consume(5).unwrap();
+        assert_eq!(raw_bytes(&ring, 0, 5), b"plain");
+    }
+
+    #[test]
+    fn wipe() {
+        let page = page_size().unwrap();
+        let mut ring = RingBuf::builder().debug_fill(false).build().unwrap();
+        ring.write(b"old").unwrap();
+        ring.consume(3).unwrap();
+        ring.write(b"pending").unwrap();
+        ring.wipe();
+        assert!(ring.is_empty());
+        assert_eq!(raw_bytes(&ring, 0, page), vec![0; page]);
+        ring.write(b"after").unwrap();
+        assert_eq!(ring.read(5).unwrap(), b"after");
+
+        RingBuf::default().wipe();
+    }
+
+    #[test]
+    #[cfg_attr(
+        any(feature = "portable", not(mapped)),
+        ignore = "counts mappings and memfds"
+    )]
+    fn lazy_rings_map_nothing_until_used() {
+        let page = page_size().unwrap();
+        let leaks = LeakCheck::new();
+        let mut rings: Vec<_> = (0..200)
+            .map(|_| RingBuf::builder().pages(2).lazy(true).build().unwrap())
+            .collect();
+        assert_eq!(leaks.live(), Live::default());
+        assert_eq!(rings[0].capacity(), 2 * page);
+        assert!(rings[0].is_empty());
+        assert_eq!(rings[0].read(0).unwrap(), b"");
+        assert!(rings[0].read(1).is_err());
+        assert_eq!(rings[0].backend_kind(), None);
+
+        rings[0].write(b"first").unwrap();
+        assert_eq!(
+            leaks.live(),
+            Live {
+                mappings: 1,
+                memfds: 1
+            }
+        );
+        rings[1].ensure_mapped().unwrap();
+        rings[1].ensure_mapped().unwrap();
+        assert_eq!(leaks.live().memfds, 2);
+
+        // And they work like any other ring once mapped.
+        let ring = &mut rings[0];
+        ring.write(&vec![1; 2 * page - 5]).unwrap();
+        assert!(ring.write(b"x").is_err());
+        assert_eq!(ring.read(5).unwrap(), b"first");
+        ring.consume(2 * page - 5).unwrap();
+        ring.write(b"wrapped").unwrap();
+        assert_eq!(ring.read(7).unwrap(), b"wrapped");
+    }
+
+    #[test]
+    #[cfg(mapped)]
+    #[cfg_attr(feature = "portable", ignore = "injects OS call failures")]
+    fn lazy_ring_reports_mapping_errors_on_first_write() {
+        let page = page_size().unwrap();
+        let _leaks = LeakCheck::new();
+        let mut ring = RingBuf::builder().lazy(true).build().unwrap();
+        inject_failure(shmem::CREATE_OP, Errno::EMFILE, 0);
+        assert!(matches!(
+            ring.write(b"data"),
+            Err(crate::ringbuf::Error::Nix(Errno::EMFILE))
+        ));
+        assert_eq!(ring.capacity(), page);
+        ring.write(b"data").unwrap();
+        assert_eq!(ring.read(4).unwrap(), b"data");
+    }
+
+    #[test]
+    fn invalid_combinations_fail_before_anything_is_made() {
+        let page = page_size().unwrap();
+        let leaks = LeakCheck::new();
+        // Were anything to get as far as making a memfd, this is the error it would see.
+        #[cfg(mapped)]
+        inject_failure(shmem::CREATE_OP, Errno::EMFILE, 0);
+
+        assert!(matches!(
+            RingBuf::builder().capacity_bytes(page + 1).build(),
+            Err(crate::ringbuf::Error::Ours(BufError::UnalignedCapacity { capacity, granularity }))
+                if capacity == page + 1 && granularity == page
+        ));
+        assert!(matches!(
+            RingBuf::builder().huge_pages(true).capacity_bytes(4 * page).build(),
+            Err(crate::ringbuf::Error::Ours(BufError::UnalignedCapacity { granularity, .. }))
+                if granularity == THP_SIZE
+        ));
+        let err = RingBuf::builder()
+            .transparent_huge_pages(true)
+            .backend(BackendKind::DoubleMap)
+            .build()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            crate::ringbuf::Error::Ours(BufError::IncompatibleOptions(_))
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Can't combine huge pages with the DoubleMap backend!"
+        );
+        assert!(matches!(
+            RingBuf::builder().capacity_bytes(0).build(),
+            Err(crate::ringbuf::Error::Ours(BufError::ZeroCapacity))
+        ));
+
+        #[cfg(mapped)]
+        clear_injected_failures();
+        leaks.finish().expect("Nothing was made to leak.");
+    }
+
+    #[test]
+    fn one_builder_stamps_out_many_rings() {
+        let page = page_size().unwrap();
+        let _leaks = LeakCheck::new();
+        let builder = RingBuf::builder()
+            .capacity_bytes(2 * page)
+            .name("stamped")
+            .overwrite(true);
+        let mut rings: Vec<RingBuf> = (0..3).map(|_| builder.build().unwrap()).collect();
+        rings.push(builder.clone().pages(1).build().unwrap());
+        assert!(rings[..3].iter().all(|ring| ring.capacity() == 2 * page));
+        assert_eq!(rings[3].capacity(), page);
+
+        // Overwrite mode: `write` keeps the latest bytes rather than failing.
+        let ring = &mut rings[3];
+        ring.write(&vec![1; page - 2]).unwrap();
+        ring.write(b"tail").unwrap();
+        assert_eq!(ring.len(), page);
+        ring.consume(page - 4).unwrap();
+        assert_eq!(ring.read(4).unwrap(), b"tail");
+    }
+}
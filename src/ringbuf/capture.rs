@@ -0,0 +1,227 @@
+//! A child process's stdout streamed into a ring, for supervisors that tail their children.
+
+use super::{BufError, Error, FullPolicy, RingBuf, SharedRingBuf, SharedWriter, MSG_HEADER_LEN};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::thread::{self, JoinHandle};
+
+/// How `SharedRingBuf::spawn_capture` cuts the output into messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// One message per line, newline included. A last line without one still gets its own.
+    Lines,
+    /// One message per read from the pipe, however much that brought in.
+    Chunks,
+}
+
+/// The thread moving a child's output into the ring. See `SharedRingBuf::spawn_capture`.
+pub struct CaptureHandle {
+    thread: JoinHandle<io::Result<u64>>,
+    writer: SharedWriter,
+}
+
+impl CaptureHandle {
+    /// Messages dropped for lack of room so far.
+    pub fn dropped(&self) -> u64 {
+        self.writer.dropped()
+    }
+
+    /// Whether the child has closed its stdout and the stream's end is marked in the ring.
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Waits for `is_finished`, then returns how many bytes the child wrote, dropped ones
+    /// included, or the error that stopped the capture early.
+    pub fn join(self) -> io::Result<u64> {
+        self.thread
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    }
+}
+
+impl SharedRingBuf {
+    /// Spawns `cmd` with its stdout piped into this ring, one message (as from
+    /// `RingBuf::write_msg`) per line or per read, by a background thread. Anything too long for
+    /// one message is split across several. `when_full` decides between holding up the pipe, and
+    /// so the child, until there's room, and dropping what doesn't fit.
+    ///
+    /// Once the child closes its stdout, `SharedRingBuf::write_msg_end` marks the end of the
+    /// stream, so `recv_msg` tells a finished capture from one that's just quiet. The marker is
+    /// never dropped, so the thread waits for room for it.
+    /// Nothing else about `cmd` is changed, so stderr goes wherever it was going.
+    pub fn spawn_capture(
+        &self,
+        cmd: &mut Command,
+        framing: Framing,
+        when_full: FullPolicy,
+    ) -> io::Result<(Child, CaptureHandle)> {
+        let max = self
+            .with(|ring| ring.capacity())?
+            .saturating_sub(MSG_HEADER_LEN);
+        if max == 0 {
+            return Err(Error::Ours(BufError::ZeroCapacity).into());
+        }
+        let mut child = cmd.stdout(Stdio::piped()).spawn()?;
+        let stdout = child.stdout.take().expect("Piped above.");
+        let writer = self.writer(when_full);
+        let (ring, pump_writer) = (self.clone(), writer.clone());
+        let thread = thread::spawn(move || {
+            let total = pump(stdout, &pump_writer, framing, max)?;
+            end_stream(&ring)?;
+            Ok(total)
+        });
+        Ok((child, CaptureHandle { thread, writer }))
+    }
+}
+
+/// Moves everything `stdout` has into `writer`, `max` bytes a message at most.
+fn pump(
+    mut stdout: ChildStdout,
+    mut writer: &SharedWriter,
+    framing: Framing,
+    max: usize,
+) -> io::Result<u64> {
+    let mut total = 0;
+    match framing {
+        Framing::Lines => {
+            let mut stdout = BufReader::new(stdout);
+            let mut line = Vec::new();
+            while stdout.read_until(b'\n', &mut line)? > 0 {
+                for piece in line.chunks(max) {
+                    writer.write_all(piece)?;
+                }
+                total += line.len() as u64;
+                line.clear();
+            }
+        }
+        Framing::Chunks => {
+            let mut chunk = vec![0; max.min(64 * 1024)];
+            loop {
+                let n = match stdout.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                writer.write_all(&chunk[..n])?;
+                total += n as u64;
+            }
+        }
+    }
+    Ok(total)
+}
+
+/// Marks the end of the stream, waiting for room if need be.
+fn end_stream(ring: &SharedRingBuf) -> io::Result<()> {
+    Ok(ring.write_waiting(None, RingBuf::write_msg_end)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{MsgEvent, RingBuf};
+    use super::*;
+    use std::time::Duration;
+
+    /// Messages up to the end marker.
+    fn drain(shared: &SharedRingBuf) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        loop {
+            match shared.recv_msg().unwrap() {
+                Some(MsgEvent::Data(msg)) => messages.push(msg),
+                Some(MsgEvent::End) => return messages,
+                None => thread::yield_now(),
+            }
+        }
+    }
+
+    #[test]
+    fn lines_then_the_end_marker() {
+        let shared = SharedRingBuf::new(RingBuf::new(1).unwrap());
+        let (mut child, capture) = shared
+            .spawn_capture(
+                Command::new("printf").arg("one\\ntwo\\n\\nlast"),
+                Framing::Lines,
+                FullPolicy::Drop,
+            )
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(capture.join().unwrap(), 13);
+        assert_eq!(drain(&shared), [&b"one\n"[..], b"two\n", b"\n", b"last"]);
+        // The marker stays put, for anyone else who reads on.
+        assert_eq!(shared.recv_msg().unwrap(), Some(MsgEvent::End));
+    }
+
+    #[test]
+    fn chunks_add_up_to_the_output() {
+        let shared = SharedRingBuf::new(RingBuf::new(1).unwrap());
+        let (mut child, capture) = shared
+            .spawn_capture(
+                Command::new("echo").arg("hello"),
+                Framing::Chunks,
+                FullPolicy::Drop,
+            )
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(capture.join().unwrap(), 6);
+        assert_eq!(drain(&shared).concat(), b"hello\n");
+    }
+
+    #[test]
+    fn yes_into_a_tiny_ring_drops_and_counts() {
+        let shared = SharedRingBuf::new(RingBuf::new(1).unwrap());
+        let (mut child, capture) = shared
+            .spawn_capture(&mut Command::new("yes"), Framing::Lines, FullPolicy::Drop)
+            .unwrap();
+        while capture.dropped() == 0 {
+            thread::yield_now();
+        }
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        let kept = drain(&shared);
+        assert!(kept.iter().all(|line| line == b"y\n"));
+        let dropped = capture.dropped();
+        assert_eq!(capture.join().unwrap(), 2 * (kept.len() as u64 + dropped));
+    }
+
+    #[test]
+    fn yes_into_a_tiny_ring_blocks_the_pipe() {
+        let shared = SharedRingBuf::new(RingBuf::new(1).unwrap());
+        let (mut child, capture) = shared
+            .spawn_capture(
+                &mut Command::new("yes"),
+                Framing::Lines,
+                FullPolicy::Block(Duration::MAX),
+            )
+            .unwrap();
+        let mut seen = 0;
+        while seen < 10_000 {
+            match shared.read_msg().unwrap() {
+                Some(line) => assert_eq!(line, b"y\n"),
+                None => continue,
+            }
+            seen += 1;
+        }
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        let rest = drain(&shared);
+        assert!(rest.iter().all(|line| line == b"y\n"));
+        assert_eq!(capture.dropped(), 0);
+        assert_eq!(capture.join().unwrap(), 2 * (seen + rest.len() as u64));
+    }
+
+    #[test]
+    fn spawn_failures_come_back() {
+        let shared = SharedRingBuf::new(RingBuf::new(1).unwrap());
+        let Err(err) = shared.spawn_capture(
+            &mut Command::new("/nonexistent/command"),
+            Framing::Lines,
+            FullPolicy::Drop,
+        ) else {
+            panic!("There's no such command.");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
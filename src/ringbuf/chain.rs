@@ -0,0 +1,178 @@
+//! One `Read` over several split rings, one after another.
+
+use super::{BufError, Consumer, Error};
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, Read},
+};
+
+/// Reads the rings behind `consumers` in order, as one stream: everything from the first, then
+/// everything from the second, and so on. It only moves on from a ring at its end of stream,
+/// once the producer has been dropped and everything it wrote has been read. A ring whose
+/// producer went without writing anything is simply skipped.
+///
+/// Made with `new`, a read from a ring that's empty but still has a producer fails with
+/// `WouldBlock`, for callers that poll. Made with `new_blocking`, it sleeps until the producer
+/// writes something or goes away instead. Either way `Ok(0)` means every ring is done.
+pub struct ChainedReader {
+    consumers: VecDeque<Consumer>,
+    blocking: bool,
+}
+
+impl ChainedReader {
+    pub fn new(consumers: Vec<Consumer>) -> Self {
+        Self {
+            consumers: consumers.into(),
+            blocking: false,
+        }
+    }
+
+    pub fn new_blocking(consumers: Vec<Consumer>) -> Self {
+        Self {
+            consumers: consumers.into(),
+            blocking: true,
+        }
+    }
+
+    /// How many rings haven't reached their end yet, counting the one being read.
+    pub fn remaining(&self) -> usize {
+        self.consumers.len()
+    }
+
+    /// Drops the rings that are done with, until the first one has something to read or there
+    /// are none left.
+    fn settle(&mut self) -> io::Result<()> {
+        while let Some(current) = self.consumers.front() {
+            // Checked before looking for data, so nothing written before the producer went is
+            // missed.
+            let gone = current.producer_gone();
+            if !current.is_empty() {
+                return Ok(());
+            }
+            if gone {
+                self.consumers.pop_front();
+                continue;
+            }
+            if !self.blocking {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            match current.wait_for_data() {
+                Ok(()) | Err(Error::Ours(BufError::Disconnected)) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for ChainedReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+        let pending = self.fill_buf()?;
+        let n = pending.len().min(out.len());
+        out[..n].copy_from_slice(&pending[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+/// `fill_buf` is the current ring's pending bytes, straight out of its mapping.
+impl BufRead for ChainedReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.settle()?;
+        Ok(self.consumers.front().map_or(&[], Consumer::peek))
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if let Some(current) = self.consumers.front_mut() {
+            current
+                .consume(amt)
+                .expect("Can't consume more than `fill_buf` returned.");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::RingBuf;
+    use super::*;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn rings_finishing_at_different_times() {
+        let (producers, consumers): (Vec<_>, Vec<_>) = (0..4)
+            .map(|_| RingBuf::new(1).unwrap().split().unwrap())
+            .unzip();
+        let mut chain = ChainedReader::new_blocking(consumers);
+        assert_eq!(chain.remaining(), 4);
+
+        // The third ring never gets anything. The later ones write faster, so they sit on full
+        // rings until the chain gets to them, while it waits on the first.
+        let writers: Vec<_> = producers
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut producer)| {
+                thread::spawn(move || {
+                    if i == 2 {
+                        return;
+                    }
+                    let data: Vec<u8> = (0..10_000).map(|j| (i * 31 + j) as u8).collect();
+                    for chunk in data.chunks(1000) {
+                        producer.write_all_blocking(chunk).unwrap();
+                        thread::sleep(Duration::from_millis(4 - i as u64));
+                    }
+                })
+            })
+            .collect();
+
+        let mut all = Vec::new();
+        chain.read_to_end(&mut all).unwrap();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let expected: Vec<u8> = [0, 1, 3]
+            .iter()
+            .flat_map(|i| (0..10_000).map(move |j| (i * 31 + j) as u8))
+            .collect();
+        assert!(all == expected);
+        assert_eq!(chain.remaining(), 0);
+        assert_eq!(chain.read(&mut [0; 8]).unwrap(), 0);
+    }
+
+    #[test]
+    fn non_blocking_chain_would_block() {
+        let (mut first, a) = RingBuf::new(1).unwrap().split().unwrap();
+        let (empty, b) = RingBuf::new(1).unwrap().split().unwrap();
+        let (mut last, c) = RingBuf::new(1).unwrap().split().unwrap();
+        drop(empty);
+        last.write(b" world").unwrap();
+        let mut chain = ChainedReader::new(vec![a, b, c]);
+
+        // The last ring has data, but the first isn't finished.
+        let mut out = [0; 16];
+        let err = chain.read(&mut out).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        first.write(b"hello").unwrap();
+        assert_eq!(chain.fill_buf().unwrap(), b"hello");
+        chain.consume(5);
+        assert_eq!(
+            chain.read(&mut out).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        // Once the first is done, the empty one is skipped.
+        drop(first);
+        assert_eq!(chain.read(&mut out).unwrap(), 6);
+        assert_eq!(&out[..6], b" world");
+        assert_eq!(chain.remaining(), 1);
+        assert_eq!(
+            chain.read(&mut out).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        drop(last);
+        assert_eq!(chain.read(&mut out).unwrap(), 0);
+    }
+}
@@ -0,0 +1,64 @@
+//! Where the ring gets its idea of "now" from. Everything time-based goes through `Clock` so
+//! tests can swap in a `MockClock` and step time by hand instead of sleeping.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// A monotonic time source.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Waits until `now` has moved on by `dur`.
+    fn sleep(&self, dur: Duration) {
+        std::thread::sleep(dur);
+    }
+}
+
+/// The real thing, backed by `Instant::now`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time, so a test can keep one
+/// handle and give the other to the ring.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sleeping just moves the time on, so code that waits for a while can be tested without
+/// waiting.
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, dur: Duration) {
+        self.advance(dur);
+    }
+}
@@ -0,0 +1,34 @@
+//! A stand-in for `nix::errno::Errno` on builds without `mapped`, which have no nix to take it
+//! from. Only heap rings exist there, so it only has to cover the few errnos they can still fail
+//! with.
+
+use std::{fmt, io};
+
+/// An OS error number, like `nix::errno::Errno`, which is what `Error::Nix` holds everywhere
+/// else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(i32);
+
+impl Errno {
+    pub const EBUSY: Self = Self(libc::EBUSY);
+    pub const EINVAL: Self = Self(libc::EINVAL);
+    pub const ENOMEM: Self = Self(libc::ENOMEM);
+
+    pub const fn from_raw(errno: i32) -> Self {
+        Self(errno)
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", io::Error::from_raw_os_error(self.0))
+    }
+}
+
+impl std::error::Error for Errno {}
+
+impl From<Errno> for io::Error {
+    fn from(value: Errno) -> Self {
+        io::Error::from_raw_os_error(value.0)
+    }
+}
@@ -0,0 +1,93 @@
+//! Fault injection for the OS calls the crate makes, so the error paths around them can actually
+//! be tested. Every call goes through `os_call`, which is a plain call unless `cfg(test)` or the
+//! `fault-inject` feature is on, in which case it first checks whether the current thread asked
+//! for that operation to fail.
+
+/// The OS operations failures can be injected into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OsOp {
+    MemfdCreate,
+    /// Opening `/dev/ashmem`, Android's fallback when `memfd_create` is blocked.
+    AshmemCreate,
+    /// Opening a POSIX shared memory object, the fallback when there's no memfd or ashmem.
+    ShmOpen,
+    /// Sizing the fd, which is an `ftruncate` for everything but ashmem.
+    Ftruncate,
+    /// Sealing a memfd's size, for rings built with `RingBufBuilder::seal`.
+    Seal,
+    /// Mapping the fd at twice its length, which covers both views and maps the first. See
+    /// `BackendKind::DoubleMap`.
+    MapDouble,
+    /// The `PROT_NONE` reservation covering both views.
+    Reserve,
+    /// Mapping the first view.
+    MapLow,
+    /// Mapping the mirror view.
+    MapHigh,
+    /// Punching the ring's pages out of its fd, for `RingBuf::clear_and_release`.
+    PunchHole,
+    /// Locking a ring's views into memory, for `RingBufBuilder::lock_memory`.
+    Mlock,
+    /// Creating the eventfd behind `Consumer::data_fd` or `Producer::space_fd`.
+    EventfdCreate,
+}
+
+#[cfg(not(any(test, feature = "fault-inject")))]
+#[inline(always)]
+pub(crate) fn os_call<T>(_: OsOp, call: impl FnOnce() -> nix::Result<T>) -> nix::Result<T> {
+    call()
+}
+
+#[cfg(any(test, feature = "fault-inject"))]
+pub use enabled::*;
+
+#[cfg(any(test, feature = "fault-inject"))]
+mod enabled {
+    use super::OsOp;
+    use nix::errno::Errno;
+    use std::cell::RefCell;
+
+    struct Injected {
+        op: OsOp,
+        errno: Errno,
+        // Calls to let through before failing.
+        remaining: usize,
+    }
+
+    thread_local! {
+        static INJECTED: RefCell<Vec<Injected>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Makes the next `op` on this thread fail with `errno` once `after_n_calls` calls of it have
+    /// gone through normally. Each injection fires once.
+    pub fn inject_failure(op: OsOp, errno: Errno, after_n_calls: usize) {
+        INJECTED.with_borrow_mut(|injected| {
+            injected.push(Injected {
+                op,
+                errno,
+                remaining: after_n_calls,
+            })
+        });
+    }
+
+    /// Forgets any injections on this thread that haven't fired yet.
+    pub fn clear_injected_failures() {
+        INJECTED.with_borrow_mut(Vec::clear);
+    }
+
+    pub(crate) fn os_call<T>(op: OsOp, call: impl FnOnce() -> nix::Result<T>) -> nix::Result<T> {
+        let fail = INJECTED.with_borrow_mut(|injected| {
+            let pos = injected.iter().position(|i| i.op == op)?;
+            if injected[pos].remaining == 0 {
+                Some(injected.remove(pos).errno)
+            } else {
+                injected[pos].remaining -= 1;
+                None
+            }
+        });
+        match fail {
+            Some(errno) => Err(errno),
+            None => call(),
+        }
+    }
+}
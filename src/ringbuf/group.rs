@@ -0,0 +1,156 @@
+//! Many rings sharing one memfd and one mapping, for when a ring per core/connection would
+//! otherwise mean a memfd and a reservation each.
+
+use super::{
+    mirror::{MapOptions, Mirror},
+    page_size, BufError, Result, RingBuf,
+};
+use std::num::NonZeroUsize;
+
+/// A set of independent rings carved out of one memfd and one address-space reservation.
+///
+/// Ring `i` owns bytes `i * capacity..(i + 1) * capacity` of the memfd, mapped twice in a row at
+/// `2 * capacity * i` into the reservation. Every ring has its own pair of views, so the mirror
+/// trick works for each one exactly as it does for a standalone ring and no ring can see
+/// another's bytes. One big mirror split by offset wouldn't do: reading off the end of a
+/// partition would land in the next partition, not back at its own start.
+///
+/// That costs one memfd and one reservation however many rings there are, plus two views per
+/// ring mapped into the reservation. Everything is torn down in one go once the last ring is
+/// dropped, whether that's through the group or after `into_rings`. A ring that gets
+/// `shrink_to`'d moves into a mapping of its own. With the `portable` feature there's no mapping
+/// to share and every ring gets its own heap allocation.
+pub struct RingGroup {
+    rings: Vec<RingBuf>,
+}
+
+impl RingGroup {
+    /// `count` rings of at least `ring_capacity` bytes each, rounded up to whole pages.
+    pub fn new(ring_capacity: usize, count: usize) -> Result<Self> {
+        let size = ring_capacity
+            .checked_next_multiple_of(page_size()?)
+            .ok_or(BufError::CapacityOverflow)?;
+        let (Some(size), Some(count)) = (NonZeroUsize::new(size), NonZeroUsize::new(count)) else {
+            return Err(BufError::ZeroCapacity.into());
+        };
+        let rings = Mirror::group(size, count, MapOptions::default())?
+            .into_iter()
+            .map(RingBuf::from_mirror)
+            .collect();
+        Ok(Self { rings })
+    }
+
+    pub fn rings_mut(&mut self) -> &mut [RingBuf] {
+        &mut self.rings
+    }
+
+    /// Hands the rings out separately. The shared mapping lives until the last of them is
+    /// dropped.
+    pub fn into_rings(self) -> Vec<RingBuf> {
+        self.rings
+    }
+
+    pub fn len(&self) -> usize {
+        self.rings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rings.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(mapped)]
+    use super::super::{assert_no_leaks, inject_failure, OsOp};
+    use super::super::{LeakCheck, Live};
+    use super::*;
+    #[cfg(mapped)]
+    use nix::errno::Errno;
+
+    #[test]
+    fn partitions_are_independent() {
+        let page = page_size().unwrap();
+        let _leaks = LeakCheck::new();
+        let mut group = RingGroup::new(page, 8).unwrap();
+        assert_eq!(group.len(), 8);
+        let rings = group.rings_mut();
+        assert!(rings.iter().all(|ring| ring.capacity() == page));
+
+        // Interleave traffic across the partitions, at different phases so they all wrap at
+        // different points, and check nobody sees anyone else's bytes.
+        for round in 0..20usize {
+            for (i, ring) in rings.iter_mut().enumerate() {
+                let data = vec![i as u8; 500 + 97 * i + round];
+                ring.write(&data).unwrap();
+            }
+            for (i, ring) in rings.iter_mut().enumerate() {
+                let n = 500 + 97 * i + round;
+                assert_eq!(ring.read(n).unwrap(), &vec![i as u8; n][..]);
+            }
+        }
+        // Filling one to the brim leaves its neighbours alone.
+        rings[3].write(&vec![0xff; page]).unwrap();
+        rings[2].write(b"two").unwrap();
+        rings[4].write(b"four").unwrap();
+        assert_eq!(rings[2].read(3).unwrap(), b"two");
+        assert_eq!(rings[4].read(4).unwrap(), b"four");
+        assert_eq!(rings[3].read(page).unwrap(), &vec![0xff; page][..]);
+    }
+
+    #[test]
+    #[cfg_attr(
+        any(feature = "portable", not(mapped)),
+        ignore = "counts mappings and memfds"
+    )]
+    fn one_memfd_and_mapping_for_the_lot() {
+        let page = page_size().unwrap();
+        let leaks = LeakCheck::new();
+        let one = Live {
+            mappings: 1,
+            memfds: 1,
+        };
+        let group = RingGroup::new(3 * page, 64).unwrap();
+        assert_eq!(leaks.live(), one);
+        drop(group);
+        assert_eq!(leaks.live(), Live::default());
+
+        // Handed out separately, the mapping goes with the last ring.
+        let mut rings = RingGroup::new(page, 4).unwrap().into_rings();
+        let last = rings.pop().unwrap();
+        drop(rings);
+        assert_eq!(leaks.live(), one);
+        drop(last);
+        assert_eq!(leaks.live(), Live::default());
+    }
+
+    #[test]
+    fn tap_on_a_partition() {
+        let page = page_size().unwrap();
+        let _leaks = LeakCheck::new();
+        let mut rings = RingGroup::new(page, 3).unwrap().into_rings();
+        rings[0].write(b"zero").unwrap();
+        rings[1].write(b"one").unwrap();
+        let tap = rings[1].try_clone_reader().unwrap();
+        assert_eq!(tap.peek(), b"one");
+    }
+
+    #[test]
+    fn empty_groups_are_rejected() {
+        let page = page_size().unwrap();
+        assert!(RingGroup::new(0, 4).is_err());
+        assert!(RingGroup::new(page, 0).is_err());
+        assert!(RingGroup::new(usize::MAX / 2, 4).is_err());
+    }
+
+    #[test]
+    #[cfg(mapped)]
+    #[cfg_attr(feature = "portable", ignore = "counts mappings and memfds")]
+    fn failure_partway_through_leaks_nothing() {
+        let page = page_size().unwrap();
+        let _leaks = LeakCheck::new();
+        inject_failure(OsOp::MapHigh, Errno::ENOMEM, 5);
+        assert!(RingGroup::new(page, 16).is_err());
+        assert_no_leaks();
+    }
+}
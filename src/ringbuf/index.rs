@@ -0,0 +1,128 @@
+//! The index arithmetic behind `RingBuf`, pulled out as pure functions so it can be checked on
+//! its own, exhaustively for small capacities in the tests and for all inputs up to a bound with
+//! Kani:
+//!
+//! ```text
+//! cargo install --locked kani-verifier && cargo kani setup
+//! cargo kani --harness advance_stays_in_range   # or just `cargo kani` for all of them
+//! ```
+//!
+//! Head and tail alone can't tell an empty ring from a full one (both have `head == tail`), which
+//! is why `RingBuf` takes the number of pending bytes from its running totals of bytes written and
+//! read, which never wrap, and everything here is in terms of that length. Capacities are any
+//! multiple of the page size, not just powers of two, so wrapping is a compare and subtract
+//! rather than a mask.
+
+/// Moves `idx` forward by `n`, wrapping at `cap`. Expects `idx < cap` (or `idx == 0` when
+/// `cap == 0`) and `n <= cap`, which is all the ring ever needs, so a subtraction does the job of
+/// `%` and a zero capacity doesn't divide by zero.
+pub(crate) fn advance(idx: usize, n: usize, cap: usize) -> usize {
+    let idx = idx + n;
+    if idx >= cap {
+        idx - cap
+    } else {
+        idx
+    }
+}
+
+/// Bytes that can still be written into a ring of capacity `cap` holding `len` bytes.
+pub(crate) fn free_space(len: usize, cap: usize) -> usize {
+    cap - len
+}
+
+/// Whether a transfer of `n` bytes fits in `free` bytes of space.
+pub(crate) fn fits(n: usize, free: usize) -> bool {
+    n <= free
+}
+
+#[cfg(kani)]
+mod proofs {
+    use super::*;
+
+    // Big enough to cover multi-page capacities' worth of edge cases, small enough for the solver.
+    const MAX_CAP: usize = 64;
+
+    /// Any reachable ring state: head in range, `len <= cap`, tail derived from the two.
+    fn any_state() -> (usize, usize, usize, usize) {
+        let cap: usize = kani::any();
+        let head: usize = kani::any();
+        let len: usize = kani::any();
+        kani::assume(cap > 0 && cap <= MAX_CAP);
+        kani::assume(head < cap && len <= cap);
+        (cap, head, advance(head, len, cap), len)
+    }
+
+    #[kani::proof]
+    fn advance_stays_in_range() {
+        let (cap, head, tail, _) = any_state();
+        let n: usize = kani::any();
+        kani::assume(n <= cap);
+        assert!(tail < cap);
+        assert!(advance(head, n, cap) < cap);
+        assert_eq!(advance(head, n, cap), (head + n) % cap);
+    }
+
+    #[kani::proof]
+    fn len_plus_free_is_cap() {
+        let (cap, _, _, len) = any_state();
+        assert_eq!(len + free_space(len, cap), cap);
+    }
+
+    #[kani::proof]
+    fn write_then_read_restores_len() {
+        let (cap, head, tail, len) = any_state();
+        let n: usize = kani::any();
+        kani::assume(fits(n, free_space(len, cap)));
+
+        let (tail, len_after_write) = (advance(tail, n, cap), len + n);
+        assert!(len_after_write <= cap);
+        assert_eq!(tail, advance(head, len_after_write, cap));
+
+        let (head, len_after_read) = (advance(head, n, cap), len_after_write - n);
+        assert_eq!(len_after_read, len);
+        assert_eq!(tail, advance(head, len_after_read, cap));
+    }
+
+    #[kani::proof]
+    fn empty_and_full_are_distinct() {
+        let (cap, head, tail, len) = any_state();
+        // head == tail exactly when the ring is empty or full, and `len` tells which.
+        assert_eq!(head == tail, len == 0 || len == cap);
+        assert!(!(len == 0 && free_space(len, cap) == 0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The Kani proofs, exhaustively over small capacities so they run with the rest of the tests.
+    #[test]
+    fn small_capacities_exhaustively() {
+        for cap in 1..=16 {
+            for head in 0..cap {
+                for len in 0..=cap {
+                    let tail = advance(head, len, cap);
+                    assert!(tail < cap);
+                    assert_eq!(len + free_space(len, cap), cap);
+                    assert_eq!(head == tail, len == 0 || len == cap);
+
+                    for n in 0..=cap {
+                        assert_eq!(advance(head, n, cap), (head + n) % cap);
+                        if !fits(n, free_space(len, cap)) {
+                            assert!(len + n > cap);
+                            continue;
+                        }
+                        let tail = advance(tail, n, cap);
+                        let head = advance(head, n, cap);
+                        assert_eq!(tail, advance(head, len, cap));
+                    }
+                }
+            }
+        }
+        // The placeholder ring.
+        assert_eq!(advance(0, 0, 0), 0);
+        assert_eq!(free_space(0, 0), 0);
+        assert!(!fits(1, free_space(0, 0)));
+    }
+}
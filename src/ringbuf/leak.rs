@@ -0,0 +1,185 @@
+//! Bookkeeping of the mappings and memfds the crate creates, so tests can prove everything gets
+//! cleaned up. Only active under `cfg(test)` or the `leak-check` feature; otherwise every hook
+//! here compiles to nothing.
+//!
+//! Counts are kept per scope. A `LeakCheck` guard opens a fresh scope for the current thread,
+//! and every resource created on that thread while the guard lives is charged to it, wherever it
+//! ends up being released. That keeps tests running in parallel from seeing each other's
+//! resources. Resources created outside any guard are charged to a shared catch-all scope.
+
+#[cfg(any(test, feature = "leak-check"))]
+pub use enabled::*;
+
+// Builds without `mapped` make neither, so they never count anything.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(not(mapped), allow(dead_code))]
+pub(crate) enum Resource {
+    Mapping,
+    MemFd,
+}
+
+#[cfg(all(mapped, not(any(test, feature = "leak-check"))))]
+mod disabled {
+    use super::Resource;
+
+    #[derive(Debug, Clone, Copy)]
+    pub(crate) struct Scope;
+
+    impl Scope {
+        pub(crate) fn current() -> Self {
+            Self
+        }
+
+        pub(crate) fn created(&self, _: Resource) {}
+
+        pub(crate) fn released(&self, _: Resource) {}
+    }
+}
+
+#[cfg(all(mapped, not(any(test, feature = "leak-check"))))]
+pub(crate) use disabled::Scope;
+
+#[cfg(any(test, feature = "leak-check"))]
+mod enabled {
+    use super::Resource;
+    use std::{
+        cell::Cell,
+        collections::HashMap,
+        fmt::Display,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+    };
+
+    static REGISTRY: Mutex<Option<HashMap<usize, Live>>> = Mutex::new(None);
+    // Scope 0 is the catch-all for resources created outside a `LeakCheck`.
+    static NEXT_SCOPE: AtomicUsize = AtomicUsize::new(1);
+
+    thread_local! {
+        static CURRENT_SCOPE: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// How many resources a scope currently has alive.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct Live {
+        pub mappings: isize,
+        pub memfds: isize,
+    }
+
+    impl Live {
+        pub fn is_empty(&self) -> bool {
+            *self == Self::default()
+        }
+    }
+
+    impl Display for Live {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "{} mapping(s) and {} memfd(s) still alive",
+                self.mappings, self.memfds
+            )
+        }
+    }
+
+    /// The scope a resource is charged to, remembered by whatever owns it.
+    #[derive(Debug, Clone, Copy)]
+    pub(crate) struct Scope(usize);
+
+    #[cfg_attr(not(mapped), allow(dead_code))]
+    impl Scope {
+        pub(crate) fn current() -> Self {
+            Self(CURRENT_SCOPE.get())
+        }
+
+        pub(crate) fn created(&self, resource: Resource) {
+            self.adjust(resource, 1);
+        }
+
+        pub(crate) fn released(&self, resource: Resource) {
+            self.adjust(resource, -1);
+        }
+
+        fn adjust(&self, resource: Resource, by: isize) {
+            let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+            let live = registry
+                .get_or_insert_with(HashMap::new)
+                .entry(self.0)
+                .or_default();
+            match resource {
+                Resource::Mapping => live.mappings += by,
+                Resource::MemFd => live.memfds += by,
+            }
+        }
+
+        fn live(&self) -> Live {
+            let registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+            registry
+                .as_ref()
+                .and_then(|scopes| scopes.get(&self.0))
+                .copied()
+                .unwrap_or_default()
+        }
+    }
+
+    /// Panics if anything created in the current thread's `LeakCheck` scope is still alive.
+    pub fn assert_no_leaks() {
+        let live = Scope::current().live();
+        assert!(live.is_empty(), "Leaked: {live}");
+    }
+
+    /// Opens a leak-checking scope for the current thread and, when dropped, panics if anything
+    /// created inside it is still alive. Use `finish` instead of dropping to get the result back
+    /// without panicking.
+    #[must_use]
+    pub struct LeakCheck {
+        scope: Scope,
+        outer: usize,
+        finished: bool,
+    }
+
+    impl LeakCheck {
+        pub fn new() -> Self {
+            let scope = Scope(NEXT_SCOPE.fetch_add(1, Ordering::Relaxed));
+            let outer = CURRENT_SCOPE.replace(scope.0);
+            Self {
+                scope,
+                outer,
+                finished: false,
+            }
+        }
+
+        /// What's alive in this scope right now.
+        pub fn live(&self) -> Live {
+            self.scope.live()
+        }
+
+        /// Closes the scope, returning what leaked, if anything.
+        pub fn finish(mut self) -> Result<(), Live> {
+            self.finished = true;
+            let live = self.live();
+            if live.is_empty() {
+                Ok(())
+            } else {
+                Err(live)
+            }
+        }
+    }
+
+    impl Default for LeakCheck {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Drop for LeakCheck {
+        fn drop(&mut self) {
+            CURRENT_SCOPE.set(self.outer);
+            if !self.finished && !std::thread::panicking() {
+                let live = self.live();
+                assert!(live.is_empty(), "Leaked: {live}");
+            }
+        }
+    }
+}
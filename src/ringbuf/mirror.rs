@@ -0,0 +1,894 @@
+//! The mapping trick itself: one memfd mapped twice, back to back, so that reads and writes
+//! running off the end of the first view land at the start of the buffer.
+
+#[cfg(mapped)]
+use super::{
+    fault::{os_call, OsOp},
+    leak::{Resource, Scope},
+    shmem,
+};
+use super::{
+    page_size,
+    shmem::{FdOptions, FdSource},
+    BufError, Errno, Error, Result, POISON,
+};
+#[cfg(mapped)]
+use nix::sys::{
+    mman::{mlock, mmap, mmap_anonymous, munlock, munmap, MapFlags, ProtFlags},
+    stat::fstat,
+};
+use std::{alloc::Layout, num::NonZeroUsize, os::fd::BorrowedFd, sync::Arc};
+#[cfg(mapped)]
+use std::{
+    borrow::Borrow,
+    ffi::c_void,
+    os::fd::{AsFd, AsRawFd, OwnedFd},
+    ptr::NonNull,
+};
+
+/// Size of a transparent huge page on the platforms we care about, and of the hugetlb pages
+/// `RingBufBuilder::huge_pages` asks for.
+pub(crate) const THP_SIZE: usize = 2 * 1024 * 1024;
+
+/// How a mirror should be mapped. See `RingBufBuilder` for what each knob means.
+#[derive(Debug, Clone)]
+pub(crate) struct MapOptions {
+    /// `Some(true)` asks for transparent huge pages, `Some(false)` asks for none, `None` leaves it
+    /// up to the kernel.
+    pub(crate) thp: Option<bool>,
+    /// Scrub consumed bytes, and the whole mapping on drop.
+    pub(crate) zeroize: bool,
+    /// Keep free space filled with a poison pattern for `RingBuf::check_invariants` to verify.
+    pub(crate) debug_fill: bool,
+    /// Map the views without write access.
+    pub(crate) read_only: bool,
+    /// Insist on one way of building the mapping instead of picking the cheapest that works.
+    pub(crate) backend: Option<BackendKind>,
+    /// How to make the fd.
+    pub(crate) fd: FdOptions,
+    /// If `fd.hugetlb` can't be had, build the mirror with normal pages instead of failing.
+    pub(crate) hugetlb_fallback: bool,
+    /// `mlock` both views once they're mapped.
+    pub(crate) mlock: bool,
+}
+
+/// The syscall sequence a mirror was built with. See `RingBuf::backend_kind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    /// Map the fd once at twice its length, which reserves the whole range and maps the first
+    /// view in one call, then map the mirror view over the second half. Only usable when the
+    /// mapping doesn't need more than page alignment.
+    DoubleMap,
+    /// Reserve the range with a `PROT_NONE` mapping, then map both views over it. One call more,
+    /// but it can align the reservation, so it's what huge-page rings use. Also the fallback if
+    /// the kernel refuses the double-length mapping.
+    Reserve,
+    /// No mapping at all: both views are a plain heap allocation, and every write into one is
+    /// copied into the other by hand. What the `portable` feature and Miri get, since neither can
+    /// count on the mapping trick. Behaves the same, but everything written costs a second copy
+    /// and the ring uses twice its capacity in memory.
+    Heap,
+}
+
+// Derivable only when the `poison` feature is off.
+#[allow(clippy::derivable_impls)]
+impl Default for MapOptions {
+    fn default() -> Self {
+        Self {
+            thp: None,
+            zeroize: false,
+            debug_fill: cfg!(feature = "poison"),
+            read_only: false,
+            backend: None,
+            fd: FdOptions::default(),
+            hugetlb_fallback: false,
+            mlock: false,
+        }
+    }
+}
+
+impl MapOptions {
+    #[cfg(mapped)]
+    fn prot(&self) -> ProtFlags {
+        if self.read_only {
+            ProtFlags::PROT_READ
+        } else {
+            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE
+        }
+    }
+
+    /// Whether to use `BackendKind::Heap`, which is the default with the `portable` feature,
+    /// under Miri, and on builds without `mapped`, where it's all there is.
+    fn heap(&self) -> bool {
+        match self.backend {
+            Some(backend) => backend == BackendKind::Heap,
+            None => cfg!(any(feature = "portable", miri, not(mapped))),
+        }
+    }
+
+    /// What capacities have to be a multiple of (and the mapping aligned to) with these options.
+    pub(crate) fn granularity(&self) -> Result<usize> {
+        if self.thp == Some(true) || self.fd.hugetlb {
+            Ok(THP_SIZE)
+        } else {
+            page_size()
+        }
+    }
+}
+
+/// A memfd and the address range it's mapped into, torn down together on drop. Normally owned by
+/// a single `Mirror`; the mirrors of a `RingGroup` all share one.
+#[cfg(mapped)]
+pub(crate) struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+    fd: OwnedFd,
+    // `None` for an fd handed to us, whose origin we can't know.
+    source: Option<FdSource>,
+    leak_scope: Scope,
+}
+
+// SAFETY: Neither the mapping nor the fd is tied to the thread that made them, and after
+// construction a `Mapping` is never mutated; the memory itself is only accessed through the
+// `Mirror`s, which carry their own pointers.
+#[cfg(mapped)]
+unsafe impl Send for Mapping {}
+#[cfg(mapped)]
+unsafe impl Sync for Mapping {}
+
+#[cfg(mapped)]
+impl Mapping {
+    /// A fresh shared memory fd of `len` bytes with nothing mapped yet.
+    fn create(len: usize, options: &FdOptions) -> Result<Self> {
+        let leak_scope = Scope::current();
+        let (fd, source) = shmem::open(options)?;
+        leak_scope.created(Resource::MemFd);
+        // From here on, an early return drops `mapping`, which cleans up whatever exists so far.
+        let mapping = Self::with_fd(fd, Some(source), leak_scope);
+        shmem::set_size(&mapping.fd, source, len)?;
+        shmem::seal(&mapping.fd, source, options)?;
+        Ok(mapping)
+    }
+
+    /// Takes over `fd`, which `leak_scope` has already counted.
+    fn with_fd(fd: OwnedFd, source: Option<FdSource>, leak_scope: Scope) -> Self {
+        Self {
+            ptr: std::ptr::null_mut(),
+            len: 0,
+            fd,
+            source,
+            leak_scope,
+        }
+    }
+
+    /// Reserves `len` bytes of address space for the views to go in.
+    unsafe fn reserve(&mut self, len: NonZeroUsize, align: usize) -> Result<()> {
+        // Reserve the whole range first so nothing else can land in the second half.
+        self.ptr = os_call(OsOp::Reserve, || reserve(len, align))?;
+        self.len = len.get();
+        self.leak_scope.created(Resource::Mapping);
+        Ok(())
+    }
+
+    /// Maps `size` bytes of the fd starting at `file_offset` over `at`, which has to be inside
+    /// the reservation.
+    unsafe fn map_view(
+        &self,
+        op: OsOp,
+        at: *mut u8,
+        size: NonZeroUsize,
+        file_offset: usize,
+        options: &MapOptions,
+    ) -> Result<()> {
+        os_call(op, || {
+            mmap(
+                Some(NonZeroUsize::new_unchecked(at as usize)),
+                size,
+                options.prot(),
+                MapFlags::MAP_SHARED | MapFlags::MAP_FIXED,
+                self.fd.borrow(),
+                file_offset as i64,
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Maps the `size` bytes at `file_offset` twice in a row, as the only thing in this mapping.
+    ///
+    /// # Safety
+    /// The fd has to be at least `file_offset + size` bytes long.
+    unsafe fn map_mirror(
+        &mut self,
+        size: NonZeroUsize,
+        file_offset: usize,
+        options: &MapOptions,
+    ) -> Result<BackendKind> {
+        let map_size = NonZeroUsize::new_unchecked(size.get() * 2);
+        let mut backend = BackendKind::Reserve;
+        if options.granularity()? == page_size()? && options.backend != Some(BackendKind::Reserve) {
+            // Past the end of the file, so the second half would SIGBUS until the mirror view
+            // replaces it. Nothing touches it before then.
+            match os_call(OsOp::MapDouble, || {
+                mmap(
+                    None,
+                    map_size,
+                    options.prot(),
+                    MapFlags::MAP_SHARED,
+                    self.fd.borrow(),
+                    file_offset as i64,
+                )
+            }) {
+                Ok(ptr) => {
+                    self.ptr = ptr.as_ptr() as *mut u8;
+                    self.len = map_size.get();
+                    self.leak_scope.created(Resource::Mapping);
+                    backend = BackendKind::DoubleMap;
+                }
+                Err(e) if options.backend == Some(BackendKind::DoubleMap) => return Err(e.into()),
+                Err(_) => {}
+            }
+        }
+
+        if backend == BackendKind::Reserve {
+            self.reserve(map_size, options.granularity()?)?;
+            self.map_view(OsOp::MapLow, self.ptr, size, file_offset, options)?;
+        }
+        self.map_view(
+            OsOp::MapHigh,
+            self.ptr.add(size.get()),
+            size,
+            file_offset,
+            options,
+        )?;
+        Ok(backend)
+    }
+}
+
+#[cfg(mapped)]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // munmap the buffer.
+        // Not sure why you wouldn't keep a structure like this around for the duration of the
+        // whole program but you know best.
+        if !self.ptr.is_null() {
+            // munmap only fails for a bad range, which would be our bug, and panicking here could
+            // be a panic during unwinding, i.e. an abort. Leaking the range is the lesser evil.
+            let _ = unsafe {
+                munmap(
+                    std::ptr::NonNull::new_unchecked(self.ptr as *mut c_void),
+                    self.len,
+                )
+            };
+            self.leak_scope.released(Resource::Mapping);
+        }
+        // The fd itself is closed when the field is dropped right after this.
+        self.leak_scope.released(Resource::MemFd);
+    }
+}
+
+/// Both views of a `BackendKind::Heap` mirror, freed on drop. Shared by the mirror and any taps
+/// on it.
+struct HeapViews {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+// SAFETY: Just memory, which is only accessed through the `Mirror`s, as with `Mapping`.
+unsafe impl Send for HeapViews {}
+unsafe impl Sync for HeapViews {}
+
+impl HeapViews {
+    /// `2 * size` zeroed bytes aligned to `align`, zeroed like a fresh memfd would be.
+    fn alloc(size: NonZeroUsize, align: usize) -> Result<Self> {
+        let layout = size
+            .get()
+            .checked_mul(2)
+            .and_then(|len| Layout::from_size_align(len, align).ok())
+            .ok_or(BufError::CapacityOverflow)?;
+        let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            return Err(Errno::ENOMEM.into());
+        }
+        Ok(Self { ptr, layout })
+    }
+}
+
+impl Drop for HeapViews {
+    fn drop(&mut self) {
+        unsafe { std::alloc::dealloc(self.ptr, self.layout) };
+    }
+}
+
+/// What a mirror's views live in.
+#[derive(Clone)]
+enum Backing {
+    #[cfg(mapped)]
+    Mapped(Arc<Mapping>),
+    Heap(Arc<HeapViews>),
+}
+
+/// `size` bytes of a memfd mapped twice in a row at `ptr`. The mapping goes away once the last
+/// mirror sharing it is dropped. With `BackendKind::Heap` the two views are separate memory
+/// instead, and whoever writes through `ptr` has to `sync` afterwards.
+pub(crate) struct Mirror {
+    pub(crate) ptr: *mut u8,
+    /// Size of one view; the mapping is twice this.
+    pub(crate) size: usize,
+    // Where the mirrored bytes start in the memfd.
+    #[cfg(mapped)]
+    file_offset: usize,
+    backing: Backing,
+    pub(crate) options: MapOptions,
+    /// Whether the kernel accepted the `options.thp` hint.
+    pub(crate) thp_advised: bool,
+    /// Whether `lock` has locked the views, so drop has to unlock them.
+    pub(crate) locked: bool,
+    pub(crate) backend: BackendKind,
+}
+
+impl Mirror {
+    pub(crate) fn new(size: NonZeroUsize) -> Result<Self> {
+        Self::with_options(size, MapOptions::default())
+    }
+
+    /// `size` must be a multiple of `options.granularity()`. Nothing is left mapped or open if
+    /// this fails.
+    ///
+    /// A hugetlb mirror the kernel has no pages for fails with `BufError::HugePagesUnavailable`,
+    /// or is built from normal pages if `options.hugetlb_fallback` says so.
+    pub(crate) fn with_options(size: NonZeroUsize, options: MapOptions) -> Result<Self> {
+        let mut mirror = match Self::build(size, &options) {
+            // EINVAL from a kernel without hugetlbfs, ENOMEM from mapping with none reserved.
+            Err(Error::Nix(Errno::EINVAL | Errno::ENOMEM))
+            | Err(Error::Ours(BufError::HugePagesUnavailable))
+                if options.fd.hugetlb =>
+            {
+                if !options.hugetlb_fallback {
+                    return Err(BufError::HugePagesUnavailable.into());
+                }
+                let mut options = options.clone();
+                options.fd.hugetlb = false;
+                Self::build(size, &options)?
+            }
+            result => result?,
+        };
+        if options.mlock {
+            mirror.lock()?;
+        }
+        Ok(mirror)
+    }
+
+    fn build(size: NonZeroUsize, options: &MapOptions) -> Result<Self> {
+        debug_assert_eq!(size.get() % options.granularity()?, 0);
+        if options.heap() && options.fd.hugetlb {
+            return Err(BufError::HugePagesUnavailable.into());
+        }
+        if options.heap() {
+            let heap = HeapViews::alloc(size, options.granularity()?)?;
+            let ptr = heap.ptr;
+            if options.debug_fill && !options.read_only {
+                unsafe { std::ptr::write_bytes(ptr, POISON, 2 * size.get()) };
+            }
+            return Ok(Self::on_heap(
+                Arc::new(heap),
+                ptr,
+                size.get(),
+                options.clone(),
+            ));
+        }
+        Self::map(size, options)
+    }
+
+    /// `build` for every backend but `BackendKind::Heap`.
+    #[cfg(mapped)]
+    fn map(size: NonZeroUsize, options: &MapOptions) -> Result<Self> {
+        let mut mapping = Mapping::create(size.get(), &options.fd)?;
+        let backend = unsafe { mapping.map_mirror(size, 0, options)? };
+        Ok(Self::in_mapping(
+            Arc::new(mapping),
+            0,
+            size,
+            0,
+            options.clone(),
+            backend,
+        ))
+    }
+
+    /// Maps another mirror of the same bytes as `existing`, through a duplicate of its fd, so the
+    /// two see the same data at the same offsets.
+    /// Heap mirrors have nothing to map, so the new one just shares `existing`'s memory.
+    pub(crate) fn remap(existing: &Mirror, options: MapOptions) -> Result<Self> {
+        match &existing.backing {
+            #[cfg(mapped)]
+            Backing::Mapped(mapping) => Self::remap_mapped(existing, mapping, options),
+            Backing::Heap(heap) => Ok(Self::on_heap(
+                Arc::clone(heap),
+                existing.ptr,
+                existing.size,
+                options,
+            )),
+        }
+    }
+
+    /// `remap` for a mirror in `existing_mapping`.
+    #[cfg(mapped)]
+    fn remap_mapped(
+        existing: &Mirror,
+        existing_mapping: &Mapping,
+        options: MapOptions,
+    ) -> Result<Self> {
+        let leak_scope = Scope::current();
+        let fd = existing_mapping
+            .fd
+            .try_clone()
+            .map_err(|e| nix::Error::from_raw(e.raw_os_error().unwrap_or(0)))?;
+        leak_scope.created(Resource::MemFd);
+        let mut mapping = Mapping::with_fd(fd, existing_mapping.source, leak_scope);
+        let size = NonZeroUsize::new(existing.size).expect("Mirrors are never empty.");
+        let backend = unsafe { mapping.map_mirror(size, existing.file_offset, &options)? };
+        Ok(Self::in_mapping(
+            Arc::new(mapping),
+            0,
+            size,
+            existing.file_offset,
+            options,
+            backend,
+        ))
+    }
+
+    /// A mirror of `size` bytes with a `header_len`-byte header in front, both in one fd: the
+    /// header at offset 0 and the mirrored bytes right after it. Creates the fd, or takes over
+    /// `fd` and maps whatever is already in it, which fails with `BufError::CapacityMismatch` if
+    /// it's too short. Returns where the header got mapped along with the mirror.
+    #[cfg(mapped)]
+    pub(crate) fn with_header(
+        size: NonZeroUsize,
+        header_len: NonZeroUsize,
+        fd: Option<OwnedFd>,
+    ) -> Result<(Self, *mut u8)> {
+        let options = MapOptions {
+            backend: Some(BackendKind::Reserve),
+            // Poisoning would clobber whatever another process already put in `fd`.
+            debug_fill: false,
+            ..MapOptions::default()
+        };
+        let file_len = size
+            .checked_add(header_len.get())
+            .ok_or(BufError::CapacityOverflow)?;
+        let mut mapping = match fd {
+            None => Mapping::create(file_len.get(), &options.fd)?,
+            Some(fd) => {
+                let leak_scope = Scope::current();
+                leak_scope.created(Resource::MemFd);
+                let mapping = Mapping::with_fd(fd, None, leak_scope);
+                // Anything mapped past the end of the file would SIGBUS when touched.
+                if (fstat(mapping.fd.as_raw_fd())?.st_size as u64) < file_len.get() as u64 {
+                    return Err(BufError::CapacityMismatch.into());
+                }
+                mapping
+            }
+        };
+        let total = file_len
+            .checked_add(size.get())
+            .ok_or(BufError::CapacityOverflow)?;
+        unsafe {
+            mapping.reserve(total, options.granularity()?)?;
+            let header = mapping.ptr;
+            mapping.map_view(OsOp::MapLow, header, header_len, 0, &options)?;
+            let views = header.add(header_len.get());
+            mapping.map_view(OsOp::MapLow, views, size, header_len.get(), &options)?;
+            mapping.map_view(
+                OsOp::MapHigh,
+                views.add(size.get()),
+                size,
+                header_len.get(),
+                &options,
+            )?;
+        }
+        let header = mapping.ptr;
+        let mirror = Self::in_mapping(
+            Arc::new(mapping),
+            header_len.get(),
+            size,
+            header_len.get(),
+            options,
+            BackendKind::Reserve,
+        );
+        Ok((mirror, header))
+    }
+
+    /// `count` mirrors of `size` bytes each, sharing one memfd and one reservation. Mirror `i`
+    /// covers bytes `i * size..(i + 1) * size` of the memfd and sits at `2 * size * i` in the
+    /// reservation, so every mirror still gets its own pair of back-to-back views. `size` must be
+    /// a multiple of `options.granularity()`. Heap mirrors get an allocation each instead.
+    pub(crate) fn group(
+        size: NonZeroUsize,
+        count: NonZeroUsize,
+        options: MapOptions,
+    ) -> Result<Vec<Self>> {
+        debug_assert_eq!(size.get() % options.granularity()?, 0);
+        if options.heap() {
+            return (0..count.get())
+                .map(|_| Self::with_options(size, options.clone()))
+                .collect();
+        }
+        Self::group_mapped(size, count, options)
+    }
+
+    /// `group` for every backend but `BackendKind::Heap`.
+    #[cfg(mapped)]
+    fn group_mapped(
+        size: NonZeroUsize,
+        count: NonZeroUsize,
+        options: MapOptions,
+    ) -> Result<Vec<Self>> {
+        let total = size
+            .checked_mul(count)
+            .and_then(|total| total.checked_mul(NonZeroUsize::new(2).unwrap()))
+            .ok_or(BufError::CapacityOverflow)?;
+        let mut mapping = Mapping::create(total.get() / 2, &options.fd)?;
+        unsafe {
+            mapping.reserve(total, options.granularity()?)?;
+            for i in 0..count.get() {
+                let at = mapping.ptr.add(2 * size.get() * i);
+                let file_offset = size.get() * i;
+                mapping.map_view(OsOp::MapLow, at, size, file_offset, &options)?;
+                mapping.map_view(
+                    OsOp::MapHigh,
+                    at.add(size.get()),
+                    size,
+                    file_offset,
+                    &options,
+                )?;
+            }
+        }
+        let mapping = Arc::new(mapping);
+        Ok((0..count.get())
+            .map(|i| {
+                Self::in_mapping(
+                    Arc::clone(&mapping),
+                    2 * size.get() * i,
+                    size,
+                    size.get() * i,
+                    options.clone(),
+                    BackendKind::Reserve,
+                )
+            })
+            .collect())
+    }
+
+    /// The mirror `offset` bytes into `mapping`, which is fully mapped. Applies the THP hint and
+    /// the debug fill.
+    #[cfg(mapped)]
+    fn in_mapping(
+        mapping: Arc<Mapping>,
+        offset: usize,
+        size: NonZeroUsize,
+        file_offset: usize,
+        options: MapOptions,
+        backend: BackendKind,
+    ) -> Self {
+        let ptr = unsafe { mapping.ptr.add(offset) };
+        let thp_advised = options
+            .thp
+            .is_some_and(|enable| unsafe { advise_thp(ptr, 2 * size.get(), enable) });
+        if options.debug_fill && !options.read_only {
+            unsafe { std::ptr::write_bytes(ptr, POISON, size.get()) };
+        }
+        Self {
+            ptr,
+            size: size.get(),
+            file_offset,
+            backing: Backing::Mapped(mapping),
+            options,
+            thp_advised,
+            locked: false,
+            backend,
+        }
+    }
+
+    /// A `BackendKind::Heap` mirror whose views start at `ptr` in `heap`.
+    fn on_heap(heap: Arc<HeapViews>, ptr: *mut u8, size: usize, options: MapOptions) -> Self {
+        Self {
+            ptr,
+            size,
+            #[cfg(mapped)]
+            file_offset: 0,
+            backing: Backing::Heap(heap),
+            options,
+            thp_advised: false,
+            locked: false,
+            backend: BackendKind::Heap,
+        }
+    }
+
+    /// `None` for heap mirrors, which have no fd.
+    pub(crate) fn fd_source(&self) -> Option<FdSource> {
+        match &self.backing {
+            #[cfg(mapped)]
+            Backing::Mapped(mapping) => mapping.source,
+            Backing::Heap(_) => None,
+        }
+    }
+
+    /// The fd the views map, or `None` for heap mirrors.
+    pub(crate) fn fd(&self) -> Option<BorrowedFd<'_>> {
+        match &self.backing {
+            #[cfg(mapped)]
+            Backing::Mapped(mapping) => Some(mapping.fd.as_fd()),
+            Backing::Heap(_) => None,
+        }
+    }
+
+    /// Where the mirrored bytes start in the fd from `fd`.
+    #[cfg(feature = "linux-splice")]
+    pub(crate) fn file_offset(&self) -> usize {
+        self.file_offset
+    }
+
+    /// Locks both views into memory and faults every page in, so nothing touching the ring
+    /// afterwards can page fault. Undone on drop. Fails with `BufError::MemoryLockLimit` if that
+    /// would go over `RLIMIT_MEMLOCK`.
+    #[cfg(mapped)]
+    pub(crate) fn lock(&mut self) -> Result<()> {
+        if self.locked {
+            return Ok(());
+        }
+        let len = 2 * self.size;
+        os_call(OsOp::Mlock, || unsafe {
+            mlock(NonNull::new_unchecked(self.ptr as *mut c_void), len)
+        })
+        .map_err(|e| match e {
+            // EPERM is what a limit of zero looks like without CAP_IPC_LOCK.
+            Errno::ENOMEM | Errno::EPERM => BufError::MemoryLockLimit.into(),
+            e => Error::from(e),
+        })?;
+        self.locked = true;
+        self.options.mlock = true;
+        // `mlock` faults the pages in already, but only touching them fills in the page tables.
+        for offset in (0..len).step_by(page_size()?) {
+            unsafe { self.ptr.add(offset).read_volatile() };
+        }
+        Ok(())
+    }
+
+    /// Hands the pages behind the views back to the kernel by punching them out of the fd. They
+    /// read as zeros afterwards, and get faulted back in as they're written. Only does anything
+    /// on Linux: heap mirrors and other platforms keep their memory.
+    pub(crate) fn release(&self) -> Result<()> {
+        #[cfg(all(mapped, target_os = "linux"))]
+        if let Backing::Mapped(mapping) = &self.backing {
+            use nix::fcntl::{fallocate, FallocateFlags};
+
+            let punch = FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE;
+            os_call(OsOp::PunchHole, || {
+                fallocate(
+                    mapping.fd.as_raw_fd(),
+                    punch,
+                    self.file_offset as libc::off_t,
+                    self.size as libc::off_t,
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Whether both mirrors map the same bytes of the same memfd (or share the same heap views).
+    pub(crate) fn same_file(&self, other: &Mirror) -> bool {
+        match (&self.backing, &other.backing) {
+            (Backing::Heap(a), Backing::Heap(b)) => Arc::ptr_eq(a, b),
+            #[cfg(mapped)]
+            (Backing::Mapped(ours), Backing::Mapped(theirs)) => {
+                let id = |m: &Mapping| fstat(m.fd.as_raw_fd()).map(|st| (st.st_dev, st.st_ino));
+                self.file_offset == other.file_offset
+                    && matches!((id(ours), id(theirs)), (Ok(a), Ok(b)) if a == b)
+            }
+            #[cfg(mapped)]
+            _ => false,
+        }
+    }
+
+    /// Makes the `len` bytes at `offset` look the same from the other view. A no-op unless the
+    /// views are separate memory, i.e. for `BackendKind::Heap`, where it has to follow every
+    /// write through `ptr`.
+    ///
+    /// # Safety
+    /// `offset + len` can run into the second view but not past it, and `len` can't be more than
+    /// one view.
+    #[inline]
+    pub(crate) unsafe fn sync(&self, offset: usize, len: usize) {
+        if self.backend != BackendKind::Heap {
+            return;
+        }
+        debug_assert!(len <= self.size && offset + len <= 2 * self.size);
+        let end = offset + len;
+        // Whatever landed in the first view goes to the second...
+        let low_end = end.min(self.size);
+        if offset < low_end {
+            std::ptr::copy_nonoverlapping(
+                self.ptr.add(offset),
+                self.ptr.add(offset + self.size),
+                low_end - offset,
+            );
+        }
+        // ...and whatever landed in the second goes back to the first.
+        let high_start = offset.max(self.size);
+        if high_start < end {
+            std::ptr::copy_nonoverlapping(
+                self.ptr.add(high_start),
+                self.ptr.add(high_start - self.size),
+                end - high_start,
+            );
+        }
+    }
+}
+
+/// Builds without `mapped` have nothing to map with or lock memory with, so they only ever get
+/// `BackendKind::Heap` mirrors, and unlocked ones at that.
+#[cfg(not(mapped))]
+impl Mirror {
+    fn map(_: NonZeroUsize, _: &MapOptions) -> Result<Self> {
+        Err(BufError::IncompatibleOptions("a mapped backend with a heap-only build").into())
+    }
+
+    fn group_mapped(_: NonZeroUsize, _: NonZeroUsize, _: MapOptions) -> Result<Vec<Self>> {
+        Err(BufError::IncompatibleOptions("a mapped backend with a heap-only build").into())
+    }
+
+    pub(crate) fn lock(&mut self) -> Result<()> {
+        Err(BufError::IncompatibleOptions("locked memory with a heap-only build").into())
+    }
+}
+
+impl Mirror {
+    /// Whether any of the mapping is actually backed by huge pages right now, going by
+    /// `/proc/self/smaps`. Best-effort: `false` if THP wasn't asked for or smaps can't be read.
+    /// Note that pages are only allocated once touched.
+    pub(crate) fn thp_backed(&self) -> bool {
+        if !(self.options.thp == Some(true) && self.thp_advised) {
+            return false;
+        }
+        let Ok(smaps) = std::fs::read_to_string("/proc/self/smaps") else {
+            return false;
+        };
+
+        let (start, end) = (self.ptr as usize, self.ptr as usize + 2 * self.size);
+        let mut in_mapping = false;
+        for line in smaps.lines() {
+            // Each mapping starts with a line like `7f0000000000-7f0000200000 rw-s ...`.
+            if let Some((lo, hi)) = line
+                .split_whitespace()
+                .next()
+                .and_then(|range| range.split_once('-'))
+                .and_then(|(lo, hi)| {
+                    Some((
+                        usize::from_str_radix(lo, 16).ok()?,
+                        usize::from_str_radix(hi, 16).ok()?,
+                    ))
+                })
+            {
+                in_mapping = lo < end && start < hi;
+                continue;
+            }
+            if !in_mapping {
+                continue;
+            }
+            let Some((field, value)) = line.split_once(':') else {
+                continue;
+            };
+            if matches!(field, "ShmemPmdMapped" | "FilePmdMapped" | "AnonHugePages")
+                && value
+                    .trim()
+                    .trim_end_matches("kB")
+                    .trim()
+                    .parse::<usize>()
+                    .is_ok_and(|kb| kb > 0)
+            {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Zeroes `len` bytes at `ptr` in a way the compiler can't optimize out, even if it can tell
+/// nothing reads them afterwards.
+///
+/// # Safety
+/// `ptr..ptr + len` must be valid for writes.
+pub(crate) unsafe fn zero_volatile(ptr: *mut u8, len: usize) {
+    for i in 0..len {
+        ptr.add(i).write_volatile(0);
+    }
+    std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+}
+
+/// Asks for (or against) transparent huge pages over `len` bytes at `ptr`, returning whether the
+/// kernel took the advice. Kernels without THP reject it, which just means we get normal pages.
+///
+/// # Safety
+/// `ptr..ptr + len` has to be mapped.
+#[cfg(all(mapped, any(target_os = "linux", target_os = "android")))]
+unsafe fn advise_thp(ptr: *mut u8, len: usize, enable: bool) -> bool {
+    use nix::sys::mman::{madvise, MmapAdvise};
+
+    let advice = if enable {
+        MmapAdvise::MADV_HUGEPAGE
+    } else {
+        MmapAdvise::MADV_NOHUGEPAGE
+    };
+    madvise(NonNull::new_unchecked(ptr as *mut c_void), len, advice).is_ok()
+}
+
+/// There's no THP outside Linux, so the hint is never taken.
+#[cfg(all(mapped, not(any(target_os = "linux", target_os = "android"))))]
+unsafe fn advise_thp(_: *mut u8, _: usize, _: bool) -> bool {
+    false
+}
+
+/// Reserves `map_size` bytes of address space starting at a multiple of `align`, which has to be
+/// a multiple of the page size.
+#[cfg(mapped)]
+unsafe fn reserve(map_size: NonZeroUsize, align: usize) -> nix::Result<*mut u8> {
+    if page_size().is_ok_and(|page| align <= page) {
+        return Ok(
+            mmap_anonymous(None, map_size, ProtFlags::PROT_NONE, MapFlags::MAP_PRIVATE)?.as_ptr()
+                as *mut u8,
+        );
+    }
+
+    // Over-reserve, then trim off whatever hangs over on either side of the aligned range.
+    let padded = map_size.get() + align;
+    let raw = mmap_anonymous(
+        None,
+        NonZeroUsize::new_unchecked(padded),
+        ProtFlags::PROT_NONE,
+        MapFlags::MAP_PRIVATE,
+    )?
+    .as_ptr() as *mut u8;
+    let start = (raw as usize).next_multiple_of(align) as *mut u8;
+    let before = start as usize - raw as usize;
+    let after = padded - before - map_size.get();
+    let trimmed = (|| {
+        if before > 0 {
+            munmap(NonNull::new_unchecked(raw as *mut c_void), before)?;
+        }
+        if after > 0 {
+            let tail = start.add(map_size.get());
+            munmap(NonNull::new_unchecked(tail as *mut c_void), after)?;
+        }
+        Ok(())
+    })();
+    if let Err(e) = trimmed {
+        // Unmapping ranges that are already gone is fine.
+        let _ = munmap(NonNull::new_unchecked(raw as *mut c_void), padded);
+        return Err(e);
+    }
+    Ok(start)
+}
+
+impl Drop for Mirror {
+    fn drop(&mut self) {
+        if self.options.zeroize && !self.options.read_only {
+            // Both views share the same pages, so one view's worth covers everything. Unless
+            // they're on the heap.
+            let views = if self.backend == BackendKind::Heap {
+                2
+            } else {
+                1
+            };
+            unsafe { zero_volatile(self.ptr, views * self.size) };
+        }
+        #[cfg(mapped)]
+        if self.locked {
+            // Nothing to be done about a failure, and the unmapping unlocks anyway.
+            let _ = unsafe {
+                munlock(
+                    NonNull::new_unchecked(self.ptr as *mut c_void),
+                    2 * self.size,
+                )
+            };
+        }
+        // The mapping itself goes once the last mirror in it does.
+    }
+}
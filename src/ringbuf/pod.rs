@@ -0,0 +1,20 @@
+//! Types that are safe to move through the buffer as raw bytes.
+
+/// Plain old data: types with no padding, no pointers or ownership, and for which every bit
+/// pattern is a valid value. Copying such a value in and out of the mapping byte-for-byte is
+/// all it takes to move it.
+///
+/// # Safety
+/// Implementors must be `#[repr(C)]` (or primitive) without padding bytes, must not contain
+/// references, pointers or anything with a `Drop` impl, and must accept any bit pattern.
+pub unsafe trait Pod: Copy + 'static {}
+
+macro_rules! impl_pod {
+    ($($t:ty),* $(,)?) => {
+        $(unsafe impl Pod for $t {})*
+    };
+}
+
+impl_pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
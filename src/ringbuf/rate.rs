@@ -0,0 +1,289 @@
+//! A token bucket in front of a `Producer`, so one chatty writer can't take all of a ring's
+//! downstream bandwidth.
+
+use super::{frame_len, BufError, Clock, Producer, Result, MSG_HEADER_LEN};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// A `Producer` that lets bytes through at `bytes_per_sec` on average, and at most `burst` at
+/// once after a quiet spell. Every byte written, message headers included, takes a token from a
+/// bucket of `burst` that refills at the rate, and starts out full.
+///
+/// `write` and `write_msg` fail with `BufError::Throttled` when the bucket is short, and the
+/// `_blocking` kinds sleep until it isn't. Time is the producer's clock, from
+/// `RingBuf::set_clock` before the split, so a `MockClock` can stand in for it; sleeping on one
+/// just moves it on. A write bigger than `burst` never gets enough tokens, so it fails straight
+/// away with `BufError::NotEnoughSpace`. Tokens are only taken for writes that go through, so
+/// one turned away for lack of room in the ring costs nothing.
+pub struct RateLimitedProducer {
+    producer: Producer,
+    clock: Arc<dyn Clock>,
+    bytes_per_sec: u64,
+    burst: u64,
+    tokens: u64,
+    // Fractions of a token earned but not yet whole, in billionths.
+    carry: u128,
+    refilled_at: Instant,
+    stats: ThrottleStats,
+}
+
+/// See `RateLimitedProducer::throttle_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ThrottleStats {
+    /// Bytes let through.
+    pub bytes: u64,
+    /// Writes that found the bucket short, whether they failed or slept.
+    pub throttled: u64,
+    /// How long the `_blocking` writes have slept for tokens in all.
+    pub waited: Duration,
+}
+
+impl RateLimitedProducer {
+    /// A rate of zero lets nothing more through once the first `burst` bytes are spent.
+    pub fn new(producer: Producer, bytes_per_sec: u64, burst: usize) -> Self {
+        let clock = producer.clock();
+        Self {
+            refilled_at: clock.now(),
+            producer,
+            clock,
+            bytes_per_sec,
+            burst: burst as u64,
+            tokens: burst as u64,
+            carry: 0,
+            stats: ThrottleStats::default(),
+        }
+    }
+
+    /// Writes all of `raw` like `Producer::write`, or fails with `BufError::Throttled` if the
+    /// bucket doesn't hold enough tokens for it yet.
+    pub fn write(&mut self, raw: &[u8]) -> Result<()> {
+        self.take(raw.len(), false, |producer| producer.write(raw))
+    }
+
+    /// `write` for one message, as from `Producer::write_msg`.
+    pub fn write_msg(&mut self, payload: &[u8]) -> Result<()> {
+        frame_len(payload, MSG_HEADER_LEN, self.producer.capacity())?;
+        let size = MSG_HEADER_LEN + payload.len();
+        self.take(size, false, |producer| producer.write_msg(payload))
+    }
+
+    /// `write` that sleeps until there are enough tokens, then waits for room in the ring like
+    /// `Producer::write_blocking`.
+    pub fn write_blocking(&mut self, raw: &[u8]) -> Result<()> {
+        self.take(raw.len(), true, |producer| producer.write_blocking(raw))
+    }
+
+    /// `write_msg` that sleeps until there are enough tokens, then waits for room in the ring.
+    pub fn write_msg_blocking(&mut self, payload: &[u8]) -> Result<()> {
+        let len = frame_len(payload, MSG_HEADER_LEN, self.producer.capacity())?;
+        let frame = [&len.to_le_bytes()[..], payload].concat();
+        self.take(frame.len(), true, |producer| {
+            producer.write_blocking(&frame)
+        })
+    }
+
+    /// Changes the rate from now on. Tokens earned at the old one so far are kept.
+    pub fn set_rate(&mut self, bytes_per_sec: u64) {
+        self.refill();
+        self.bytes_per_sec = bytes_per_sec;
+    }
+
+    /// How many bytes could go through right now, as far as the bucket is concerned.
+    pub fn available_tokens(&mut self) -> usize {
+        self.refill();
+        self.tokens as usize
+    }
+
+    /// How the bucket has held the producer back since it was made.
+    pub fn throttle_stats(&self) -> ThrottleStats {
+        self.stats
+    }
+
+    pub fn into_inner(self) -> Producer {
+        self.producer
+    }
+
+    /// Waits for (or fails for want of) `n` tokens, and takes them if `write` succeeds.
+    fn take(
+        &mut self,
+        n: usize,
+        blocking: bool,
+        write: impl FnOnce(&mut Producer) -> Result<()>,
+    ) -> Result<()> {
+        if n as u64 > self.burst {
+            return Err(BufError::NotEnoughSpace {
+                requested: n,
+                available: self.burst as usize,
+            }
+            .into());
+        }
+        self.refill();
+        if self.tokens < n as u64 {
+            self.stats.throttled += 1;
+            let wait = self.time_for(n as u64 - self.tokens);
+            if !blocking {
+                return Err(BufError::Throttled { retry_after: wait }.into());
+            }
+            self.clock.sleep(wait);
+            self.stats.waited = self.stats.waited.saturating_add(wait);
+            self.refill();
+        }
+        write(&mut self.producer)?;
+        self.tokens -= n as u64;
+        self.stats.bytes += n as u64;
+        Ok(())
+    }
+
+    /// Adds the tokens earned since the last refill, up to `burst`.
+    fn refill(&mut self) {
+        let now = self.clock.now();
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_nanos();
+        self.refilled_at = now;
+        let earned = self.carry + elapsed * self.bytes_per_sec as u128;
+        let tokens = self.tokens as u128 + earned / NANOS_PER_SEC;
+        if tokens >= self.burst as u128 {
+            self.tokens = self.burst;
+            self.carry = 0;
+        } else {
+            self.tokens = tokens as u64;
+            self.carry = earned % NANOS_PER_SEC;
+        }
+    }
+
+    /// How long until `more` tokens have been earned.
+    fn time_for(&self, more: u64) -> Duration {
+        if self.bytes_per_sec == 0 {
+            return Duration::MAX;
+        }
+        let owed = more as u128 * NANOS_PER_SEC - self.carry;
+        let nanos = owed.div_ceil(self.bytes_per_sec as u128);
+        Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Consumer, Error, MockClock, RingBuf};
+    use super::*;
+
+    fn limited(bytes_per_sec: u64, burst: usize) -> (RateLimitedProducer, Consumer, MockClock) {
+        let clock = MockClock::new();
+        let mut ring = RingBuf::new(1).unwrap();
+        ring.set_clock(clock.clone());
+        let (producer, consumer) = ring.split().unwrap();
+        let limited = RateLimitedProducer::new(producer, bytes_per_sec, burst);
+        (limited, consumer, clock)
+    }
+
+    fn throttled_for(result: Result<()>) -> Duration {
+        match result {
+            Err(Error::Ours(BufError::Throttled { retry_after })) => retry_after,
+            other => panic!("Expected to be throttled, got {other:?}."),
+        }
+    }
+
+    #[test]
+    fn a_full_bucket_then_the_rate() {
+        let us = Duration::from_micros;
+        let (mut limited, _consumer, clock) = limited(1000, 100);
+        limited.write(&[0; 100]).unwrap();
+        assert_eq!(throttled_for(limited.write(&[0; 1])), us(1000));
+        assert_eq!(throttled_for(limited.write(&[0; 10])), us(10_000));
+
+        // Half a token isn't one, but it counts towards the next.
+        clock.advance(us(500));
+        assert_eq!(limited.available_tokens(), 0);
+        assert_eq!(throttled_for(limited.write(&[0; 1])), us(500));
+        clock.advance(us(9500));
+        limited.write(&[0; 10]).unwrap();
+        assert_eq!(limited.available_tokens(), 0);
+
+        // However long it's quiet, the bucket holds `burst`.
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(limited.available_tokens(), 100);
+        limited.write(&[0; 100]).unwrap();
+        assert!(limited.write(&[0; 1]).is_err());
+        assert_eq!(
+            limited.throttle_stats(),
+            ThrottleStats {
+                bytes: 210,
+                throttled: 4,
+                waited: Duration::ZERO,
+            }
+        );
+    }
+
+    #[test]
+    fn the_long_run_rate_is_exact() {
+        let (mut limited, mut consumer, clock) = limited(3000, 64);
+        let start = clock.now();
+        for _ in 0..1000 {
+            limited.write_blocking(&[7; 30]).unwrap();
+            consumer.consume(consumer.len()).unwrap();
+        }
+        // The first 64 bytes were in the bucket, and the other 29,936 came at 3000 a second.
+        let took = clock.now() - start;
+        assert_eq!(
+            took,
+            Duration::from_nanos(29_936 * 1_000_000_000 / 3000 + 1)
+        );
+        let stats = limited.throttle_stats();
+        assert_eq!(stats.bytes, 30_000);
+        assert_eq!(stats.waited, took);
+        assert_eq!(stats.throttled, 998);
+    }
+
+    #[test]
+    fn messages_pay_for_their_headers() {
+        let (mut limited, mut consumer, clock) = limited(1000, 2 * (MSG_HEADER_LEN + 10));
+        limited.write_msg(&[1; 10]).unwrap();
+        limited.write_msg_blocking(&[2; 10]).unwrap();
+        assert!(limited.write_msg(&[3; 10]).is_err());
+        let start = clock.now();
+        limited.write_msg_blocking(&[3; 10]).unwrap();
+        let took = Duration::from_millis((MSG_HEADER_LEN + 10) as u64);
+        assert_eq!(clock.now() - start, took);
+        assert_eq!(limited.throttle_stats().waited, took);
+        let mut buf = Vec::new();
+        for expected in 1..=3 {
+            consumer.recv_msg_into(&mut buf).unwrap();
+            assert_eq!(buf, [expected; 10]);
+        }
+    }
+
+    #[test]
+    fn changing_the_rate_and_the_limits() {
+        let us = Duration::from_micros;
+        let (mut limited, _consumer, clock) = limited(1000, 100);
+        limited.write(&[0; 100]).unwrap();
+        clock.advance(us(1500));
+        limited.set_rate(2000);
+        // The token and a half from before are kept, and the rest come twice as fast.
+        assert_eq!(limited.available_tokens(), 1);
+        assert_eq!(throttled_for(limited.write(&[0; 2])), us(250));
+
+        // Too big for the bucket ever to hold enough.
+        assert!(matches!(
+            limited.write(&[0; 101]),
+            Err(Error::Ours(BufError::NotEnoughSpace {
+                requested: 101,
+                available: 100
+            }))
+        ));
+
+        // A full ring costs no tokens.
+        let mut producer = limited.into_inner();
+        producer.write(&vec![0; producer.free_space()]).unwrap();
+        let mut limited = RateLimitedProducer::new(producer, 0, 10);
+        assert!(matches!(
+            limited.write(&[0; 10]),
+            Err(Error::Ours(BufError::NotEnoughSpace { .. }))
+        ));
+        assert_eq!(limited.available_tokens(), 10);
+        assert_eq!(limited.throttle_stats(), ThrottleStats::default());
+    }
+}
@@ -0,0 +1,404 @@
+//! Where the shared memory behind a mirror comes from. `memfd_create` where the kernel allows it;
+//! where it doesn't (kernels before 3.17, or a seccomp filter like the one on Android before API
+//! 30) we fall back to ashmem on Android and to POSIX shared memory everywhere else. Which one
+//! works is only known at runtime, so the same binary copes with all of them. Platforms without
+//! memfd at all, like macOS, go straight to POSIX shared memory.
+
+#[cfg(mapped)]
+use super::fault::{os_call, OsOp};
+use super::{BufError, Result};
+#[cfg(all(mapped, any(target_os = "linux", target_os = "android")))]
+use nix::errno::Errno;
+#[cfg(mapped)]
+use nix::unistd::ftruncate;
+#[cfg(mapped)]
+use std::os::fd::OwnedFd;
+use std::{ffi::CStr, sync::Arc};
+
+/// What kind of fd a ring's memory lives in. See `RingBuf::fd_source`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdSource {
+    MemFd,
+    /// Android's `/dev/ashmem`, for devices that block `memfd_create`.
+    Ashmem,
+    /// An unlinked POSIX shared memory object, the last resort.
+    PosixShm,
+}
+
+/// How the fd should be made. See `RingBufBuilder::name`, `cloexec` and `seal`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FdOptions {
+    /// What the fd shows up as in `/proc/<pid>/fd` and `/proc/<pid>/maps`. Checked by
+    /// `fd_name`. Shared, since options get cloned for every ring a builder makes.
+    pub(crate) name: Arc<CStr>,
+    pub(crate) cloexec: bool,
+    /// Seal the memfd's size once it's set.
+    pub(crate) seal: bool,
+    /// Back the memfd with 2 MiB hugetlb pages. Only memfds can be.
+    pub(crate) hugetlb: bool,
+}
+
+impl Default for FdOptions {
+    fn default() -> Self {
+        Self {
+            name: c"ringbuf".into(),
+            cloexec: true,
+            seal: false,
+            hugetlb: false,
+        }
+    }
+}
+
+/// The longest name `memfd_create` takes, not counting the NUL.
+const MAX_NAME_LEN: usize = 249;
+
+/// Checks `name` is usable as an fd name and makes a C string of it. Fails with
+/// `BufError::InvalidName` if it has a NUL in it or is too long for `memfd_create`.
+pub(crate) fn fd_name(name: &str) -> Result<Arc<CStr>> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(BufError::InvalidName.into());
+    }
+    let owned = std::ffi::CString::new(name).map_err(|_| BufError::InvalidName)?;
+    Ok(owned.into())
+}
+
+/// A fresh, anonymous shared memory fd of size zero. Trying each source in turn, as long as the
+/// previous one failed in a way that means it isn't available here rather than that it ran into
+/// a real problem like running out of fds.
+#[cfg(mapped)]
+pub(crate) fn open(options: &FdOptions) -> Result<(OwnedFd, FdSource)> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    match os_call(OsOp::MemfdCreate, || open_memfd(options)) {
+        Ok(fd) => return Ok((fd, FdSource::MemFd)),
+        Err(e) if !unavailable(e) => return Err(e.into()),
+        Err(_) => {}
+    }
+    // None of the fallbacks can do hugetlb pages.
+    if options.hugetlb {
+        return Err(BufError::HugePagesUnavailable.into());
+    }
+
+    // Bionic has no `shm_open`, so ashmem is the last resort there.
+    #[cfg(target_os = "android")]
+    return Ok((
+        os_call(OsOp::AshmemCreate, || ashmem::open(options))?,
+        FdSource::Ashmem,
+    ));
+
+    #[cfg(not(target_os = "android"))]
+    Ok((
+        os_call(OsOp::ShmOpen, || posix::open(options.cloexec))?,
+        FdSource::PosixShm,
+    ))
+}
+
+/// The operation that creates the fd when nothing gets in the way, for tests that make it fail.
+#[cfg(all(mapped, test))]
+pub(crate) const CREATE_OP: OsOp = if cfg!(any(target_os = "linux", target_os = "android")) {
+    OsOp::MemfdCreate
+} else {
+    OsOp::ShmOpen
+};
+
+#[cfg(all(mapped, any(target_os = "linux", target_os = "android")))]
+fn open_memfd(options: &FdOptions) -> nix::Result<OwnedFd> {
+    use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+
+    let mut flags = MemFdCreateFlag::empty();
+    if options.cloexec {
+        flags |= MemFdCreateFlag::MFD_CLOEXEC;
+    }
+    if options.seal {
+        flags |= MemFdCreateFlag::MFD_ALLOW_SEALING;
+    }
+    if options.hugetlb {
+        flags |= MemFdCreateFlag::MFD_HUGETLB | MemFdCreateFlag::MFD_HUGE_2MB;
+    }
+    // I forget why we need the FD to do this trick.
+    // Apparently the file system guarantees we have this page unperturbed?
+    memfd_create(&options.name, flags)
+}
+
+/// Grows a fresh fd from `open` to `len` bytes.
+#[cfg(mapped)]
+pub(crate) fn set_size(fd: &OwnedFd, source: FdSource, len: usize) -> Result<()> {
+    os_call(OsOp::Ftruncate, || match source {
+        #[cfg(target_os = "android")]
+        FdSource::Ashmem => ashmem::set_size(fd, len),
+        _ => ftruncate(fd, len as i64),
+    })?;
+    Ok(())
+}
+
+/// Stops the fd's size from ever changing again, and its seals along with it, if `options` ask
+/// for it. Only memfds can be sealed; fds from the fallbacks are left as they are.
+#[cfg(mapped)]
+pub(crate) fn seal(fd: &OwnedFd, source: FdSource, options: &FdOptions) -> Result<()> {
+    if !options.seal || source != FdSource::MemFd {
+        return Ok(());
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    os_call(OsOp::Seal, || {
+        use nix::fcntl::{fcntl, FcntlArg, SealFlag};
+        use std::os::fd::AsRawFd;
+
+        let seals = SealFlag::F_SEAL_GROW | SealFlag::F_SEAL_SHRINK | SealFlag::F_SEAL_SEAL;
+        fcntl(fd.as_raw_fd(), FcntlArg::F_ADD_SEALS(seals)).map(drop)
+    })?;
+    // There are no memfds anywhere else.
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = fd;
+    Ok(())
+}
+
+/// Errors that mean a source isn't there at all (an old kernel, a seccomp filter, a missing
+/// device) rather than that it failed.
+#[cfg(all(mapped, any(target_os = "linux", target_os = "android")))]
+fn unavailable(e: Errno) -> bool {
+    matches!(
+        e,
+        Errno::ENOSYS | Errno::EPERM | Errno::EACCES | Errno::ENOENT
+    )
+}
+
+#[cfg(all(mapped, not(target_os = "android")))]
+mod posix {
+    use nix::{
+        errno::Errno,
+        fcntl::OFlag,
+        sys::{
+            mman::{shm_open, shm_unlink},
+            stat::Mode,
+        },
+    };
+    use std::{
+        os::fd::OwnedFd,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    /// A shared memory object that's unlinked straight away, before anything else can fail, so
+    /// it's as anonymous as a memfd once this returns and no named segment is ever left behind.
+    pub(super) fn open(cloexec: bool) -> nix::Result<OwnedFd> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        // Darwin only accepts the POSIX flags here, and glibc adds `O_CLOEXEC` whether we ask or
+        // not, so close-on-exec gets settled separately. Asking up front where we can at least
+        // closes the window in which a concurrent `fork` could inherit the fd.
+        let flags = OFlag::O_RDWR | OFlag::O_CREAT | OFlag::O_EXCL;
+        #[cfg(not(target_vendor = "apple"))]
+        let flags = if cloexec {
+            flags | OFlag::O_CLOEXEC
+        } else {
+            flags
+        };
+        loop {
+            // Short enough for Darwin's 31-byte limit on shm names.
+            let name = format!(
+                "/ringbuf.{}.{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            );
+            match shm_open(name.as_str(), flags, Mode::S_IRUSR | Mode::S_IWUSR) {
+                Ok(fd) => {
+                    shm_unlink(name.as_str())?;
+                    set_cloexec(&fd, cloexec)?;
+                    return Ok(fd);
+                }
+                // Left behind by an earlier process with our pid; try the next name.
+                Err(Errno::EEXIST) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn set_cloexec(fd: &OwnedFd, cloexec: bool) -> nix::Result<()> {
+        use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+        use std::os::fd::AsRawFd;
+
+        let flags = if cloexec {
+            FdFlag::FD_CLOEXEC
+        } else {
+            FdFlag::empty()
+        };
+        fcntl(fd.as_raw_fd(), FcntlArg::F_SETFD(flags))?;
+        Ok(())
+    }
+}
+
+#[cfg(all(mapped, target_os = "android"))]
+mod ashmem {
+    use super::FdOptions;
+    use nix::{
+        errno::Errno,
+        fcntl::{open as open_path, OFlag},
+        sys::stat::Mode,
+    };
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    const NAME_LEN: usize = 256;
+
+    /// `_IOW(__ASHMEMIOC, nr, size)` from `linux/ashmem.h`.
+    const fn iow(nr: u32, size: usize) -> u32 {
+        (1 << 30) | ((size as u32) << 16) | (0x77 << 8) | nr
+    }
+
+    const SET_NAME: u32 = iow(1, NAME_LEN);
+    const SET_SIZE: u32 = iow(3, size_of::<usize>());
+
+    pub(super) fn open(options: &FdOptions) -> nix::Result<OwnedFd> {
+        let mut flags = OFlag::O_RDWR;
+        if options.cloexec {
+            flags |= OFlag::O_CLOEXEC;
+        }
+        let raw = open_path("/dev/ashmem", flags, Mode::empty())?;
+        let fd = unsafe { OwnedFd::from_raw_fd(raw) };
+        // `fd_name` keeps names short enough to leave room for the NUL.
+        let mut name = [0u8; NAME_LEN];
+        let bytes = options.name.to_bytes();
+        name[..bytes.len()].copy_from_slice(bytes);
+        Errno::result(unsafe { libc::ioctl(fd.as_raw_fd(), SET_NAME as _, name.as_ptr()) })?;
+        Ok(fd)
+    }
+
+    /// Ashmem regions can't be `ftruncate`d; the size has to be set before the first mmap.
+    pub(super) fn set_size(fd: &OwnedFd, len: usize) -> nix::Result<()> {
+        Errno::result(unsafe { libc::ioctl(fd.as_raw_fd(), SET_SIZE as _, len) }).map(drop)
+    }
+}
+
+#[cfg(all(test, mapped, target_os = "linux", not(feature = "portable")))]
+mod tests {
+    use super::super::{inject_failure, page_size, Error, LeakCheck, RingBuf, RingGroup};
+    use super::*;
+    use nix::fcntl::{fcntl, FcntlArg, FdFlag, SealFlag};
+    use std::os::fd::AsRawFd;
+
+    /// A ring built as if `memfd_create` were blocked.
+    fn without_memfd(pages: usize) -> RingBuf {
+        inject_failure(OsOp::MemfdCreate, Errno::ENOSYS, 0);
+        let ring = RingBuf::new(pages).unwrap();
+        assert_eq!(ring.fd_source(), Some(FdSource::PosixShm));
+        ring
+    }
+
+    #[test]
+    fn memfd_when_available() {
+        let ring = RingBuf::new(1).unwrap();
+        assert_eq!(ring.fd_source(), Some(FdSource::MemFd));
+        assert_eq!(RingBuf::default().fd_source(), None);
+    }
+
+    #[test]
+    fn fallback_behaves_like_memfd() {
+        let _leaks = LeakCheck::new();
+        let mut ring = without_memfd(2);
+        for i in 0..50usize {
+            let data: Vec<u8> = (0..(i * 331) % 8000).map(|j| (i ^ j) as u8).collect();
+            ring.write(&data).unwrap();
+            assert_eq!(ring.read(data.len()).unwrap(), &data[..]);
+        }
+
+        // Mirrored: a write across the end reads back in one piece.
+        ring.write(&[1; 3000]).unwrap();
+        let mut tap = ring.try_clone_reader().unwrap();
+        assert_eq!(tap.read(3000).unwrap(), &[1; 3000][..]);
+        assert_eq!(ring.read(3000).unwrap(), &[1; 3000][..]);
+
+        inject_failure(OsOp::MemfdCreate, Errno::EPERM, 0);
+        ring.write(b"kept").unwrap();
+        ring.shrink_to_fit().unwrap();
+        assert_eq!(ring.fd_source(), Some(FdSource::PosixShm));
+        assert_eq!(ring.read(4).unwrap(), b"kept");
+
+        inject_failure(OsOp::MemfdCreate, Errno::ENOSYS, 0);
+        let mut rings = RingGroup::new(page_size().unwrap(), 3)
+            .unwrap()
+            .into_rings();
+        rings[2].write(b"group").unwrap();
+        assert_eq!(rings[2].fd_source(), Some(FdSource::PosixShm));
+        assert_eq!(rings[2].read(5).unwrap(), b"group");
+    }
+
+    #[test]
+    fn real_memfd_errors_dont_fall_back() {
+        let _leaks = LeakCheck::new();
+        inject_failure(OsOp::MemfdCreate, Errno::EMFILE, 0);
+        assert!(RingBuf::new(1).is_err());
+
+        inject_failure(OsOp::MemfdCreate, Errno::ENOSYS, 0);
+        inject_failure(OsOp::ShmOpen, Errno::ENOSPC, 0);
+        assert!(RingBuf::new(1).is_err());
+    }
+
+    /// The `F_GETFD` flags and `F_GET_SEALS` seals of a ring's memfd, and what it links to.
+    fn fd_state(ring: &RingBuf) -> (FdFlag, SealFlag, String) {
+        let fd = ring.memfd().unwrap().as_raw_fd();
+        let flags = FdFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFD).unwrap());
+        let seals = SealFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GET_SEALS).unwrap());
+        let link = std::fs::read_link(format!("/proc/self/fd/{fd}")).unwrap();
+        (flags, seals, link.to_string_lossy().into_owned())
+    }
+
+    #[test]
+    fn named_and_sealed() {
+        let mut ring = RingBuf::builder()
+            .name("audio-in")
+            .seal(true)
+            .build()
+            .unwrap();
+        let (flags, seals, link) = fd_state(&ring);
+        assert!(flags.contains(FdFlag::FD_CLOEXEC));
+        assert_eq!(
+            seals,
+            SealFlag::F_SEAL_GROW | SealFlag::F_SEAL_SHRINK | SealFlag::F_SEAL_SEAL
+        );
+        assert_eq!(link, "/memfd:audio-in (deleted)");
+        let fd = ring.memfd().unwrap();
+        assert_eq!(ftruncate(fd, 0), Err(Errno::EPERM));
+
+        let page = page_size().unwrap();
+        ring.write(&vec![1; page - 2]).unwrap();
+        ring.consume(page - 2).unwrap();
+        ring.write(b"sealed").unwrap();
+        assert_eq!(ring.read(6).unwrap(), b"sealed");
+
+        // Names survive a shrink, which makes a new memfd.
+        let mut big = RingBuf::builder().pages(2).name("big").build().unwrap();
+        big.shrink_to_fit().unwrap();
+        assert_eq!(fd_state(&big).2, "/memfd:big (deleted)");
+    }
+
+    #[test]
+    fn cloexec_by_default() {
+        let (flags, seals, link) = fd_state(&RingBuf::new(1).unwrap());
+        assert!(flags.contains(FdFlag::FD_CLOEXEC));
+        // Memfds made without `MFD_ALLOW_SEALING` come sealed against more seals.
+        assert_eq!(seals, SealFlag::F_SEAL_SEAL);
+        assert_eq!(link, "/memfd:ringbuf (deleted)");
+
+        let inherited = RingBuf::builder().cloexec(false).build().unwrap();
+        assert!(!fd_state(&inherited).0.contains(FdFlag::FD_CLOEXEC));
+        let fallback = {
+            inject_failure(OsOp::MemfdCreate, Errno::ENOSYS, 0);
+            RingBuf::builder()
+                .cloexec(false)
+                .seal(true)
+                .build()
+                .unwrap()
+        };
+        assert_eq!(fallback.fd_source(), Some(FdSource::PosixShm));
+        assert!(!fd_state(&fallback).0.contains(FdFlag::FD_CLOEXEC));
+    }
+
+    #[test]
+    fn invalid_names() {
+        for name in ["nul\0inside", &"x".repeat(MAX_NAME_LEN + 1)] {
+            assert!(matches!(
+                RingBuf::builder().name(name).build(),
+                Err(Error::Ours(BufError::InvalidName))
+            ));
+            assert!(RingBuf::builder().name(name).lazy(true).build().is_err());
+        }
+        let longest = "x".repeat(MAX_NAME_LEN);
+        RingBuf::builder().name(&longest).build().unwrap();
+    }
+}
@@ -0,0 +1,364 @@
+//! A ring of fixed-size records. Instead of a byte stream, the buffer is carved into slots of
+//! `size_of::<T>()` bytes, each push claims exactly one, and any slot that hasn't been popped yet
+//! can be looked up directly by the index its push returned.
+
+use super::{mirror::Mirror, page_size, BufError, Pod, Result};
+use std::{
+    marker::PhantomData,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// Names a slot filled by `SlotRing::push`. Slots get reused as the ring wraps, so an index also
+/// carries a generation (how many times the ring had wrapped when it was handed out), which is
+/// what lets `get` tell a live slot from a recycled one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SlotIndex {
+    // Pushes so far when this slot was claimed. Slot and generation both fall out of it.
+    seq: u64,
+    slot_count: u64,
+}
+
+impl SlotIndex {
+    pub fn slot(&self) -> usize {
+        (self.seq % self.slot_count) as usize
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.seq / self.slot_count
+    }
+}
+
+/// A ring buffer of `T` records.
+pub struct SlotRing<T: Pod> {
+    mirror: Mirror,
+    // Only whole slots are used; any leftover bytes at the end of the mapping are dead space, so
+    // a slot never runs past the wrap and every slot stays aligned.
+    slot_count: usize,
+    // Monotonic push/pop counters, see `SlotIndex`.
+    head: u64,
+    tail: u64,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Pod> SlotRing<T> {
+    /// Creates a ring with room for at least `min_slots` records, rounded up to fill whole pages.
+    pub fn new(min_slots: usize) -> Result<Self> {
+        let slot_size = size_of::<T>();
+        if min_slots == 0 || slot_size == 0 {
+            return Err(BufError::ZeroCapacity.into());
+        }
+        // Slot offsets are multiples of the size, which is a multiple of the alignment, so this
+        // is all it takes for every slot in the (page-aligned) mapping to be aligned.
+        let page_size = page_size()?;
+        assert!(align_of::<T>() <= page_size, "Alignment past a page.");
+
+        let buf_size = min_slots
+            .checked_mul(slot_size)
+            .and_then(|bytes| bytes.checked_next_multiple_of(page_size))
+            .ok_or(BufError::CapacityOverflow)?;
+        let mirror = Mirror::new(NonZeroUsize::new(buf_size).unwrap())?;
+
+        Ok(Self {
+            mirror,
+            slot_count: buf_size / slot_size,
+            head: 0,
+            tail: 0,
+            _marker: PhantomData,
+        })
+    }
+
+    /// How many records fit.
+    pub fn capacity(&self) -> usize {
+        self.slot_count
+    }
+
+    /// How many records are waiting to be popped.
+    pub fn len(&self) -> usize {
+        (self.tail - self.head) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head == self.tail
+    }
+
+    /// Copies `value` into the next free slot.
+    pub fn push(&mut self, value: &T) -> Result<SlotIndex> {
+        if self.len() == self.slot_count {
+            return Err(BufError::NotEnoughSpace {
+                requested: size_of::<T>(),
+                available: 0,
+            }
+            .into());
+        }
+
+        let index = self.index(self.tail);
+        unsafe { self.slot_ptr(index).write(*value) };
+        self.tail += 1;
+        Ok(index)
+    }
+
+    /// The record in `index`, if it hasn't been popped (and its slot reused) since.
+    pub fn get(&self, index: SlotIndex) -> Option<&T> {
+        if index.slot_count != self.slot_count as u64
+            || index.seq < self.head
+            || index.seq >= self.tail
+        {
+            return None;
+        }
+        unsafe { Some(&*self.slot_ptr(index)) }
+    }
+
+    /// Takes the oldest record out of the ring.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let value = unsafe { self.slot_ptr(self.index(self.head)).read() };
+        self.head += 1;
+        Some(value)
+    }
+
+    /// Splits the ring into a half that pushes and a half that pops, for handing records from one
+    /// thread to another without a lock. Whatever was waiting to be popped still is, and indices
+    /// handed out so far stay good for the consumer's `get`.
+    pub fn split(self) -> (SlotProducer<T>, SlotConsumer<T>) {
+        let (head, tail) = (self.head, self.tail);
+        let shared = Arc::new(SlotShared {
+            mirror: self.mirror,
+            slot_count: self.slot_count,
+            head: AtomicU64::new(head),
+            tail: AtomicU64::new(tail),
+            _marker: PhantomData,
+        });
+        (
+            SlotProducer {
+                shared: shared.clone(),
+                tail,
+            },
+            SlotConsumer { shared, head },
+        )
+    }
+
+    fn index(&self, seq: u64) -> SlotIndex {
+        index(seq, self.slot_count)
+    }
+
+    fn slot_ptr(&self, index: SlotIndex) -> *mut T {
+        slot_ptr(&self.mirror, index)
+    }
+}
+
+fn index(seq: u64, slot_count: usize) -> SlotIndex {
+    SlotIndex {
+        seq,
+        slot_count: slot_count as u64,
+    }
+}
+
+fn slot_ptr<T>(mirror: &Mirror, index: SlotIndex) -> *mut T {
+    unsafe { mirror.ptr.add(index.slot() * size_of::<T>()) as *mut T }
+}
+
+/// What both halves of a split `SlotRing` see. The producer only ever stores `tail` and the
+/// consumer only ever stores `head`.
+struct SlotShared<T> {
+    mirror: Mirror,
+    slot_count: usize,
+    head: AtomicU64,
+    tail: AtomicU64,
+    _marker: PhantomData<T>,
+}
+
+// SAFETY: Each slot is only touched by one half at a time: the producer until it publishes it in
+// `tail`, the consumer after, until it hands it back in `head`.
+unsafe impl<T: Pod> Send for SlotShared<T> {}
+unsafe impl<T: Pod> Sync for SlotShared<T> {}
+
+/// The pushing half of a split `SlotRing`. See `SlotRing::split`.
+pub struct SlotProducer<T: Pod> {
+    shared: Arc<SlotShared<T>>,
+    // Our own copy of `shared.tail`, which nobody else stores to.
+    tail: u64,
+}
+
+/// The popping half of a split `SlotRing`. See `SlotRing::split`.
+pub struct SlotConsumer<T: Pod> {
+    shared: Arc<SlotShared<T>>,
+    // Our own copy of `shared.head`, which nobody else stores to.
+    head: u64,
+}
+
+impl<T: Pod> SlotProducer<T> {
+    /// `SlotRing::push`. Fails with `BufError::NotEnoughSpace` until the consumer pops something.
+    pub fn push(&mut self, value: &T) -> Result<SlotIndex> {
+        // Acquire, so the consumer is done reading the slot before we overwrite it.
+        let head = self.shared.head.load(Ordering::Acquire);
+        if (self.tail - head) as usize == self.shared.slot_count {
+            return Err(BufError::NotEnoughSpace {
+                requested: size_of::<T>(),
+                available: 0,
+            }
+            .into());
+        }
+        let index = index(self.tail, self.shared.slot_count);
+        unsafe { slot_ptr::<T>(&self.shared.mirror, index).write(*value) };
+        self.tail += 1;
+        self.shared.tail.store(self.tail, Ordering::Release);
+        Ok(index)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.slot_count
+    }
+
+    /// How many records can be pushed right now. Only grows until the next `push`.
+    pub fn free_slots(&self) -> usize {
+        self.capacity() - (self.tail - self.shared.head.load(Ordering::Acquire)) as usize
+    }
+}
+
+impl<T: Pod> SlotConsumer<T> {
+    /// `SlotRing::pop`.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let index = index(self.head, self.shared.slot_count);
+        let value = unsafe { slot_ptr::<T>(&self.shared.mirror, index).read() };
+        self.head += 1;
+        // Release, so the read above is done before the producer can reuse the slot.
+        self.shared.head.store(self.head, Ordering::Release);
+        Some(value)
+    }
+
+    /// `SlotRing::get`. The producer can't reuse a slot until it's popped, which takes `&mut self`,
+    /// so the record can be lent out in place.
+    pub fn get(&self, index: SlotIndex) -> Option<&T> {
+        // Acquire, so the record the producer published is visible.
+        let tail = self.shared.tail.load(Ordering::Acquire);
+        if index.slot_count != self.shared.slot_count as u64
+            || index.seq < self.head
+            || index.seq >= tail
+        {
+            return None;
+        }
+        unsafe { Some(&*slot_ptr::<T>(&self.shared.mirror, index)) }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.slot_count
+    }
+
+    /// How many records are waiting to be popped. Only grows until the next `pop`.
+    pub fn len(&self) -> usize {
+        (self.shared.tail.load(Ordering::Acquire) - self.head) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ringbuf::{page_size, LeakCheck};
+
+    #[test]
+    fn slot_reuse_after_wrap() {
+        let _leaks = LeakCheck::new();
+        let mut ring = SlotRing::<u64>::new(1).expect("Creation should work.");
+        assert_eq!(ring.capacity(), page_size().unwrap() / 8);
+
+        let indices = (0..ring.capacity() as u64)
+            .map(|i| ring.push(&i).expect("Should fit."))
+            .collect::<Vec<_>>();
+        ring.push(&0).expect_err("Every slot is taken.");
+        assert_eq!(ring.get(indices[7]), Some(&7));
+
+        assert_eq!(ring.pop(), Some(0));
+        let reused = ring.push(&1000).expect("The popped slot is free again.");
+        assert_eq!(reused.slot(), indices[0].slot());
+        assert_eq!(reused.generation(), 1);
+        assert_eq!(ring.get(reused), Some(&1000));
+
+        // Drain and refill a few times over to make sure the wrap keeps working.
+        for round in 0..3u64 {
+            while ring.pop().is_some() {}
+            for i in 0..ring.capacity() as u64 {
+                ring.push(&(round * 10_000 + i)).expect("Should fit.");
+            }
+            assert_eq!(ring.pop(), Some(round * 10_000));
+        }
+    }
+
+    #[test]
+    fn stale_index() {
+        let _leaks = LeakCheck::new();
+        let mut ring = SlotRing::<[u32; 3]>::new(2).expect("Creation should work.");
+        let slots = ring.capacity();
+        let first = ring.push(&[1, 2, 3]).expect("Should fit.");
+        assert_eq!(ring.pop(), Some([1, 2, 3]));
+        assert_eq!(ring.get(first), None);
+
+        // Go all the way around so the same slot is live again, one generation later.
+        for i in 1..slots as u32 {
+            ring.push(&[i; 3]).expect("Should fit.");
+            ring.pop();
+        }
+        let current = ring.push(&[7; 3]).expect("Should fit.");
+        assert_eq!(current.slot(), first.slot());
+        assert_eq!(current.generation(), first.generation() + 1);
+        assert_eq!(ring.get(first), None);
+        assert_eq!(ring.get(current), Some(&[7; 3]));
+    }
+
+    #[test]
+    fn split_halves_across_threads() {
+        const RECORDS: u64 = 200_000;
+        let _leaks = LeakCheck::new();
+        let mut ring = SlotRing::<[u64; 2]>::new(16).expect("Creation should work.");
+        ring.push(&[0, 0]).expect("Should fit.");
+        let early = ring.push(&[1, 1]).expect("Should fit.");
+        let (mut producer, mut consumer) = ring.split();
+        assert_eq!(producer.free_slots(), producer.capacity() - 2);
+        assert_eq!(consumer.get(early), Some(&[1, 1]));
+        assert_eq!(consumer.pop(), Some([0, 0]));
+        assert_eq!(consumer.pop(), Some([1, 1]));
+        assert_eq!(consumer.get(early), None);
+
+        let pusher = std::thread::spawn(move || {
+            for i in 0..RECORDS {
+                while producer.push(&[i, !i]).is_err() {
+                    std::thread::yield_now();
+                }
+            }
+            producer
+        });
+        let mut next = 0;
+        while next < RECORDS {
+            match consumer.pop() {
+                Some(record) => {
+                    assert_eq!(record, [next, !next]);
+                    next += 1;
+                }
+                None => std::thread::yield_now(),
+            }
+        }
+        let mut producer = pusher.join().unwrap();
+        assert!(consumer.is_empty());
+
+        // Still the same slots and generations as the unsplit ring would hand out.
+        let index = producer.push(&[7, 7]).expect("Empty again.");
+        assert_eq!(
+            index.generation(),
+            (RECORDS + 2) / producer.capacity() as u64
+        );
+        assert_eq!(consumer.get(index), Some(&[7, 7]));
+    }
+}
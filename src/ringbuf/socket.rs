@@ -0,0 +1,454 @@
+//! Duplex channels of shared rings, set up over a Unix socket like a stream socket would be.
+//!
+//! The client sends a hello with the protocol version and how many pages it wants each ring to
+//! have. The listener answers with its own hello, giving the pages it settled on, and passes the
+//! memfds of two fresh rings from `RingBuf::new_shared` along with it, one for each direction.
+//! After that the socket has done its job, and everything goes through the rings.
+
+use super::{BufError, Consumer, Error, MsgEvent, Producer, Result, RingBuf};
+use nix::errno::Errno;
+use std::{
+    io::{self, Read, Write},
+    mem,
+    os::{
+        fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    ptr,
+};
+
+/// The version of the handshake. Both ends have to speak the same one.
+const VERSION: u16 = 1;
+const MAGIC: u32 = u32::from_le_bytes(*b"RNGS");
+const HELLO_LEN: usize = 16;
+
+/// Listens on a Unix socket and hands each client that connects with `RingStream::connect` a
+/// pair of shared rings, for a `RingStream` at either end.
+pub struct RingListener {
+    listener: UnixListener,
+    num_pages: usize,
+}
+
+impl RingListener {
+    /// Listens at `path`, offering clients rings of up to `num_pages` pages each way. Fails if
+    /// something is already there, like `UnixListener::bind`.
+    pub fn bind<P: AsRef<Path>>(path: P, num_pages: usize) -> Result<Self> {
+        if num_pages == 0 {
+            return Err(BufError::ZeroCapacity.into());
+        }
+        let listener = UnixListener::bind(path).map_err(from_io)?;
+        Ok(Self {
+            listener,
+            num_pages,
+        })
+    }
+
+    /// Waits for the next client and the handshake with it, then returns this end of the new
+    /// channel. The rings get the fewer of the pages the client asked for and the ones this
+    /// listener offers.
+    ///
+    /// Fails with `BufError::VersionMismatch` for a client speaking another version, which is
+    /// told so before the socket closes, and with `BufError::HandshakeDisconnected` if the client
+    /// goes away before the handshake is over. Anything that isn't a hello at all fails with
+    /// `EPROTO`. None of these stop the listener from accepting the next client.
+    pub fn accept(&self) -> Result<RingStream> {
+        let (mut socket, _) = self.listener.accept().map_err(from_io)?;
+        let mut hello = [0; HELLO_LEN];
+        socket.read_exact(&mut hello).map_err(handshake_error)?;
+        let (version, asked) = parse_hello(&hello)?;
+        if version != VERSION {
+            // So the client can say what went wrong too. It may be gone already, which is fine.
+            let _ = send_hello(&socket, 0, &[]);
+            return Err(BufError::VersionMismatch {
+                ours: VERSION,
+                theirs: version,
+            }
+            .into());
+        }
+        let num_pages = asked.min(self.num_pages);
+        if num_pages == 0 {
+            return Err(BufError::ZeroCapacity.into());
+        }
+        // SAFETY: Fresh rings, with only the other ends going to the client.
+        let producer = unsafe { Producer::from_fd(fresh_ring(num_pages)?, num_pages)? };
+        let consumer = unsafe { Consumer::from_fd(fresh_ring(num_pages)?, num_pages)? };
+        let fds = [producer.memfd(), consumer.memfd()].map(|fd| fd.expect("Shared, so mapped."));
+        send_hello(&socket, num_pages, &fds)?;
+        Ok(RingStream { producer, consumer })
+    }
+}
+
+/// The memfd of a new ring from `RingBuf::new_shared`. Its halves are dropped, so the ends
+/// opened from the fd don't see each other as gone: they don't share an `Arc` with them.
+fn fresh_ring(num_pages: usize) -> Result<OwnedFd> {
+    let (producer, _) = RingBuf::new_shared(num_pages)?;
+    let fd = producer.memfd().expect("Shared, so mapped.");
+    fd.try_clone_to_owned().map_err(from_io)
+}
+
+impl AsRawFd for RingListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+/// One end of a duplex channel over two shared rings, from `RingStream::connect` or
+/// `RingListener::accept`. Each end sends messages (framed as by `Producer::write_msg`) to the
+/// other and receives the other's. Neither end can tell that the other has gone away, short of
+/// the end marker from `finish`, since only processes sharing a `Producer`'s `Arc` see it drop.
+pub struct RingStream {
+    producer: Producer,
+    consumer: Consumer,
+}
+
+impl RingStream {
+    /// Connects to the `RingListener` at `path`, asking for rings of `num_pages` pages each way,
+    /// and waits for the handshake. The listener may settle on fewer; see `capacity`.
+    ///
+    /// Fails like `RingListener::accept`, but for the listener's version and its going away, and
+    /// with `BufError::CapacityMismatch` if the listener settles on more pages than asked for.
+    pub fn connect<P: AsRef<Path>>(path: P, num_pages: usize) -> Result<Self> {
+        if num_pages == 0 {
+            return Err(BufError::ZeroCapacity.into());
+        }
+        let socket = UnixStream::connect(path).map_err(from_io)?;
+        Self::handshake(socket, num_pages)
+    }
+
+    fn handshake(mut socket: UnixStream, num_pages: usize) -> Result<Self> {
+        let hello = hello(num_pages);
+        socket.write_all(&hello).map_err(handshake_error)?;
+        let mut reply = [0; HELLO_LEN];
+        let fds = recv_hello(&socket, &mut reply)?;
+        let (version, settled) = parse_hello(&reply)?;
+        if version != VERSION {
+            return Err(BufError::VersionMismatch {
+                ours: VERSION,
+                theirs: version,
+            }
+            .into());
+        }
+        if settled > num_pages {
+            return Err(BufError::CapacityMismatch.into());
+        }
+        let Ok([to_us, from_us]) = <[OwnedFd; 2]>::try_from(fds) else {
+            return Err(Errno::EPROTO.into());
+        };
+        // SAFETY: The listener made these rings for us alone, and keeps only the other ends.
+        let consumer = unsafe { Consumer::from_fd(to_us, settled)? };
+        let producer = unsafe { Producer::from_fd(from_us, settled)? };
+        Ok(Self { producer, consumer })
+    }
+
+    /// Sends `payload` as one message. Fails like `Producer::write_msg` while the other end
+    /// hasn't made room for it.
+    pub fn send(&mut self, payload: &[u8]) -> Result<()> {
+        self.producer.write_msg(payload)
+    }
+
+    /// Receives the next message into `buf`, like `Consumer::recv_msg_into`.
+    pub fn recv_into(&mut self, buf: &mut Vec<u8>) -> Result<Option<MsgEvent<usize>>> {
+        self.consumer.recv_msg_into(buf)
+    }
+
+    /// How many bytes each ring holds, as settled in the handshake.
+    pub fn capacity(&self) -> usize {
+        self.producer.capacity()
+    }
+
+    /// Marks the end of what this end sends, for the other end's `recv_into`, like
+    /// `Producer::finish`, and gives back the receiving half to read the rest with.
+    pub fn finish(self) -> Result<Consumer> {
+        self.producer.finish()?;
+        Ok(self.consumer)
+    }
+
+    /// The two halves, to send and receive from different threads.
+    pub fn into_split(self) -> (Producer, Consumer) {
+        (self.producer, self.consumer)
+    }
+}
+
+fn hello(num_pages: usize) -> [u8; HELLO_LEN] {
+    let mut hello = [0; HELLO_LEN];
+    hello[..4].copy_from_slice(&MAGIC.to_le_bytes());
+    hello[4..6].copy_from_slice(&VERSION.to_le_bytes());
+    hello[8..].copy_from_slice(&(num_pages as u64).to_le_bytes());
+    hello
+}
+
+/// The version and page count in `hello`.
+fn parse_hello(hello: &[u8; HELLO_LEN]) -> Result<(u16, usize)> {
+    if hello[..4] != MAGIC.to_le_bytes() {
+        return Err(Errno::EPROTO.into());
+    }
+    let version = u16::from_le_bytes(hello[4..6].try_into().unwrap());
+    let num_pages = u64::from_le_bytes(hello[8..].try_into().unwrap());
+    Ok((version, usize::try_from(num_pages).unwrap_or(usize::MAX)))
+}
+
+/// Sends our hello, saying we settled on `num_pages`, with `fds` attached.
+fn send_hello(socket: &UnixStream, num_pages: usize, fds: &[BorrowedFd<'_>]) -> Result<()> {
+    let hello = hello(num_pages);
+    let mut iov = libc::iovec {
+        iov_base: hello.as_ptr() as *mut _,
+        iov_len: hello.len(),
+    };
+    let mut control = Control::new();
+    let mut msg = message(&mut iov, &mut control);
+    if !fds.is_empty() {
+        let fds_len = mem::size_of_val(fds);
+        // SAFETY: `control` has room for a header and two fds, and `msg` points at it.
+        unsafe {
+            msg.msg_controllen = libc::CMSG_SPACE(fds_len as u32) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len as u32) as _;
+            ptr::copy_nonoverlapping(fds.as_ptr().cast::<u8>(), libc::CMSG_DATA(cmsg), fds_len);
+        }
+    } else {
+        msg.msg_control = ptr::null_mut();
+        msg.msg_controllen = 0;
+    }
+    loop {
+        let sent = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+        match Errno::result(sent) {
+            // The hello is far smaller than any socket buffer, so it goes in one piece.
+            Ok(_) => return Ok(()),
+            Err(Errno::EINTR) => continue,
+            Err(Errno::EPIPE | Errno::ECONNRESET) => {
+                return Err(BufError::HandshakeDisconnected.into())
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Receives the listener's hello into `hello`, and the fds that came with it.
+fn recv_hello(socket: &UnixStream, hello: &mut [u8; HELLO_LEN]) -> Result<Vec<OwnedFd>> {
+    let mut iov = libc::iovec {
+        iov_base: hello.as_mut_ptr().cast(),
+        iov_len: hello.len(),
+    };
+    let mut control = Control::new();
+    let mut msg = message(&mut iov, &mut control);
+    let flags = libc::MSG_CMSG_CLOEXEC;
+    let n = loop {
+        match Errno::result(unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, flags) }) {
+            Ok(n) => break n as usize,
+            Err(Errno::EINTR) => continue,
+            Err(Errno::ECONNRESET) => return Err(BufError::HandshakeDisconnected.into()),
+            Err(e) => return Err(e.into()),
+        }
+    };
+    // Taken first, so they're closed whatever goes wrong below.
+    let mut fds = Vec::new();
+    // SAFETY: The kernel filled in `control` up to `msg_controllen`, and the fds in it are new
+    // and ours.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg);
+                let len = (*cmsg).cmsg_len as usize - (data as usize - cmsg as usize);
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    let fd = ptr::read_unaligned(data.cast::<RawFd>().add(i));
+                    fds.push(OwnedFd::from_raw_fd(fd));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(Errno::EPROTO.into());
+    }
+    match n {
+        0 => Err(BufError::HandshakeDisconnected.into()),
+        n => {
+            // A stream socket can split even this little, but the fds come with the first byte.
+            let mut socket = socket;
+            socket
+                .read_exact(&mut hello[n..])
+                .map_err(handshake_error)?;
+            Ok(fds)
+        }
+    }
+}
+
+/// Room for a control message carrying two fds, aligned for its header.
+#[repr(C)]
+struct Control {
+    _align: [libc::cmsghdr; 0],
+    bytes: [u8; 64],
+}
+
+impl Control {
+    fn new() -> Self {
+        Self {
+            _align: [],
+            bytes: [0; 64],
+        }
+    }
+}
+
+fn message(iov: &mut libc::iovec, control: &mut Control) -> libc::msghdr {
+    // SAFETY: All zeroes is an empty `msghdr`.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.bytes.as_mut_ptr().cast();
+    msg.msg_controllen = control.bytes.len() as _;
+    msg
+}
+
+fn from_io(e: io::Error) -> Error {
+    Errno::from_raw(e.raw_os_error().unwrap_or(0)).into()
+}
+
+/// `from_io`, but with the other end going away mid-handshake said as much.
+fn handshake_error(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::ConnectionReset => BufError::HandshakeDisconnected.into(),
+        _ => from_io(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::LeakCheck;
+    use super::*;
+    use std::{path::PathBuf, thread};
+
+    /// A socket path no other test uses, cleaned up on drop.
+    struct SocketPath(PathBuf);
+
+    impl SocketPath {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("ringbuf-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for SocketPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// Waits for the next message from `recv_into`.
+    fn recv(
+        mut recv_into: impl FnMut(&mut Vec<u8>) -> Result<Option<MsgEvent<usize>>>,
+    ) -> MsgEvent<Vec<u8>> {
+        let mut buf = Vec::new();
+        loop {
+            match recv_into(&mut buf).unwrap() {
+                Some(MsgEvent::Data(_)) => return MsgEvent::Data(buf),
+                Some(MsgEvent::End) => return MsgEvent::End,
+                None => thread::yield_now(),
+            }
+        }
+    }
+
+    #[test]
+    fn messages_both_ways_then_a_clean_teardown() {
+        let path = SocketPath::new("duplex");
+        let listener = RingListener::bind(&path.0, 2).unwrap();
+        let server = thread::spawn(move || {
+            let leaks = LeakCheck::new();
+            let mut stream = listener.accept().unwrap();
+            let ping = recv(|buf| stream.recv_into(buf));
+            assert_eq!(ping, MsgEvent::Data(b"ping".to_vec()));
+            stream.send(b"pong").unwrap();
+            let mut rest = stream.finish().unwrap();
+            assert_eq!(recv(|buf| rest.recv_msg_into(buf)), MsgEvent::End);
+            drop(rest);
+            leaks.finish().expect("Nothing leaked.");
+        });
+
+        let leaks = LeakCheck::new();
+        // Asks for more than the listener offers, and gets what it offers.
+        let mut stream = RingStream::connect(&path.0, 4).unwrap();
+        assert_eq!(stream.capacity(), 2 * super::super::page_size().unwrap());
+        stream.send(b"ping").unwrap();
+        let pong = recv(|buf| stream.recv_into(buf));
+        assert_eq!(pong, MsgEvent::Data(b"pong".to_vec()));
+        let (producer, mut consumer) = stream.into_split();
+        producer.finish().unwrap();
+        assert_eq!(recv(|buf| consumer.recv_msg_into(buf)), MsgEvent::End);
+        drop(consumer);
+        server.join().unwrap();
+        leaks.finish().expect("Nothing leaked.");
+    }
+
+    #[test]
+    fn versions_have_to_match() {
+        let path = SocketPath::new("version");
+        let listener = RingListener::bind(&path.0, 1).unwrap();
+        let client = {
+            let path = path.0.clone();
+            thread::spawn(move || {
+                let mut socket = UnixStream::connect(path).unwrap();
+                let mut hello = hello(1);
+                hello[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+                socket.write_all(&hello).unwrap();
+                let mut reply = [0; HELLO_LEN];
+                let fds = recv_hello(&socket, &mut reply).unwrap();
+                assert!(fds.is_empty());
+                parse_hello(&reply).unwrap()
+            })
+        };
+        assert!(matches!(
+            listener.accept(),
+            Err(Error::Ours(BufError::VersionMismatch {
+                ours: VERSION,
+                theirs
+            })) if theirs == VERSION + 1
+        ));
+        assert_eq!(client.join().unwrap(), (VERSION, 0));
+    }
+
+    #[test]
+    fn hanging_up_mid_handshake() {
+        let path = SocketPath::new("hangup");
+        let listener = RingListener::bind(&path.0, 1).unwrap();
+
+        // A client that goes before saying hello.
+        drop(UnixStream::connect(&path.0).unwrap());
+        assert!(matches!(
+            listener.accept(),
+            Err(Error::Ours(BufError::HandshakeDisconnected))
+        ));
+
+        // A listener that goes before answering.
+        let client = {
+            let path = path.0.clone();
+            thread::spawn(move || RingStream::connect(path, 1).map(drop))
+        };
+        let (mut socket, _) = listener.listener.accept().unwrap();
+        let mut hello = [0; HELLO_LEN];
+        socket.read_exact(&mut hello).unwrap();
+        drop(socket);
+        assert!(matches!(
+            client.join().unwrap(),
+            Err(Error::Ours(BufError::HandshakeDisconnected))
+        ));
+    }
+
+    #[test]
+    fn strangers_are_turned_away() {
+        let path = SocketPath::new("stranger");
+        let listener = RingListener::bind(&path.0, 1).unwrap();
+        let mut socket = UnixStream::connect(&path.0).unwrap();
+        socket.write_all(&[0xAA; HELLO_LEN]).unwrap();
+        assert!(matches!(listener.accept(), Err(Error::Nix(Errno::EPROTO))));
+        // And the next client is still welcome.
+        let client = thread::spawn(move || RingStream::connect(path.0.clone(), 1).map(drop));
+        listener.accept().unwrap();
+        client.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,377 @@
+//! Moving bytes between a ring and a pipe with `splice(2)`, without copying them through this
+//! process. See `RingBuf::splice_to_pipe`.
+
+use super::{mirror::Mirror, BufError, Result, RingBuf};
+use nix::{
+    errno::Errno,
+    fcntl::{open, splice, OFlag, SpliceFFlags},
+    sys::stat::{fstat, Mode},
+};
+use std::{
+    collections::VecDeque,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+};
+
+/// The pipe `splice_to_pipe` last fed, which may still reference the ring's pages.
+pub(crate) struct PipeHold {
+    // Our own read end, so the pipe can still be asked how much it holds after the caller closes
+    // theirs. Not a write end, which would keep the reader from ever seeing end-of-file.
+    pipe: OwnedFd,
+    // (st_dev, st_ino), to tell whether a later call is for the same pipe.
+    id: (u64, u64),
+    // Bytes spliced into the pipe so far.
+    sent: u64,
+    // (position in `sent`, stream position in the ring) where each run of spliced bytes starts,
+    // oldest first. Runs that carry on from the previous one in both streams get merged.
+    runs: VecDeque<(u64, u64)>,
+    /// Stream position, counted like `bytes_read`, of the oldest consumed byte the pipe may still
+    /// reference. Everything from here to the head is kept out of the free space.
+    pub(crate) floor: u64,
+}
+
+impl PipeHold {
+    fn new(pipe: BorrowedFd<'_>, id: (u64, u64), floor: u64) -> Result<Self> {
+        Ok(Self {
+            pipe: read_end(pipe, id)?,
+            id,
+            sent: 0,
+            runs: VecDeque::new(),
+            floor,
+        })
+    }
+
+    /// Notes that the `n` bytes at stream position `pos` went into the pipe.
+    fn record(&mut self, pos: u64, n: usize) {
+        let carries_on = self
+            .runs
+            .back()
+            .is_some_and(|&(sent, start)| start + (self.sent - sent) == pos);
+        if !carries_on {
+            self.runs.push_back((self.sent, pos));
+        }
+        self.sent += n as u64;
+    }
+
+    /// Moves `floor` up past whatever the pipe's reader has taken. `None` once it's taken all of
+    /// it. If the pipe can't be asked, nothing is released.
+    fn oldest_referenced(&mut self) -> Option<u64> {
+        let mut queued: libc::c_int = 0;
+        let asked = Errno::result(unsafe {
+            libc::ioctl(self.pipe.as_raw_fd(), libc::FIONREAD as _, &mut queued)
+        });
+        if asked.is_err() {
+            return Some(self.floor);
+        }
+        if queued == 0 {
+            return None;
+        }
+        // Bytes other writers put in the pipe only make this more conservative.
+        let unread = self.sent.saturating_sub(queued as u64);
+        while self.runs.len() > 1 && self.runs[1].0 <= unread {
+            self.runs.pop_front();
+        }
+        let &(sent, start) = self.runs.front()?;
+        self.floor = self.floor.max(start + unread.saturating_sub(sent));
+        Some(self.floor)
+    }
+}
+
+/// A new read end of the pipe `pipe` (normally a write end) is one end of, by reopening it
+/// through procfs. Non-blocking, since it's only there to be asked `FIONREAD`.
+fn read_end(pipe: BorrowedFd<'_>, id: (u64, u64)) -> Result<OwnedFd> {
+    let path = format!("/proc/self/fd/{}", pipe.as_raw_fd());
+    let flags = OFlag::O_RDONLY | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC;
+    let fd = unsafe { OwnedFd::from_raw_fd(open(path.as_str(), flags, Mode::empty())?) };
+    if pipe_id(fd.as_fd())? != id {
+        return Err(Errno::EBADF.into());
+    }
+    Ok(fd)
+}
+
+/// Identifies the pipe behind `fd` for `PipeHold::id`.
+fn pipe_id(fd: BorrowedFd<'_>) -> Result<(u64, u64)> {
+    let stat = fstat(fd.as_raw_fd())?;
+    Ok((stat.st_dev as u64, stat.st_ino as u64))
+}
+
+/// The memfd behind the ring and where its bytes start in it, or `EOPNOTSUPP` for heap rings.
+fn memfd(ring: &RingBuf) -> Result<(BorrowedFd<'_>, usize)> {
+    let mirror = ring.mirror.as_ref().ok_or(BufError::ZeroCapacity)?;
+    let fd = Mirror::fd(mirror).ok_or(Errno::EOPNOTSUPP)?;
+    Ok((fd, mirror.file_offset()))
+}
+
+impl RingBuf {
+    /// Gives back the space of whatever the pipe `splice_to_pipe` fed has been read out of,
+    /// scrubbing it first if the ring scrubs consumed bytes.
+    pub(crate) fn release_spliced(&mut self) {
+        let Some(hold) = &mut self.pipe_hold else {
+            return;
+        };
+        let old_floor = hold.floor;
+        let new_floor = match hold.oldest_referenced() {
+            Some(floor) => floor,
+            None => {
+                self.pipe_hold = None;
+                self.bytes_read
+            }
+        };
+        if new_floor > old_floor && self.scrubbing() {
+            let behind = (self.bytes_read - old_floor) as usize;
+            // Behind the head, so contiguous when viewed from the second copy.
+            self.scrub_range(
+                self.head + self.buf_size - behind,
+                (new_floor - old_floor) as usize,
+            );
+        }
+    }
+}
+
+/// Only the first splice of a call may block, like a single `read` or `write`.
+fn flags(moved: usize) -> SpliceFFlags {
+    if moved == 0 {
+        SpliceFFlags::empty()
+    } else {
+        SpliceFFlags::SPLICE_F_NONBLOCK
+    }
+}
+
+pub(crate) fn to_pipe(ring: &mut RingBuf, pipe: BorrowedFd<'_>, max: usize) -> Result<usize> {
+    ring.ensure_mapped()?;
+    memfd(ring)?;
+    let id = pipe_id(pipe)?;
+    ring.reclaim();
+    match &ring.pipe_hold {
+        Some(hold) if hold.id != id => return Err(Errno::EBUSY.into()),
+        Some(_) => {}
+        None => ring.pipe_hold = Some(PipeHold::new(pipe, id, ring.bytes_read)?),
+    }
+
+    let mut moved = 0;
+    while moved < max && ring.contents_size() > 0 {
+        let run = (max - moved)
+            .min(ring.contents_size())
+            .min(ring.buf_size - ring.head);
+        let (fd, file_offset) = memfd(ring)?;
+        let mut offset = (file_offset + ring.head) as libc::loff_t;
+        match splice(fd, Some(&mut offset), pipe, None, run, flags(moved)) {
+            Ok(0) => break,
+            Ok(n) => {
+                let pos = ring.bytes_read;
+                ring.pipe_hold.as_mut().unwrap().record(pos, n);
+                ring.advance_head(n);
+                // Still in the pipe; `release_spliced` scrubs them once it's done with them.
+                ring.unscrubbed = 0;
+                moved += n;
+            }
+            Err(Errno::EINTR) => {}
+            Err(Errno::EAGAIN) if moved > 0 => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(moved)
+}
+
+pub(crate) fn from_pipe(ring: &mut RingBuf, pipe: BorrowedFd<'_>, max: usize) -> Result<usize> {
+    ring.check_fits(0)?;
+    memfd(ring)?;
+    let mut moved = 0;
+    while moved < max {
+        let run = (max - moved)
+            .min(ring.free_space())
+            .min(ring.buf_size - ring.tail);
+        if run == 0 {
+            break;
+        }
+        let (fd, file_offset) = memfd(ring)?;
+        let mut offset = (file_offset + ring.tail) as libc::loff_t;
+        match splice(pipe, None, fd, Some(&mut offset), run, flags(moved)) {
+            // Every writer has closed its end.
+            Ok(0) => break,
+            Ok(n) => {
+                unsafe { ring.advance_write(n) };
+                moved += n;
+            }
+            Err(Errno::EINTR) => {}
+            Err(Errno::EAGAIN) if moved > 0 => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(moved)
+}
+
+#[cfg(all(test, not(feature = "portable")))]
+mod tests {
+    use super::super::{BackendKind, Error};
+    use super::*;
+    use nix::unistd::{pipe, read, write};
+    use std::io::Read;
+
+    /// Bytes `from..from + n` of a stream where byte `i` is `(i % 251) as u8`.
+    fn stream(from: usize, n: usize) -> Vec<u8> {
+        (from..from + n).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn read_exact(fd: &OwnedFd, n: usize) -> Vec<u8> {
+        let mut got = vec![0; n];
+        let mut filled = 0;
+        while filled < n {
+            filled += read(fd.as_raw_fd(), &mut got[filled..]).unwrap();
+        }
+        got
+    }
+
+    #[test]
+    fn spliced_space_comes_back_once_the_pipe_is_read() {
+        let (rx, tx) = pipe().unwrap();
+        let mut ring = RingBuf::builder()
+            .pages(1)
+            .debug_fill(true)
+            .build()
+            .unwrap();
+        let cap = ring.capacity();
+        // Across the wrap, so it takes two splices.
+        ring.write(&vec![0; cap - 100]).unwrap();
+        ring.consume(cap - 100).unwrap();
+        ring.write(&stream(0, 300)).unwrap();
+
+        assert_eq!(ring.splice_to_pipe(tx.as_fd(), 250).unwrap(), 250);
+        assert_eq!(ring.len(), 50);
+        // The pipe still points at those pages, so writes can't have them yet.
+        assert_eq!(ring.free_space(), cap - 300);
+        let err = ring.write(&vec![9; cap - 299]).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Ours(BufError::NotEnoughSpace { available, .. }) if available == cap - 300
+        ));
+
+        assert_eq!(read_exact(&rx, 200), stream(0, 200));
+        ring.write(b"").unwrap();
+        assert_eq!(
+            ring.free_space(),
+            cap - 100,
+            "Only 50 are still in the pipe."
+        );
+        assert_eq!(read_exact(&rx, 50), stream(200, 50));
+        ring.write(&vec![9; cap - 50]).unwrap();
+        assert_eq!(ring.read(50).unwrap(), stream(250, 50));
+    }
+
+    #[test]
+    fn clear_and_wipe_leave_the_pipe_alone() {
+        let (rx, tx) = pipe().unwrap();
+        let mut ring = RingBuf::builder()
+            .pages(1)
+            .debug_fill(true)
+            .build()
+            .unwrap();
+        let cap = ring.capacity();
+        ring.write(&stream(0, 1000)).unwrap();
+        assert_eq!(ring.splice_to_pipe(tx.as_fd(), 600).unwrap(), 600);
+
+        // What was cleared after the spliced bytes is held along with them, being in between
+        // them and the head.
+        ring.clear();
+        assert_eq!(ring.free_space(), cap - 1000);
+        ring.write(&vec![1; cap - 1000]).unwrap();
+        ring.wipe();
+        assert_eq!(ring.free_space(), 0);
+        assert_eq!(read_exact(&rx, 600), stream(0, 600));
+        ring.write(&vec![3; cap]).unwrap();
+    }
+
+    #[test]
+    fn one_pipe_at_a_time() {
+        let (rx, tx) = pipe().unwrap();
+        let (_other_rx, other_tx) = pipe().unwrap();
+        let mut ring = RingBuf::new(1).unwrap();
+        ring.write(b"first second").unwrap();
+        assert_eq!(ring.splice_to_pipe(tx.as_fd(), 6).unwrap(), 6);
+        assert!(matches!(
+            ring.splice_to_pipe(other_tx.as_fd(), 6),
+            Err(Error::Nix(Errno::EBUSY))
+        ));
+        assert_eq!(read_exact(&rx, 6), b"first ");
+        assert_eq!(ring.splice_to_pipe(other_tx.as_fd(), 6).unwrap(), 6);
+    }
+
+    #[test]
+    fn splice_from_pipe_across_the_wrap() {
+        let (rx, tx) = pipe().unwrap();
+        let mut ring = RingBuf::new(1).unwrap();
+        let cap = ring.capacity();
+        ring.write(&vec![0; cap - 10]).unwrap();
+        ring.consume(cap - 10).unwrap();
+        write(&tx, &stream(0, 40)).unwrap();
+
+        assert_eq!(ring.splice_from_pipe(rx.as_fd(), 100).unwrap(), 40);
+        assert_eq!(ring.read(40).unwrap(), stream(0, 40));
+        drop(tx);
+        assert_eq!(ring.splice_from_pipe(rx.as_fd(), 100).unwrap(), 0);
+    }
+
+    #[test]
+    fn heap_rings_cannot_splice() {
+        let (_rx, tx) = pipe().unwrap();
+        let mut ring = RingBuf::builder()
+            .pages(1)
+            .backend(BackendKind::Heap)
+            .build()
+            .unwrap();
+        ring.write(b"x").unwrap();
+        assert!(matches!(
+            ring.splice_to_pipe(tx.as_fd(), 1),
+            Err(Error::Nix(Errno::EOPNOTSUPP))
+        ));
+    }
+
+    #[test]
+    fn sixty_four_megabytes_to_a_reader_thread() {
+        const TOTAL: usize = 64 << 20;
+        let (rx, tx) = pipe().unwrap();
+        let reader = std::thread::spawn(move || {
+            let mut rx = std::fs::File::from(rx);
+            let mut chunk = vec![0; 1 << 16];
+            let mut seen = 0;
+            loop {
+                let n = rx.read(&mut chunk).unwrap();
+                if n == 0 {
+                    return seen;
+                }
+                assert_eq!(chunk[..n], stream(seen, n));
+                seen += n;
+            }
+        });
+
+        let mut ring = RingBuf::new(16).unwrap();
+        let (mut written, mut spliced) = (0, 0);
+        while spliced < TOTAL {
+            let n = ring.free_space().min(TOTAL - written);
+            ring.write(&stream(written, n)).unwrap();
+            written += n;
+            spliced += ring.splice_to_pipe(tx.as_fd(), usize::MAX).unwrap();
+        }
+        drop(tx);
+        assert_eq!(reader.join().unwrap(), TOTAL);
+        drop(ring);
+    }
+
+    #[test]
+    fn the_reader_sees_eof_while_the_ring_lives() {
+        let (rx, tx) = pipe().unwrap();
+        let mut ring = RingBuf::new(1).unwrap();
+        let cap = ring.capacity();
+        ring.write(b"hello").unwrap();
+        assert_eq!(ring.splice_to_pipe(tx.as_fd(), 5).unwrap(), 5);
+        drop(tx);
+
+        let reader = std::thread::spawn(move || {
+            let mut got = Vec::new();
+            std::fs::File::from(rx).read_to_end(&mut got).unwrap();
+            got
+        });
+        assert_eq!(reader.join().unwrap(), b"hello");
+        // The pipe is gone, and the ring noticed it was read.
+        ring.write(&vec![1; cap]).unwrap();
+    }
+}
@@ -0,0 +1,2152 @@
+//! A ring split into a producer and a consumer half, for passing bytes between two threads
+//! without a lock, or between two processes through a memfd.
+
+#[cfg(eventfd)]
+use super::fault::{os_call, OsOp};
+use super::{
+    frame_len, index,
+    mirror::{self, Mirror},
+    BufError, Clock, Error, Frame, IntervalStats, MsgEvent, Result, RingBuf, Stats, END_FRAME,
+    MAX_AGE_MARKS, MSG_HEADER_LEN, POISON, TIMED_FRAME, TIMED_MSG_HEADER_LEN,
+};
+#[cfg(mapped)]
+use super::{page_size, SystemClock};
+use std::{
+    io::{self, Read, Write},
+    ops::Deref,
+    os::fd::BorrowedFd,
+    sync::{
+        atomic::{fence, AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+#[cfg(mapped)]
+use std::{num::NonZeroUsize, os::fd::OwnedFd};
+#[cfg(eventfd)]
+use {
+    nix::sys::eventfd::{EfdFlags, EventFd},
+    std::{os::fd::AsFd, sync::OnceLock},
+};
+
+/// The indices both halves agree on. `head` and `tail` are positions in `0..2 * capacity` rather
+/// than offsets, so a full ring and an empty one look different without a length both sides would
+/// have to update. The producer only ever stores `tail` and the consumer only ever stores `head`.
+///
+/// For rings made by `RingBuf::new_shared` this lives in the first page of the memfd, so it's
+/// laid out for other processes (built from the same source) to read too.
+#[repr(C)]
+struct Header {
+    /// `MAGIC`, so `from_fd` can tell a shared ring's fd from any other.
+    magic: u64,
+    capacity: u64,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+const MAGIC: u64 = u64::from_le_bytes(*b"ringbuf\x01");
+
+/// Where a ring's `Header` is.
+enum Indices {
+    /// In this process only, for rings that were `split`.
+    Local(Header),
+    /// In the memfd's header page, mapped at this address.
+    #[cfg(mapped)]
+    Mapped(*const Header),
+}
+
+/// What the two halves share.
+struct Shared {
+    mirror: Mirror,
+    indices: Indices,
+    parking: Parking,
+    #[cfg(eventfd)]
+    readiness: Readiness,
+    // Each half's counters for `stats`, on cache lines of their own so bumping them doesn't slow
+    // the other half down. Local to this process, even for shared rings.
+    producer_stats: Padded<ProducerCounters>,
+    consumer_stats: Padded<ConsumerCounters>,
+    clock: Arc<dyn Clock>,
+    // What the send times in `write_msg_timed` frames count from. `None` for rings from
+    // `new_shared`, like `ages`, since there's no clock both processes go by.
+    epoch: Option<Instant>,
+    // Where the current `stats_interval` began. Only ever locked by `stats_interval` itself.
+    interval: Mutex<IntervalStart>,
+    // `None` for rings from `new_shared`, whose other half may be in a process we can't see.
+    ages: Option<AgeMarks>,
+}
+
+/// Keeps what it holds off any cache line something else is on.
+#[repr(align(128))]
+#[derive(Default)]
+struct Padded<T>(T);
+
+/// The counters only the producer bumps. Atomics just so the consumer's `stats` can read them
+/// whole; with one writer, bumping is a plain load and store.
+#[derive(Default)]
+struct ProducerCounters {
+    bytes_written: AtomicU64,
+    high_water: AtomicUsize,
+    rejected_writes: AtomicU64,
+    wraps: AtomicU64,
+    // Never reset; `stats_interval` takes differences of them.
+    bytes_in: AtomicU64,
+    ops_in: AtomicU64,
+    drops: AtomicU64,
+    // The exception: `stats_interval` starts it over from either half, so the producer raises it
+    // with `fetch_max` rather than a plain store.
+    max_fill: AtomicUsize,
+}
+
+/// The counters only the consumer bumps.
+#[derive(Default)]
+struct ConsumerCounters {
+    bytes_read: AtomicU64,
+    expired: AtomicU64,
+    // Never reset, like the producer's.
+    bytes_out: AtomicU64,
+    ops_out: AtomicU64,
+}
+
+/// When the current `stats_interval` began, and the never-reset counters at the time.
+struct IntervalStart {
+    at: Instant,
+    bytes_in: u64,
+    bytes_out: u64,
+    ops_in: u64,
+    ops_out: u64,
+    drops: u64,
+}
+
+/// Adds `n` to a counter nobody else writes to.
+fn bump(counter: &AtomicU64, n: u64) {
+    counter.store(counter.load(Ordering::Relaxed) + n, Ordering::Relaxed);
+}
+
+/// The (stream position, time) marks behind `Consumer::oldest_data_age`, like a `RingBuf`'s but
+/// in a fixed ring of their own: the producer pushes one per write while there's room, and the
+/// consumer pops them once it has read past the next one, so the front mark is always at or
+/// before the oldest unread byte. Positions count bytes since the split, and times are
+/// nanoseconds since `epoch`.
+struct AgeMarks {
+    epoch: Instant,
+    producer: Padded<MarksIn>,
+    consumer: Padded<MarksOut>,
+}
+
+/// The half of `AgeMarks` only the producer stores to.
+struct MarksIn {
+    marks: [AgeMark; MAX_AGE_MARKS],
+    // Marks ever pushed, so the next goes in `marks[pushed % MAX_AGE_MARKS]`.
+    pushed: AtomicU64,
+    written: AtomicU64,
+}
+
+/// The half of `AgeMarks` only the consumer stores to.
+#[derive(Default)]
+struct MarksOut {
+    popped: AtomicU64,
+    read: AtomicU64,
+}
+
+#[derive(Default)]
+struct AgeMark {
+    pos: AtomicU64,
+    at: AtomicU64,
+}
+
+impl AgeMarks {
+    /// Picks up where `ring` is, marks and all.
+    fn from_ring(ring: &RingBuf) -> Self {
+        let epoch = match ring.age_marks.front() {
+            Some(&(_, at)) => at,
+            None => ring.clock.now(),
+        };
+        let ages = Self {
+            epoch,
+            producer: Padded(MarksIn {
+                marks: std::array::from_fn(|_| AgeMark::default()),
+                pushed: AtomicU64::new(0),
+                written: AtomicU64::new(0),
+            }),
+            consumer: Padded::default(),
+        };
+        for &(pos, at) in &ring.age_marks {
+            // The front mark can be for a write that's partly read already.
+            ages.push(pos.saturating_sub(ring.bytes_read), at);
+        }
+        ages.producer
+            .0
+            .written
+            .store(ring.contents_size() as u64, Ordering::Relaxed);
+        ages
+    }
+
+    fn mark(&self, i: u64) -> &AgeMark {
+        &self.producer.0.marks[i as usize % MAX_AGE_MARKS]
+    }
+
+    /// Marks `pos` as written at `at`, unless every mark is still in use.
+    fn push(&self, pos: u64, at: Instant) {
+        let pushed = self.producer.0.pushed.load(Ordering::Relaxed);
+        // Acquire, so the consumer is done with a mark before we reuse it.
+        if pushed - self.consumer.0.popped.load(Ordering::Acquire) < MAX_AGE_MARKS as u64 {
+            let mark = self.mark(pushed);
+            mark.pos.store(pos, Ordering::Relaxed);
+            let at = at.saturating_duration_since(self.epoch).as_nanos() as u64;
+            mark.at.store(at, Ordering::Relaxed);
+            self.producer.0.pushed.store(pushed + 1, Ordering::Release);
+        }
+    }
+
+    /// Called by the producer for a write of `n` bytes.
+    fn wrote(&self, n: usize, now: Instant) {
+        let written = &self.producer.0.written;
+        self.push(written.load(Ordering::Relaxed), now);
+        bump(written, n as u64);
+    }
+
+    /// Called by the consumer once it has consumed `n` bytes.
+    fn read(&self, n: usize) {
+        let read = self.consumer.0.read.load(Ordering::Relaxed) + n as u64;
+        self.consumer.0.read.store(read, Ordering::Release);
+        let popped = self.front(read);
+        self.consumer.0.popped.store(popped, Ordering::Release);
+    }
+
+    /// The first mark that isn't behind a later one at or before `read`.
+    fn front(&self, read: u64) -> u64 {
+        let pushed = self.producer.0.pushed.load(Ordering::Acquire);
+        let mut front = self.consumer.0.popped.load(Ordering::Acquire);
+        while front + 1 < pushed && self.mark(front + 1).pos.load(Ordering::Relaxed) <= read {
+            front += 1;
+        }
+        front
+    }
+
+    /// When the oldest unread byte was written, as far as the marks can tell. Too old rather than
+    /// too young for bytes written while they were all in use.
+    fn oldest(&self) -> Option<Instant> {
+        let front = self.front(self.consumer.0.read.load(Ordering::Acquire));
+        if front == self.producer.0.pushed.load(Ordering::Acquire) {
+            return None;
+        }
+        let at = self.mark(front).at.load(Ordering::Relaxed);
+        Some(self.epoch + Duration::from_nanos(at))
+    }
+}
+
+/// Where a half blocked in `write_blocking` or `read_blocking` waits for the other to make
+/// progress. Only the halves in this process can wake each other up through it, so a waiter on a
+/// ring from `from_fd` also checks back every `CROSS_PROCESS_POLL`.
+struct Parking {
+    lock: Mutex<()>,
+    wake: Condvar,
+    // How many halves are waiting, so the other side can skip the lock when nobody is.
+    waiters: AtomicUsize,
+    producer_gone: AtomicBool,
+    consumer_gone: AtomicBool,
+    // Times a waiter woke up, spuriously or not.
+    #[cfg(test)]
+    wakeups: AtomicUsize,
+}
+
+/// How often a blocked half rechecks a ring the other side may be using from another process.
+#[cfg(mapped)]
+const CROSS_PROCESS_POLL: Duration = Duration::from_millis(1);
+
+impl Parking {
+    fn new() -> Self {
+        Self {
+            lock: Mutex::new(()),
+            wake: Condvar::new(),
+            waiters: AtomicUsize::new(0),
+            producer_gone: AtomicBool::new(false),
+            consumer_gone: AtomicBool::new(false),
+            #[cfg(test)]
+            wakeups: AtomicUsize::new(0),
+        }
+    }
+
+    /// Blocks until `ready()`, or fails with `BufError::Disconnected` once `peer_gone` is set and
+    /// `ready()` still isn't, or with `BufError::TimedOut` at `deadline`. `poll` caps each wait,
+    /// for when the other side can't notify us.
+    fn wait_until(
+        &self,
+        deadline: Option<Instant>,
+        poll: Option<Duration>,
+        peer_gone: &AtomicBool,
+        ready: impl Fn() -> bool,
+    ) -> Result<()> {
+        if ready() {
+            return Ok(());
+        }
+        let mut guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        let result = loop {
+            // Pairs with the fence in `notify`: either it sees us waiting, or we see its progress.
+            fence(Ordering::SeqCst);
+            if ready() {
+                break Ok(());
+            }
+            if peer_gone.load(Ordering::SeqCst) {
+                break Err(BufError::Disconnected.into());
+            }
+            let timeout = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(left) if !left.is_zero() => Some(left),
+                    _ => break Err(BufError::TimedOut.into()),
+                },
+                None => None,
+            };
+            let timeout = match (timeout, poll) {
+                (Some(timeout), Some(poll)) => Some(timeout.min(poll)),
+                (timeout, poll) => timeout.or(poll),
+            };
+            guard = match timeout {
+                Some(timeout) => {
+                    self.wake
+                        .wait_timeout(guard, timeout)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+                None => self.wake.wait(guard).unwrap_or_else(|e| e.into_inner()),
+            };
+            // Whatever woke us, spurious or not, the loop rechecks everything.
+            #[cfg(test)]
+            self.wakeups.fetch_add(1, Ordering::Relaxed);
+        };
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+
+    /// Wakes the other half if it's waiting. Call after publishing progress.
+    fn notify(&self) {
+        fence(Ordering::SeqCst);
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+            self.wake.notify_all();
+        }
+    }
+}
+
+/// The eventfds behind `Consumer::data_fd` and `Producer::space_fd`. Each is only created the
+/// first time it's asked for, so halves that never end up in a poll loop don't pay a syscall per
+/// call to keep it up to date.
+///
+/// A half signals its peer's fd after publishing progress, and clears its own when it runs out
+/// (of data, or of room), then rechecks: the same handshake `Parking` does with `waiters`, so a
+/// signal is never lost between the two.
+#[cfg(eventfd)]
+#[derive(Default)]
+struct Readiness {
+    data: OnceLock<EventFd>,
+    space: OnceLock<EventFd>,
+}
+
+#[cfg(eventfd)]
+impl Readiness {
+    /// The eventfd in `cell`, made and signalled if `ready()` if there wasn't one yet.
+    fn get_or_create<'a>(
+        cell: &'a OnceLock<EventFd>,
+        ready: impl Fn() -> bool,
+    ) -> Result<BorrowedFd<'a>> {
+        if let Some(fd) = cell.get() {
+            return Ok(fd.as_fd());
+        }
+        let fd = os_call(OsOp::EventfdCreate, || {
+            EventFd::from_flags(EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK)
+        })?;
+        // Should two threads race to make it, the loser's fd is simply dropped.
+        let _ = cell.set(fd);
+        let fd = cell.get().unwrap();
+        // The peer signals it from here on; anything it did before, we check for ourselves.
+        fence(Ordering::SeqCst);
+        if ready() {
+            Self::signal(cell);
+        }
+        Ok(fd.as_fd())
+    }
+
+    /// Makes the eventfd in `cell`, if any, readable. Call after publishing progress.
+    fn signal(cell: &OnceLock<EventFd>) {
+        if let Some(fd) = cell.get() {
+            // Only fails with the counter at `u64::MAX - 1`, which a poller would have to ignore
+            // for a few centuries of writes.
+            let _ = fd.arm();
+        }
+    }
+
+    /// Clears the eventfd in `cell`, if any, unless `ready()` once that's done.
+    fn clear(cell: &OnceLock<EventFd>, ready: impl Fn() -> bool) {
+        if let Some(fd) = cell.get() {
+            // EAGAIN if it was already clear, which is fine.
+            let _ = fd.read();
+            // Pairs with the fence in `Parking::notify`, which the peer goes through before
+            // signalling: either we see its progress, or its signal comes after our read.
+            fence(Ordering::SeqCst);
+            if ready() {
+                Self::signal(cell);
+            }
+        }
+    }
+}
+
+// SAFETY: The mapping isn't tied to the thread that made it, and the halves never touch the same
+// bytes at the same time: the producer only writes free space, the consumer only reads pending
+// bytes, and ownership of a byte changes hands through a release store of `tail` or `head`
+// matched by an acquire load on the other side. The mapped header is only accessed atomically
+// after construction.
+unsafe impl Send for Shared {}
+unsafe impl Sync for Shared {}
+
+impl Shared {
+    /// `interval` is the one in progress, which the halves carry on with.
+    fn new(
+        mirror: Mirror,
+        indices: Indices,
+        clock: Arc<dyn Clock>,
+        ages: Option<AgeMarks>,
+        interval: IntervalStats,
+    ) -> Arc<Self> {
+        let producer_stats = ProducerCounters {
+            bytes_in: AtomicU64::new(interval.bytes_in),
+            ops_in: AtomicU64::new(interval.ops_in),
+            drops: AtomicU64::new(interval.drops),
+            max_fill: AtomicUsize::new(interval.max_fill),
+            ..ProducerCounters::default()
+        };
+        let consumer_stats = ConsumerCounters {
+            bytes_out: AtomicU64::new(interval.bytes_out),
+            ops_out: AtomicU64::new(interval.ops_out),
+            ..ConsumerCounters::default()
+        };
+        let start = IntervalStart {
+            at: interval.started_at,
+            bytes_in: 0,
+            bytes_out: 0,
+            ops_in: 0,
+            ops_out: 0,
+            drops: 0,
+        };
+        Arc::new(Self {
+            mirror,
+            indices,
+            parking: Parking::new(),
+            #[cfg(eventfd)]
+            readiness: Readiness::default(),
+            producer_stats: Padded(producer_stats),
+            consumer_stats: Padded(consumer_stats),
+            epoch: ages.as_ref().map(|_| clock.now()),
+            clock,
+            interval: Mutex::new(start),
+            ages,
+        })
+    }
+
+    /// `Parking::wait_until` with this ring's deadline and cross-process polling.
+    fn wait_until(
+        &self,
+        timeout: Option<Duration>,
+        peer_gone: &AtomicBool,
+        ready: impl Fn() -> bool,
+    ) -> Result<()> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let poll = match self.indices {
+            Indices::Local(_) => None,
+            #[cfg(mapped)]
+            Indices::Mapped(_) => Some(CROSS_PROCESS_POLL),
+        };
+        self.parking.wait_until(deadline, poll, peer_gone, ready)
+    }
+
+    fn header(&self) -> &Header {
+        match &self.indices {
+            Indices::Local(header) => header,
+            // SAFETY: The header page is mapped for as long as `mirror` is.
+            #[cfg(mapped)]
+            Indices::Mapped(header) => unsafe { &**header },
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.mirror.size
+    }
+
+    /// Pending bytes between two positions.
+    fn distance(&self, head: usize, tail: usize) -> usize {
+        let wrap = 2 * self.capacity();
+        if tail >= head {
+            tail - head
+        } else {
+            tail + wrap - head
+        }
+    }
+
+    /// Pending bytes, as far as whoever is asking can tell.
+    fn len(&self) -> usize {
+        let header = self.header();
+        self.distance(
+            header.head.load(Ordering::Acquire),
+            header.tail.load(Ordering::Acquire),
+        )
+    }
+
+    /// Everything from the consumer's `head` to the tail. See `Consumer::peek`.
+    fn pending(&self, head: usize) -> &[u8] {
+        // Acquire, so the bytes the producer published are visible.
+        let tail = self.header().tail.load(Ordering::Acquire);
+        let len = self.distance(head, tail);
+        unsafe { std::slice::from_raw_parts(self.offset(head), len) }
+    }
+
+    /// See `Consumer::oldest_data_age`.
+    fn oldest_data_age(&self) -> Option<Duration> {
+        let ages = self.ages.as_ref()?;
+        if self.len() == 0 {
+            return None;
+        }
+        let written_at = ages.oldest()?;
+        Some(self.clock.now().saturating_duration_since(written_at))
+    }
+
+    /// Both halves' counters, as `RingBuf::stats` reports them.
+    fn stats(&self) -> Stats {
+        let (producer, consumer) = (&self.producer_stats.0, &self.consumer_stats.0);
+        Stats {
+            oldest_data_age: self.oldest_data_age(),
+            thp_backed: self.mirror.thp_backed(),
+            bytes_written: producer.bytes_written.load(Ordering::Relaxed),
+            bytes_read: consumer.bytes_read.load(Ordering::Relaxed),
+            high_water: producer.high_water.load(Ordering::Relaxed),
+            rejected_writes: producer.rejected_writes.load(Ordering::Relaxed),
+            wraps: producer.wraps.load(Ordering::Relaxed),
+            dropped_frames: 0,
+            expired_frames: consumer.expired.load(Ordering::Relaxed),
+        }
+    }
+
+    /// See `Producer::stats_interval`.
+    fn stats_interval(&self) -> IntervalStats {
+        let mut start = self.interval.lock().unwrap_or_else(|e| e.into_inner());
+        let now = self.clock.now();
+        let (producer, consumer) = (&self.producer_stats.0, &self.consumer_stats.0);
+        let end = IntervalStart {
+            at: now,
+            bytes_in: producer.bytes_in.load(Ordering::Relaxed),
+            bytes_out: consumer.bytes_out.load(Ordering::Relaxed),
+            ops_in: producer.ops_in.load(Ordering::Relaxed),
+            ops_out: consumer.ops_out.load(Ordering::Relaxed),
+            drops: producer.drops.load(Ordering::Relaxed),
+        };
+        // A write racing with this can leave its fill out of the next interval's maximum, but
+        // never out of this one's.
+        let fill = self.len();
+        let max_fill = producer.max_fill.swap(fill, Ordering::Relaxed).max(fill);
+        let finished = IntervalStats {
+            started_at: start.at,
+            elapsed: now.saturating_duration_since(start.at),
+            bytes_in: end.bytes_in - start.bytes_in,
+            bytes_out: end.bytes_out - start.bytes_out,
+            ops_in: end.ops_in - start.ops_in,
+            ops_out: end.ops_out - start.ops_out,
+            drops: end.drops - start.drops,
+            max_fill,
+        };
+        *start = end;
+        finished
+    }
+
+    /// Where position `pos` sits in the first view.
+    fn offset(&self, pos: usize) -> *mut u8 {
+        let cap = self.capacity();
+        let offset = if pos >= cap { pos - cap } else { pos };
+        unsafe { self.mirror.ptr.add(offset) }
+    }
+
+    /// `Mirror::sync` for the `n` bytes at position `pos`.
+    unsafe fn sync(&self, pos: usize, n: usize) {
+        let offset = self.offset(pos).offset_from(self.mirror.ptr) as usize;
+        self.mirror.sync(offset, n);
+    }
+
+    fn memfd(&self) -> Option<BorrowedFd<'_>> {
+        match self.indices {
+            Indices::Local(_) => None,
+            #[cfg(mapped)]
+            Indices::Mapped(_) => self.mirror.fd(),
+        }
+    }
+
+    fn advance(&self, pos: usize, n: usize) -> usize {
+        index::advance(pos, n, 2 * self.capacity())
+    }
+}
+
+/// The writing half of a split ring. See `RingBuf::split`.
+pub struct Producer {
+    shared: Arc<Shared>,
+    // Our own copy of `shared.tail`, which nobody else stores to.
+    tail: usize,
+}
+
+/// The reading half of a split ring. See `RingBuf::split`.
+pub struct Consumer {
+    shared: Arc<Shared>,
+    // Our own copy of `shared.head`, which nobody else stores to.
+    head: usize,
+    ttl: Option<Duration>,
+    on_expired: Option<Box<OnExpired>>,
+}
+
+/// See `Consumer::on_expired`.
+type OnExpired = dyn FnMut(&[u8], Duration) + Send;
+
+pub(crate) fn split(mut ring: RingBuf) -> Result<(Producer, Consumer)> {
+    ring.ensure_mapped()?;
+    // Leftovers from a borrowed read would otherwise never get scrubbed.
+    ring.scrub();
+    let ages = AgeMarks::from_ring(&ring);
+    let mirror = ring.mirror.take().ok_or(BufError::ZeroCapacity)?;
+    let (head, tail) = (ring.head, ring.head + ring.contents_size());
+    let header = Header {
+        magic: MAGIC,
+        capacity: mirror.size as u64,
+        head: AtomicUsize::new(head),
+        tail: AtomicUsize::new(tail),
+    };
+    let indices = Indices::Local(header);
+    let shared = Shared::new(
+        mirror,
+        indices,
+        ring.clock.clone(),
+        Some(ages),
+        ring.interval,
+    );
+    Ok(halves(shared))
+}
+
+#[cfg(mapped)]
+pub(crate) fn new_shared(num_pages: usize) -> Result<(Producer, Consumer)> {
+    let (size, header_len) = shared_sizes(num_pages)?;
+    let (mirror, header) = Mirror::with_header(size, header_len, None)?;
+    let header = header as *mut Header;
+    // SAFETY: The page is ours alone until the fd goes anywhere. It starts out zeroed, so the
+    // indices already say empty.
+    unsafe {
+        (*header).magic = MAGIC;
+        (*header).capacity = size.get() as u64;
+    }
+    let indices = Indices::Mapped(header);
+    let interval = IntervalStats::starting_at(Instant::now(), 0);
+    let shared = Shared::new(mirror, indices, Arc::new(SystemClock), None, interval);
+    Ok(halves(shared))
+}
+
+/// The capacity of a shared ring of `num_pages`, and the size of its header.
+#[cfg(mapped)]
+fn shared_sizes(num_pages: usize) -> Result<(NonZeroUsize, NonZeroUsize)> {
+    let page = page_size()?;
+    let size = num_pages
+        .checked_mul(page)
+        .ok_or(BufError::CapacityOverflow)?;
+    let size = NonZeroUsize::new(size).ok_or(BufError::ZeroCapacity)?;
+    Ok((size, NonZeroUsize::new(page).unwrap()))
+}
+
+/// Maps the ring in `fd`, which `new_shared` made.
+#[cfg(mapped)]
+unsafe fn open_shared(fd: OwnedFd, num_pages: usize) -> Result<Arc<Shared>> {
+    let (size, header_len) = shared_sizes(num_pages)?;
+    let (mirror, header) = Mirror::with_header(size, header_len, Some(fd))?;
+    let header = header as *const Header;
+    // Written once before the fd was handed out, so there's nothing to race with.
+    if (*header).magic != MAGIC || (*header).capacity != size.get() as u64 {
+        return Err(BufError::CapacityMismatch.into());
+    }
+    let indices = Indices::Mapped(header);
+    let interval = IntervalStats::starting_at(Instant::now(), 0);
+    let shared = Shared::new(mirror, indices, Arc::new(SystemClock), None, interval);
+    // Whatever the other process left pending is where this interval's maximum starts.
+    let fill = shared.len();
+    shared
+        .producer_stats
+        .0
+        .max_fill
+        .store(fill, Ordering::Relaxed);
+    Ok(shared)
+}
+
+fn halves(shared: Arc<Shared>) -> (Producer, Consumer) {
+    let header = shared.header();
+    let (head, tail) = (
+        header.head.load(Ordering::Acquire),
+        header.tail.load(Ordering::Acquire),
+    );
+    (
+        Producer {
+            shared: shared.clone(),
+            tail,
+        },
+        Consumer {
+            shared,
+            head,
+            ttl: None,
+            on_expired: None,
+        },
+    )
+}
+
+impl Producer {
+    /// Appends all of `raw`, or fails with `BufError::NotEnoughSpace` and writes nothing if the
+    /// consumer hasn't freed up enough space yet.
+    pub fn write(&mut self, raw: &[u8]) -> Result<()> {
+        self.write_parts(&[raw])
+    }
+
+    /// Writes every slice in `parts` back-to-back as one write, all of them or none, like
+    /// `RingBuf::write_all_slices`. The consumer never sees some of them without the rest.
+    pub fn write_all_slices(&mut self, parts: &[&[u8]]) -> Result<()> {
+        self.write_parts(parts)
+    }
+
+    /// Writes `payload` as one message, framed like `RingBuf::write_msg`, for
+    /// `Consumer::recv_msg_into`. Fails like `write` if it doesn't fit yet, and like
+    /// `RingBuf::write_msg` if it never will.
+    pub fn write_msg(&mut self, payload: &[u8]) -> Result<()> {
+        let len = frame_len(payload, MSG_HEADER_LEN, self.capacity())?;
+        self.write_all_slices(&[&len.to_le_bytes(), payload])
+    }
+
+    /// `write_msg` with the time of sending in the frame, for a consumer with `set_ttl` to go
+    /// by. The time is the ring's clock's, counted from the split. A ring from
+    /// `RingBuf::new_shared` has no clock both ends go by, so there timed messages are never
+    /// too old.
+    pub fn write_msg_timed(&mut self, payload: &[u8]) -> Result<()> {
+        let len = frame_len(payload, TIMED_MSG_HEADER_LEN, self.capacity())?;
+        let sent = self.shared.epoch.map_or(Duration::ZERO, |epoch| {
+            self.shared.clock.now().saturating_duration_since(epoch)
+        });
+        let sent = u64::try_from(sent.as_nanos()).unwrap_or(u64::MAX);
+        self.write_all_slices(&[
+            &(len | TIMED_FRAME).to_le_bytes(),
+            &sent.to_le_bytes(),
+            payload,
+        ])
+    }
+
+    fn write_parts<P: Deref<Target = [u8]>>(&mut self, parts: &[P]) -> Result<()> {
+        let total = parts
+            .iter()
+            .try_fold(0usize, |acc, part| acc.checked_add(part.len()))
+            .unwrap_or(usize::MAX);
+        let free = self.free_space();
+        if total > free {
+            // So a level-triggered `space_fd` doesn't keep waking us up for space we can't use.
+            #[cfg(eventfd)]
+            Readiness::clear(&self.shared.readiness.space, || self.free_space() > free);
+            let counters = &self.shared.producer_stats.0;
+            bump(&counters.rejected_writes, 1);
+            bump(&counters.drops, 1);
+            return Err(BufError::NotEnoughSpace {
+                requested: total,
+                available: free,
+            }
+            .into());
+        }
+        // The mirror makes the free space contiguous, wrap or no wrap.
+        unsafe {
+            let mut dst = self.shared.offset(self.tail);
+            for part in parts {
+                std::ptr::copy_nonoverlapping(part.as_ptr(), dst, part.len());
+                dst = dst.add(part.len());
+            }
+            self.shared.sync(self.tail, total);
+        }
+        self.count_write(total, free);
+        if let Some(ages) = &self.shared.ages {
+            ages.wrote(total, self.shared.clock.now());
+        }
+        self.tail = self.shared.advance(self.tail, total);
+        self.shared
+            .header()
+            .tail
+            .store(self.tail, Ordering::Release);
+        self.shared.parking.notify();
+        #[cfg(eventfd)]
+        {
+            Readiness::signal(&self.shared.readiness.data);
+            if total == free {
+                Readiness::clear(&self.shared.readiness.space, || self.free_space() > 0);
+            }
+        }
+        Ok(())
+    }
+
+    /// `write`, but waits for the consumer to free up enough space instead of failing. Fails with
+    /// `BufError::Disconnected` if the consumer is dropped first, and straight away with
+    /// `BufError::NotEnoughSpace` if `raw` is bigger than the whole ring.
+    ///
+    /// Only a consumer in this process can wake the wait up; for one in another process (see
+    /// `from_fd`) the producer checks back every millisecond, and can't tell if it's gone.
+    pub fn write_blocking(&mut self, raw: &[u8]) -> Result<()> {
+        self.write_within(raw, None)
+    }
+
+    /// `write_blocking` that gives up with `BufError::TimedOut` after `timeout`, having written
+    /// nothing.
+    pub fn write_timeout(&mut self, raw: &[u8], timeout: Duration) -> Result<()> {
+        self.write_within(raw, Some(timeout))
+    }
+
+    fn write_within(&mut self, raw: &[u8], timeout: Option<Duration>) -> Result<()> {
+        if raw.len() > self.capacity() {
+            return Err(BufError::NotEnoughSpace {
+                requested: raw.len(),
+                available: self.capacity(),
+            }
+            .into());
+        }
+        let consumer_gone = &self.shared.parking.consumer_gone;
+        self.shared
+            .wait_until(timeout, consumer_gone, || self.free_space() >= raw.len())?;
+        self.write(raw)
+    }
+
+    /// Writes all of `data`, a piece at a time as the consumer frees up space, so unlike
+    /// `write_blocking` it can take more than the whole ring holds. Fails with
+    /// `BufError::Incomplete`, saying how much got in, if the consumer is dropped first.
+    pub fn write_all_blocking(&mut self, data: &[u8]) -> Result<()> {
+        self.write_all_within(data, None)
+    }
+
+    /// `write_all_blocking` that gives up with `BufError::Incomplete` once `timeout` has passed,
+    /// keeping whatever it had written by then.
+    pub fn write_all_timeout(&mut self, data: &[u8], timeout: Duration) -> Result<()> {
+        self.write_all_within(data, Some(timeout))
+    }
+
+    fn write_all_within(&mut self, data: &[u8], timeout: Option<Duration>) -> Result<()> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let shared = self.shared.clone();
+        let consumer_gone = &shared.parking.consumer_gone;
+        let mut written = 0;
+        while written < data.len() {
+            if consumer_gone.load(Ordering::SeqCst) {
+                return Err(BufError::Incomplete { written }.into());
+            }
+            written += self.write_up_to(&data[written..]);
+            if written == data.len() {
+                break;
+            }
+            let left = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            match shared.wait_until(left, consumer_gone, || self.free_space() > 0) {
+                Ok(()) => {}
+                Err(Error::Ours(BufError::Disconnected | BufError::TimedOut)) => {
+                    return Err(BufError::Incomplete { written }.into())
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes as much of `raw` as fits right now and returns how much that was, which is zero
+    /// while the ring is full.
+    pub fn write_up_to(&mut self, raw: &[u8]) -> usize {
+        let n = raw.len().min(self.free_space());
+        if n > 0 {
+            self.write(&raw[..n]).expect("Only what fits.");
+        }
+        n
+    }
+
+    /// Wraps the producer in a `Write` that waits for room rather than failing with
+    /// `WouldBlock`, for `io::copy` and friends.
+    pub fn into_blocking(self) -> BlockingProducer {
+        BlockingProducer(self)
+    }
+
+    /// Ends the stream on purpose: writes the end marker `Consumer::recv_msg_into` returns as
+    /// `MsgEvent::End`, after everything written so far, and gives up the producer so nothing
+    /// can follow it. Simply dropping the producer instead reads as `BufError::Disconnected`, so
+    /// the consumer can tell the two apart. The marker is never dropped: this waits for room for
+    /// it, and fails with `BufError::Disconnected` only if the consumer is gone first.
+    pub fn finish(mut self) -> Result<()> {
+        self.write_within(&END_FRAME.to_le_bytes(), None)
+    }
+
+    /// The ring's clock, which it kept from `RingBuf::set_clock` through the split.
+    pub(crate) fn clock(&self) -> Arc<dyn Clock> {
+        self.shared.clock.clone()
+    }
+
+    /// How much `write` can take right now. Only ever grows until the next `write`, since only
+    /// the consumer can change it.
+    pub fn free_space(&self) -> usize {
+        // Acquire, so the consumer is done with those bytes before we overwrite them.
+        let head = self.shared.header().head.load(Ordering::Acquire);
+        self.capacity() - self.shared.distance(head, self.tail)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity()
+    }
+
+    /// Totals for both halves, like `RingBuf::stats`, from zero when the ring was split. For a
+    /// ring shared with another process, each process only counts its own halves.
+    pub fn stats(&self) -> Stats {
+        self.shared.stats()
+    }
+
+    /// Starts the producer's counters over: everything in `stats` but `bytes_read`, with the
+    /// high-water mark at what's pending now.
+    pub fn reset_stats(&mut self) {
+        let counters = &self.shared.producer_stats.0;
+        counters.bytes_written.store(0, Ordering::Relaxed);
+        counters
+            .high_water
+            .store(self.capacity() - self.free_space(), Ordering::Relaxed);
+        counters.rejected_writes.store(0, Ordering::Relaxed);
+        counters.wraps.store(0, Ordering::Relaxed);
+    }
+
+    /// Traffic through both halves since the previous call from either of them, like
+    /// `RingBuf::stats_interval`, carrying on with the interval the ring was in when it was
+    /// split. The interval belongs to the ring, so calls from the two halves take turns ending
+    /// it. Each half only counts its own side as it goes, and the two are added up here.
+    pub fn stats_interval(&mut self) -> IntervalStats {
+        self.shared.stats_interval()
+    }
+
+    /// Counts a write of `n` bytes into `free` bytes of space, before `tail` moves past them.
+    fn count_write(&self, n: usize, free: usize) {
+        let counters = &self.shared.producer_stats.0;
+        bump(&counters.bytes_written, n as u64);
+        let cap = self.capacity();
+        let offset = if self.tail >= cap {
+            self.tail - cap
+        } else {
+            self.tail
+        };
+        bump(&counters.wraps, u64::from(offset + n >= cap));
+        let fill = cap - free + n;
+        if fill > counters.high_water.load(Ordering::Relaxed) {
+            counters.high_water.store(fill, Ordering::Relaxed);
+        }
+        if n > 0 {
+            bump(&counters.bytes_in, n as u64);
+            bump(&counters.ops_in, 1);
+            counters.max_fill.fetch_max(fill, Ordering::Relaxed);
+        }
+    }
+
+    /// The memfd a ring from `RingBuf::new_shared` lives in, to hand to another process, or
+    /// `None` for a ring that was `split`.
+    pub fn memfd(&self) -> Option<BorrowedFd<'_>> {
+        self.shared.memfd()
+    }
+
+    /// An eventfd that polls readable while there's room to write, for driving the producer
+    /// from an epoll or `mio` loop. Made the first time it's asked for.
+    ///
+    /// It stays readable until a `write` fills the ring or fails for lack of room, and turns
+    /// readable again once the consumer frees some up. Level-triggered pollers can rely on
+    /// that; edge-triggered ones get an edge for every `consume`. Readable doesn't mean the next
+    /// write fits, only that there's more room than when the last one didn't. Leave reading it to
+    /// the producer, which clears it as described.
+    ///
+    /// Only a consumer in this process signals it, so it's no use for a ring shared with another
+    /// process (see `from_fd`).
+    #[cfg(eventfd)]
+    pub fn space_fd(&self) -> Result<BorrowedFd<'_>> {
+        Readiness::get_or_create(&self.shared.readiness.space, || self.free_space() > 0)
+    }
+
+    /// Attaches to the producing end of a ring made by `RingBuf::new_shared`, through (a copy of)
+    /// its `memfd`. `num_pages` has to match, or this fails with `BufError::CapacityMismatch`;
+    /// so does an fd that doesn't hold a shared ring.
+    ///
+    /// # Safety
+    /// The fd can be anything, but if it is a shared ring, nothing else may write to it while
+    /// this producer is alive: not the `Producer` it was created with (in this process or any
+    /// other), nor another one made from the fd.
+    #[cfg(mapped)]
+    pub unsafe fn from_fd(fd: OwnedFd, num_pages: usize) -> Result<Self> {
+        let shared = open_shared(fd, num_pages)?;
+        let tail = shared.header().tail.load(Ordering::Acquire);
+        Ok(Self { shared, tail })
+    }
+}
+
+impl Consumer {
+    /// Everything written so far and not yet consumed, without consuming it. Contiguous even
+    /// across the wrap.
+    pub fn peek(&self) -> &[u8] {
+        self.shared.pending(self.head)
+    }
+
+    /// Hands the oldest `n` pending bytes back to the producer. Scrubbed first if the ring was
+    /// built with `RingBufBuilder::zeroize` or `debug_fill`.
+    pub fn consume(&mut self, n: usize) -> Result<()> {
+        if n > self.len() {
+            return Err(BufError::NotEnoughData {
+                requested: n,
+                available: self.len(),
+            }
+            .into());
+        }
+        let options = &self.shared.mirror.options;
+        let start = self.shared.offset(self.head);
+        unsafe {
+            if options.debug_fill {
+                std::ptr::write_bytes(start, POISON, n);
+            } else if options.zeroize {
+                mirror::zero_volatile(start, n);
+            }
+            if options.debug_fill || options.zeroize {
+                self.shared.sync(self.head, n);
+            }
+        }
+        let counters = &self.shared.consumer_stats.0;
+        bump(&counters.bytes_read, n as u64);
+        if n > 0 {
+            bump(&counters.bytes_out, n as u64);
+            bump(&counters.ops_out, 1);
+        }
+        if let Some(ages) = &self.shared.ages {
+            ages.read(n);
+        }
+        self.head = self.shared.advance(self.head, n);
+        self.shared
+            .header()
+            .head
+            .store(self.head, Ordering::Release);
+        self.shared.parking.notify();
+        #[cfg(eventfd)]
+        {
+            Readiness::signal(&self.shared.readiness.space);
+            if self.is_empty() {
+                Readiness::clear(&self.shared.readiness.data, || !self.is_empty());
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits until `out.len()` bytes are pending, then copies them into `out` and consumes
+    /// them. Fails with `BufError::Disconnected` if the producer is dropped before they all
+    /// arrive, having consumed nothing, and straight away with `BufError::NotEnoughSpace` if `out`
+    /// is bigger than the whole ring. See `Producer::write_blocking` for rings shared with another
+    /// process.
+    pub fn read_blocking(&mut self, out: &mut [u8]) -> Result<()> {
+        self.read_within(out, None)
+    }
+
+    /// `read_blocking` that gives up with `BufError::TimedOut` after `timeout`, having consumed
+    /// nothing.
+    pub fn read_timeout(&mut self, out: &mut [u8], timeout: Duration) -> Result<()> {
+        self.read_within(out, Some(timeout))
+    }
+
+    fn read_within(&mut self, out: &mut [u8], timeout: Option<Duration>) -> Result<()> {
+        if out.len() > self.capacity() {
+            return Err(BufError::NotEnoughSpace {
+                requested: out.len(),
+                available: self.capacity(),
+            }
+            .into());
+        }
+        let producer_gone = &self.shared.parking.producer_gone;
+        self.shared
+            .wait_until(timeout, producer_gone, || self.len() >= out.len())?;
+        let n = self.read_into(out);
+        debug_assert_eq!(n, out.len());
+        Ok(())
+    }
+
+    /// Copies as many pending bytes into `out` as fit and consumes them. Returns how many that
+    /// was, which is zero if nothing is pending.
+    pub fn read_into(&mut self, out: &mut [u8]) -> usize {
+        let pending = self.peek();
+        let n = pending.len().min(out.len());
+        out[..n].copy_from_slice(&pending[..n]);
+        self.consume(n).expect("Just peeked at them.");
+        n
+    }
+
+    /// The next message from `Producer::write_msg` or `write_msg_timed`, copied into `buf` and
+    /// consumed, or the end marker from `Producer::finish`, which stays put so every call from
+    /// then on returns `MsgEvent::End` again. `None` until a whole message has arrived, and
+    /// `BufError::Disconnected` if the producer was dropped without finishing and everything it
+    /// wrote has been read. Fails like `RingBuf::read_msg` for a frame that could never fit or a
+    /// checked one, consuming nothing. Timed messages past the `set_ttl` are skipped on the way.
+    pub fn recv_msg_into(&mut self, buf: &mut Vec<u8>) -> Result<Option<MsgEvent<usize>>> {
+        // Checked first, so anything written before the producer went is seen below.
+        let producer_gone = self.producer_gone();
+        loop {
+            let pending = self.shared.pending(self.head);
+            let frame = match pending.get(..MSG_HEADER_LEN) {
+                Some(header) => Frame::parse(header, self.capacity())?,
+                None if producer_gone => return Err(BufError::Disconnected.into()),
+                None => return Ok(None),
+            };
+            let len = match frame {
+                Frame::End => return Ok(Some(MsgEvent::End)),
+                Frame::Msg { checked: true, .. } => return Err(BufError::MixedFrames.into()),
+                Frame::Msg { len, .. } | Frame::Timed { len } => len,
+            };
+            let Some(payload) = pending.get(frame.header_len()..frame.size()) else {
+                return if producer_gone {
+                    Err(BufError::Disconnected.into())
+                } else {
+                    Ok(None)
+                };
+            };
+            if let (Frame::Timed { .. }, Some(ttl), Some(epoch)) =
+                (frame, self.ttl, self.shared.epoch)
+            {
+                let sent = u64::from_le_bytes(pending[MSG_HEADER_LEN..][..8].try_into().unwrap());
+                let sent_at = epoch + Duration::from_nanos(sent);
+                let age = self.shared.clock.now().saturating_duration_since(sent_at);
+                if age > ttl {
+                    if let Some(on_expired) = &mut self.on_expired {
+                        on_expired(payload, age);
+                    }
+                    bump(&self.shared.consumer_stats.0.expired, 1);
+                    self.consume(frame.size())?;
+                    continue;
+                }
+            }
+            buf.clear();
+            buf.extend_from_slice(payload);
+            self.consume(frame.size())?;
+            return Ok(Some(MsgEvent::Data(len)));
+        }
+    }
+
+    /// Has `recv_msg_into` skip messages from `Producer::write_msg_timed` sent more than `ttl`
+    /// ago, counting them in `Stats::expired_frames`. Each is looked at once, as it comes up.
+    /// Messages from plain `write_msg` carry no time, so they're never too old.
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = Some(ttl);
+    }
+
+    /// Turns `set_ttl` off again: every message is delivered, however old.
+    pub fn clear_ttl(&mut self) {
+        self.ttl = None;
+    }
+
+    /// Has `recv_msg_into` call `on_expired` with each message it skips for `set_ttl`, and how
+    /// old it was, before it goes.
+    pub fn on_expired(&mut self, on_expired: impl FnMut(&[u8], Duration) + Send + 'static) {
+        self.on_expired = Some(Box::new(on_expired));
+    }
+
+    /// Whether the producer has been dropped. Anything it wrote first is visible once this
+    /// says so.
+    pub(crate) fn producer_gone(&self) -> bool {
+        self.shared.parking.producer_gone.load(Ordering::SeqCst)
+    }
+
+    /// Waits until something is pending, or fails with `BufError::Disconnected` once the
+    /// producer is gone and nothing is.
+    pub(crate) fn wait_for_data(&self) -> Result<()> {
+        let producer_gone = &self.shared.parking.producer_gone;
+        self.shared
+            .wait_until(None, producer_gone, || !self.is_empty())
+    }
+
+    /// Wraps the consumer in a `Read` that waits for data rather than failing with
+    /// `WouldBlock`, for `io::copy` and friends.
+    pub fn into_blocking(self) -> BlockingConsumer {
+        BlockingConsumer(self)
+    }
+
+    pub fn len(&self) -> usize {
+        self.peek().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity()
+    }
+
+    /// How long the oldest unread byte has been waiting, like `RingBuf::oldest_data_age`, or
+    /// `None` if nothing is. Always `None` for rings from `RingBuf::new_shared`, since the writes
+    /// may come from another process.
+    pub fn oldest_data_age(&self) -> Option<Duration> {
+        self.shared.oldest_data_age()
+    }
+
+    /// See `Producer::stats`.
+    pub fn stats(&self) -> Stats {
+        self.shared.stats()
+    }
+
+    /// See `Producer::stats_interval`.
+    pub fn stats_interval(&mut self) -> IntervalStats {
+        self.shared.stats_interval()
+    }
+
+    /// Starts the consumer's counters, `bytes_read` and `expired_frames`, over.
+    pub fn reset_stats(&mut self) {
+        let counters = &self.shared.consumer_stats.0;
+        counters.bytes_read.store(0, Ordering::Relaxed);
+        counters.expired.store(0, Ordering::Relaxed);
+    }
+
+    /// See `Producer::memfd`.
+    pub fn memfd(&self) -> Option<BorrowedFd<'_>> {
+        self.shared.memfd()
+    }
+
+    /// An eventfd that polls readable while anything is pending, for driving the consumer from
+    /// an epoll or `mio` loop. Made the first time it's asked for.
+    ///
+    /// Level-triggered pollers see it readable for as long as the ring isn't empty; a `consume`
+    /// or `read_into` that empties it clears it. Edge-triggered ones get an edge for every
+    /// `write`. It can be readable with nothing pending, if the data that signalled it was read
+    /// before the poll came back; a `read_into` that returns zero clears it again. Leave reading
+    /// it to the consumer.
+    ///
+    /// See `Producer::space_fd` for rings shared with another process.
+    #[cfg(eventfd)]
+    pub fn data_fd(&self) -> Result<BorrowedFd<'_>> {
+        Readiness::get_or_create(&self.shared.readiness.data, || !self.is_empty())
+    }
+
+    /// `Producer::from_fd` for the consuming end.
+    ///
+    /// # Safety
+    /// As for `Producer::from_fd`, but with readers: nothing else may consume from the ring
+    /// while this consumer is alive.
+    #[cfg(mapped)]
+    pub unsafe fn from_fd(fd: OwnedFd, num_pages: usize) -> Result<Self> {
+        let shared = open_shared(fd, num_pages)?;
+        let head = shared.header().head.load(Ordering::Acquire);
+        Ok(Self {
+            shared,
+            head,
+            ttl: None,
+            on_expired: None,
+        })
+    }
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        self.shared
+            .parking
+            .producer_gone
+            .store(true, Ordering::SeqCst);
+        self.shared.parking.notify();
+    }
+}
+
+impl Drop for Consumer {
+    fn drop(&mut self) {
+        self.shared
+            .parking
+            .consumer_gone
+            .store(true, Ordering::SeqCst);
+        self.shared.parking.notify();
+    }
+}
+
+/// Takes as much as fits without waiting: a full ring is `WouldBlock`, and a dropped consumer
+/// `BrokenPipe`. See `into_blocking` for the waiting kind.
+impl Write for Producer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.shared.parking.consumer_gone.load(Ordering::SeqCst) {
+            return Err(Error::from(BufError::Disconnected).into());
+        }
+        match self.write_up_to(data) {
+            0 if !data.is_empty() => Err(io::ErrorKind::WouldBlock.into()),
+            n => Ok(n),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads whatever is pending without waiting: an empty ring is `WouldBlock` while the producer
+/// is around, and the end of the stream (`Ok(0)`) once it's been dropped and everything it wrote
+/// has been read. See `into_blocking` for the waiting kind.
+impl Read for Consumer {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        // Checked first, so anything written before the producer went is seen below.
+        let producer_gone = self.producer_gone();
+        match self.read_into(out) {
+            0 if !out.is_empty() && !producer_gone => Err(io::ErrorKind::WouldBlock.into()),
+            n => Ok(n),
+        }
+    }
+}
+
+/// A `Producer` whose `Write` impl waits for the consumer to make room. See
+/// `Producer::into_blocking`.
+pub struct BlockingProducer(Producer);
+
+impl BlockingProducer {
+    pub fn into_inner(self) -> Producer {
+        self.0
+    }
+}
+
+/// Waits until at least one byte fits, then takes as much as does. Fails with `BrokenPipe` once
+/// the consumer is gone.
+impl Write for BlockingProducer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let producer = &mut self.0;
+        let consumer_gone = &producer.shared.parking.consumer_gone;
+        producer
+            .shared
+            .wait_until(None, consumer_gone, || producer.free_space() > 0)?;
+        Write::write(producer, data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A `Consumer` whose `Read` impl waits for the producer to write something. See
+/// `Consumer::into_blocking`.
+pub struct BlockingConsumer(Consumer);
+
+impl BlockingConsumer {
+    pub fn into_inner(self) -> Consumer {
+        self.0
+    }
+}
+
+/// Waits until something is pending and reads as much of it as fits, or returns `Ok(0)` once
+/// the producer is gone and everything it wrote has been read.
+impl Read for BlockingConsumer {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+        match self.0.wait_for_data() {
+            Ok(()) => Ok(self.0.read_into(out)),
+            Err(Error::Ours(BufError::Disconnected)) => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{page_size, BackendKind, LeakCheck, Live, MockClock};
+    use super::*;
+    use std::{thread, time::Instant};
+
+    /// The byte at position `pos` of the test stream.
+    fn stream_byte(pos: usize) -> u8 {
+        (pos % 251) as u8 ^ (pos >> 12) as u8
+    }
+
+    #[test]
+    fn each_half_keeps_its_own_counters() {
+        let (mut producer, mut consumer) = RingBuf::new(1).unwrap().split().unwrap();
+        let cap = producer.capacity();
+        producer.write(&[0; 1000]).unwrap();
+        producer.write(&vec![0; cap]).unwrap_err();
+        consumer.consume(600).unwrap();
+        producer.write(&vec![0; cap - 400]).unwrap();
+        consumer.consume(cap).unwrap();
+
+        for stats in [producer.stats(), consumer.stats()] {
+            assert_eq!(stats.bytes_written, cap as u64 + 600);
+            assert_eq!(stats.bytes_read, cap as u64 + 600);
+            assert_eq!(stats.high_water, cap);
+            assert_eq!(stats.rejected_writes, 1);
+            assert_eq!(stats.wraps, 1);
+        }
+
+        consumer.reset_stats();
+        let stats = producer.stats();
+        assert_eq!(
+            (stats.bytes_written, stats.bytes_read),
+            (cap as u64 + 600, 0)
+        );
+        producer.write(&[0; 10]).unwrap();
+        producer.reset_stats();
+        let stats = consumer.stats();
+        assert_eq!(
+            (
+                stats.bytes_written,
+                stats.high_water,
+                stats.rejected_writes,
+                stats.wraps
+            ),
+            (0, 10, 0, 0)
+        );
+    }
+
+    #[test]
+    fn megabytes_across_threads() {
+        let _leaks = LeakCheck::new();
+        let (mut producer, mut consumer) = RingBuf::new(3).unwrap().split().unwrap();
+        const TOTAL: usize = 8 << 20;
+
+        let writer = thread::spawn(move || {
+            let mut pos = 0;
+            let mut chunk = Vec::new();
+            for i in 0.. {
+                if pos == TOTAL {
+                    break;
+                }
+                // Uneven sizes, up to the whole ring, so the wrap lands everywhere.
+                let n = (1 + i * 7919 % producer.capacity()).min(TOTAL - pos);
+                chunk.clear();
+                chunk.extend((pos..pos + n).map(stream_byte));
+                while producer.write(&chunk).is_err() {
+                    thread::yield_now();
+                }
+                pos += n;
+            }
+        });
+
+        let mut pos = 0;
+        let mut out = vec![0; 5000];
+        while pos < TOTAL {
+            let n = consumer.read_into(&mut out[..1 + pos % 5000]);
+            if n == 0 {
+                thread::yield_now();
+            }
+            for (i, &b) in out[..n].iter().enumerate() {
+                assert_eq!(b, stream_byte(pos + i), "at {}", pos + i);
+            }
+            pos += n;
+        }
+        writer.join().unwrap();
+        assert!(consumer.is_empty());
+    }
+
+    #[test]
+    fn full_and_empty_at_the_wrap() {
+        let (mut producer, mut consumer) = RingBuf::new(1).unwrap().split().unwrap();
+        let cap = producer.capacity();
+        for round in 0..5u8 {
+            producer.write(&vec![round; cap - 10]).unwrap();
+            assert_eq!(consumer.len(), cap - 10);
+            producer.write(&[round; 10]).unwrap();
+            assert_eq!(producer.free_space(), 0);
+            assert!(producer.write(b"x").is_err());
+            assert_eq!(consumer.peek(), &vec![round; cap][..]);
+            consumer.consume(cap - 3).unwrap();
+            assert!(consumer.consume(4).is_err());
+            producer.write(b"abc").unwrap();
+            assert_eq!(consumer.len(), 6);
+            let mut out = [0; 10];
+            assert_eq!(consumer.read_into(&mut out), 6);
+            assert_eq!(&out[..6], &[round, round, round, b'a', b'b', b'c']);
+            assert!(consumer.is_empty());
+            assert_eq!(producer.free_space(), cap);
+            // Offset the next round so the full ring wraps somewhere else.
+            producer.write(&vec![0; 77 * (round as usize + 1)]).unwrap();
+            consumer.consume(77 * (round as usize + 1)).unwrap();
+        }
+    }
+
+    #[test]
+    fn write_all_slices_exact_fit() {
+        let (mut producer, consumer) = RingBuf::new(1).unwrap().split().unwrap();
+        let cap = producer.capacity();
+        producer.write(&[0; 100]).unwrap();
+        producer
+            .write_all_slices(&[&[1; 96], &[], &vec![2; cap - 196]])
+            .unwrap();
+        assert_eq!(producer.free_space(), 0);
+        assert_eq!(consumer.len(), cap);
+    }
+
+    #[test]
+    fn write_all_slices_one_byte_too_big() {
+        let (mut producer, consumer) = RingBuf::new(1).unwrap().split().unwrap();
+        let cap = producer.capacity();
+        producer.write(&[0; 100]).unwrap();
+        assert!(matches!(
+            producer.write_all_slices(&[&[1; 97], &vec![2; cap - 196]]),
+            Err(Error::Ours(BufError::NotEnoughSpace { requested, available }))
+                if (requested, available) == (cap - 99, cap - 100)
+        ));
+        // Not even the first slice made it in.
+        assert_eq!(consumer.peek(), [0; 100]);
+        assert_eq!(producer.stats().rejected_writes, 1);
+    }
+
+    #[test]
+    #[cfg_attr(not(mapped), ignore = "builds a DoubleMap ring")]
+    fn write_all_slices_wraps() {
+        for backend in [BackendKind::DoubleMap, BackendKind::Heap] {
+            let ring = RingBuf::builder().backend(backend).build().unwrap();
+            let (mut producer, mut consumer) = ring.split().unwrap();
+            let cap = producer.capacity();
+            producer.write(&vec![0; cap - 96]).unwrap();
+            consumer.consume(cap - 96).unwrap();
+            producer
+                .write_all_slices(&[b"head", &[], &[3; 200], b"tail"])
+                .unwrap();
+            let mut out = [0; 208];
+            assert_eq!(consumer.read_into(&mut out), 208);
+            assert_eq!(&out[..4], b"head");
+            assert_eq!(out[4..204], [3; 200]);
+            assert_eq!(&out[204..], b"tail");
+        }
+    }
+
+    #[test]
+    fn split_keeps_what_was_pending() {
+        let mut ring = RingBuf::new(1).unwrap();
+        let page = page_size().unwrap();
+        ring.write(&vec![0; page - 4]).unwrap();
+        ring.consume(page - 4).unwrap();
+        ring.write(b"wrapped").unwrap();
+        let (mut producer, consumer) = ring.split().unwrap();
+        assert_eq!(producer.free_space(), page - 7);
+        assert_eq!(consumer.peek(), b"wrapped");
+        producer.write(b" and more").unwrap();
+        assert_eq!(consumer.peek(), b"wrapped and more");
+
+        assert!(RingBuf::default().split().is_err());
+        let lazy = RingBuf::builder().lazy(true).build().unwrap();
+        let (mut producer, consumer) = lazy.split().unwrap();
+        producer.write(b"mapped").unwrap();
+        assert_eq!(consumer.peek(), b"mapped");
+    }
+
+    #[test]
+    fn oldest_data_age_across_the_split() {
+        let ms = Duration::from_millis;
+        let clock = MockClock::new();
+        let mut ring = RingBuf::new(1).unwrap();
+        ring.set_clock(clock.clone());
+        ring.write(&[1; 100]).unwrap();
+        ring.consume(40).unwrap();
+        clock.advance(ms(10));
+        let (mut producer, mut consumer) = ring.split().unwrap();
+        assert_eq!(consumer.oldest_data_age(), Some(ms(10)));
+
+        producer.write(&[2; 100]).unwrap();
+        clock.advance(ms(5));
+        assert_eq!(consumer.oldest_data_age(), Some(ms(15)));
+        // Partially consuming what was left of the first write keeps it the oldest.
+        consumer.consume(50).unwrap();
+        assert_eq!(consumer.oldest_data_age(), Some(ms(15)));
+        assert_eq!(producer.stats().oldest_data_age, Some(ms(15)));
+        // Once the head is into the second write, that's what counts.
+        consumer.consume(20).unwrap();
+        assert_eq!(consumer.oldest_data_age(), Some(ms(5)));
+        consumer.consume(90).unwrap();
+        assert_eq!(consumer.oldest_data_age(), None);
+        assert_eq!(consumer.stats().oldest_data_age, None);
+
+        producer.write(&[3; 10]).unwrap();
+        clock.advance(ms(1));
+        assert_eq!(consumer.stats().oldest_data_age, Some(ms(1)));
+
+        // With the marks used up, the unmarked writes are reported as the age of the last mark.
+        let mut ring = RingBuf::new(1).unwrap();
+        ring.set_clock(clock.clone());
+        let (mut producer, mut consumer) = ring.split().unwrap();
+        for _ in 0..MAX_AGE_MARKS + 10 {
+            producer.write(&[0; 8]).unwrap();
+            clock.advance(ms(1));
+        }
+        consumer.consume(MAX_AGE_MARKS * 8).unwrap();
+        assert_eq!(consumer.oldest_data_age(), Some(ms(11)));
+
+        #[cfg(mapped)]
+        {
+            let (mut producer, consumer) = RingBuf::new_shared(1).unwrap();
+            producer.write(b"untracked").unwrap();
+            assert_eq!(consumer.oldest_data_age(), None);
+        }
+    }
+
+    #[test]
+    fn stats_interval_adds_up_both_halves() {
+        let clock = MockClock::new();
+        let mut ring = RingBuf::new(1).unwrap();
+        ring.set_clock(clock.clone());
+        let cap = ring.capacity();
+        // Carried over into the halves' first interval.
+        ring.write(&[0; 1000]).unwrap();
+        clock.advance(Duration::from_millis(100));
+        let (mut producer, mut consumer) = ring.split().unwrap();
+
+        producer.write(&[0; 2000]).unwrap();
+        producer.write(&vec![0; cap]).expect_err("Doesn't fit.");
+        consumer.consume(500).unwrap();
+        consumer.consume(0).unwrap();
+        clock.advance(Duration::from_millis(150));
+
+        let first = consumer.stats_interval();
+        assert_eq!(first.elapsed, Duration::from_millis(250));
+        assert_eq!(first.bytes_in, 3000);
+        assert_eq!(first.ops_in, 2);
+        assert_eq!(first.bytes_out, 500);
+        assert_eq!(first.ops_out, 1);
+        assert_eq!(first.drops, 1);
+        assert_eq!(first.max_fill, 3000);
+
+        consumer.consume(2000).unwrap();
+        producer.write(&[0; 10]).unwrap();
+        clock.advance(Duration::from_secs(1));
+
+        // Either half can end the interval.
+        let second = producer.stats_interval();
+        assert_eq!(second.started_at, first.started_at + first.elapsed);
+        assert_eq!(second.elapsed, Duration::from_secs(1));
+        assert_eq!(second.bytes_in, 10);
+        assert_eq!(second.ops_in, 1);
+        assert_eq!(second.bytes_out, 2000);
+        assert_eq!(second.ops_out, 1);
+        assert_eq!(second.drops, 0);
+        // What was left over from the first interval counts.
+        assert_eq!(second.max_fill, 2500);
+
+        // The lifetime totals keep counting across intervals, from the split.
+        let stats = producer.stats();
+        assert_eq!((stats.bytes_written, stats.bytes_read), (2010, 2500));
+        assert_eq!(stats.rejected_writes, 1);
+    }
+
+    #[test]
+    fn finishing_versus_dropping() {
+        let mut buf = Vec::new();
+        let (mut producer, mut consumer) = RingBuf::new(1).unwrap().split().unwrap();
+        producer.write_msg(b"first").unwrap();
+        producer.write_msg(b"second").unwrap();
+        producer.finish().unwrap();
+        // The marker comes after everything buffered ahead of it, and stays.
+        assert_eq!(
+            consumer.recv_msg_into(&mut buf).unwrap(),
+            Some(MsgEvent::Data(5))
+        );
+        assert_eq!(buf, b"first");
+        assert_eq!(
+            consumer.recv_msg_into(&mut buf).unwrap(),
+            Some(MsgEvent::Data(6))
+        );
+        assert_eq!(buf, b"second");
+        assert_eq!(
+            consumer.recv_msg_into(&mut buf).unwrap(),
+            Some(MsgEvent::End)
+        );
+        assert_eq!(
+            consumer.recv_msg_into(&mut buf).unwrap(),
+            Some(MsgEvent::End)
+        );
+
+        // Dropped without finishing, the stream just stops.
+        let (mut producer, mut consumer) = RingBuf::new(1).unwrap().split().unwrap();
+        assert_eq!(consumer.recv_msg_into(&mut buf).unwrap(), None);
+        producer.write_msg(b"last words").unwrap();
+        drop(producer);
+        assert_eq!(
+            consumer.recv_msg_into(&mut buf).unwrap(),
+            Some(MsgEvent::Data(10))
+        );
+        assert!(matches!(
+            consumer.recv_msg_into(&mut buf),
+            Err(Error::Ours(BufError::Disconnected))
+        ));
+    }
+
+    #[test]
+    fn finish_waits_for_room_for_the_marker() {
+        let (mut producer, mut consumer) = RingBuf::new(1).unwrap().split().unwrap();
+        let cap = producer.capacity();
+        producer.write_msg(&vec![7; cap - MSG_HEADER_LEN]).unwrap();
+        let finisher = thread::spawn(move || producer.finish());
+
+        let mut buf = Vec::new();
+        let mut events = Vec::new();
+        loop {
+            match consumer.recv_msg_into(&mut buf).unwrap() {
+                Some(MsgEvent::End) => break,
+                Some(event) => events.push(event),
+                None => thread::yield_now(),
+            }
+        }
+        assert_eq!(events, [MsgEvent::Data(cap - MSG_HEADER_LEN)]);
+        finisher.join().unwrap().unwrap();
+    }
+
+    type Payloads = Arc<Mutex<Vec<Vec<u8>>>>;
+
+    /// A split ring on a `MockClock`, with a consumer that keeps what it skips.
+    fn with_ttl(ttl: Duration) -> (Producer, Consumer, MockClock, Payloads) {
+        let clock = MockClock::new();
+        let mut ring = RingBuf::new(1).unwrap();
+        ring.set_clock(clock.clone());
+        let (producer, mut consumer) = ring.split().unwrap();
+        consumer.set_ttl(ttl);
+        let expired = Arc::new(Mutex::new(Vec::new()));
+        let seen = expired.clone();
+        consumer.on_expired(move |payload, _| seen.lock().unwrap().push(payload.to_vec()));
+        (producer, consumer, clock, expired)
+    }
+
+    fn recv_all(consumer: &mut Consumer) -> Vec<Vec<u8>> {
+        let mut buf = Vec::new();
+        let mut got = Vec::new();
+        while let Some(MsgEvent::Data(_)) = consumer.recv_msg_into(&mut buf).unwrap() {
+            got.push(buf.clone());
+        }
+        got
+    }
+
+    #[test]
+    fn stale_messages_are_skipped_between_fresh_ones() {
+        let ms = Duration::from_millis;
+        let (mut producer, mut consumer, clock, expired) = with_ttl(ms(50));
+        producer.write_msg_timed(b"old").unwrap();
+        clock.advance(ms(30));
+        producer.write_msg_timed(b"just in time").unwrap();
+        producer.write_msg(b"untimed").unwrap();
+        clock.advance(ms(21));
+        producer.write_msg_timed(b"fresh").unwrap();
+        // `old` is 80 ms old by now, and the next one exactly 50, which is still fine.
+        clock.advance(ms(29));
+        assert_eq!(
+            recv_all(&mut consumer),
+            [&b"just in time"[..], b"untimed", b"fresh"]
+        );
+        assert_eq!(*expired.lock().unwrap(), [b"old"]);
+        assert_eq!(consumer.stats().expired_frames, 1);
+    }
+
+    #[test]
+    fn an_all_stale_backlog_drains_to_nothing() {
+        let ms = Duration::from_millis;
+        let (mut producer, mut consumer, clock, expired) = with_ttl(ms(50));
+        for i in 0..100u8 {
+            producer.write_msg_timed(&[i]).unwrap();
+        }
+        producer.finish().unwrap();
+        clock.advance(ms(51));
+        let mut buf = Vec::new();
+        assert_eq!(
+            consumer.recv_msg_into(&mut buf).unwrap(),
+            Some(MsgEvent::End)
+        );
+        assert!(buf.is_empty());
+        assert_eq!(expired.lock().unwrap().len(), 100);
+        assert_eq!(consumer.stats().expired_frames, 100);
+        assert_eq!(consumer.len(), MSG_HEADER_LEN);
+        consumer.reset_stats();
+        assert_eq!(consumer.stats().expired_frames, 0);
+    }
+
+    #[test]
+    fn without_a_ttl_timed_messages_are_just_messages() {
+        let (mut producer, mut consumer, clock, expired) = with_ttl(Duration::ZERO);
+        consumer.clear_ttl();
+        producer.write_msg_timed(b"one").unwrap();
+        producer.write_msg_timed(b"two").unwrap();
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(recv_all(&mut consumer), [b"one", b"two"]);
+        assert!(expired.lock().unwrap().is_empty());
+        assert_eq!(consumer.stats().expired_frames, 0);
+    }
+
+    #[test]
+    #[cfg_attr(
+        any(feature = "portable", not(mapped)),
+        ignore = "counts mappings and memfds"
+    )]
+    fn unmapped_once_both_halves_are_gone() {
+        let leaks = LeakCheck::new();
+        let (producer, consumer) = RingBuf::new(1).unwrap().split().unwrap();
+        let one = Live {
+            mappings: 1,
+            memfds: 1,
+        };
+        assert_eq!(leaks.live(), one);
+        thread::spawn(move || drop(producer)).join().unwrap();
+        assert_eq!(leaks.live(), one);
+        drop(consumer);
+        assert_eq!(leaks.live(), Live::default());
+    }
+
+    #[test]
+    fn consume_scrubs_in_zeroize_mode() {
+        let ring = RingBuf::builder()
+            .zeroize(true)
+            .debug_fill(false)
+            .build()
+            .unwrap();
+        let ptr = ring.data_ptr();
+        let (mut producer, mut consumer) = ring.split().unwrap();
+        producer.write(b"secret").unwrap();
+        consumer.consume(6).unwrap();
+        assert_eq!(unsafe { std::slice::from_raw_parts(ptr, 6) }, &[0; 6]);
+    }
+
+    #[test]
+    #[cfg(mapped)]
+    #[cfg_attr(feature = "portable", ignore = "counts mappings and memfds")]
+    fn shared_through_a_duplicated_fd() {
+        let leaks = LeakCheck::new();
+        let (mut producer, consumer) = RingBuf::new_shared(2).unwrap();
+        let fd = consumer.memfd().unwrap().try_clone_to_owned().unwrap();
+        // Everything the other side needs goes through the fd, so the original consumer can go.
+        drop(consumer);
+        assert_eq!(leaks.live().mappings, 1);
+        const TOTAL: usize = 1 << 20;
+
+        let reader = thread::spawn(move || {
+            let mut consumer = unsafe { Consumer::from_fd(fd, 2) }.unwrap();
+            let mut pos = 0;
+            let mut out = vec![0; 3000];
+            while pos < TOTAL {
+                let n = consumer.read_into(&mut out);
+                if n == 0 {
+                    thread::yield_now();
+                }
+                for (i, &b) in out[..n].iter().enumerate() {
+                    assert_eq!(b, stream_byte(pos + i), "at {}", pos + i);
+                }
+                pos += n;
+            }
+        });
+        assert_eq!(leaks.live().mappings, 1);
+
+        let mut pos = 0;
+        while pos < TOTAL {
+            let n = (1 + pos % 4093).min(TOTAL - pos);
+            let chunk: Vec<u8> = (pos..pos + n).map(stream_byte).collect();
+            while producer.write(&chunk).is_err() {
+                thread::yield_now();
+            }
+            pos += n;
+        }
+        reader.join().unwrap();
+        assert_eq!(producer.free_space(), producer.capacity());
+    }
+
+    #[test]
+    #[cfg(mapped)]
+    fn from_fd_picks_up_where_the_ring_is() {
+        let (mut producer, mut consumer) = RingBuf::new_shared(1).unwrap();
+        producer.write(b"before").unwrap();
+        consumer.consume(2).unwrap();
+        let fd = consumer.memfd().unwrap();
+        let dup = || fd.try_clone_to_owned().unwrap();
+
+        // Taking over from the original halves, one at a time.
+        let mut second = unsafe { Consumer::from_fd(dup(), 1) }.unwrap();
+        assert_eq!(second.peek(), b"fore");
+        drop(producer);
+        let mut producer = unsafe { Producer::from_fd(dup(), 1) }.unwrap();
+        producer.write(b" and after").unwrap();
+        assert_eq!(second.peek(), b"fore and after");
+        second.consume(4).unwrap();
+        assert_eq!(producer.free_space(), producer.capacity() - 10);
+
+        assert!(matches!(
+            unsafe { Consumer::from_fd(dup(), 2) },
+            Err(Error::Ours(BufError::CapacityMismatch))
+        ));
+        // A plain ring's memfd has no header, and is one page short or has no magic.
+        for pages in [1, 2] {
+            let plain = RingBuf::builder()
+                .pages(pages)
+                .backend(BackendKind::Reserve)
+                .build()
+                .unwrap();
+            let fd = plain.memfd().unwrap().try_clone_to_owned().unwrap();
+            assert!(matches!(
+                unsafe { Consumer::from_fd(fd, 1) },
+                Err(Error::Ours(BufError::CapacityMismatch))
+            ));
+        }
+        let (producer, _) = RingBuf::new(1).unwrap().split().unwrap();
+        assert!(producer.memfd().is_none());
+    }
+
+    #[test]
+    fn blocking_writes_wait_for_a_slow_consumer() {
+        let (mut producer, mut consumer) = RingBuf::new(1).unwrap().split().unwrap();
+        let chunk = producer.capacity() / 4;
+        const CHUNKS: usize = 40;
+
+        let reader = thread::spawn(move || {
+            let mut out = vec![0; chunk];
+            for i in 0..CHUNKS {
+                thread::sleep(Duration::from_millis(2));
+                consumer.read_blocking(&mut out).unwrap();
+                assert!(out.iter().all(|&b| b == i as u8), "chunk {i}");
+            }
+            consumer
+        });
+        for i in 0..CHUNKS {
+            producer.write_blocking(&vec![i as u8; chunk]).unwrap();
+        }
+        let consumer = reader.join().unwrap();
+        assert!(consumer.is_empty());
+        // Asleep between the consumer's reads rather than spinning: a wakeup or so per read.
+        let wakeups = producer.shared.parking.wakeups.load(Ordering::Relaxed);
+        assert!(wakeups <= 2 * CHUNKS, "{wakeups} wakeups");
+    }
+
+    #[test]
+    fn blocking_calls_time_out() {
+        let (mut producer, mut consumer) = RingBuf::new(1).unwrap().split().unwrap();
+        let cap = producer.capacity();
+        let timeout = Duration::from_millis(20);
+
+        let start = Instant::now();
+        assert!(matches!(
+            consumer.read_timeout(&mut [0; 1], timeout),
+            Err(Error::Ours(BufError::TimedOut))
+        ));
+        assert!(start.elapsed() >= timeout);
+
+        producer.write(&vec![1; cap - 1]).unwrap();
+        let start = Instant::now();
+        assert!(matches!(
+            producer.write_timeout(b"xy", timeout),
+            Err(Error::Ours(BufError::TimedOut))
+        ));
+        assert!(start.elapsed() >= timeout);
+        producer.write_timeout(b"x", timeout).unwrap();
+        assert!(matches!(
+            producer.write_blocking(&vec![0; cap + 1]),
+            Err(Error::Ours(BufError::NotEnoughSpace { available, .. })) if available == cap
+        ));
+
+        let mut out = vec![0; cap];
+        consumer.read_timeout(&mut out, timeout).unwrap();
+        assert_eq!(out.last(), Some(&b'x'));
+    }
+
+    #[test]
+    fn write_all_outlasts_the_ring() {
+        let (mut producer, mut consumer) = RingBuf::new(1).unwrap().split().unwrap();
+        let input: Vec<u8> = (0..4 * producer.capacity()).map(stream_byte).collect();
+        let total = input.len();
+        let reader = thread::spawn(move || {
+            let mut output = Vec::new();
+            let mut out = vec![0; 3000];
+            while output.len() < total {
+                thread::sleep(Duration::from_millis(1));
+                let n = consumer.read_into(&mut out);
+                output.extend_from_slice(&out[..n]);
+            }
+            output
+        });
+        producer.write_all_blocking(&input).unwrap();
+        assert!(reader.join().unwrap() == input);
+        producer.write_all_blocking(b"").unwrap();
+
+        // Out of time, and the consumer gone, partway through: the error says how far it got.
+        let (mut producer, mut consumer) = RingBuf::new(1).unwrap().split().unwrap();
+        let cap = producer.capacity();
+        assert!(matches!(
+            producer.write_all_timeout(&input, Duration::from_millis(10)),
+            Err(Error::Ours(BufError::Incomplete { written })) if written == cap
+        ));
+        let reader = thread::spawn(move || {
+            let mut out = vec![0; cap / 2];
+            consumer.read_blocking(&mut out).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        });
+        let err = producer.write_all_blocking(&input).unwrap_err();
+        reader.join().unwrap();
+        assert!(matches!(
+            err,
+            Error::Ours(BufError::Incomplete { written }) if written == cap / 2
+        ));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn dropping_a_half_wakes_the_other() {
+        let (mut producer, consumer) = RingBuf::new(1).unwrap().split().unwrap();
+        producer.write(&vec![0; producer.capacity()]).unwrap();
+        let writer = thread::spawn(move || producer.write_blocking(b"never fits"));
+        thread::sleep(Duration::from_millis(10));
+        drop(consumer);
+        assert!(matches!(
+            writer.join().unwrap(),
+            Err(Error::Ours(BufError::Disconnected))
+        ));
+
+        let (mut producer, mut consumer) = RingBuf::new(1).unwrap().split().unwrap();
+        let reader = thread::spawn(move || {
+            let mut out = [0; 8];
+            let result = consumer.read_blocking(&mut out);
+            (result, consumer.len())
+        });
+        producer.write(b"half").unwrap();
+        thread::sleep(Duration::from_millis(10));
+        drop(producer);
+        let (result, left) = reader.join().unwrap();
+        assert!(matches!(result, Err(Error::Ours(BufError::Disconnected))));
+        // What did arrive is still there.
+        assert_eq!(left, 4);
+    }
+
+    #[test]
+    fn io_copy_through_the_halves() {
+        let (producer, consumer) = RingBuf::new(1).unwrap().split().unwrap();
+        let input: Vec<u8> = (0..3 << 20).map(stream_byte).collect();
+        let source = input.clone();
+        let writer = thread::spawn(move || {
+            let mut producer = producer.into_blocking();
+            io::copy(&mut &source[..], &mut producer).unwrap()
+            // Dropping the producer here is the reader's end of stream.
+        });
+        let mut output = Vec::new();
+        let copied = io::copy(&mut consumer.into_blocking(), &mut output).unwrap();
+        assert_eq!(writer.join().unwrap(), input.len() as u64);
+        assert_eq!(copied, input.len() as u64);
+        assert!(output == input);
+
+        // Once the reader is gone, so is the pipe.
+        let (producer, consumer) = RingBuf::new(1).unwrap().split().unwrap();
+        drop(consumer);
+        let err = producer.into_blocking().write(b"x").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn non_blocking_io_would_block() {
+        let (mut producer, mut consumer) = RingBuf::new(1).unwrap().split().unwrap();
+        let cap = producer.capacity();
+        let mut out = [0; 8];
+        assert_eq!(
+            consumer.read(&mut out).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        assert_eq!(Write::write(&mut producer, &vec![1; cap + 5]).unwrap(), cap);
+        assert_eq!(
+            Write::write(&mut producer, b"x").unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        assert_eq!(Write::write(&mut producer, b"").unwrap(), 0);
+
+        assert_eq!(consumer.read(&mut out).unwrap(), 8);
+        drop(producer);
+        // What was written before the producer went still comes out, then the end of stream.
+        let mut rest = Vec::new();
+        consumer.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest.len(), cap - 8);
+        assert_eq!(consumer.read(&mut out).unwrap(), 0);
+
+        let (mut producer, consumer) = RingBuf::new(1).unwrap().split().unwrap();
+        drop(consumer);
+        assert_eq!(
+            Write::write(&mut producer, b"x").unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+
+    /// Whether `fd` polls readable within `timeout_ms` (-1 for no timeout).
+    #[cfg(eventfd)]
+    fn readable(fd: &OwnedFd, timeout_ms: i32) -> bool {
+        use std::os::fd::AsRawFd;
+        let mut pollfd = libc::pollfd {
+            fd: fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let n = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+        assert!(n >= 0, "poll: {}", std::io::Error::last_os_error());
+        pollfd.revents & libc::POLLIN != 0
+    }
+
+    #[test]
+    #[cfg(eventfd)]
+    fn readiness_fds_follow_the_ring() {
+        let (mut producer, mut consumer) = RingBuf::new(1).unwrap().split().unwrap();
+        let cap = producer.capacity();
+        producer.write(b"early").unwrap();
+        // Duplicates share the eventfd, and don't keep the halves borrowed.
+        let data = consumer.data_fd().unwrap().try_clone_to_owned().unwrap();
+        let space = producer.space_fd().unwrap().try_clone_to_owned().unwrap();
+        // Ready from the start, for what happened before anyone asked.
+        assert!(readable(&data, 0) && readable(&space, 0));
+
+        assert_eq!(consumer.read_into(&mut [0; 16]), 5);
+        assert!(!readable(&data, 0) && readable(&space, 0));
+
+        producer.write(&vec![1; cap]).unwrap();
+        assert!(readable(&data, 0) && !readable(&space, 0));
+        assert!(producer.write(b"x").is_err());
+        assert!(!readable(&space, 0));
+
+        consumer.consume(1).unwrap();
+        assert!(readable(&space, 0));
+        // Not enough room for this, and no more until the consumer frees some.
+        assert!(producer.write(b"xy").is_err());
+        assert!(!readable(&space, 0));
+        consumer.consume(1).unwrap();
+        assert!(readable(&space, 0));
+        producer.write(b"xy").unwrap();
+        assert!(!readable(&space, 0));
+
+        let mut out = vec![0; cap];
+        assert_eq!(consumer.read_into(&mut out), cap);
+        assert!(!readable(&data, 0) && readable(&space, 0));
+        assert_eq!(consumer.read_into(&mut out), 0);
+        assert!(!readable(&data, 0));
+
+        // And a poller blocked on it wakes up.
+        let poller = thread::spawn(move || readable(&data, -1));
+        thread::sleep(Duration::from_millis(10));
+        producer.write(b"wake").unwrap();
+        assert!(poller.join().unwrap());
+    }
+
+    #[test]
+    #[cfg(eventfd)]
+    fn poll_loops_never_miss_a_wakeup() {
+        let (mut producer, mut consumer) = RingBuf::new(1).unwrap().split().unwrap();
+        const TOTAL: usize = 2 << 20;
+        // Long enough that only a lost signal gets there.
+        const STUCK_MS: i32 = 5000;
+
+        let reader = thread::spawn(move || {
+            let data = consumer.data_fd().unwrap().try_clone_to_owned().unwrap();
+            let (mut pos, mut out) = (0, vec![0; 1000]);
+            while pos < TOTAL {
+                assert!(readable(&data, STUCK_MS), "stuck at {pos}");
+                let n = consumer.read_into(&mut out);
+                assert!(out[..n]
+                    .iter()
+                    .enumerate()
+                    .all(|(i, &b)| b == stream_byte(pos + i)));
+                pos += n;
+            }
+        });
+        let space = producer.space_fd().unwrap().try_clone_to_owned().unwrap();
+        let (mut pos, mut chunk) = (0, 1);
+        while pos < TOTAL {
+            let len = chunk.min(TOTAL - pos);
+            let raw: Vec<u8> = (pos..pos + len).map(stream_byte).collect();
+            match producer.write(&raw) {
+                Ok(()) => {
+                    pos += len;
+                    chunk = chunk * 7 % 1999 + 1;
+                }
+                Err(_) => assert!(readable(&space, STUCK_MS), "stuck at {pos}"),
+            }
+        }
+        reader.join().unwrap();
+    }
+}
@@ -0,0 +1,459 @@
+//! A ring behind a lock, for when several threads write and it isn't worth a queue per writer.
+
+use super::{BufError, Error, MsgEvent, Result, RingBuf};
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// A `RingBuf` behind a mutex, shared by cloning the handle. Any number of threads can `write`
+/// or `write_msg` through their own clone while another drains it, each call taking the lock
+/// for just as long as it needs. For exactly one writer and one reader, `RingBuf::split` is
+/// lock-free and faster.
+///
+/// Nothing borrowed from the ring outlives the lock: reads copy out (`read_msg`) or lend the
+/// bytes to a closure (`with_read`). If a thread panics while holding the lock, say inside such
+/// a closure, the ring may be half-updated, so every call on every handle fails with
+/// `BufError::Poisoned` from then on.
+#[derive(Clone)]
+pub struct SharedRingBuf {
+    ring: Arc<Mutex<RingBuf>>,
+    // Notified whenever a handle may have consumed something, for writers waiting for room.
+    freed: Arc<Condvar>,
+}
+
+impl SharedRingBuf {
+    pub fn new(ring: RingBuf) -> Self {
+        Self {
+            ring: Arc::new(Mutex::new(ring)),
+            freed: Arc::new(Condvar::new()),
+        }
+    }
+
+    /// `RingBuf::write` under the lock.
+    pub fn write(&self, raw: &[u8]) -> Result<()> {
+        self.lock()?.write(raw)
+    }
+
+    /// `RingBuf::write_msg` under the lock, so messages from different threads never interleave.
+    pub fn write_msg(&self, payload: &[u8]) -> Result<()> {
+        self.lock()?.write_msg(payload)
+    }
+
+    /// `RingBuf::read_msg`, copied out so the lock can go.
+    pub fn read_msg(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.lock_to_read()?.read_msg()?.map(<[u8]>::to_vec))
+    }
+
+    /// `RingBuf::write_msg_end` under the lock.
+    pub fn write_msg_end(&self) -> Result<()> {
+        self.lock()?.write_msg_end()
+    }
+
+    /// `RingBuf::recv_msg`, copied out so the lock can go.
+    pub fn recv_msg(&self) -> Result<Option<MsgEvent<Vec<u8>>>> {
+        Ok(match self.lock_to_read()?.recv_msg()? {
+            Some(MsgEvent::Data(msg)) => Some(MsgEvent::Data(msg.to_vec())),
+            Some(MsgEvent::End) => Some(MsgEvent::End),
+            None => None,
+        })
+    }
+
+    /// Consumes the next `num_bytes` and hands them to `f` without copying, holding the lock
+    /// until it returns. Fails like `RingBuf::read`, without calling `f`.
+    pub fn with_read<T>(&self, num_bytes: usize, f: impl FnOnce(&[u8]) -> T) -> Result<T> {
+        let mut ring = self.lock_to_read()?;
+        Ok(f(ring.read(num_bytes)?))
+    }
+
+    /// Runs `f` on the ring itself under the lock, for anything the handle doesn't cover.
+    pub fn with<T>(&self, f: impl FnOnce(&mut RingBuf) -> T) -> Result<T> {
+        Ok(f(&mut *self.lock_to_read()?))
+    }
+
+    pub fn len(&self) -> Result<usize> {
+        Ok(self.lock()?.len())
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.lock()?.is_empty())
+    }
+
+    /// A `Write` sink over this ring. See `SharedWriter`.
+    pub fn writer(&self, when_full: FullPolicy) -> SharedWriter {
+        SharedWriter {
+            ring: self.clone(),
+            when_full,
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Runs `write` under the lock until it stops failing with `BufError::NotEnoughSpace`,
+    /// sleeping until a read on some handle frees space in between. Gives up with that error
+    /// once `deadline` has passed; `None` waits for as long as it takes.
+    pub(crate) fn write_waiting(
+        &self,
+        deadline: Option<Instant>,
+        mut write: impl FnMut(&mut RingBuf) -> Result<()>,
+    ) -> Result<()> {
+        let mut ring = self.lock()?;
+        loop {
+            let full = match write(&mut ring) {
+                Err(e @ Error::Ours(BufError::NotEnoughSpace { .. })) => e,
+                result => return result,
+            };
+            ring = match deadline {
+                None => self.freed.wait(ring).map_err(|_| BufError::Poisoned)?,
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(left) if !left.is_zero() => {
+                        let waited = self.freed.wait_timeout(ring, left);
+                        waited.map_err(|_| BufError::Poisoned)?.0
+                    }
+                    _ => return Err(full),
+                },
+            };
+        }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, RingBuf>> {
+        self.ring.lock().map_err(|_| BufError::Poisoned.into())
+    }
+
+    /// The lock, for something that may consume.
+    fn lock_to_read(&self) -> Result<Reading<'_>> {
+        Ok(Reading {
+            ring: self.lock()?,
+            freed: &self.freed,
+        })
+    }
+}
+
+/// The lock held by something that may consume. Letting go of it wakes the writers waiting for
+/// room, even if the holder panicked, so they find out the ring is poisoned.
+struct Reading<'a> {
+    ring: MutexGuard<'a, RingBuf>,
+    freed: &'a Condvar,
+}
+
+impl Deref for Reading<'_> {
+    type Target = RingBuf;
+
+    fn deref(&self) -> &RingBuf {
+        &self.ring
+    }
+}
+
+impl DerefMut for Reading<'_> {
+    fn deref_mut(&mut self) -> &mut RingBuf {
+        &mut self.ring
+    }
+}
+
+impl Drop for Reading<'_> {
+    fn drop(&mut self) {
+        self.freed.notify_all();
+    }
+}
+
+/// What a `SharedWriter` does with a record the ring has no room for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FullPolicy {
+    /// Drop it and count it in `SharedWriter::dropped`.
+    Drop,
+    /// Wait up to this long for a read to make room, then drop it the same way. `Duration::MAX`
+    /// waits for as long as it takes.
+    Block(Duration),
+}
+
+/// An `io::Write` sink that any number of components can log into through a clone (or a shared
+/// reference), without each one owning a ring handle. Every `write` call becomes one message
+/// from `RingBuf::write_msg`, so records from different threads never shear into each other;
+/// `write!` and `writeln!` format the whole record first and write it in one call.
+///
+/// A record that doesn't fit is dropped according to the `FullPolicy` and still reported as
+/// written, so a full ring never turns into errors at the call site. One that could never fit,
+/// or a poisoned ring, does fail.
+#[derive(Clone)]
+pub struct SharedWriter {
+    ring: SharedRingBuf,
+    when_full: FullPolicy,
+    dropped: Arc<AtomicU64>,
+}
+
+impl SharedWriter {
+    /// Records dropped for lack of room, across this writer and its clones.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn write_record(&self, record: &[u8]) -> io::Result<usize> {
+        // `None` for a wait too long to have an end.
+        let deadline = match self.when_full {
+            FullPolicy::Drop => Some(Instant::now()),
+            FullPolicy::Block(patience) => Instant::now().checked_add(patience),
+        };
+        match self
+            .ring
+            .write_waiting(deadline, |ring| ring.write_msg(record))
+        {
+            Ok(()) => Ok(record.len()),
+            Err(Error::Ours(BufError::NotEnoughSpace { .. })) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                Ok(record.len())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Write for &SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_record(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_fmt(&mut self, args: fmt::Arguments<'_>) -> io::Result<()> {
+        match args.as_str() {
+            Some(record) => self.write_record(record.as_bytes()),
+            None => self.write_record(args.to_string().as_bytes()),
+        }
+        .map(drop)
+    }
+}
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_fmt(&mut self, args: fmt::Arguments<'_>) -> io::Result<()> {
+        (&*self).write_fmt(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// Writer `writer`'s record number `seq`: both, then a payload whose length and contents
+    /// follow from them.
+    fn record(writer: u8, seq: u32) -> Vec<u8> {
+        let len = (seq as usize * 37 + writer as usize * 11) % 300;
+        let mut bytes = vec![writer];
+        bytes.extend(seq.to_le_bytes());
+        bytes.extend((0..len).map(|i| (i as u32 ^ seq) as u8 ^ writer));
+        bytes
+    }
+
+    #[test]
+    fn eight_writers_one_reader() {
+        const WRITERS: u8 = 8;
+        const PER_WRITER: u32 = 2000;
+        let shared = SharedRingBuf::new(RingBuf::new(4).unwrap());
+
+        let writers: Vec<_> = (0..WRITERS)
+            .map(|writer| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    for seq in 0..PER_WRITER {
+                        let record = record(writer, seq);
+                        loop {
+                            match shared.write_msg(&record) {
+                                Ok(()) => break,
+                                Err(Error::Ours(BufError::NotEnoughSpace { .. })) => {
+                                    thread::yield_now()
+                                }
+                                Err(e) => panic!("{e}"),
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        let mut next_seq = [0; WRITERS as usize];
+        let mut total = 0;
+        while total < WRITERS as u32 * PER_WRITER {
+            let Some(msg) = shared.read_msg().unwrap() else {
+                thread::yield_now();
+                continue;
+            };
+            let writer = msg[0];
+            let seq = u32::from_le_bytes(msg[1..5].try_into().unwrap());
+            // Each writer's records arrive whole, in order, and exactly once.
+            assert_eq!(seq, next_seq[writer as usize]);
+            assert_eq!(msg, record(writer, seq));
+            next_seq[writer as usize] += 1;
+            total += 1;
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(next_seq, [PER_WRITER; WRITERS as usize]);
+        assert!(shared.is_empty().unwrap());
+    }
+
+    #[test]
+    fn with_read_lends_bytes_under_the_lock() {
+        let shared = SharedRingBuf::new(RingBuf::new(1).unwrap());
+        shared.write(b"headerbody").unwrap();
+        assert_eq!(
+            shared.with_read(6, |bytes| bytes.to_vec()).unwrap(),
+            b"header"
+        );
+        assert_eq!(shared.len().unwrap(), 4);
+        assert!(matches!(
+            shared.with_read(5, |_| unreachable!("Not enough pending.")),
+            Err(Error::Ours(BufError::NotEnoughData { .. }))
+        ));
+        assert_eq!(shared.with(|ring| ring.copy_to_vec()).unwrap(), b"body");
+    }
+
+    #[test]
+    fn a_panic_under_the_lock_poisons_every_handle() {
+        let shared = SharedRingBuf::new(RingBuf::new(1).unwrap());
+        shared.write(b"data").unwrap();
+        let other = shared.clone();
+        thread::spawn(move || other.with_read(4, |_| panic!("Parser blew up.")))
+            .join()
+            .unwrap_err();
+        assert!(matches!(
+            shared.write(b"more"),
+            Err(Error::Ours(BufError::Poisoned))
+        ));
+        assert!(matches!(
+            shared.read_msg(),
+            Err(Error::Ours(BufError::Poisoned))
+        ));
+    }
+
+    #[test]
+    fn writers_log_whole_records_from_many_threads() {
+        const THREADS: usize = 8;
+        const PER_THREAD: usize = 500;
+        let shared = SharedRingBuf::new(RingBuf::new(1).unwrap());
+        let writer = shared.writer(FullPolicy::Block(Duration::from_secs(10)));
+
+        let loggers: Vec<_> = (0..THREADS)
+            .map(|thread| {
+                let writer = writer.clone();
+                thread::spawn(move || {
+                    for seq in 0..PER_THREAD {
+                        // Several pieces, but one record.
+                        writeln!(
+                            &writer,
+                            "thread {thread} record {seq}: {}",
+                            "x".repeat(seq % 50)
+                        )
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        let mut next_seq = [0; THREADS];
+        let mut total = 0;
+        while total < THREADS * PER_THREAD {
+            let Some(record) = shared.read_msg().unwrap() else {
+                thread::yield_now();
+                continue;
+            };
+            let record = String::from_utf8(record).unwrap();
+            let thread: usize = record["thread ".len()..][..1].parse().unwrap();
+            let seq = next_seq[thread];
+            assert_eq!(
+                record,
+                format!("thread {thread} record {seq}: {}\n", "x".repeat(seq % 50))
+            );
+            next_seq[thread] += 1;
+            total += 1;
+        }
+        for logger in loggers {
+            logger.join().unwrap();
+        }
+        assert_eq!(writer.dropped(), 0);
+        assert!(shared.is_empty().unwrap());
+    }
+
+    #[test]
+    fn full_rings_drop_and_count_records() {
+        let shared = SharedRingBuf::new(RingBuf::new(1).unwrap());
+        let capacity = shared.with(|ring| ring.capacity()).unwrap();
+        let mut writer = shared.writer(FullPolicy::Drop);
+        let record = [7; 96];
+        let fit = capacity / (record.len() + 4);
+        for _ in 0..fit + 25 {
+            assert_eq!(writer.write(&record).unwrap(), record.len());
+        }
+        assert_eq!(writer.dropped(), 25);
+
+        // A clone shares the count, and a brief block still gives up.
+        let patient = SharedWriter {
+            when_full: FullPolicy::Block(Duration::from_millis(5)),
+            ..writer.clone()
+        };
+        (&patient).write_all(&record).unwrap();
+        assert_eq!(writer.dropped(), 26);
+
+        for _ in 0..fit {
+            assert_eq!(shared.read_msg().unwrap().unwrap(), record);
+        }
+        assert!(shared.is_empty().unwrap());
+
+        // Too big to ever fit isn't a drop.
+        assert_eq!(
+            writer.write(&vec![0; capacity]).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(writer.dropped(), 26);
+    }
+
+    #[test]
+    fn blocked_writers_wait_for_a_read() {
+        let shared = SharedRingBuf::new(RingBuf::new(1).unwrap());
+        let capacity = shared.with(|ring| ring.capacity()).unwrap();
+        let record = vec![7; capacity / 2];
+        let writer = shared.writer(FullPolicy::Block(Duration::MAX));
+        (&writer).write_all(&record).unwrap();
+
+        // Only the read frees enough room, and the writer is asleep until it happens.
+        let blocked = {
+            let writer = writer.clone();
+            let record = record.clone();
+            thread::spawn(move || (&writer).write_all(&record))
+        };
+        thread::sleep(Duration::from_millis(20));
+        assert!(!blocked.is_finished());
+        assert_eq!(shared.read_msg().unwrap().unwrap(), record);
+        blocked.join().unwrap().unwrap();
+        assert_eq!(shared.read_msg().unwrap().unwrap(), record);
+        assert_eq!(writer.dropped(), 0);
+
+        // A reader that panics wakes it too, to find the ring poisoned.
+        (&writer).write_all(&record).unwrap();
+        let blocked = {
+            let writer = writer.clone();
+            thread::spawn(move || (&writer).write_all(&record))
+        };
+        thread::sleep(Duration::from_millis(20));
+        let reader = shared.clone();
+        thread::spawn(move || reader.with_read(1, |_| panic!("Parser blew up.")))
+            .join()
+            .unwrap_err();
+        let err = blocked.join().unwrap().unwrap_err();
+        assert!(matches!(
+            err.into_inner().unwrap().downcast::<Error>().as_deref(),
+            Ok(Error::Ours(BufError::Poisoned))
+        ));
+    }
+}
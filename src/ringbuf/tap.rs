@@ -0,0 +1,164 @@
+//! A read-only tap on a live ring, for watching the traffic without disturbing the real reader.
+
+use super::{
+    mirror::{MapOptions, Mirror},
+    BufError, Result, RingBuf,
+};
+
+/// A second, independent reader over a ring's memory, made by `RingBuf::try_clone_reader`. It
+/// has its own mapping of the same memfd and its own read cursor, so reading from the tap never
+/// moves the ring's indices and vice versa. It can't write. (A tap on a `BackendKind::Heap` ring
+/// shares the ring's memory instead, and nothing but the API stops it writing.)
+///
+/// The tap only learns about new data when told to with `refresh`. Nothing stops the ring's
+/// writer from reusing space the tap hasn't read yet, so the tap can see bytes change under it
+/// or get lapped; `refresh` skips whatever it knows was overwritten, but anything written
+/// between a `refresh` and a read is a race. In zeroize mode it will see zeros wherever the real
+/// reader has already consumed. A tap follows one memfd, so make a new one after `shrink_to` or
+/// `swap_contents` on the ring.
+pub struct RingTap {
+    mirror: Mirror,
+    // Offset of the tap's cursor in `0..capacity`.
+    head: usize,
+    // Bytes between the cursor and the end of what the ring had written at the last refresh.
+    len: usize,
+    // The ring's `bytes_written` as of the last refresh.
+    seen_written: u64,
+}
+
+impl RingTap {
+    pub(crate) fn new(ring: &RingBuf) -> Result<Self> {
+        let source = ring.mirror.as_ref().ok_or(BufError::ZeroCapacity)?;
+        let options = MapOptions {
+            read_only: true,
+            ..MapOptions::default()
+        };
+        Ok(Self {
+            mirror: Mirror::remap(source, options)?,
+            head: ring.head,
+            len: ring.contents_size(),
+            seen_written: ring.bytes_written,
+        })
+    }
+
+    /// Catches up with everything `ring` has written since the last refresh. Returns how many
+    /// bytes the tap fell so far behind on that they may have been overwritten, which it skips.
+    ///
+    /// `ring` has to be the one this tap was cloned from.
+    pub fn refresh(&mut self, ring: &RingBuf) -> usize {
+        debug_assert!(
+            ring.mirror
+                .as_ref()
+                .is_some_and(|m| m.same_file(&self.mirror)),
+            "refreshed a tap from a ring with different memory"
+        );
+        let added = (ring.bytes_written - self.seen_written) as usize;
+        self.seen_written = ring.bytes_written;
+        self.len += added;
+        let lapped = self.len.saturating_sub(self.capacity());
+        self.advance(lapped);
+        lapped
+    }
+
+    /// Everything the tap hasn't read yet, without consuming it.
+    pub fn peek(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.mirror.ptr.add(self.head), self.len) }
+    }
+
+    /// Reads the next `num_bytes`, moving only the tap's cursor.
+    pub fn read(&mut self, num_bytes: usize) -> Result<&[u8]> {
+        if num_bytes > self.len {
+            return Err(BufError::NotEnoughData {
+                requested: num_bytes,
+                available: self.len,
+            }
+            .into());
+        }
+        let start = self.head;
+        self.advance(num_bytes);
+        unsafe {
+            Ok(std::slice::from_raw_parts(
+                self.mirror.ptr.add(start),
+                num_bytes,
+            ))
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.mirror.size
+    }
+
+    /// How many bytes the tap can read before it needs a `refresh`.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn advance(&mut self, n: usize) {
+        self.head = super::index::advance(self.head, n, self.mirror.size);
+        self.len -= n;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{page_size, LeakCheck};
+    use super::*;
+
+    #[test]
+    fn tap_follows_writes_without_moving_the_ring() {
+        let _leaks = LeakCheck::new();
+        let mut ring = RingBuf::new(1).unwrap();
+        ring.write(b"before").unwrap();
+        ring.consume(3).unwrap();
+
+        let mut tap = ring.try_clone_reader().unwrap();
+        assert_eq!(tap.capacity(), ring.capacity());
+        assert_eq!(tap.peek(), b"ore");
+
+        ring.write(b"|after").unwrap();
+        assert_eq!(tap.len(), 3);
+        assert_eq!(tap.refresh(&ring), 0);
+        let (head, tail, len) = (ring.read_offset(), ring.write_offset(), ring.len());
+        assert_eq!(tap.read(4).unwrap(), b"ore|");
+        assert_eq!(tap.peek(), b"after");
+        assert!(tap.read(6).is_err());
+        assert_eq!(
+            (ring.read_offset(), ring.write_offset(), ring.len()),
+            (head, tail, len)
+        );
+
+        // And the ring's reader is unaffected by the tap.
+        assert_eq!(ring.read(9).unwrap(), b"ore|after");
+        assert_eq!(tap.read(5).unwrap(), b"after");
+        assert!(tap.is_empty());
+    }
+
+    #[test]
+    fn lapped_tap_skips_overwritten_bytes() {
+        let page = page_size().unwrap();
+        let _leaks = LeakCheck::new();
+        // Consumed bytes have to stay as they were for the tap to see them.
+        let mut ring = RingBuf::builder().debug_fill(false).build().unwrap();
+        let mut tap = ring.try_clone_reader().unwrap();
+        let chunk: Vec<u8> = (0..page / 2).map(|i| i as u8).collect();
+        for _ in 0..3 {
+            ring.write(&chunk).unwrap();
+            ring.consume(chunk.len()).unwrap();
+        }
+        ring.write(b"tail").unwrap();
+
+        assert_eq!(tap.refresh(&ring), page / 2 + 4);
+        assert_eq!(tap.len(), page);
+        assert_eq!(&tap.peek()[..page - 4], &[&chunk[4..], &chunk[..]].concat());
+        assert!(tap.peek().ends_with(b"tail"));
+    }
+
+    #[test]
+    fn no_tap_on_placeholder() {
+        assert!(RingBuf::default().try_clone_reader().is_err());
+    }
+}
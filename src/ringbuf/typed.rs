@@ -0,0 +1,186 @@
+//! A ring of whole values of any type, moved in and out by `push` and `pop`. Unlike the typed
+//! byte API and `SlotRing`, the values don't have to be `Pod`: the ring owns whatever is in it and
+//! drops anything left unread along with itself.
+
+use super::{mirror::Mirror, page_size, BufError, Result};
+use std::{marker::PhantomData, num::NonZeroUsize};
+
+/// A ring buffer owning up to `capacity()` values of `T`.
+///
+/// Laid out like `SlotRing`: the mapping is carved into `size_of::<T>()`-byte slots, which are
+/// all aligned since the mapping is page-aligned, and a value never straddles the wrap.
+pub struct TypedRingBuf<T> {
+    mirror: Mirror,
+    capacity: usize,
+    // Monotonic push/pop counters; the slot is the counter modulo `capacity`.
+    head: u64,
+    tail: u64,
+    // We own the `T`s in the mapping, as far as drop check is concerned.
+    _marker: PhantomData<T>,
+}
+
+impl<T> TypedRingBuf<T> {
+    /// Creates a ring with room for at least `min_len` values, rounded up to fill whole pages.
+    /// Fails with `BufError::ZeroCapacity` for a `min_len` of zero or a zero-sized `T`.
+    pub fn new(min_len: usize) -> Result<Self> {
+        let slot_size = size_of::<T>();
+        if min_len == 0 || slot_size == 0 {
+            return Err(BufError::ZeroCapacity.into());
+        }
+        let page_size = page_size()?;
+        assert!(align_of::<T>() <= page_size, "Alignment past a page.");
+
+        let buf_size = min_len
+            .checked_mul(slot_size)
+            .and_then(|bytes| bytes.checked_next_multiple_of(page_size))
+            .ok_or(BufError::CapacityOverflow)?;
+        let mirror = Mirror::new(NonZeroUsize::new(buf_size).unwrap())?;
+
+        Ok(Self {
+            mirror,
+            capacity: buf_size / slot_size,
+            head: 0,
+            tail: 0,
+            _marker: PhantomData,
+        })
+    }
+
+    /// How many values fit.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// How many values are waiting to be popped.
+    pub fn len(&self) -> usize {
+        (self.tail - self.head) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head == self.tail
+    }
+
+    /// Moves `value` into the ring, or hands it back if the ring is full.
+    pub fn push(&mut self, value: T) -> std::result::Result<(), T> {
+        if self.len() == self.capacity {
+            return Err(value);
+        }
+        unsafe { self.slot_ptr(self.tail).write(value) };
+        self.tail += 1;
+        Ok(())
+    }
+
+    /// Moves the oldest value out of the ring.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        // The slot counts as free from here on, so this is the only copy.
+        let value = unsafe { self.slot_ptr(self.head).read() };
+        self.head += 1;
+        Some(value)
+    }
+
+    /// The oldest value, without popping it.
+    pub fn peek(&self) -> Option<&T> {
+        if self.is_empty() {
+            return None;
+        }
+        unsafe { Some(&*self.slot_ptr(self.head)) }
+    }
+
+    /// Drops everything in the ring.
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+
+    fn slot_ptr(&self, seq: u64) -> *mut T {
+        let slot = (seq % self.capacity as u64) as usize;
+        unsafe { self.mirror.ptr.add(slot * size_of::<T>()) as *mut T }
+    }
+}
+
+impl<T> Drop for TypedRingBuf<T> {
+    fn drop(&mut self) {
+        // The mapping goes with `mirror` right after this, but the values need their own drops.
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ringbuf::LeakCheck;
+    use std::{cell::Cell, rc::Rc};
+
+    /// Counts its own drops in a shared counter, and owns a heap allocation so a double drop or
+    /// a use after free shows up under Miri or ASan too.
+    struct Tracked {
+        id: Box<u64>,
+        drops: Rc<Cell<usize>>,
+    }
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+        }
+    }
+
+    #[test]
+    fn every_value_dropped_exactly_once() {
+        let _leaks = LeakCheck::new();
+        let drops = Rc::new(Cell::new(0));
+        let tracked = |id| Tracked {
+            id: Box::new(id),
+            drops: Rc::clone(&drops),
+        };
+
+        let mut ring = TypedRingBuf::new(1).unwrap();
+        let cap = ring.capacity() as u64;
+        let (mut created, mut next_id, mut next_pop) = (0, 0, 0);
+        // Go around a few times, popping some and leaving the rest in the ring.
+        for round in 0..3 {
+            while ring.len() < ring.capacity() {
+                assert!(ring.push(tracked(next_id)).is_ok());
+                next_id += 1;
+                created += 1;
+            }
+            let refused = ring.push(tracked(u64::MAX)).unwrap_err();
+            assert_eq!(*refused.id, u64::MAX);
+            drop(refused);
+            created += 1;
+
+            for _ in 0..cap / 2 + round {
+                let value = ring.pop().unwrap();
+                assert_eq!(*value.id, next_pop);
+                next_pop += 1;
+            }
+        }
+        assert_eq!(ring.peek().map(|t| *t.id), Some(next_pop));
+        // Everything popped, plus the three that didn't fit.
+        assert_eq!(drops.get() as u64, next_pop + 3);
+        drop(ring);
+        assert_eq!(drops.get() as u64, created);
+    }
+
+    #[test]
+    fn strings_and_alignment() {
+        let mut ring = TypedRingBuf::<(u8, String, u64)>::new(3).unwrap();
+        assert_eq!(
+            ring.mirror.ptr as usize % align_of::<(u8, String, u64)>(),
+            0
+        );
+        for i in 0..10 * ring.capacity() {
+            ring.push((i as u8, i.to_string(), i as u64)).unwrap();
+            if i % 3 == 0 {
+                let (small, text, big) = ring.pop().unwrap();
+                assert_eq!(text.parse::<u64>().unwrap(), big);
+                assert_eq!(small, big as u8);
+            }
+            if ring.len() == ring.capacity() {
+                ring.clear();
+            }
+        }
+        assert!(TypedRingBuf::<u32>::new(0).is_err());
+        assert!(TypedRingBuf::<()>::new(1).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+//! Tags for catching `write_typed::<Foo>` being read back as `read_typed::<Bar>`. With typed
+//! checks on, every typed value goes into the ring behind a tag naming its type, which the reader
+//! compares against the type it asked for. With them off nothing is added, so the wire format is
+//! just the value's bytes.
+
+use super::BufError;
+use std::{any::type_name, borrow::Cow, collections::HashMap, sync::Mutex};
+
+/// Marks the start of a tag, so a checked reader can tell a tagged value from a bare one.
+const MAGIC: u8 = 0xc7;
+
+/// Magic byte and three bytes of padding, the type's size as a `u32`, then an 8-byte hash of the
+/// type's name, little-endian. A multiple of 8 long so it doesn't knock the value after it out of
+/// alignment.
+pub(crate) const TAG_LEN: usize = 16;
+
+/// Type names by hash, so a mismatch can say what was actually found. Only types that have been
+/// written with checks on in this process are in here; nothing else depends on it.
+static NAMES: Mutex<Option<HashMap<u64, &'static str>>> = Mutex::new(None);
+
+/// FNV-1a. Stable across builds and runs, unlike `TypeId`, and plenty for telling type names
+/// apart.
+fn name_hash(name: &str) -> u64 {
+    name.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+pub(crate) fn tag_for<T>() -> [u8; TAG_LEN] {
+    let name = type_name::<T>();
+    let hash = name_hash(name);
+    NAMES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(HashMap::new)
+        .insert(hash, name);
+
+    let mut tag = [0; TAG_LEN];
+    tag[0] = MAGIC;
+    tag[4..8].copy_from_slice(&(size_of::<T>() as u32).to_le_bytes());
+    tag[8..].copy_from_slice(&hash.to_le_bytes());
+    tag
+}
+
+/// Checks the tag at the start of `pending` against `T`. The tag only has to match `T` itself,
+/// so values tagged by another process check out just the same.
+pub(crate) fn check<T>(pending: &[u8]) -> Result<(), BufError> {
+    let tag = pending.get(..TAG_LEN).ok_or(BufError::NotEnoughData {
+        requested: TAG_LEN,
+        available: pending.len(),
+    })?;
+    if tag[0] != MAGIC || tag[1..4] != [0; 3] {
+        return Err(BufError::MixedTypeChecks);
+    }
+    let expected = type_name::<T>();
+    let size = u32::from_le_bytes(tag[4..8].try_into().unwrap());
+    let hash = u64::from_le_bytes(tag[8..].try_into().unwrap());
+    if hash != name_hash(expected) || size as usize != size_of::<T>() {
+        return Err(BufError::TypeMismatch {
+            expected,
+            found: name_for(hash),
+        });
+    }
+    Ok(())
+}
+
+/// The name behind `hash`, if this process has tagged that type, or else the hash itself.
+fn name_for(hash: u64) -> Cow<'static, str> {
+    let names = NAMES.lock().unwrap_or_else(|e| e.into_inner());
+    match names.as_ref().and_then(|names| names.get(&hash)) {
+        Some(&name) => Cow::Borrowed(name),
+        None => Cow::Owned(format!("type whose name hashes to {hash:#018x}")),
+    }
+}
+
+/// Whether `bytes` start with what looks like the tag of a `size`-byte value: the magic byte,
+/// zeroed padding and that size. Bare values can start that way too, but it's unlikely, and it
+/// doesn't depend on what anything else has written.
+pub(crate) fn looks_tagged(bytes: &[u8], size: usize) -> bool {
+    bytes.len() >= TAG_LEN
+        && bytes[0] == MAGIC
+        && bytes[1..4] == [0; 3]
+        && u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize == size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Error, Pod, RingBuf};
+    use super::*;
+
+    fn checked() -> RingBuf {
+        RingBuf::builder().typed_checks(true).build().unwrap()
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[repr(C)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    unsafe impl Pod for Point {}
+
+    #[test]
+    fn matched_round_trip() {
+        let mut ring = checked();
+        ring.write_typed(Point { x: 1, y: -2 }).unwrap();
+        ring.write_typed(7u64).unwrap();
+        assert_eq!(ring.len(), 2 * TAG_LEN + 16);
+        assert_eq!(ring.read_typed::<Point>().unwrap(), Point { x: 1, y: -2 });
+        assert_eq!(ring.read_typed::<u64>().unwrap(), 7);
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn mismatched_types() {
+        let mut ring = checked();
+        ring.write_typed(1.5f32).unwrap();
+        let err = ring.read_typed::<u16>().unwrap_err();
+        assert!(matches!(
+            &err,
+            Error::Ours(BufError::TypeMismatch {
+                expected: "u16",
+                found
+            }) if found == "f32"
+        ));
+        assert_eq!(err.to_string(), "Expected a u16 but found a f32!");
+        // Nothing was consumed, so the right type still reads fine.
+        assert_eq!(ring.read_typed::<f32>().unwrap(), 1.5);
+    }
+
+    #[test]
+    fn same_size_different_name() {
+        let mut ring = checked();
+        ring.write_typed(Point { x: 3, y: 4 }).unwrap();
+        assert!(matches!(
+            ring.read_typed::<u64>(),
+            Err(Error::Ours(BufError::TypeMismatch {
+                expected: "u64",
+                ..
+            }))
+        ));
+        assert!(matches!(
+            ring.read_typed::<[i32; 2]>(),
+            Err(Error::Ours(BufError::TypeMismatch { .. }))
+        ));
+        assert_eq!(ring.read_typed::<Point>().unwrap(), Point { x: 3, y: 4 });
+    }
+
+    #[test]
+    fn mixed_modes_are_detected() {
+        let mut unchecked = RingBuf::builder().typed_checks(false).build().unwrap();
+        unchecked.write_typed(5u32).unwrap();
+        assert_eq!(unchecked.len(), 4);
+        assert_eq!(unchecked.read_typed::<u32>().unwrap(), 5);
+
+        // Untagged bytes going into a checked reader.
+        let mut ring = checked();
+        ring.write(&[0; 32]).unwrap();
+        assert!(matches!(
+            ring.read_typed::<u32>(),
+            Err(Error::Ours(BufError::MixedTypeChecks))
+        ));
+
+        // Tagged bytes going into an unchecked reader.
+        let mut tagged = checked();
+        tagged.write_typed(5u32).unwrap();
+        unchecked.write(tagged.read(TAG_LEN + 4).unwrap()).unwrap();
+        assert!(matches!(
+            unchecked.read_typed::<u32>(),
+            Err(Error::Ours(BufError::MixedTypeChecks))
+        ));
+        let mut out = [0u32; 1];
+        assert!(matches!(
+            unchecked.read_slice_into(&mut out),
+            Err(Error::Ours(BufError::MixedTypeChecks))
+        ));
+        assert_eq!(unchecked.len(), TAG_LEN + 4);
+    }
+
+    #[test]
+    fn bare_bytes_that_look_like_a_tag() {
+        // A bare value that starts like a tag for its own size can't be told apart from a tagged
+        // one, so an unchecked reader turns it down; the byte API still gets it out.
+        let mut lookalike = [0u8; TAG_LEN];
+        lookalike[0] = MAGIC;
+        lookalike[4] = TAG_LEN as u8;
+        let mut unchecked = RingBuf::builder().typed_checks(false).build().unwrap();
+        unchecked.write_typed(lookalike).unwrap();
+        assert!(matches!(
+            unchecked.read_typed::<[u8; TAG_LEN]>(),
+            Err(Error::Ours(BufError::MixedTypeChecks))
+        ));
+        assert_eq!(unchecked.read(TAG_LEN).unwrap(), lookalike);
+
+        // The magic byte with another size isn't enough, even for a real tag.
+        let near_miss = tag_for::<u16>();
+        unchecked.write_typed(near_miss).unwrap();
+        assert_eq!(unchecked.read_typed::<[u8; TAG_LEN]>().unwrap(), near_miss);
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[repr(C)]
+    struct OnlyThePeerWritesThis(u32);
+
+    unsafe impl Pod for OnlyThePeerWritesThis {}
+
+    /// A tag as another process would have written it.
+    fn peer_tag(name: &str, size: u32) -> Vec<u8> {
+        let mut tag = vec![MAGIC, 0, 0, 0];
+        tag.extend(size.to_le_bytes());
+        tag.extend(name_hash(name).to_le_bytes());
+        tag
+    }
+
+    #[test]
+    fn tags_from_another_process() {
+        let mut ring = checked();
+        ring.write(&peer_tag(type_name::<OnlyThePeerWritesThis>(), 4))
+            .unwrap();
+        ring.write(&5u32.to_le_bytes()).unwrap();
+        assert_eq!(
+            ring.read_typed::<OnlyThePeerWritesThis>().unwrap(),
+            OnlyThePeerWritesThis(5)
+        );
+
+        // A type this process has never tagged is named by its hash.
+        ring.write(&peer_tag("peer::Secret", 4)).unwrap();
+        ring.write(&5u32.to_le_bytes()).unwrap();
+        let Err(Error::Ours(BufError::TypeMismatch { expected, found })) = ring.read_typed::<u32>()
+        else {
+            panic!("Should be a mismatch.");
+        };
+        assert_eq!(expected, "u32");
+        assert_eq!(
+            found,
+            format!(
+                "type whose name hashes to {:#018x}",
+                name_hash("peer::Secret")
+            )
+        );
+    }
+}